@@ -0,0 +1,160 @@
+#![feature(const_fn, const_panic, const_alloc_layout)]
+#![feature(const_mut_refs, const_type_name)]
+
+// Benchmarks catnip as a server: Bob accepts a connection and writes back everything he reads on it, while Alice
+// keeps sending so data flows both ways on the connection at once. Both engines run in this process over a
+// loopback pair, so this measures the stack itself rather than any network, and there's no separate client to point
+// at it.
+//
+// Usage: loopback_echo [port] [buffer size] [total bytes]
+//
+// Alice sends in pushes of `buffer size` bytes until `total bytes` have gone, and the throughput reported counts
+// each byte once, when it's arrived back at Alice.
+
+use catnip::{
+    engine::Engine,
+    loopback::{
+        self,
+        LoopbackRuntime,
+    },
+    protocols::{
+        ip,
+        ipv4,
+        tcp::operations::{
+            PopFuture,
+            PushFuture,
+        },
+    },
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+    test_helpers::{
+        ALICE_IPV4,
+        ALICE_MAC,
+        BOB_IPV4,
+        BOB_MAC,
+    },
+};
+use futures::future;
+use std::{
+    cmp,
+    convert::TryFrom,
+    env,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    task::Poll,
+    time::Instant,
+};
+
+const DEFAULT_PORT: u16 = 7;
+const DEFAULT_BUFFER_SIZE: usize = 4096;
+const DEFAULT_LEN: usize = 64 * 1024 * 1024;
+
+enum Event {
+    Pushed,
+    BobPopped(Bytes),
+    AlicePopped(Bytes),
+}
+
+fn arg<T: FromStr>(args: &[String], i: usize, default: T) -> T {
+    match args.get(i) {
+        Some(s) => s
+            .parse()
+            .unwrap_or_else(|_| panic!("Usage: loopback_echo [port] [buffer size] [total bytes]")),
+        None => default,
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let port = ip::Port::try_from(arg(&args, 0, DEFAULT_PORT)).expect("Port must be nonzero");
+    let buffer_size = arg(&args, 1, DEFAULT_BUFFER_SIZE);
+    let len = arg(&args, 2, DEFAULT_LEN);
+    assert!(buffer_size > 0, "Buffer size must be positive");
+
+    let (alice_rt, bob_rt) = LoopbackRuntime::pair(Instant::now(), (ALICE_MAC, ALICE_IPV4), (BOB_MAC, BOB_IPV4));
+    let mut alice = Engine::new(alice_rt).unwrap();
+    let mut bob = Engine::new(bob_rt).unwrap();
+
+    let listen_addr = ipv4::Endpoint::new(BOB_IPV4, port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let accept_future = bob.tcp_accept(listen_fd);
+
+    let alice_fd = alice.tcp_socket();
+    let connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    loopback::run_until(&mut alice, &mut bob, connect_future).unwrap();
+    let (bob_fd, remote) = loopback::run_until(&mut alice, &mut bob, accept_future).unwrap();
+    println!("Accepted a connection from {:?} on port {:?}", remote, port);
+
+    let chunk = BytesMut::from(&vec![0x5a; buffer_size][..]).freeze();
+    let start = Instant::now();
+    let (mut sent, mut echoed, mut received) = (0, 0, 0);
+    // Every operation stays outstanding until it completes, so that a push that doesn't fit in the send buffer
+    // can't hold up the reads that would make room for it.
+    let mut alice_push: Option<PushFuture<LoopbackRuntime>> = None;
+    let mut alice_pop: Option<PopFuture<LoopbackRuntime>> = None;
+    let mut bob_push: Option<PushFuture<LoopbackRuntime>> = None;
+    let mut bob_pop: Option<PopFuture<LoopbackRuntime>> = None;
+    while received < len {
+        if alice_push.is_none() && sent < len {
+            let n = cmp::min(buffer_size, len - sent);
+            alice_push = Some(alice.tcp_push(alice_fd, chunk.clone().split(n).0));
+            sent += n;
+        }
+        // Bob only reads once he's handed back what he last read, so it goes back in order.
+        if bob_pop.is_none() && bob_push.is_none() && echoed < len {
+            bob_pop = Some(bob.tcp_pop(bob_fd));
+        }
+        if alice_pop.is_none() {
+            alice_pop = Some(alice.tcp_pop(alice_fd));
+        }
+
+        let event = loopback::run_until(
+            &mut alice,
+            &mut bob,
+            future::poll_fn(|ctx| {
+                if let Some(Poll::Ready(r)) = alice_push.as_mut().map(|f| Pin::new(f).poll(ctx)) {
+                    r.unwrap();
+                    alice_push = None;
+                    return Poll::Ready(Event::Pushed);
+                }
+                if let Some(Poll::Ready(r)) = bob_push.as_mut().map(|f| Pin::new(f).poll(ctx)) {
+                    r.unwrap();
+                    bob_push = None;
+                    return Poll::Ready(Event::Pushed);
+                }
+                if let Some(Poll::Ready(r)) = bob_pop.as_mut().map(|f| Pin::new(f).poll(ctx)) {
+                    bob_pop = None;
+                    return Poll::Ready(Event::BobPopped(r.unwrap()));
+                }
+                if let Some(Poll::Ready(r)) = alice_pop.as_mut().map(|f| Pin::new(f).poll(ctx)) {
+                    alice_pop = None;
+                    return Poll::Ready(Event::AlicePopped(r.unwrap()));
+                }
+                Poll::Pending
+            }),
+        );
+        match event {
+            Event::Pushed => (),
+            Event::BobPopped(segment) => {
+                echoed += segment.len();
+                bob_push = Some(bob.tcp_push(bob_fd, segment));
+            },
+            Event::AlicePopped(segment) => received += segment.len(),
+        }
+    }
+    let elapsed = start.elapsed();
+
+    alice.tcp_close(alice_fd).unwrap();
+    bob.tcp_close(bob_fd).unwrap();
+    println!(
+        "Echoed {} bytes in {:?}: {:.2} MB/s",
+        received,
+        elapsed,
+        received as f64 / elapsed.as_secs_f64() / 1e6
+    );
+}
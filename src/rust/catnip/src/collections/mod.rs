@@ -5,6 +5,7 @@ pub mod async_map;
 pub mod bytes;
 pub mod hashttlcache;
 pub mod waker_page;
+pub mod waker_set;
 pub mod watched;
 
 pub use hashttlcache::HashTtlCache;
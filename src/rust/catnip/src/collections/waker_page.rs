@@ -63,6 +63,12 @@ impl WakerPage {
         notified
     }
 
+    // Put back notifications taken with `take_notified` that we didn't get around to polling. The root waker
+    // isn't woken again, since whoever took them already knows there's work left.
+    pub fn restore_notified(&self, notified: u64) {
+        self.notified.fetch_or(notified);
+    }
+
     pub fn has_completed(&self, ix: usize) -> bool {
         debug_assert!(ix < 64);
         self.completed.load() & (1 << ix) != 0
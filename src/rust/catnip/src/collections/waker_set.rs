@@ -0,0 +1,57 @@
+use std::{
+    cell::RefCell,
+    task::Waker,
+};
+
+// Everyone waiting on the same condition. Unlike a single waker slot, a second waiter doesn't push out the first,
+// so each of them hears about the change.
+#[derive(Debug, Default)]
+pub struct WakerSet {
+    wakers: RefCell<Vec<Waker>>,
+}
+
+impl WakerSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds `waker` unless it's already waiting, so a future that's polled repeatedly only takes up one entry.
+    pub fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.borrow_mut();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    pub fn wake_all(&self) {
+        // Take them all first, since waking one may poll it straight away and register it again.
+        let wakers = self.wakers.replace(vec![]);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WakerSet;
+    use crate::test_helpers::CountingWaker;
+
+    #[test]
+    fn test_wakes_every_waiter_once() {
+        let first = CountingWaker::new();
+        let second = CountingWaker::new();
+        let set = WakerSet::new();
+        set.register(&first.waker());
+        set.register(&first.waker());
+        set.register(&second.waker());
+
+        set.wake_all();
+        assert_eq!(first.count(), 1);
+        assert_eq!(second.count(), 1);
+
+        // Waking takes them out of the set.
+        set.wake_all();
+        assert_eq!(first.count(), 1);
+    }
+}
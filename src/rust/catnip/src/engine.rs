@@ -17,11 +17,22 @@ use crate::{
             Ethernet2Header,
         },
         ipv4,
+        tcp::congestion_ctrl::{
+            self as cc,
+            CongestionControlConstructor,
+            CwndObserver,
+        },
+        tcp::{
+            CloseReason,
+            ConnectionState,
+        },
         tcp::operations::{
             AcceptFuture,
             ConnectFuture,
+            FlushFuture,
             PopFuture,
             PushFuture,
+            PushvFuture,
         },
         udp::peer::{
             PopFuture as UdpPopFuture,
@@ -32,15 +43,30 @@ use crate::{
     scheduler::Operation,
     sync::Bytes,
 };
+use futures::task::noop_waker_ref;
 use std::{
+    collections::vec_deque,
     future::Future,
     net::Ipv4Addr,
-    time::Duration,
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
+#[cfg(feature = "async-io")]
+use crate::protocols::tcp::io::TcpStream;
+#[cfg(feature = "fault-injection")]
+use crate::protocols::tcp::AckFaults;
 #[cfg(test)]
 use crate::protocols::ethernet2::MacAddress;
 #[cfg(test)]
+use crate::protocols::tcp::segment::TcpHeader;
+#[cfg(test)]
 use hashbrown::HashMap;
 
 pub struct Engine<RT: Runtime> {
@@ -49,6 +75,40 @@ pub struct Engine<RT: Runtime> {
     ipv4: ipv4::Peer<RT>,
 
     file_table: FileTable,
+    drop_stats: DropStats,
+}
+
+// How many received frames the engine has dropped, by the `Fail` that `receive` returned for them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DropStats {
+    // Frames that didn't parse, or failed a checksum.
+    pub malformed: usize,
+    // Frames sent to our link address, but with someone else's IP address on them.
+    pub misdelivered: usize,
+    // Frames that parsed but had nothing to do, such as ones for another link address or a port nobody is
+    // listening on.
+    pub ignored: usize,
+    // Frames using a protocol or option we don't implement.
+    pub unsupported: usize,
+    // Frames dropped for any other reason, such as a SYN turned away because the listener's backlog is full.
+    pub other: usize,
+}
+
+impl DropStats {
+    fn record(&mut self, fail: &Fail) {
+        let count = match fail {
+            Fail::Malformed { .. } => &mut self.malformed,
+            Fail::Misdelivered {} => &mut self.misdelivered,
+            Fail::Ignored { .. } => &mut self.ignored,
+            Fail::Unsupported { .. } => &mut self.unsupported,
+            _ => &mut self.other,
+        };
+        *count += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        self.malformed + self.misdelivered + self.ignored + self.unsupported + self.other
+    }
 }
 
 pub enum Protocol {
@@ -58,6 +118,9 @@ pub enum Protocol {
 
 impl<RT: Runtime> Engine<RT> {
     pub fn new(rt: RT) -> Result<Self, Fail> {
+        // Options set directly on the struct never went through the `try_` setters, so check them before anything
+        // relies on them.
+        rt.tcp_options().validate()?;
         let now = rt.now();
         let file_table = FileTable::new();
         let arp = arp::Peer::new(now, rt.clone())?;
@@ -67,6 +130,7 @@ impl<RT: Runtime> Engine<RT> {
             arp,
             ipv4,
             file_table,
+            drop_stats: DropStats::default(),
         })
     }
 
@@ -75,7 +139,21 @@ impl<RT: Runtime> Engine<RT> {
     }
 
     pub fn receive(&mut self, bytes: Bytes) -> Result<(), Fail> {
+        let now = self.rt.now();
+        self.receive_at(bytes, now)
+    }
+
+    // Like `receive`, for a frame the runtime took off the wire at `arrival`.
+    pub fn receive_at(&mut self, bytes: Bytes, arrival: Instant) -> Result<(), Fail> {
         let _s = static_span!();
+        let r = self.receive_frame(bytes, arrival);
+        if let Err(ref e) = r {
+            self.drop_stats.record(e);
+        }
+        r
+    }
+
+    fn receive_frame(&mut self, bytes: Bytes, arrival: Instant) -> Result<(), Fail> {
         let (header, payload) = Ethernet2Header::parse(bytes)?;
         if self.rt.local_link_addr() != header.dst_addr && !header.dst_addr.is_broadcast() {
             return Err(Fail::Ignored {
@@ -84,7 +162,32 @@ impl<RT: Runtime> Engine<RT> {
         }
         match header.ether_type {
             EtherType2::Arp => self.arp.receive(payload),
-            EtherType2::Ipv4 => self.ipv4.receive(payload),
+            EtherType2::Ipv4 => self.ipv4.receive(payload, arrival),
+        }
+    }
+
+    // Every frame `receive` has returned an error for so far.
+    pub fn drop_stats(&self) -> DropStats {
+        self.drop_stats
+    }
+
+    // Drives the engine until `future` completes: runs background work, takes in every frame the runtime has
+    // received and keeps the runtime's clock in step with `Instant::now()`, polling `future` after each round.
+    // This busy-polls, so it's meant for the top-level loop of an application that has nothing else to do.
+    pub fn run_until<F: Future>(&mut self, future: F) -> F::Output {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        futures::pin_mut!(future);
+        loop {
+            if let Poll::Ready(r) = Future::poll(future.as_mut(), &mut ctx) {
+                return r;
+            }
+            self.rt.scheduler().poll();
+            while let Some((pkt, arrival)) = self.rt.receive() {
+                if let Err(e) = self.receive_at(pkt, arrival) {
+                    warn!("Dropped packet: {:?}", e);
+                }
+            }
+            self.rt.advance_clock(Instant::now());
         }
     }
 
@@ -223,18 +326,146 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.tcp.push(socket_fd, buf)
     }
 
+    // Pushes `buf` as urgent data: the remote is told where it ends as soon as possible, regardless of how much
+    // unread data is ahead of it.
+    pub fn tcp_push_urgent(&mut self, socket_fd: FileDescriptor, buf: Bytes) -> PushFuture<RT> {
+        self.ipv4.tcp.push_urgent(socket_fd, buf)
+    }
+
+    // How far ahead the last byte of urgent data is in what's left to read, if any of it's still unread.
+    pub fn tcp_urgent_offset(&self, socket_fd: FileDescriptor) -> Result<Option<usize>, Fail> {
+        self.ipv4.tcp.urgent_offset(socket_fd)
+    }
+
+    // How many bytes the application can read from the socket right now.
+    pub fn tcp_bytes_available(&self, socket_fd: FileDescriptor) -> Result<usize, Fail> {
+        self.ipv4.tcp.bytes_available(socket_fd)
+    }
+
+    // Whether a pop on the socket would complete right away, either with data or because the remote has closed its
+    // side and everything before its FIN has been read.
+    pub fn tcp_is_readable(&self, socket_fd: FileDescriptor) -> Result<bool, Fail> {
+        self.ipv4.tcp.is_readable(socket_fd)
+    }
+
+    // How much of the socket's send buffer is taken up by data that's unsent or unacknowledged. Pushes wait once it
+    // reaches `TcpOptions::send_buffer_size`, e.g. while the remote isn't reading.
+    pub fn tcp_send_buffer_used(&self, socket_fd: FileDescriptor) -> Result<usize, Fail> {
+        self.ipv4.tcp.send_buffer_used(socket_fd)
+    }
+
+    // Uses `congestion_ctrl_type` with `congestion_ctrl_options` for this socket's connection, or for every
+    // connection it accepts, instead of the engine-wide `TcpOptions` defaults. Must be called before connecting or
+    // listening.
+    pub fn tcp_set_congestion_ctrl(
+        &mut self,
+        socket_fd: FileDescriptor,
+        congestion_ctrl_type: CongestionControlConstructor,
+        congestion_ctrl_options: Option<cc::Options>,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_congestion_ctrl(socket_fd, congestion_ctrl_type, congestion_ctrl_options)
+    }
+
+    // Calls `observer` with the new congestion window, ssthresh and the reason for the change every time the
+    // connection's congestion window changes.
+    pub fn tcp_set_cwnd_observer(&mut self, socket_fd: FileDescriptor, observer: CwndObserver) -> Result<(), Fail> {
+        self.ipv4.tcp.set_cwnd_observer(socket_fd, observer)
+    }
+
+    // Limits how many bytes per second the connection sends, pacing segments out once the limit's reached. `None`
+    // lifts the limit.
+    pub fn tcp_set_send_rate_limit(&mut self, socket_fd: FileDescriptor, rate: Option<u64>) -> Result<(), Fail> {
+        self.ipv4.tcp.set_send_rate_limit(socket_fd, rate)
+    }
+
+    // Limits how many bytes per second the application can pop off the connection. Since unread data holds back
+    // the advertised window, this slows the remote down as well.
+    pub fn tcp_set_recv_rate_limit(&mut self, socket_fd: FileDescriptor, rate: Option<u64>) -> Result<(), Fail> {
+        self.ipv4.tcp.set_recv_rate_limit(socket_fd, rate)
+    }
+
+    // Fault injection: drops or holds back the ACKs the connection on `socket_fd` sends, to see how the remote's
+    // sender copes with a peer that's slow to acknowledge data. `None` goes back to ACKing normally.
+    #[cfg(feature = "fault-injection")]
+    pub fn tcp_set_ack_faults(&mut self, socket_fd: FileDescriptor, faults: Option<AckFaults>) -> Result<(), Fail> {
+        self.ipv4.tcp.set_ack_faults(socket_fd, faults)
+    }
+
+    // Moves the connection on `socket_fd` to new local and remote IPv4 addresses, e.g. after the host has moved to
+    // another network, without resetting it. The remote has to be told to follow with a rebind of its own, and the
+    // runtime to answer for the new local address.
+    pub fn tcp_rebind(&mut self, socket_fd: FileDescriptor, local_addr: Ipv4Addr, remote_addr: Ipv4Addr) -> Result<(), Fail> {
+        self.ipv4.tcp.rebind(socket_fd, local_addr, remote_addr)
+    }
+
+    // Pushes a message made up of several buffers without concatenating them first.
+    pub fn tcp_pushv(&mut self, socket_fd: FileDescriptor, bufs: &[Bytes]) -> PushvFuture<RT> {
+        self.ipv4.tcp.pushv(socket_fd, bufs)
+    }
+
+    // Resolves once everything pushed so far has been sent at least once. Unlike flushing a `TcpStream`, this doesn't
+    // wait for the remote to acknowledge it, so it marks e.g. the point a request is on its way.
+    pub fn tcp_flush(&mut self, socket_fd: FileDescriptor) -> FlushFuture<RT> {
+        self.ipv4.tcp.flush(socket_fd)
+    }
+
     pub fn tcp_pop(&mut self, socket_fd: FileDescriptor) -> PopFuture<RT> {
         self.ipv4.tcp.pop(socket_fd)
     }
 
+    // Looks at everything received on the connection without copying or consuming it. Pair with `tcp_consume`
+    // once the caller knows how much of it it's done with.
+    pub fn tcp_peek_segments(&self, socket_fd: FileDescriptor) -> Result<vec_deque::IntoIter<Bytes>, Fail> {
+        self.ipv4.tcp.peek_segments(socket_fd)
+    }
+
+    pub fn tcp_consume(&mut self, socket_fd: FileDescriptor, amount: usize) -> Result<(), Fail> {
+        self.ipv4.tcp.consume(socket_fd, amount)
+    }
+
     pub fn tcp_close(&mut self, socket_fd: FileDescriptor) -> Result<(), Fail> {
         self.ipv4.tcp.close(socket_fd)
     }
 
+    // Resets the connection on `socket_fd` instead of closing it gracefully. Anything not yet sent or read is
+    // dropped, outstanding operations fail with `ConnectionAborted`, and the remote gets a RST.
+    pub fn tcp_abort(&mut self, socket_fd: FileDescriptor) -> Result<(), Fail> {
+        self.ipv4.tcp.abort(socket_fd)
+    }
+
+    // Wraps an established connection in the `futures` `AsyncRead`/`AsyncWrite` traits.
+    #[cfg(feature = "async-io")]
+    pub fn tcp_stream(&self, socket_fd: FileDescriptor) -> TcpStream<RT> {
+        TcpStream::new(&self.ipv4.tcp, socket_fd)
+    }
+
+    // How long each of the connection's most recently acknowledged segments took from being sent to being
+    // acknowledged, oldest first. Only the last 1024 are kept, and retransmitted segments are left out.
+    pub fn tcp_ack_latencies(&self, socket_fd: FileDescriptor) -> Result<Vec<Duration>, Fail> {
+        self.ipv4.tcp.ack_latencies(socket_fd)
+    }
+
+    // Why the connection on `socket_fd` was torn down, or `None` while it's still up.
+    pub fn tcp_close_reason(&self, socket_fd: FileDescriptor) -> Result<Option<CloseReason>, Fail> {
+        self.ipv4.tcp.close_reason(socket_fd)
+    }
+
+    // Where the connection on `socket_fd` is in RFC 793's state machine, e.g. CLOSE_WAIT once the remote has closed
+    // its side and we haven't.
+    pub fn tcp_connection_state(&self, socket_fd: FileDescriptor) -> Result<ConnectionState, Fail> {
+        self.ipv4.tcp.connection_state(socket_fd)
+    }
+
     pub fn tcp_listen(&mut self, socket_fd: FileDescriptor, backlog: usize) -> Result<(), Fail> {
         self.ipv4.tcp.listen(socket_fd, backlog)
     }
 
+    // Returns the CRC32s of all payload pushed and popped on the connection so far, if the `payload_checksums`
+    // TCP option is enabled.
+    pub fn tcp_payload_checksums(&self, socket_fd: FileDescriptor) -> Result<(u32, u32), Fail> {
+        self.ipv4.tcp.payload_checksums(socket_fd)
+    }
+
     #[cfg(test)]
     pub fn arp_query(&self, ipv4_addr: Ipv4Addr) -> impl Future<Output = Result<MacAddress, Fail>> {
         self.arp.query(ipv4_addr)
@@ -250,6 +481,23 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.tcp_rto(handle)
     }
 
+    // The RTO the connection's RTT samples point to, whether or not it's using it; see `TcpOptions::fixed_rto`.
+    pub fn tcp_rto_estimate(&self, socket_fd: FileDescriptor) -> Result<Duration, Fail> {
+        self.ipv4.tcp.rto_estimate(socket_fd)
+    }
+
+    // Sends a segment on `socket_fd`'s connection without going through its sender; see
+    // `ControlBlock::emit_crafted`.
+    #[cfg(test)]
+    pub fn tcp_emit_crafted(
+        &self,
+        socket_fd: FileDescriptor,
+        craft: impl FnOnce(&mut TcpHeader),
+        data: Bytes,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp_emit_crafted(socket_fd, craft, data)
+    }
+
     #[cfg(test)]
     pub fn export_arp_cache(&self) -> HashMap<Ipv4Addr, MacAddress> {
         self.arp.export_cache()
@@ -260,3 +508,72 @@ impl<RT: Runtime> Engine<RT> {
         self.arp.import_cache(cache)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DropStats,
+        Protocol,
+    };
+    use crate::{
+        fail::Fail,
+        protocols::{
+            ip,
+            ipv4,
+        },
+        sync::BytesMut,
+        test_helpers,
+    };
+    use hashbrown::HashMap;
+    use must_let::must_let;
+    use std::{
+        convert::TryFrom,
+        time::Instant,
+    };
+
+    #[test]
+    fn test_drop_stats() {
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice(now);
+        let mut bob = test_helpers::new_bob(now);
+        let port = ip::Port::try_from(80).unwrap();
+        let bob_fd = bob.socket(Protocol::Udp);
+        bob.bind(bob_fd, ipv4::Endpoint::new(test_helpers::BOB_IPV4, port)).unwrap();
+        let alice_fd = alice.socket(Protocol::Udp);
+        let _ = alice.connect(alice_fd, ipv4::Endpoint::new(test_helpers::BOB_IPV4, port));
+        assert_eq!(bob.drop_stats(), DropStats::default());
+
+        let buf = BytesMut::from(&b"abc"[..]).freeze();
+        alice.udp_push(alice_fd, buf.clone()).unwrap();
+        alice.rt().poll_scheduler();
+        let frame = alice.rt().pop_frame();
+
+        // Too short for an Ethernet header.
+        must_let!(let Err(Fail::Malformed { .. }) = bob.receive(BytesMut::from(&frame[..5]).freeze()));
+        // Meant for some other host on the link.
+        let mut misaddressed = BytesMut::from(&frame[..]);
+        misaddressed[..6].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x99]);
+        must_let!(let Err(Fail::Ignored { .. }) = bob.receive(misaddressed.freeze()));
+        // Sent to Bob's link address for Carrie's IP address, as if Alice had a stale ARP entry for her.
+        let mut cache = HashMap::new();
+        cache.insert(test_helpers::CARRIE_IPV4, test_helpers::BOB_MAC);
+        alice.import_arp_cache(cache);
+        let _ = alice.pushto(alice_fd, buf, ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port));
+        alice.rt().poll_scheduler();
+        must_let!(let Err(Fail::Misdelivered {}) = bob.receive(alice.rt().pop_frame()));
+        bob.receive(frame).unwrap();
+
+        let stats = bob.drop_stats();
+        assert_eq!(
+            stats,
+            DropStats {
+                malformed: 1,
+                misdelivered: 1,
+                ignored: 1,
+                ..DropStats::default()
+            }
+        );
+        assert_eq!(stats.total(), 3);
+        assert_eq!(alice.drop_stats(), DropStats::default());
+    }
+}
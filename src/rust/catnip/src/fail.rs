@@ -5,7 +5,10 @@ use custom_error::custom_error;
 use float_duration;
 use std::{
     cell::BorrowMutError,
-    io::Error as IoError,
+    io::{
+        Error as IoError,
+        ErrorKind,
+    },
     num::TryFromIntError,
 };
 
@@ -16,6 +19,7 @@ type Str = &'static str;
 custom_error! {#[derive(Clone)] pub Fail
     ConnectionAborted{} = "connection aborted",
     ConnectionRefused{} = "connection refused",
+    HostUnreachable{} = "host unreachable",
     IoError {} = "IO Error",
     BorrowMutError {} = "BorrowMut Error",
     Ignored{details: Str} = "operation had no effect ({details})",
@@ -37,6 +41,23 @@ impl From<IoError> for Fail {
     }
 }
 
+impl From<Fail> for IoError {
+    fn from(fail: Fail) -> Self {
+        let kind = match fail {
+            Fail::ConnectionAborted {} => ErrorKind::ConnectionAborted,
+            Fail::ConnectionRefused {} => ErrorKind::ConnectionRefused,
+            Fail::Malformed { .. } => ErrorKind::InvalidData,
+            Fail::Misdelivered {} => ErrorKind::AddrNotAvailable,
+            Fail::ResourceBusy { .. } => ErrorKind::AddrInUse,
+            Fail::ResourceNotFound { .. } => ErrorKind::NotFound,
+            Fail::Timeout {} => ErrorKind::TimedOut,
+            Fail::Invalid { .. } | Fail::OutOfRange { .. } => ErrorKind::InvalidInput,
+            _ => ErrorKind::Other,
+        };
+        IoError::new(kind, fail)
+    }
+}
+
 impl From<BorrowMutError> for Fail {
     fn from(_: BorrowMutError) -> Self {
         Fail::BorrowMutError {}
@@ -69,6 +90,7 @@ impl Fail {
         match self {
             Fail::ConnectionAborted {} => libc::ECONNABORTED,
             Fail::ConnectionRefused {} => libc::ECONNREFUSED,
+            Fail::HostUnreachable {} => libc::EHOSTUNREACH,
             Fail::Ignored { .. } => 0,
             Fail::Malformed { .. } => libc::EILSEQ,
             Fail::Misdelivered {} => libc::EHOSTUNREACH,
@@ -3,11 +3,14 @@
 use crate::{
     file_table::FileDescriptor,
     operations::OperationResult,
+    protocols::ipv4,
 };
 use libc::{
     c_int,
     c_void,
+    sa_family_t,
     sockaddr_in,
+    AF_INET,
 };
 use std::{
     mem,
@@ -97,6 +100,16 @@ pub struct dmtr_qresult_t {
     pub qr_value: dmtr_qr_value_t,
 }
 
+// Ports and addresses go in network byte order, the way the C side (and `catnip_libos`) reads them back.
+fn to_sockaddr_in(endpoint: ipv4::Endpoint) -> sockaddr_in {
+    let mut sin: sockaddr_in = unsafe { mem::zeroed() };
+    sin.sin_family = AF_INET as sa_family_t;
+    let port: u16 = endpoint.port.into();
+    sin.sin_port = port.to_be();
+    sin.sin_addr.s_addr = u32::from(endpoint.addr).to_be();
+    sin
+}
+
 impl dmtr_qresult_t {
     pub fn pack(result: OperationResult, qd: FileDescriptor, qt: u64) -> Self {
         match result {
@@ -106,12 +119,11 @@ impl dmtr_qresult_t {
                 qr_qt: qt,
                 qr_value: unsafe { mem::zeroed() },
             },
-            OperationResult::Accept(new_qd) => {
-                let sin = unsafe { mem::zeroed() };
+            OperationResult::Accept(new_qd, remote) => {
                 let qr_value = dmtr_qr_value_t {
                     ares: dmtr_accept_result_t {
                         qd: new_qd as c_int,
-                        addr: sin,
+                        addr: to_sockaddr_in(remote),
                     },
                 };
                 Self {
@@ -130,8 +142,7 @@ impl dmtr_qresult_t {
             OperationResult::Pop(addr, bytes) => {
                 let mut sga = dmtr_sgarray_t::from(&bytes[..]);
                 if let Some(addr) = addr {
-                    sga.sga_addr.sin_port = addr.port.into();
-                    sga.sga_addr.sin_addr.s_addr = u32::from_le_bytes(addr.addr.octets());
+                    sga.sga_addr = to_sockaddr_in(addr);
                 }
                 let qr_value = dmtr_qr_value_t { sga };
                 Self {
@@ -147,3 +158,34 @@ impl dmtr_qresult_t {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dmtr_opcode_t,
+        dmtr_qresult_t,
+    };
+    use crate::{
+        operations::OperationResult,
+        protocols::{
+            ip,
+            ipv4,
+        },
+    };
+    use libc::AF_INET;
+    use std::{
+        convert::TryFrom,
+        net::Ipv4Addr,
+    };
+
+    #[test]
+    fn test_accept_address_byte_order() {
+        let remote = ipv4::Endpoint::new(Ipv4Addr::new(192, 168, 1, 2), ip::Port::try_from(0x1234).unwrap());
+        let r = dmtr_qresult_t::pack(OperationResult::Accept(5, remote), 4, 0);
+        assert_eq!(r.qr_opcode, dmtr_opcode_t::DMTR_OPC_ACCEPT);
+        let sin = unsafe { r.qr_value.ares.addr };
+        assert_eq!(sin.sin_family as i32, AF_INET);
+        assert_eq!(sin.sin_port.to_ne_bytes(), [0x12, 0x34]);
+        assert_eq!(sin.sin_addr.s_addr.to_ne_bytes(), [192, 168, 1, 2]);
+    }
+}
@@ -34,6 +34,7 @@ pub mod file_table;
 pub mod interop;
 pub mod libos;
 pub mod logging;
+pub mod loopback;
 pub mod operations;
 pub mod options;
 pub mod protocols;
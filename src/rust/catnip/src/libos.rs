@@ -197,8 +197,8 @@ impl<RT: Runtime> LibOS<RT> {
     fn poll_bg_work(&mut self) {
         let _s = static_span!();
         self.rt.scheduler().poll();
-        while let Some(pkt) = self.rt.receive() {
-            if let Err(e) = self.engine.receive(pkt) {
+        while let Some((pkt, arrival)) = self.rt.receive() {
+            if let Err(e) = self.engine.receive_at(pkt, arrival) {
                 warn!("Dropped packet: {:?}", e);
             }
         }
@@ -0,0 +1,260 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+// A runtime that connects two engines in the same process through in-memory queues, so catnip-to-catnip tests can
+// run without raw sockets, root or a virtual network. Frames one side transmits are received by the other exactly
+// as sent, in order. Each runtime's clock only moves when it's advanced, so a pair can run in real time with
+// `run_until` or entirely on simulated time with `run_until_simulated`.
+
+use crate::{
+    engine::Engine,
+    protocols::{
+        arp,
+        ethernet2::MacAddress,
+        tcp,
+    },
+    runtime::{
+        PacketBuf,
+        Runtime,
+    },
+    scheduler::{
+        Operation,
+        Scheduler,
+        SchedulerHandle,
+    },
+    sync::Bytes,
+    timer::{
+        Timer,
+        TimerRc,
+    },
+};
+use futures::{
+    task::noop_waker_ref,
+    FutureExt,
+};
+use rand::{
+    distributions::{
+        Distribution,
+        Standard,
+    },
+    rngs::SmallRng,
+    Rng,
+    SeedableRng,
+};
+use std::{
+    cell::RefCell,
+    cmp,
+    collections::VecDeque,
+    future::Future,
+    net::Ipv4Addr,
+    rc::Rc,
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+type FrameQueue = Rc<RefCell<VecDeque<Bytes>>>;
+
+#[derive(Clone)]
+pub struct LoopbackRuntime {
+    inner: Rc<RefCell<Inner>>,
+    scheduler: Scheduler<Operation<LoopbackRuntime>>,
+}
+
+struct Inner {
+    timer: TimerRc,
+    rng: SmallRng,
+    // Shared with the other runtime of the pair, whose `outgoing` is our `incoming` and vice versa.
+    incoming: FrameQueue,
+    outgoing: FrameQueue,
+
+    link_addr: MacAddress,
+    ipv4_addr: Ipv4Addr,
+    tcp_options: tcp::Options,
+    arp_options: arp::Options,
+}
+
+impl LoopbackRuntime {
+    // Creates two runtimes wired to each other, one for each of the given addresses.
+    pub fn pair(now: Instant, a: (MacAddress, Ipv4Addr), b: (MacAddress, Ipv4Addr)) -> (Self, Self) {
+        let a_to_b = Rc::new(RefCell::new(VecDeque::new()));
+        let b_to_a = Rc::new(RefCell::new(VecDeque::new()));
+        let a_rt = Self::new(now, a, b_to_a.clone(), a_to_b.clone());
+        let b_rt = Self::new(now, b, a_to_b, b_to_a);
+        (a_rt, b_rt)
+    }
+
+    fn new(now: Instant, (link_addr, ipv4_addr): (MacAddress, Ipv4Addr), incoming: FrameQueue, outgoing: FrameQueue) -> Self {
+        let inner = Inner {
+            timer: TimerRc(Rc::new(Timer::new(now))),
+            rng: SmallRng::from_seed([0; 16]),
+            incoming,
+            outgoing,
+            link_addr,
+            ipv4_addr,
+            tcp_options: tcp::Options::default(),
+            arp_options: arp::Options::default(),
+        };
+        Self {
+            inner: Rc::new(RefCell::new(inner)),
+            scheduler: Scheduler::new(),
+        }
+    }
+
+    pub fn set_tcp_options(&self, options: tcp::Options) {
+        self.inner.borrow_mut().tcp_options = options;
+    }
+
+    pub fn set_arp_options(&self, options: arp::Options) {
+        self.inner.borrow_mut().arp_options = options;
+    }
+
+    // Moves the runtime to new addresses, e.g. to follow a connection that's been rebound.
+    pub fn set_local_addrs(&self, link_addr: MacAddress, ipv4_addr: Ipv4Addr) {
+        let mut inner = self.inner.borrow_mut();
+        inner.link_addr = link_addr;
+        inner.ipv4_addr = ipv4_addr;
+    }
+
+    // When the next timer set on this runtime goes off, if any are set.
+    pub fn next_timer(&self) -> Option<Instant> {
+        self.inner.borrow().timer.0.next_expiry()
+    }
+}
+
+impl Runtime for LoopbackRuntime {
+    type WaitFuture = crate::timer::WaitFuture<TimerRc>;
+
+    fn transmit(&self, pkt: impl PacketBuf) {
+        let buf = pkt.to_bytes();
+        self.inner.borrow().outgoing.borrow_mut().push_back(buf);
+    }
+
+    fn receive(&self) -> Option<(Bytes, Instant)> {
+        let inner = self.inner.borrow();
+        let buf = inner.incoming.borrow_mut().pop_front()?;
+        Some((buf, inner.timer.0.now()))
+    }
+
+    fn scheduler(&self) -> &Scheduler<Operation<Self>> {
+        &self.scheduler
+    }
+
+    fn local_link_addr(&self) -> MacAddress {
+        self.inner.borrow().link_addr
+    }
+
+    fn local_ipv4_addr(&self) -> Ipv4Addr {
+        self.inner.borrow().ipv4_addr
+    }
+
+    fn tcp_options(&self) -> tcp::Options {
+        self.inner.borrow().tcp_options.clone()
+    }
+
+    fn arp_options(&self) -> arp::Options {
+        self.inner.borrow().arp_options.clone()
+    }
+
+    fn advance_clock(&self, now: Instant) {
+        self.inner.borrow_mut().timer.0.advance_clock(now);
+    }
+
+    fn wait(&self, duration: Duration) -> Self::WaitFuture {
+        let inner = self.inner.borrow_mut();
+        let now = inner.timer.0.now();
+        inner
+            .timer
+            .0
+            .wait_until(inner.timer.clone(), now + duration)
+    }
+
+    fn wait_until(&self, when: Instant) -> Self::WaitFuture {
+        let inner = self.inner.borrow_mut();
+        inner.timer.0.wait_until(inner.timer.clone(), when)
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.borrow().timer.0.now()
+    }
+
+    fn rng_gen<T>(&self) -> T
+    where
+        Standard: Distribution<T>,
+    {
+        let mut inner = self.inner.borrow_mut();
+        inner.rng.gen()
+    }
+
+    fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) -> SchedulerHandle {
+        self.scheduler
+            .insert(Operation::Background(future.boxed_local()))
+    }
+}
+
+// Like `Engine::run_until`, but drives both engines of a loopback pair, since neither gets anywhere while the
+// other isn't taking in its frames. Their clocks follow `Instant::now()`.
+pub fn run_until<F: Future>(a: &mut Engine<LoopbackRuntime>, b: &mut Engine<LoopbackRuntime>, future: F) -> F::Output {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    futures::pin_mut!(future);
+    loop {
+        if let Poll::Ready(r) = Future::poll(future.as_mut(), &mut ctx) {
+            return r;
+        }
+        for engine in &mut [&mut *a, &mut *b] {
+            engine.rt().scheduler().poll();
+            while let Some((pkt, arrival)) = engine.rt().receive() {
+                if let Err(e) = engine.receive_at(pkt, arrival) {
+                    warn!("Dropped packet: {:?}", e);
+                }
+            }
+            // The clock may have been advanced past real time by hand, and it can't go backwards.
+            let now = cmp::max(Instant::now(), engine.rt().now());
+            engine.rt().advance_clock(now);
+        }
+    }
+}
+
+// Like `run_until`, but on simulated time. Both engines run until neither has any work or frames left, and then
+// both clocks jump straight to the earliest timer either of them has set. Nothing depends on how fast the test
+// actually runs, so timing is exactly reproducible: a retransmission fires precisely one RTO after its segment was
+// sent, say. Panics if the future is still waiting once there are no timers left.
+pub fn run_until_simulated<F: Future>(
+    a: &mut Engine<LoopbackRuntime>,
+    b: &mut Engine<LoopbackRuntime>,
+    future: F,
+) -> F::Output {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    futures::pin_mut!(future);
+    assert_eq!(a.rt().now(), b.rt().now(), "Simulated clocks out of step");
+    loop {
+        if let Poll::Ready(r) = Future::poll(future.as_mut(), &mut ctx) {
+            return r;
+        }
+        let mut progress = false;
+        for engine in &mut [&mut *a, &mut *b] {
+            progress |= engine.rt().scheduler().poll_budget(usize::MAX) > 0;
+            while let Some((pkt, arrival)) = engine.rt().receive() {
+                progress = true;
+                if let Err(e) = engine.receive_at(pkt, arrival) {
+                    warn!("Dropped packet: {:?}", e);
+                }
+            }
+        }
+        if progress {
+            continue;
+        }
+        let next = match (a.rt().next_timer(), b.rt().next_timer()) {
+            (Some(x), Some(y)) => cmp::min(x, y),
+            (Some(x), None) | (None, Some(x)) => x,
+            (None, None) => panic!("Nothing left to happen, but the future isn't done"),
+        };
+        a.rt().advance_clock(next);
+        b.rt().advance_clock(next);
+    }
+}
@@ -46,7 +46,7 @@ where
 
 pub enum OperationResult {
     Connect,
-    Accept(FileDescriptor),
+    Accept(FileDescriptor, ipv4::Endpoint),
     Push,
     Pop(Option<ipv4::Endpoint>, Bytes),
     Failed(Fail),
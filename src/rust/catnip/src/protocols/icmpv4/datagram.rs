@@ -7,7 +7,11 @@ use crate::{
             Ethernet2Header,
             MIN_PAYLOAD_SIZE,
         },
-        ipv4::datagram::Ipv4Header,
+        ipv4::datagram::{
+            Ipv4Header,
+            Ipv4Protocol2,
+            IPV4_HEADER2_SIZE,
+        },
     },
     runtime::PacketBuf,
     sync::Bytes,
@@ -18,7 +22,11 @@ use byteorder::{
 };
 use std::{
     cmp,
-    convert::TryInto,
+    convert::{
+        TryFrom,
+        TryInto,
+    },
+    net::Ipv4Addr,
 };
 
 #[allow(unused)]
@@ -90,14 +98,16 @@ pub struct Icmpv4Message {
     pub ethernet2_hdr: Ethernet2Header,
     pub ipv4_hdr: Ipv4Header,
     pub icmpv4_hdr: Icmpv4Header,
-    // TODO: Add a body enum when we need it.
+    // Whatever follows the header, e.g. the start of the datagram an error is about.
+    pub data: Bytes,
 }
 
 impl PacketBuf for Icmpv4Message {
     fn compute_size(&self) -> usize {
         let size = self.ethernet2_hdr.compute_size()
             + self.ipv4_hdr.compute_size()
-            + self.icmpv4_hdr.compute_size();
+            + self.icmpv4_hdr.compute_size()
+            + self.data.len();
 
         // Pad the end of the buffer with zeros if needed.
         cmp::max(size, MIN_PAYLOAD_SIZE)
@@ -113,7 +123,7 @@ impl PacketBuf for Icmpv4Message {
             .serialize(&mut buf[cur_pos..(cur_pos + eth_hdr_size)]);
         cur_pos += eth_hdr_size;
 
-        let ipv4_payload_len = icmpv4_hdr_size;
+        let ipv4_payload_len = icmpv4_hdr_size + self.data.len();
         self.ipv4_hdr.serialize(
             &mut buf[cur_pos..(cur_pos + ipv4_hdr_size)],
             ipv4_payload_len,
@@ -121,9 +131,12 @@ impl PacketBuf for Icmpv4Message {
         cur_pos += ipv4_hdr_size;
 
         self.icmpv4_hdr
-            .serialize(&mut buf[cur_pos..(cur_pos + icmpv4_hdr_size)]);
+            .serialize(&mut buf[cur_pos..(cur_pos + icmpv4_hdr_size)], &self.data);
         cur_pos += icmpv4_hdr_size;
 
+        buf[cur_pos..(cur_pos + self.data.len())].copy_from_slice(&self.data);
+        cur_pos += self.data.len();
+
         // Add Ethernet padding if needed.
         for byte in &mut buf[cur_pos..] {
             *byte = 0;
@@ -167,7 +180,7 @@ impl Icmpv4Header {
         Ok((Self { icmpv4_type, code }, data_buf))
     }
 
-    pub fn serialize(&self, buf: &mut [u8]) {
+    pub fn serialize(&self, buf: &mut [u8], body: &[u8]) {
         let buf: &mut [u8; ICMPV4_HEADER2_SIZE] =
             (&mut buf[..ICMPV4_HEADER2_SIZE]).try_into().unwrap();
         let (type_byte, rest_of_header) = self.icmpv4_type.serialize();
@@ -175,11 +188,66 @@ impl Icmpv4Header {
         buf[1] = self.code;
         // Skip the checksum for now.
         buf[4..8].copy_from_slice(&rest_of_header[..]);
-        let checksum = icmpv4_checksum(buf, &[]);
+        let checksum = icmpv4_checksum(buf, body);
         NetworkEndian::write_u16(&mut buf[2..4], checksum);
     }
 }
 
+// The Destination Unreachable codes (RFC 792) we act on.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Unreachable {
+    Host,
+    Port,
+}
+
+// A Destination Unreachable message about a datagram we sent. RFC 792 has the sender quote back that datagram's IPv4
+// header and at least the first 8 bytes of its payload, which is enough to find its TCP or UDP ports.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Icmpv4Error {
+    pub unreachable: Unreachable,
+    pub protocol: Ipv4Protocol2,
+    pub src_addr: Ipv4Addr,
+    pub dst_addr: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    // The four bytes after the ports, which for TCP are the quoted segment's sequence number.
+    pub seq_num: u32,
+}
+
+impl Icmpv4Error {
+    // Returns `None` for the codes we don't act on.
+    pub fn parse(code: u8, body: Bytes) -> Result<Option<Self>, Fail> {
+        let unreachable = match code {
+            1 => Unreachable::Host,
+            3 => Unreachable::Port,
+            _ => return Ok(None),
+        };
+        // The quoted header is cut short, so it won't pass `Ipv4Header::parse`'s length checks. We only need a few
+        // fields out of it anyway.
+        if body.len() < IPV4_HEADER2_SIZE {
+            return Err(Fail::Malformed {
+                details: "ICMPv4 error too small for quoted IPv4 header",
+            });
+        }
+        let ihl = (body[0] & 0xF) as usize * 4;
+        // RFC 792 has the error quote at least the first 8 bytes of the datagram's payload.
+        if ihl < IPV4_HEADER2_SIZE || body.len() < ihl + 8 {
+            return Err(Fail::Malformed {
+                details: "ICMPv4 error too small for quoted ports and sequence number",
+            });
+        }
+        Ok(Some(Self {
+            unreachable,
+            protocol: Ipv4Protocol2::try_from(body[9])?,
+            src_addr: Ipv4Addr::from(NetworkEndian::read_u32(&body[12..16])),
+            dst_addr: Ipv4Addr::from(NetworkEndian::read_u32(&body[16..20])),
+            src_port: NetworkEndian::read_u16(&body[ihl..(ihl + 2)]),
+            dst_port: NetworkEndian::read_u16(&body[(ihl + 2)..(ihl + 4)]),
+            seq_num: NetworkEndian::read_u32(&body[(ihl + 4)..(ihl + 8)]),
+        }))
+    }
+}
+
 fn icmpv4_checksum(buf: &[u8; ICMPV4_HEADER2_SIZE], body: &[u8]) -> u16 {
     let mut state = 0xffffu32;
     state += NetworkEndian::read_u16(&buf[0..2]) as u32;
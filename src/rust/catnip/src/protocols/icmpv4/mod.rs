@@ -1,7 +1,11 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-mod datagram;
+pub mod datagram;
 mod peer;
 
+pub use datagram::{
+    Icmpv4Error as Error,
+    Unreachable,
+};
 pub use peer::Icmpv4Peer as Peer;
@@ -2,6 +2,7 @@
 // Licensed under the MIT license.
 
 use super::datagram::{
+    Icmpv4Error,
     Icmpv4Header,
     Icmpv4Type2,
 };
@@ -117,6 +118,7 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                         icmpv4_type: Icmpv4Type2::EchoReply { id, seq_num },
                         code: 0,
                     },
+                    data: Bytes::empty(),
                 };
                 rt.transmit(msg);
             };
@@ -129,8 +131,9 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
         }
     }
 
-    pub fn receive(&mut self, ipv4_header: &Ipv4Header, buf: Bytes) -> Result<(), Fail> {
-        let (icmpv4_hdr, _) = Icmpv4Header::parse(buf)?;
+    // Returns any error the message reports about a datagram we sent, for the protocol that sent it to handle.
+    pub fn receive(&mut self, ipv4_header: &Ipv4Header, buf: Bytes) -> Result<Option<Icmpv4Error>, Fail> {
+        let (icmpv4_hdr, data) = Icmpv4Header::parse(buf)?;
         match icmpv4_hdr.icmpv4_type {
             Icmpv4Type2::EchoRequest { id, seq_num } => {
                 self.reply_to_ping(ipv4_header.src_addr, id, seq_num);
@@ -141,11 +144,12 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                     let _ = tx.send(());
                 }
             },
+            Icmpv4Type2::DestinationUnreachable => return Icmpv4Error::parse(icmpv4_hdr.code, data),
             _ => {
                 warn!("Unsupported ICMPv4 message: {:?}", icmpv4_hdr);
             },
         }
-        Ok(())
+        Ok(None)
     }
 
     pub fn ping(
@@ -206,6 +210,7 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                     icmpv4_type: Icmpv4Type2::EchoRequest { id, seq_num },
                     code: 0,
                 },
+                data: Bytes::empty(),
             };
             rt.transmit(msg);
             let rx = {
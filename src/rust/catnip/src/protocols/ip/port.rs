@@ -39,35 +39,57 @@ impl Port {
     }
 }
 
+pub const FIRST_EPHEMERAL_PORT: u16 = FIRST_PRIVATE_PORT;
+pub const LAST_EPHEMERAL_PORT: u16 = 65535;
+
 pub struct EphemeralPorts {
+    first: u16,
+    num_ports: usize,
     bits: BitSet,
 }
 
 impl EphemeralPorts {
-    pub fn new() -> Self {
-        let num_ephemeral = 65535 - FIRST_PRIVATE_PORT;
-        let mut bits = BitSet::with_capacity(num_ephemeral as usize);
-        for i in 0..num_ephemeral {
-            bits.set(i as usize);
+    pub fn new(first: u16, last: u16) -> Self {
+        assert!(first > 0 && first <= last);
+        let num_ports = (last - first) as usize + 1;
+        let mut bits = BitSet::with_capacity(num_ports);
+        for i in 0..num_ports {
+            bits.set(i);
+        }
+        Self {
+            first,
+            num_ports,
+            bits,
         }
-        Self { bits }
     }
 
-    pub fn alloc(&mut self) -> Result<Port, Fail> {
-        match self.bits.iter().next() {
-            Some(i) => {
-                self.bits.clear(i);
-                Ok(Port(
-                    NonZeroU16::new(FIRST_PRIVATE_PORT + i as u16).unwrap(),
-                ))
-            },
-            None => Err(Fail::ResourceExhausted {
-                details: "Out of private ports",
-            }),
+    pub fn contains(&self, port: Port) -> bool {
+        let port = port.0.get();
+        port >= self.first && ((port - self.first) as usize) < self.num_ports
+    }
+
+    // Allocates a free port, searching from `offset` into the range so callers can randomize the choice. Ports
+    // that `in_use` reports as taken (e.g. explicitly bound) are skipped.
+    pub fn alloc(&mut self, offset: usize, mut in_use: impl FnMut(Port) -> bool) -> Result<Port, Fail> {
+        for i in 0..self.num_ports {
+            let ix = (offset + i) % self.num_ports;
+            if !self.bits.test(ix) {
+                continue;
+            }
+            let port = Port(NonZeroU16::new(self.first + ix as u16).unwrap());
+            if in_use(port) {
+                continue;
+            }
+            self.bits.clear(ix);
+            return Ok(port);
         }
+        Err(Fail::ResourceExhausted {
+            details: "Out of ephemeral ports",
+        })
     }
 
     pub fn free(&mut self, port: Port) {
-        self.bits.set((port.0.get() - FIRST_PRIVATE_PORT) as usize)
+        assert!(self.contains(port));
+        self.bits.set((port.0.get() - self.first) as usize)
     }
 }
@@ -6,7 +6,10 @@ use super::datagram::{
     Ipv4Protocol2,
 };
 #[cfg(test)]
-use crate::file_table::FileDescriptor;
+use crate::{
+    file_table::FileDescriptor,
+    protocols::tcp::segment::TcpHeader,
+};
 use crate::{
     fail::Fail,
     file_table::FileTable,
@@ -22,7 +25,10 @@ use crate::{
 use std::{
     future::Future,
     net::Ipv4Addr,
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 pub struct Ipv4Peer<RT: Runtime> {
@@ -45,14 +51,17 @@ impl<RT: Runtime> Ipv4Peer<RT> {
         }
     }
 
-    pub fn receive(&mut self, buf: Bytes) -> Result<(), Fail> {
+    pub fn receive(&mut self, buf: Bytes, arrival: Instant) -> Result<(), Fail> {
         let (header, payload) = Ipv4Header::parse(buf)?;
         if header.dst_addr != self.rt.local_ipv4_addr() && !header.dst_addr.is_broadcast() {
             return Err(Fail::Misdelivered {});
         }
         match header.protocol {
-            Ipv4Protocol2::Icmpv4 => self.icmpv4.receive(&header, payload),
-            Ipv4Protocol2::Tcp => self.tcp.receive(&header, payload),
+            Ipv4Protocol2::Icmpv4 => match self.icmpv4.receive(&header, payload)? {
+                Some(error) if error.protocol == Ipv4Protocol2::Tcp => self.tcp.receive_icmp_error(&error),
+                _ => Ok(()),
+            },
+            Ipv4Protocol2::Tcp => self.tcp.receive(&header, payload, arrival),
             Ipv4Protocol2::Udp => self.udp.receive(&header, payload),
         }
     }
@@ -75,4 +84,8 @@ impl<RT: Runtime> Ipv4Peer<RT> {
     pub fn tcp_rto(&self, fd: FileDescriptor) -> Result<Duration, Fail> {
         self.tcp.current_rto(fd)
     }
+
+    pub fn tcp_emit_crafted(&self, fd: FileDescriptor, craft: impl FnOnce(&mut TcpHeader), data: Bytes) -> Result<(), Fail> {
+        self.tcp.emit_crafted(fd, craft, data)
+    }
 }
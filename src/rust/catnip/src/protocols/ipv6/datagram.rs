@@ -0,0 +1,135 @@
+use crate::{
+    fail::Fail,
+    sync::Bytes,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use num_traits::FromPrimitive;
+use std::{
+    convert::{
+        TryFrom,
+        TryInto,
+    },
+    net::Ipv6Addr,
+};
+
+pub const IPV6_HEADER_SIZE: usize = 40;
+
+pub const DEFAULT_IPV6_HOP_LIMIT: u8 = 64;
+pub const IPV6_VERSION: u8 = 6;
+
+// Unlike IPv4, the "next header" field may name an extension header rather than the upper layer protocol. We
+// don't support extension headers yet, so we only accept the protocols we can hand off directly.
+#[repr(u8)]
+#[derive(FromPrimitive, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Ipv6NextHeader {
+    Tcp = 0x06,
+    Udp = 0x11,
+    Icmpv6 = 0x3a,
+}
+
+impl TryFrom<u8> for Ipv6NextHeader {
+    type Error = Fail;
+
+    fn try_from(n: u8) -> Result<Self, Fail> {
+        match FromPrimitive::from_u8(n) {
+            Some(n) => Ok(n),
+            None => Err(Fail::Unsupported {
+                details: "Unsupported IPv6 next header",
+            }),
+        }
+    }
+}
+
+pub struct Ipv6Header {
+    // [ version 4 bits ] [ traffic class 8 bits ] [ flow label 20 bits ]
+    // As with IPv4, we parse out the version but don't let the user mutate it.
+    // pub version: u8,
+    pub traffic_class: u8,
+    pub flow_label: u32,
+
+    // Omit the payload_length since it's generated on serialization.
+    // pub payload_length: u16,
+    pub next_header: Ipv6NextHeader,
+    pub hop_limit: u8,
+
+    pub src_addr: Ipv6Addr,
+    pub dst_addr: Ipv6Addr,
+}
+
+impl Ipv6Header {
+    pub fn new(src_addr: Ipv6Addr, dst_addr: Ipv6Addr, next_header: Ipv6NextHeader) -> Self {
+        Self {
+            traffic_class: 0,
+            flow_label: 0,
+            next_header,
+            hop_limit: DEFAULT_IPV6_HOP_LIMIT,
+            src_addr,
+            dst_addr,
+        }
+    }
+
+    pub fn compute_size(&self) -> usize {
+        // We don't support extension headers, so this is always 40.
+        IPV6_HEADER_SIZE
+    }
+
+    pub fn parse(buf: Bytes) -> Result<(Self, Bytes), Fail> {
+        if buf.len() < IPV6_HEADER_SIZE {
+            return Err(Fail::Malformed {
+                details: "Datagram too small",
+            });
+        }
+        let (hdr_buf, payload_buf) = buf.split(IPV6_HEADER_SIZE);
+
+        let first_word = NetworkEndian::read_u32(&hdr_buf[0..4]);
+        let version = (first_word >> 28) as u8;
+        if version != IPV6_VERSION {
+            return Err(Fail::Unsupported {
+                details: "Unsupported IP version",
+            });
+        }
+        let traffic_class = (first_word >> 20) as u8;
+        let flow_label = first_word & 0xfffff;
+
+        // As with IPv4, Ethernet may have padded out small payloads, so truncate anything past the payload length.
+        let payload_length = NetworkEndian::read_u16(&hdr_buf[4..6]) as usize;
+        if payload_length > payload_buf.len() {
+            return Err(Fail::Malformed {
+                details: "IPv6 payload length greater than payload",
+            });
+        }
+        let (payload_buf, _padding) = payload_buf.split(payload_length);
+
+        let next_header = Ipv6NextHeader::try_from(hdr_buf[6])?;
+        let hop_limit = hdr_buf[7];
+
+        let src_octets: [u8; 16] = hdr_buf[8..24].try_into().unwrap();
+        let dst_octets: [u8; 16] = hdr_buf[24..40].try_into().unwrap();
+
+        let header = Self {
+            traffic_class,
+            flow_label,
+            next_header,
+            hop_limit,
+            src_addr: Ipv6Addr::from(src_octets),
+            dst_addr: Ipv6Addr::from(dst_octets),
+        };
+        Ok((header, payload_buf))
+    }
+
+    pub fn serialize(&self, buf: &mut [u8], payload_len: usize) {
+        let buf: &mut [u8; IPV6_HEADER_SIZE] = buf.try_into().unwrap();
+        let first_word = (IPV6_VERSION as u32) << 28
+            | (self.traffic_class as u32) << 20
+            | self.flow_label & 0xfffff;
+        NetworkEndian::write_u32(&mut buf[0..4], first_word);
+        NetworkEndian::write_u16(&mut buf[4..6], payload_len as u16);
+        buf[6] = self.next_header as u8;
+        buf[7] = self.hop_limit;
+        buf[8..24].copy_from_slice(&self.src_addr.octets());
+        buf[24..40].copy_from_slice(&self.dst_addr.octets());
+    }
+}
@@ -0,0 +1,25 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::ip;
+use std::net::Ipv6Addr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ipv6Endpoint {
+    pub addr: Ipv6Addr,
+    pub port: ip::Port,
+}
+
+impl Ipv6Endpoint {
+    pub fn new(addr: Ipv6Addr, port: ip::Port) -> Ipv6Endpoint {
+        Ipv6Endpoint { addr, port }
+    }
+
+    pub fn address(&self) -> Ipv6Addr {
+        self.addr
+    }
+
+    pub fn port(&self) -> ip::Port {
+        self.port
+    }
+}
@@ -0,0 +1,10 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+pub mod datagram;
+mod endpoint;
+
+#[cfg(test)]
+mod tests;
+
+pub use endpoint::Ipv6Endpoint as Endpoint;
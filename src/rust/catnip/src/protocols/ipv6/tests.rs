@@ -0,0 +1,42 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::datagram::{
+    Ipv6Header,
+    Ipv6NextHeader,
+    IPV6_HEADER_SIZE,
+};
+use crate::sync::BytesMut;
+use must_let::must_let;
+use std::net::Ipv6Addr;
+
+#[test]
+fn serialize_parse_round_trip() {
+    let src_addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+    let dst_addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2);
+    let mut header = Ipv6Header::new(src_addr, dst_addr, Ipv6NextHeader::Tcp);
+    header.traffic_class = 0xab;
+    header.flow_label = 0xcdef1;
+
+    // Leave some trailing padding after the payload, which parsing should strip off.
+    let payload = b"hello";
+    let mut buf = BytesMut::zeroed(IPV6_HEADER_SIZE + payload.len() + 4);
+    header.serialize(&mut buf[..IPV6_HEADER_SIZE], payload.len());
+    buf[IPV6_HEADER_SIZE..(IPV6_HEADER_SIZE + payload.len())].copy_from_slice(payload);
+
+    must_let!(let Ok((parsed, parsed_payload)) = Ipv6Header::parse(buf.freeze()));
+    assert_eq!(parsed.traffic_class, 0xab);
+    assert_eq!(parsed.flow_label, 0xcdef1);
+    assert_eq!(parsed.next_header, Ipv6NextHeader::Tcp);
+    assert_eq!(parsed.hop_limit, header.hop_limit);
+    assert_eq!(parsed.src_addr, src_addr);
+    assert_eq!(parsed.dst_addr, dst_addr);
+    assert_eq!(&parsed_payload[..], &payload[..]);
+}
+
+#[test]
+fn parse_rejects_ipv4() {
+    let mut buf = BytesMut::zeroed(IPV6_HEADER_SIZE);
+    buf[0] = 0x45;
+    assert!(Ipv6Header::parse(buf.freeze()).is_err());
+}
@@ -1,6 +1,13 @@
 use super::{
-    constants::FALLBACK_MSS,
+    constants::{
+        FALLBACK_MSS,
+        MAX_WINDOW_SCALE,
+    },
     established::state::{
+        congestion_ctrl::{
+            self as cc,
+            CongestionControlConstructor,
+        },
         receiver::Receiver,
         sender::Sender,
         ControlBlock,
@@ -34,7 +41,7 @@ use crate::{
 };
 use std::{
     cell::RefCell,
-    convert::TryInto,
+    cmp,
     future::Future,
     num::Wrapping,
     rc::Rc,
@@ -43,7 +50,7 @@ use std::{
         Poll,
         Waker,
     },
-    time::Duration,
+    time::Instant,
 };
 
 struct ConnectResult<RT: Runtime> {
@@ -60,6 +67,9 @@ pub struct ActiveOpenSocket<RT: Runtime> {
     rt: RT,
     arp: arp::Peer<RT>,
 
+    congestion_ctrl_type: CongestionControlConstructor,
+    congestion_ctrl_options: Option<cc::Options>,
+
     #[allow(unused)]
     handle: SchedulerHandle,
     result: Rc<RefCell<ConnectResult<RT>>>,
@@ -72,6 +82,8 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         remote: ipv4::Endpoint,
         rt: RT,
         arp: arp::Peer<RT>,
+        congestion_ctrl_type: CongestionControlConstructor,
+        congestion_ctrl_options: Option<cc::Options>,
     ) -> Self {
         let result = ConnectResult {
             waker: None,
@@ -97,6 +109,9 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             rt,
             arp,
 
+            congestion_ctrl_type,
+            congestion_ctrl_options,
+
             handle,
             result,
         }
@@ -119,8 +134,30 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         r.result.replace(result);
     }
 
-    pub fn receive(&mut self, header: &TcpHeader) {
-        let max_window_size: u16 = 1024;
+    // Fails the connect with `error`, unless the handshake has already completed. Returns whether it did. All we've
+    // sent is our SYN, so per RFC 5927 Section 4.1 an error quoting any other sequence number is ignored: it's
+    // either stale or forged.
+    pub fn receive_unreachable(&mut self, seq_num: SeqNumber, error: Fail) -> Result<bool, Fail> {
+        if seq_num != self.local_isn {
+            return Err(Fail::Ignored {
+                details: "ICMP error doesn't quote our SYN",
+            });
+        }
+        if let Some(Ok(..)) = self.result.borrow().result {
+            return Ok(false);
+        }
+        self.set_result(Err(error));
+        Ok(true)
+    }
+
+    pub fn receive(&mut self, header: &TcpHeader, data: Bytes, arrival: Instant) {
+        // The handshake may have completed before the connect future had a chance to move the connection over to
+        // `established`, in which case the remote can already be sending us data. Its control block can take it
+        // in the meantime.
+        if let Some(Ok(ref cb)) = self.result.borrow().result {
+            cb.receive(header, data, arrival);
+            return;
+        }
         if header.rst {
             self.set_result(Err(Fail::ConnectionRefused {}));
             return;
@@ -132,16 +169,54 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             return;
         }
 
+        let remote_seq_num = header.seq_num + Wrapping(1);
+        let mut remote_mss = FALLBACK_MSS;
+        let mut remote_window_scale = None;
+        let mut sack_permitted = false;
+        for option in header.iter_options() {
+            match option {
+                TcpOptions2::MaximumSegmentSize(m) => {
+                    remote_mss = *m as usize;
+                },
+                TcpOptions2::WindowScale(s) => {
+                    remote_window_scale = Some(*s);
+                },
+                // We only offered it if it's enabled, but check anyway in case the remote offers it regardless.
+                TcpOptions2::SelectiveAcknowlegementPermitted => {
+                    sack_permitted = self.rt.tcp_options().sack_enabled;
+                },
+                _ => continue,
+            }
+        }
+        // Neither side may send segments larger than the other advertised, so both directions use the smaller.
+        let mss = cmp::min(remote_mss, self.rt.tcp_options().advertised_mss);
+        // RFC 7323 Section 2.2: Window scaling only applies if both sides sent the option, in which case each side's
+        // shift applies to the windows it advertises from here on.
+        let (local_window_scale, remote_window_scale) = match remote_window_scale {
+            Some(s) => (self.rt.tcp_options().receive_window_scale(), cmp::min(s, MAX_WINDOW_SCALE)),
+            None => (0, 0),
+        };
+        // The window in a SYN is never scaled.
+        let window_size = header.window_size as u32;
+        let sender = Sender::new(expected_seq, window_size, remote_window_scale, mss, self.rt.tcp_options().send_buffer_size, self.congestion_ctrl_type, self.congestion_ctrl_options.clone());
+        let receiver = Receiver::new(
+            remote_seq_num,
+            self.rt.tcp_options().receive_window_size as u32,
+            local_window_scale,
+            self.rt.tcp_options().receive_buffer_limit.unwrap_or(self.rt.tcp_options().receive_window_size) as u32,
+            mss,
+            self.rt.tcp_options().effective_ack_ratio(),
+        );
+
         // Acknowledge the SYN+ACK segment.
         let remote_link_addr = match self.arp.try_query(self.remote.address()) {
             Some(r) => r,
             None => panic!("TODO: Clean up ARP query control flow"),
         };
-        let remote_seq_num = header.seq_num + Wrapping(1);
         let mut tcp_hdr = TcpHeader::new(self.local.port, self.remote.port);
         tcp_hdr.ack = true;
         tcp_hdr.ack_num = remote_seq_num;
-        tcp_hdr.window_size = max_window_size;
+        tcp_hdr.window_size = receiver.advertised_window();
         tcp_hdr.seq_num = self.local_isn + Wrapping(1);
 
         let segment = TcpSegment {
@@ -156,39 +231,15 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         };
         self.rt.transmit(segment);
 
-        let mut window_scale = 1;
-        let mut mss = FALLBACK_MSS;
-        for option in header.iter_options() {
-            match option {
-                TcpOptions2::WindowScale(w) => {
-                    window_scale = *w;
-                },
-                TcpOptions2::MaximumSegmentSize(m) => {
-                    mss = *m as usize;
-                },
-                _ => continue,
-            }
-        }
-        let window_size = header
-            .window_size
-            .checked_shl(window_scale as u32)
-            .expect("TODO: Window size overflow")
-            .try_into()
-            .expect("TODO: Window size overflow");
-        let sender = Sender::new(expected_seq, window_size, window_scale, mss, self.rt.tcp_options().congestion_ctrl_type, self.rt.tcp_options().congestion_ctrl_options);
-        let receiver = Receiver::new(
-            remote_seq_num,
-            self.rt.tcp_options().receive_window_size as u32,
-            mss
-        );
-        let cb = ControlBlock {
-            local: self.local.clone(),
-            remote: self.remote.clone(),
-            rt: self.rt.clone(),
-            arp: self.arp.clone(),
+        let cb = ControlBlock::new(
+            self.local.clone(),
+            self.remote.clone(),
+            self.rt.clone(),
+            self.arp.clone(),
             sender,
             receiver,
-        };
+            sack_permitted,
+        );
         self.set_result(Ok(cb));
     }
 
@@ -200,8 +251,8 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         arp: arp::Peer<RT>,
         result: Rc<RefCell<ConnectResult<RT>>>,
     ) -> impl Future<Output = ()> {
-        let handshake_retries = 3usize;
-        let handshake_timeout = Duration::from_secs(5);
+        let handshake_retries = rt.tcp_options().handshake_retries;
+        let mut handshake_timeout = rt.tcp_options().handshake_timeout;
         let max_window_size = 1024;
 
         async move {
@@ -221,6 +272,10 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
 
                 let mss = rt.tcp_options().advertised_mss as u16;
                 tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(mss));
+                tcp_hdr.push_option(TcpOptions2::WindowScale(rt.tcp_options().receive_window_scale()));
+                if rt.tcp_options().sack_enabled {
+                    tcp_hdr.push_option(TcpOptions2::SelectiveAcknowlegementPermitted);
+                }
 
                 let segment = TcpSegment {
                     ethernet2_hdr: Ethernet2Header {
@@ -234,6 +289,8 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
                 };
                 rt.transmit(segment);
                 rt.wait(handshake_timeout).await;
+                // RFC 6298 Section 5.5: Back off the timer for each retransmission.
+                handshake_timeout *= 2;
             }
             let mut r = result.borrow_mut();
             r.waker.take().map(|w| w.wake());
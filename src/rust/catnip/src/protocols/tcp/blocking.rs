@@ -0,0 +1,87 @@
+use super::io::TcpStream;
+use crate::{
+    engine::Engine,
+    file_table::FileDescriptor,
+    protocols::ipv4,
+    runtime::Runtime,
+};
+use futures::io::{
+    AsyncReadExt,
+    AsyncWriteExt,
+};
+use std::io;
+
+// A connection with a blocking interface along the lines of `std::net::TcpStream`, for simple clients that would
+// rather not poll futures themselves. It owns the engine and drives it (see `Engine::run_until`) inside every call
+// until that call can return, so it busy-polls and nothing else on the engine makes progress in between calls.
+pub struct BlockingConnection<RT: Runtime> {
+    engine: Engine<RT>,
+    stream: TcpStream<RT>,
+}
+
+impl<RT: Runtime> BlockingConnection<RT> {
+    pub fn connect(mut engine: Engine<RT>, remote: ipv4::Endpoint) -> io::Result<Self> {
+        let fd = engine.tcp_socket();
+        let connect_future = engine.tcp_connect(fd, remote);
+        engine.run_until(connect_future)?;
+        let stream = engine.tcp_stream(fd);
+        Ok(Self { engine, stream })
+    }
+
+    pub fn fd(&self) -> FileDescriptor {
+        self.stream.fd()
+    }
+
+    pub fn engine(&mut self) -> &mut Engine<RT> {
+        &mut self.engine
+    }
+
+    // Returns once all of `buf` is in the send buffer, not once the remote has it; see `flush` for that.
+    pub fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let Self { engine, stream } = self;
+        engine.run_until(stream.write_all(buf))
+    }
+
+    // Returns as soon as there's any data to read, or 0 once the remote has closed its side and everything before
+    // its FIN has been read.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Self { engine, stream } = self;
+        engine.run_until(stream.read(buf))
+    }
+
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let Self { engine, stream } = self;
+        engine.run_until(stream.read_exact(buf))
+    }
+
+    // Waits for the remote to acknowledge everything written so far.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let Self { engine, stream } = self;
+        engine.run_until(stream.flush())
+    }
+
+    // Flushes and sends our FIN, then hands the engine back. The rest of the close (the remote's FIN and our ACK of
+    // it) only happens while the engine is being driven, so keep driving it if that matters.
+    pub fn close(mut self) -> io::Result<Engine<RT>> {
+        let Self { engine, stream } = &mut self;
+        engine.run_until(stream.close())?;
+        Ok(self.engine)
+    }
+}
+
+impl<RT: Runtime> io::Read for BlockingConnection<RT> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        BlockingConnection::read(self, buf)
+    }
+}
+
+impl<RT: Runtime> io::Write for BlockingConnection<RT> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Self { engine, stream } = self;
+        engine.run_until(stream.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        BlockingConnection::flush(self)
+    }
+}
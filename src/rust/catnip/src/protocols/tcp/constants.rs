@@ -7,3 +7,8 @@ pub const MAX_MSS: usize = u16::max_value() as usize;
 
 // TODO: does this need to be determined through MTU discovery?
 pub const DEFAULT_MSS: usize = 1450;
+
+// RFC 7323 Section 2.3: Shifts past 14 are taken as 14, which puts the largest window that can be advertised at just
+// under 1GB.
+pub const MAX_WINDOW_SCALE: u8 = 14;
+pub const MAX_WINDOW_SIZE: usize = (u16::max_value() as usize) << MAX_WINDOW_SCALE;
@@ -2,7 +2,6 @@ use super::super::state::ControlBlock;
 use crate::{
     fail::Fail,
     runtime::Runtime,
-    sync::Bytes,
 };
 use futures::{
     future::{
@@ -24,6 +23,9 @@ pub async fn acknowledger<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fa
         let (ack_deadline, ack_deadline_changed) = cb.receiver.ack_deadline.watch();
         futures::pin_mut!(ack_deadline_changed);
 
+        #[cfg(feature = "fault-injection")]
+        let ack_deadline = ack_deadline.map(|t| t + cb.receiver.ack_faults.delay());
+
         let ack_future = match ack_deadline {
             Some(t) => Either::Left(cb.rt.wait_until(t).fuse()),
             None => Either::Right(future::pending()),
@@ -33,15 +35,16 @@ pub async fn acknowledger<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fa
         futures::select_biased! {
             _ = ack_deadline_changed => continue,
             _ = ack_future => {
+                // We may be re-ACKing `recv_seq_no` if the peer retransmitted data we'd already received.
                 let recv_seq_no = cb.receiver.recv_seq_no.get();
-                assert!(cb.receiver.ack_seq_no.get() < recv_seq_no);
+                assert!(cb.receiver.ack_seq_no.get() <= recv_seq_no);
 
-                let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
+                let remote_link_addr = cb.arp.query(cb.remote().address()).await?;
 
                 let mut header = cb.tcp_header();
                 header.ack = true;
                 header.ack_num = recv_seq_no;
-                cb.emit(header, Bytes::empty(), remote_link_addr);
+                cb.emit_ack(header, remote_link_addr);
             },
         }
     }
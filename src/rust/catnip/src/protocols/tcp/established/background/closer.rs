@@ -1,6 +1,7 @@
 use super::super::state::{
     receiver::ReceiverState,
     sender::SenderState,
+    CloseReason,
     ControlBlock,
 };
 use crate::{
@@ -12,6 +13,7 @@ use futures::FutureExt;
 use std::{
     num::Wrapping,
     rc::Rc,
+    time::Duration,
 };
 
 async fn rx_ack_sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
@@ -31,8 +33,14 @@ async fn rx_ack_sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail>
             continue;
         }
 
+        // Hold the final ACK back for `trailing_ack_delay`, with zero sending it right away.
+        let trailing_ack_delay = cb.rt.tcp_options().trailing_ack_delay;
+        if trailing_ack_delay > Duration::new(0, 0) {
+            cb.rt.wait(trailing_ack_delay).await;
+        }
+
         // Send ACK segment
-        let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
+        let remote_link_addr = cb.arp.query(cb.remote().address()).await?;
         let mut header = cb.tcp_header();
         header.ack = true;
         header.ack_num = recv_seq + Wrapping(1);
@@ -61,26 +69,68 @@ async fn tx_fin_sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail>
                     continue;
                 }
 
-                // TODO: When do we retransmit this?
-                let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
+                // If this is lost, `fin_retransmitter` sends it again.
+                let remote_link_addr = cb.arp.query(cb.remote().address()).await?;
                 let mut header = cb.tcp_header();
-                header.seq_num = sent_seq + Wrapping(1);
+                // The FIN takes the sequence number right after our data, which the remote's ACK of it then covers.
+                header.seq_num = sent_seq;
                 header.fin = true;
                 cb.emit(header, Bytes::empty(), remote_link_addr);
 
                 cb.sender.state.set(SenderState::SentFin);
             },
             SenderState::Reset => {
-                let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
+                let remote_link_addr = cb.arp.query(cb.remote().address()).await?;
                 let mut header = cb.tcp_header();
+                // RFC 793 Section 3.4: The RST has to be in the remote's window for it to take any notice.
+                header.seq_num = cb.sender.sent_seq_no.get();
                 header.rst = true;
                 cb.emit(header, Bytes::empty(), remote_link_addr);
-                panic!("Close connection here");
+                cb.set_close_reason(CloseReason::ResetSent);
+                return Err(Fail::ConnectionAborted {});
             },
         }
     }
 }
 
+// Our FIN isn't in the unacknowledged queue, so the retransmitter never resends it, whether it went out on its own or
+// with the last of our data. Once everything before it has been acknowledged, resend it on the retransmission timer
+// until the remote acknowledges it too. This is what gets us out of LAST_ACK if the remote's final ACK is lost.
+async fn fin_retransmitter<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+    let mut retransmits = 0;
+    loop {
+        let (sender_st, sender_st_changed) = cb.sender.state.watch();
+        if sender_st != SenderState::SentFin {
+            sender_st_changed.await;
+            continue;
+        }
+        let (base_seq, base_seq_changed) = cb.sender.base_seq_no.watch();
+        let sent_seq = cb.sender.sent_seq_no.get();
+        if base_seq != sent_seq {
+            base_seq_changed.await;
+            continue;
+        }
+
+        let rto = cb.sender.rto.borrow().estimate();
+        futures::select_biased! {
+            _ = sender_st_changed.fuse() => continue,
+            _ = cb.rt.wait(rto).fuse() => (),
+        }
+        retransmits += 1;
+        if retransmits > cb.rt.tcp_options().retries {
+            cb.set_close_reason(CloseReason::RetransmitTimeout);
+            return Err(Fail::Timeout {});
+        }
+        cb.sender.rto.borrow_mut().record_failure();
+
+        let remote_link_addr = cb.arp.query(cb.remote().address()).await?;
+        let mut header = cb.tcp_header();
+        header.seq_num = sent_seq;
+        header.fin = true;
+        cb.emit(header, Bytes::empty(), remote_link_addr);
+    }
+}
+
 async fn close_wait<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
     loop {
         let (sender_st, sender_st_changed) = cb.sender.state.watch();
@@ -95,15 +145,54 @@ async fn close_wait<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
             continue;
         }
 
-        // TODO: Wait for 2*MSL if active close.
+        // RFC 793 Section 3.5: Having closed first, we wait out 2*MSL in TIME_WAIT, so our final ACK can go again if
+        // the remote retransmits its FIN, and so nothing left over from this connection turns up on the next one to
+        // use its endpoints. A retransmitted FIN starts the wait over.
+        let options = cb.rt.tcp_options();
+        if !cb.remote_closed_first() && !options.release_closed_ports {
+            futures::select_biased! {
+                _ = receiver_st_changed.fuse() => continue,
+                _ = cb.rt.wait(2 * options.msl).fuse() => (),
+            }
+        }
+        cb.set_close_reason(CloseReason::Graceful);
         return Err(Fail::ConnectionAborted {});
     }
 }
 
+// Gives up on a remote that goes quiet once we've sent our FIN, whether it never ACKs the FIN or never sends its
+// own, so the connection can't hang around forever. A remote that's still sending us data isn't quiet, so each new
+// segment starts the wait over. Once both FINs have been acknowledged we're in TIME_WAIT, which `close_wait` times.
+async fn fin_wait_timer<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+    loop {
+        let (sender_st, sender_st_changed) = cb.sender.state.watch();
+        if sender_st == SenderState::SentFin || sender_st == SenderState::FinAckd {
+            break;
+        }
+        sender_st_changed.await;
+    }
+    loop {
+        let (_, recv_seq_changed) = cb.receiver.recv_seq_no.watch();
+        futures::select_biased! {
+            _ = recv_seq_changed.fuse() => continue,
+            _ = cb.rt.wait(cb.rt.tcp_options().fin_wait_timeout).fuse() => (),
+        }
+        let time_wait = cb.sender.state.get() == SenderState::FinAckd
+            && cb.receiver.state.get() == ReceiverState::AckdFin;
+        if !time_wait {
+            break;
+        }
+    }
+    cb.set_close_reason(CloseReason::FinWaitTimeout);
+    Err(Fail::Timeout {})
+}
+
 pub async fn closer<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
     futures::select_biased! {
         r = rx_ack_sender(cb.clone()).fuse() => r,
         r = tx_fin_sender(cb.clone()).fuse() => r,
-        r = close_wait(cb).fuse() => r,
+        r = fin_retransmitter(cb.clone()).fuse() => r,
+        r = close_wait(cb.clone()).fuse() => r,
+        r = fin_wait_timer(cb).fuse() => r,
     }
 }
@@ -0,0 +1,53 @@
+use super::super::state::ControlBlock;
+use crate::{
+    fail::Fail,
+    runtime::Runtime,
+};
+use futures::future;
+use log::Level;
+use std::rc::Rc;
+
+// Logs the connection's state transitions at debug level, keyed by connection id and timestamped relative to when
+// the connection was established. This never returns if debug logging is disabled, so it costs nothing beyond the
+// check.
+pub async fn event_logger<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+    if !log_enabled!(Level::Debug) {
+        return future::pending().await;
+    }
+    let start = cb.rt.now();
+    let (local, remote) = cb.four_tuple();
+    debug!("[{:016x}] Established {:?} -> {:?}", cb.connection_id(), local, remote);
+    let (mut sender_st, _) = cb.sender.state.watch();
+    let (mut receiver_st, _) = cb.receiver.state.watch();
+    let (mut fast_recovery, _) = cb.sender.congestion_ctrl.watch_fast_recovery_flag();
+
+    loop {
+        let (new_sender_st, sender_st_changed) = cb.sender.state.watch();
+        futures::pin_mut!(sender_st_changed);
+        let (new_receiver_st, receiver_st_changed) = cb.receiver.state.watch();
+        futures::pin_mut!(receiver_st_changed);
+        let (new_fast_recovery, fast_recovery_changed) = cb.sender.congestion_ctrl.watch_fast_recovery_flag();
+        futures::pin_mut!(fast_recovery_changed);
+
+        let elapsed = cb.rt.now() - start;
+        if new_sender_st != sender_st {
+            debug!("[{:016x}] {:?}: Sender {:?} -> {:?}", cb.connection_id(), elapsed, sender_st, new_sender_st);
+            sender_st = new_sender_st;
+        }
+        if new_receiver_st != receiver_st {
+            debug!("[{:016x}] {:?}: Receiver {:?} -> {:?}", cb.connection_id(), elapsed, receiver_st, new_receiver_st);
+            receiver_st = new_receiver_st;
+        }
+        if new_fast_recovery != fast_recovery {
+            let transition = if new_fast_recovery { "Entered" } else { "Exited" };
+            debug!("[{:016x}] {:?}: {} fast recovery", cb.connection_id(), elapsed, transition);
+            fast_recovery = new_fast_recovery;
+        }
+
+        futures::select_biased! {
+            _ = sender_st_changed => (),
+            _ = receiver_st_changed => (),
+            _ = fast_recovery_changed => (),
+        }
+    }
+}
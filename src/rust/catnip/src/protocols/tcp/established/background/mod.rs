@@ -1,15 +1,20 @@
 mod acknowledger;
 mod closer;
+mod event_logger;
 mod retransmitter;
 mod sender;
 
 use self::{
     acknowledger::acknowledger,
     closer::closer,
+    event_logger::event_logger,
     retransmitter::retransmitter,
     sender::sender,
 };
-use super::state::ControlBlock;
+use super::state::{
+    CloseReason,
+    ControlBlock,
+};
 use crate::runtime::Runtime;
 use futures::FutureExt;
 use std::{
@@ -24,8 +29,24 @@ use std::{
 // 1408: future total
 pub type BackgroundFuture<RT> = impl Future<Output = ()>;
 
+// Resolves once something has torn the connection down, whether one of the tasks below or a RST from the remote.
+async fn torn_down<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> CloseReason {
+    loop {
+        let (reason, reason_changed) = cb.watch_close_reason();
+        if let Some(reason) = reason {
+            return reason;
+        }
+        reason_changed.await;
+    }
+}
+
 pub fn background<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> BackgroundFuture<RT> {
     async move {
+        let connection_id = cb.connection_id();
+
+        let torn_down = torn_down(cb.clone()).fuse();
+        futures::pin_mut!(torn_down);
+
         let acknowledger = acknowledger(cb.clone()).fuse();
         futures::pin_mut!(acknowledger);
 
@@ -35,14 +56,21 @@ pub fn background<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> BackgroundFuture<RT>
         let sender = sender(cb.clone()).fuse();
         futures::pin_mut!(sender);
 
-        let closer = closer(cb).fuse();
+        let closer = closer(cb.clone()).fuse();
         futures::pin_mut!(closer);
 
+        let event_logger = event_logger(cb).fuse();
+        futures::pin_mut!(event_logger);
+
+        // The retransmitter and closer finish when they tear the connection down, having recorded why on the control
+        // block. Once that's happened, from here or from a RST, none of the tasks have anything left to do.
         futures::select_biased! {
+            r = torn_down => debug!("[{:016x}] Connection torn down: {:?}", connection_id, r),
             r = acknowledger => panic!("TODO: {:?}", r),
-            r = retransmitter => panic!("TODO: {:?}", r),
+            r = retransmitter => debug!("[{:016x}] Retransmitter finished: {:?}", connection_id, r),
             r = sender => panic!("TODO: {:?}", r),
-            r = closer => panic!("TODO: {:?}", r),
+            r = closer => debug!("[{:016x}] Closer finished: {:?}", connection_id, r),
+            r = event_logger => panic!("TODO: {:?}", r),
         }
     }
 }
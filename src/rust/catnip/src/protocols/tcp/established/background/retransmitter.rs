@@ -1,4 +1,7 @@
-use super::super::state::ControlBlock;
+use super::super::state::{
+    CloseReason,
+    ControlBlock,
+};
 use crate::{
     fail::Fail,
     runtime::Runtime,
@@ -12,6 +15,7 @@ use futures::{
 };
 use std::rc::Rc;
 
+#[derive(Debug)]
 pub enum RetransmitCause {
     TimeOut,
     FastRetransmit
@@ -20,47 +24,87 @@ pub enum RetransmitCause {
 
 pub async fn retransmit<RT: Runtime>(cause: RetransmitCause, cb: &Rc<ControlBlock<RT>>) -> Result<(), Fail>{
     // Our retransmission timer fired, so we need to resend a packet.
-    let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
-
-    let mut unacked_queue = cb.sender.unacked_queue.borrow_mut();
-    let mut rto = cb.sender.rto.borrow_mut();
+    let remote_link_addr = cb.arp.query(cb.remote().address()).await?;
 
+    // Take what we need from the unacked queue and RTO estimator in a short scope, so neither is still borrowed
+    // when we hand the segment to the runtime.
     let seq_no = cb.sender.base_seq_no.get();
-    let segment = match unacked_queue.front_mut() {
-        Some(s) => s,
-        None => panic!("Retransmission timer set with empty acknowledge queue"),
-    };
+    let (bytes, rto) = {
+        let mut unacked_queue = cb.sender.unacked_queue.try_borrow_mut()?;
+        let mut rto = cb.sender.rto.try_borrow_mut()?;
+        let segment = match unacked_queue.front_mut() {
+            Some(s) => s,
+            None => panic!("Retransmission timer set with empty acknowledge queue"),
+        };
 
-    // TODO: Repacketization
+        // TODO: Repacketization
 
-    // NOTE: Congestion Control Don't think we record a failure on Fast Retransmit, but can't find a definitive source.
-    match cause {
-        RetransmitCause::TimeOut => rto.record_failure(),
-        RetransmitCause::FastRetransmit => ()
-    };
+        // NOTE: Congestion Control Don't think we record a failure on Fast Retransmit, but can't find a definitive source.
+        match cause {
+            RetransmitCause::TimeOut => rto.record_failure(),
+            RetransmitCause::FastRetransmit => ()
+        };
 
-    // Unset the initial timestamp so we don't use this for RTT estimation.
-    segment.initial_tx.take();
+        // Unset the initial timestamp so we don't use this for RTT estimation.
+        segment.initial_tx.take();
+
+        (segment.bytes.clone(), rto.estimate())
+    };
 
+    debug!(
+        "[{:016x}] Retransmitting {} bytes at {} ({:?})",
+        cb.connection_id(),
+        bytes.len(),
+        seq_no,
+        cause
+    );
+    let fast_retransmit = match cause {
+        RetransmitCause::TimeOut => false,
+        RetransmitCause::FastRetransmit => true,
+    };
+    cb.sender.record_retransmit(seq_no, bytes.len(), fast_retransmit);
     let mut header = cb.tcp_header();
     header.seq_num = seq_no;
-    cb.emit(header, segment.bytes.clone(), remote_link_addr);
+    cb.emit(header, bytes, remote_link_addr);
 
     // Set new retransmit deadline
-    let deadline = cb.rt.now() + rto.estimate();
+    let deadline = cb.rt.now() + rto;
     cb.sender.retransmit_deadline.set(Some(deadline));
     Ok(())
 }
 
 pub async fn retransmitter<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+    // A connection that only ever receives never needs the timer, so until the application gives us something to
+    // send, only watch for that. Whatever sends it first also arms `retransmit_deadline`, which we'll pick up below.
+    loop {
+        let (unsent_seq_no, unsent_seq_no_changed) = cb.sender.unsent_seq_no.watch();
+        if unsent_seq_no != cb.sender.base_seq_no.get() {
+            break;
+        }
+        unsent_seq_no_changed.await;
+    }
+
+    // Consecutive timeouts without the remote acknowledging anything new, and where they started from.
+    let mut timeouts = 0;
+    let mut timeout_base_seq_no = cb.sender.base_seq_no.get();
     loop {
         let (rtx_deadline, rtx_deadline_changed) = cb.sender.retransmit_deadline.watch();
         futures::pin_mut!(rtx_deadline_changed);
 
         // I assume any change to the fast retransmit flag is an instruction to transmit, because I use `set_without_notify` to change it
         // back to false (which I am acutely aware is hack...).
-        let (_rtx_fast_retransmit, rtx_fast_retransmit_changed) = cb.sender.congestion_ctrl.watch_retransmit_now_flag();
+        let (rtx_fast_retransmit, rtx_fast_retransmit_changed) = cb.sender.congestion_ctrl.watch_retransmit_now_flag();
         futures::pin_mut!(rtx_fast_retransmit_changed);
+        // The flag may have been raised while we were handling something else, e.g. when a new ACK moves the
+        // deadline and the duplicates behind it arrive before we get to run. By now the remote may have
+        // acknowledged everything, in which case there's nothing left to retransmit.
+        if rtx_fast_retransmit {
+            cb.sender.congestion_ctrl.on_fast_retransmit(&cb.sender);
+            if !cb.sender.unacked_queue.borrow().is_empty() {
+                retransmit(RetransmitCause::FastRetransmit, &cb).await?;
+            }
+            continue;
+        }
 
         let rtx_future = match rtx_deadline {
             Some(t) => Either::Left(cb.rt.wait_until(t).fuse()),
@@ -70,6 +114,16 @@ pub async fn retransmitter<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, F
         futures::select_biased! {
             _ = rtx_deadline_changed => continue,
             _ = rtx_future => {
+                let base_seq_no = cb.sender.base_seq_no.get();
+                if base_seq_no != timeout_base_seq_no {
+                    timeouts = 0;
+                    timeout_base_seq_no = base_seq_no;
+                }
+                timeouts += 1;
+                if timeouts > cb.rt.tcp_options().retries {
+                    cb.set_close_reason(CloseReason::RetransmitTimeout);
+                    return Err(Fail::Timeout {});
+                }
                 cb.sender.congestion_ctrl.on_rto(&cb.sender);
                 retransmit(RetransmitCause::TimeOut, &cb).await?;
             },
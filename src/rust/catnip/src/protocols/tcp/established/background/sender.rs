@@ -1,5 +1,8 @@
 use super::super::state::{
-    sender::UnackedSegment,
+    sender::{
+        SenderState,
+        UnackedSegment,
+    },
     ControlBlock,
 };
 use crate::{
@@ -39,13 +42,13 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
         // If we don't have any window size at all, we need to transition to PERSIST state and
         // repeatedly send window probes until window opens up.
         if win_sz == 0 {
-            let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
+            let remote_link_addr = cb.arp.query(cb.remote().address()).await?;
             let buf = cb
                 .sender
                 .pop_one_unsent_byte()
                 .unwrap_or_else(|| panic!("No unsent data? {}, {}", sent_seq, unsent_seq));
 
-            cb.sender.sent_seq_no.modify(|s| s + Wrapping(1));
+            cb.sender.record_sent(1);
             let unacked_segment = UnackedSegment {
                 bytes: buf.clone(),
                 initial_tx: Some(cb.rt.now()),
@@ -62,11 +65,24 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
             // Note that we loop here *forever*, exponentially backing off.
             // TODO: Use the correct PERSIST state timer here.
             let mut timeout = Duration::from_secs(1);
+            let mut deadline = cb.rt.now() + timeout;
             loop {
+                // Every ACK updates the window, so only stop probing once it actually opens.
+                let (win_sz, win_sz_changed) = cb.sender.window_size.watch();
+                futures::pin_mut!(win_sz_changed);
+                if win_sz > 0 {
+                    // The remote most likely dropped our probe while its window was closed, so resend it ahead of
+                    // the data that follows rather than waiting for it to time out.
+                    let mut header = cb.tcp_header();
+                    header.seq_num = sent_seq;
+                    cb.emit(header, buf.clone(), remote_link_addr);
+                    continue 'top;
+                }
                 futures::select_biased! {
-                    _ = win_sz_changed => continue 'top,
-                    _ = cb.rt.wait(timeout).fuse() => {
+                    _ = win_sz_changed => continue,
+                    _ = cb.rt.wait_until(deadline).fuse() => {
                         timeout *= 2;
+                        deadline = cb.rt.now() + timeout;
                     }
                 }
                 // Retransmit our window probe.
@@ -102,14 +118,34 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
             }
         }
 
+        // Form an outgoing packet.
+        let max_size = cmp::min(cmp::min((win_sz - sent_data) as usize, cb.sender.mss), (effective_cwnd - sent_data) as usize);
+
+        // A congestion controller with a pacing rate spaces segments out rather than sending all of cwnd at once.
+        if let Some(next_paced_send) = cb.sender.next_paced_send.get() {
+            if cb.rt.now() < next_paced_send {
+                cb.rt.wait_until(next_paced_send).await;
+                continue 'top;
+            }
+        }
+
+        // With a rate limit, the segment only gets as many bytes as there are tokens for, and if there aren't
+        // enough for a worthwhile segment yet, we wait for them to build up.
+        let max_size = cmp::min(max_size, cb.sender.bytes_unsent() as usize);
+        let max_size = match cb.sender.rate_limit.try_take(max_size, cb.rt.now()) {
+            Ok(n) => n,
+            Err(ready_at) => {
+                cb.rt.wait_until(ready_at).await;
+                continue 'top;
+            },
+        };
+
         // Past this point we have data to send and it's valid to send it!
 
         // TODO: Nagle's algorithm
         // TODO: Silly window syndrome
-        let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
+        let remote_link_addr = cb.arp.query(cb.remote().address()).await?;
 
-        // Form an outgoing packet.
-        let max_size = cmp::min(cmp::min((win_sz - sent_data) as usize, cb.sender.mss), (effective_cwnd - sent_data) as usize);
         let segment_data = cb
             .sender
             .pop_unsent(max_size)
@@ -121,11 +157,19 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
 
         let mut header = cb.tcp_header();
         header.seq_num = sent_seq;
+        // If we've been closed and this is the last of the data, send our FIN along with it rather than after it.
+        let piggyback_fin = cb.sender.state.get() == SenderState::Closed && cb.sender.unsent_queue.borrow().is_empty();
+        header.fin = piggyback_fin;
         cb.emit(header, segment_data.clone(), remote_link_addr);
+        if piggyback_fin {
+            cb.sender.state.set(SenderState::SentFin);
+        }
 
-        cb.sender
-            .sent_seq_no
-            .modify(|s| s + Wrapping(segment_data_len as u32));
+        cb.sender.record_sent(segment_data_len as u32);
+        let next_paced_send = cb.sender.congestion_ctrl.get_pacing_rate().map(|rate| {
+            cb.rt.now() + Duration::from_secs_f64(segment_data_len as f64 / rate)
+        });
+        cb.sender.next_paced_send.set(next_paced_send);
         let unacked_segment = UnackedSegment {
             bytes: segment_data,
             initial_tx: Some(cb.rt.now()),
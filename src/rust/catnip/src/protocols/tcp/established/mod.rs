@@ -1,33 +1,54 @@
 mod background;
 pub mod state;
 
+#[cfg(feature = "fault-injection")]
+use self::state::ack_faults::AckFaults;
 use self::{
     background::background,
-    state::ControlBlock,
+    state::{
+        congestion_ctrl::CwndObserver,
+        CloseReason,
+        ConnectionState,
+        ControlBlock,
+    },
 };
 use crate::{
     fail::Fail,
     protocols::{
         ipv4,
-        tcp::segment::TcpHeader,
+        tcp::{
+            segment::TcpHeader,
+            SeqNumber,
+        },
     },
     runtime::Runtime,
     scheduler::SchedulerHandle,
     sync::Bytes,
 };
 use std::{
+    cell::RefCell,
+    cmp,
+    collections::{
+        vec_deque,
+        VecDeque,
+    },
     rc::Rc,
     task::{
         Context,
         Poll,
     },
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 pub struct EstablishedSocket<RT: Runtime> {
     pub cb: Rc<ControlBlock<RT>>,
     #[allow(unused)]
     background_work: SchedulerHandle,
+    // Wakes a rate limited `poll_recv` once there are tokens for it to read more.
+    recv_rate_limit_timer: RefCell<Option<SchedulerHandle>>,
 }
 
 impl<RT: Runtime> EstablishedSocket<RT> {
@@ -38,33 +59,198 @@ impl<RT: Runtime> EstablishedSocket<RT> {
         Self {
             cb: cb.clone(),
             background_work: handle,
+            recv_rate_limit_timer: RefCell::new(None),
         }
     }
 
-    pub fn receive(&self, header: &TcpHeader, data: Bytes) {
-        self.cb.receive(header, data)
+    pub fn receive(&self, header: &TcpHeader, data: Bytes, arrival: Instant) {
+        self.cb.receive(header, data, arrival)
+    }
+
+    pub fn poll_send(&self, buf: &mut Bytes, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        self.cb.sender.poll_send(buf, &self.cb, ctx)
+    }
+
+    pub fn poll_send_urgent(&self, buf: &mut Bytes, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        self.cb.sender.poll_send_urgent(buf, &self.cb, ctx)
+    }
+
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.cb.close_reason()
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        self.cb.connection_state()
+    }
+
+    pub fn urgent_offset(&self) -> Option<usize> {
+        self.cb.receiver.urgent_offset()
+    }
+
+    pub fn bytes_available(&self) -> usize {
+        self.cb.receiver.available.get()
     }
 
-    pub fn send(&self, buf: Bytes) -> Result<(), Fail> {
-        self.cb.sender.send(buf, &self.cb)
+    pub fn is_readable(&self) -> bool {
+        self.cb.receiver.is_readable()
+    }
+
+    pub fn send_buffer_used(&self) -> usize {
+        self.cb.sender.send_buffer_used()
+    }
+
+    #[cfg(test)]
+    pub fn emit_crafted(&self, craft: impl FnOnce(&mut TcpHeader), data: Bytes) -> Result<(), Fail> {
+        self.cb.emit_crafted(craft, data)
+    }
+
+    pub fn poll_sendv(&self, bufs: &mut VecDeque<Bytes>, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        self.cb.sender.poll_sendv(bufs, &self.cb, ctx)
+    }
+
+    // Everything the application has handed us so far ends just before this.
+    pub fn unsent_seq_no(&self) -> SeqNumber {
+        self.cb.sender.unsent_seq_no.get()
+    }
+
+    pub fn poll_sent(&self, seq_no: SeqNumber, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        self.cb.sender.poll_sent(seq_no, ctx)
+    }
+
+    pub fn poll_flush(&self, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        self.cb.sender.poll_flush(ctx)
     }
 
     pub fn peek(&self) -> Result<Bytes, Fail> {
         self.cb.receiver.peek()
     }
 
+    pub fn peek_amount(&self, amount: usize) -> Result<Bytes, Fail> {
+        self.cb.receiver.peek_amount(amount)
+    }
+
+    pub fn poll_peek(&self, ctx: &mut Context) -> Poll<Result<Bytes, Fail>> {
+        self.cb.receiver.poll_peek(ctx)
+    }
+
+    pub fn peek_segments(&self) -> Result<vec_deque::IntoIter<Bytes>, Fail> {
+        self.cb.receiver.peek_segments()
+    }
+
+    pub fn consume(&self, amount: usize) -> Result<(), Fail> {
+        let window_before = self.cb.receiver.window_size();
+        // Hang onto the segments so we can still checksum the consumed bytes after they're gone from the queue.
+        let segments = match self.cb.payload_checksums {
+            Some(..) if amount > 0 => Some(self.cb.receiver.peek_segments()?),
+            _ => None,
+        };
+        self.cb.receiver.consume(amount)?;
+        if let (Some(checksums), Some(segments)) = (self.cb.payload_checksums.as_ref(), segments) {
+            let mut remaining = amount;
+            for segment in segments {
+                let n = cmp::min(remaining, segment.len());
+                checksums.record_received(&segment[..n]);
+                remaining -= n;
+                if remaining == 0 {
+                    break;
+                }
+            }
+        }
+        self.cb.receiver.schedule_window_update(window_before, self.cb.rt.now());
+        Ok(())
+    }
+
     pub fn recv(&self) -> Result<Option<Bytes>, Fail> {
-        self.cb.receiver.recv()
+        if self.cb.receiver.rate_limit.is_set() {
+            let segment = match self.cb.receiver.peek() {
+                Ok(segment) => segment,
+                Err(Fail::ResourceExhausted { .. }) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            return match self.cb.receiver.rate_limit.try_take(segment.len(), self.cb.rt.now()) {
+                Ok(n) => self.read_prefix(segment, n).map(Some),
+                Err(..) => Ok(None),
+            };
+        }
+        let window_before = self.cb.receiver.window_size();
+        let r = self.cb.receiver.recv();
+        if let Ok(Some(ref buf)) = r {
+            if let Some(ref checksums) = self.cb.payload_checksums {
+                checksums.record_received(&buf[..]);
+            }
+            self.cb.receiver.schedule_window_update(window_before, self.cb.rt.now());
+        }
+        r
     }
 
     pub fn poll_recv(&self, ctx: &mut Context) -> Poll<Result<Bytes, Fail>> {
-        self.cb.receiver.poll_recv(ctx)
+        if self.cb.receiver.rate_limit.is_set() {
+            let segment = match self.cb.receiver.poll_peek(ctx) {
+                Poll::Ready(Ok(segment)) => segment,
+                r => return r,
+            };
+            return match self.cb.receiver.rate_limit.try_take(segment.len(), self.cb.rt.now()) {
+                Ok(n) => Poll::Ready(self.read_prefix(segment, n)),
+                Err(ready_at) => {
+                    // Nothing else will wake us once the tokens are there, so set a timer for it.
+                    let wait = self.cb.rt.wait_until(ready_at);
+                    let waker = ctx.waker().clone();
+                    let handle = self.cb.rt.spawn(async move {
+                        wait.await;
+                        waker.wake();
+                    });
+                    *self.recv_rate_limit_timer.borrow_mut() = Some(handle);
+                    Poll::Pending
+                },
+            };
+        }
+        let window_before = self.cb.receiver.window_size();
+        let r = self.cb.receiver.poll_recv(ctx);
+        if let Poll::Ready(Ok(ref buf)) = r {
+            if let Some(ref checksums) = self.cb.payload_checksums {
+                checksums.record_received(&buf[..]);
+            }
+            self.cb.receiver.schedule_window_update(window_before, self.cb.rt.now());
+        }
+        r
+    }
+
+    // Reads the first `len` bytes of `segment`, the front of the receive queue, leaving the rest queued.
+    fn read_prefix(&self, segment: Bytes, len: usize) -> Result<Bytes, Fail> {
+        self.consume(len)?;
+        let (prefix, _) = segment.split(len);
+        Ok(prefix)
     }
 
     pub fn close(&self) -> Result<(), Fail> {
         self.cb.close()
     }
 
+    pub fn abort(&self) -> Result<(), Fail> {
+        self.cb.abort()
+    }
+
+    // Limits how many bytes per second the connection sends, or lifts the limit if `rate` is `None`. Up to a
+    // segment's worth can go at once.
+    pub fn set_send_rate_limit(&self, rate: Option<u64>) -> Result<(), Fail> {
+        self.cb.sender.rate_limit.set(rate, self.cb.sender.mss, self.cb.rt.now())
+    }
+
+    // Likewise for how fast `recv` and `pop` hand data to the application. Reads may come back shorter than the
+    // segment they came in, and data left unread holds back the advertised window.
+    pub fn set_recv_rate_limit(&self, rate: Option<u64>) -> Result<(), Fail> {
+        self.cb.receiver.rate_limit.set(rate, self.cb.receiver.mss, self.cb.rt.now())
+    }
+
+    #[cfg(feature = "fault-injection")]
+    pub fn set_ack_faults(&self, faults: Option<AckFaults>) {
+        self.cb.receiver.ack_faults.set(faults)
+    }
+
+    pub fn set_cwnd_observer(&self, observer: CwndObserver) {
+        self.cb.sender.set_cwnd_observer(observer)
+    }
+
     pub fn remote_mss(&self) -> usize {
         self.cb.remote_mss()
     }
@@ -73,7 +259,28 @@ impl<RT: Runtime> EstablishedSocket<RT> {
         self.cb.current_rto()
     }
 
+    pub fn rto_estimate(&self) -> Duration {
+        self.cb.rto_estimate()
+    }
+
+    pub fn ack_latencies(&self) -> Vec<Duration> {
+        self.cb.sender.ack_latencies()
+    }
+
+    pub fn payload_checksums(&self) -> Result<(u32, u32), Fail> {
+        match self.cb.payload_checksums {
+            Some(ref c) => Ok((c.sent(), c.received())),
+            None => Err(Fail::Unsupported {
+                details: "Payload checksums not enabled",
+            }),
+        }
+    }
+
     pub fn endpoints(&self) -> (ipv4::Endpoint, ipv4::Endpoint) {
-        (self.cb.local.clone(), self.cb.remote.clone())
+        self.cb.four_tuple()
+    }
+
+    pub fn rebind(&self, local: ipv4::Endpoint, remote: ipv4::Endpoint) {
+        self.cb.rebind(local, remote)
     }
 }
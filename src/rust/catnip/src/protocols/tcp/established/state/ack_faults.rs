@@ -0,0 +1,56 @@
+use rand::{
+    rngs::SmallRng,
+    Rng,
+    SeedableRng,
+};
+use std::{
+    cell::RefCell,
+    time::Duration,
+};
+
+// Faults applied to the pure ACKs a connection sends, to model a remote that's slow to acknowledge data or loses
+// track of doing so, and see how the sender copes. ACKs that ride along on data, SYNs or FINs are left alone. Drops
+// are drawn from an RNG seeded with `seed`, so runs are reproducible.
+#[derive(Clone, Debug, Default)]
+pub struct AckFaults {
+    // Fraction of ACKs silently dropped.
+    pub drop_probability: f64,
+    // If set, each ACK goes out this much later than it otherwise would, including ones that would go right away.
+    pub delay: Option<Duration>,
+    pub seed: [u8; 16],
+}
+
+#[derive(Debug, Default)]
+pub struct AckFaultInjector {
+    faults: RefCell<Option<(AckFaults, SmallRng)>>,
+}
+
+impl AckFaultInjector {
+    pub fn new() -> Self {
+        Self {
+            faults: RefCell::new(None),
+        }
+    }
+
+    pub fn set(&self, faults: Option<AckFaults>) {
+        *self.faults.borrow_mut() = faults.map(|f| {
+            let rng = SmallRng::from_seed(f.seed);
+            (f, rng)
+        });
+    }
+
+    pub fn delay(&self) -> Duration {
+        match *self.faults.borrow() {
+            Some((ref faults, _)) => faults.delay.unwrap_or_else(|| Duration::new(0, 0)),
+            None => Duration::new(0, 0),
+        }
+    }
+
+    // Whether to drop the ACK that's about to go out.
+    pub fn should_drop(&self) -> bool {
+        match *self.faults.borrow_mut() {
+            Some((ref faults, ref mut rng)) => rng.gen::<f64>() < faults.drop_probability,
+            None => false,
+        }
+    }
+}
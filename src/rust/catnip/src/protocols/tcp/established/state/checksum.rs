@@ -0,0 +1,43 @@
+use crc::{
+    crc32,
+    Hasher32,
+};
+use std::cell::RefCell;
+
+// Rolling CRC32s over the application payload pushed and popped on a connection, so that transfers can be checked
+// end-to-end by comparing the sender's `sent` checksum with the receiver's `received` one.
+pub struct PayloadChecksums {
+    sent: RefCell<crc32::Digest>,
+    received: RefCell<crc32::Digest>,
+}
+
+impl Default for PayloadChecksums {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PayloadChecksums {
+    pub fn new() -> Self {
+        Self {
+            sent: RefCell::new(crc32::Digest::new(crc32::IEEE)),
+            received: RefCell::new(crc32::Digest::new(crc32::IEEE)),
+        }
+    }
+
+    pub fn record_sent(&self, buf: &[u8]) {
+        Hasher32::write(&mut *self.sent.borrow_mut(), buf);
+    }
+
+    pub fn record_received(&self, buf: &[u8]) {
+        Hasher32::write(&mut *self.received.borrow_mut(), buf);
+    }
+
+    pub fn sent(&self) -> u32 {
+        self.sent.borrow().sum32()
+    }
+
+    pub fn received(&self) -> u32 {
+        self.received.borrow().sum32()
+    }
+}
@@ -0,0 +1,556 @@
+use super::{
+    CongestionControl,
+    CwndChange,
+    CwndChangeReason,
+    CwndObserver,
+    CwndObserverSlot,
+    Options,
+    SlowStartCongestionAvoidance,
+    FastRetransmitRecovery,
+    LimitedTransmit,
+};
+use super::super::sender::Sender;
+use crate::{
+    collections::watched::{WatchedValue, WatchFuture},
+    fail::Fail,
+    protocols::tcp::{
+        seq_after,
+        SeqNumber,
+    },
+};
+use std::{
+    cell::{Cell, RefCell},
+    cmp::{max, min},
+    collections::VecDeque,
+    convert::TryInto,
+    fmt::Debug,
+    num::Wrapping,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BbrMode {
+    Startup,
+    Drain,
+    ProbeBw,
+    ProbeRtt,
+}
+
+// BBR (draft-cardwell-iccrg-bbr-congestion-control). Rather than treating loss as the signal to back off, BBR keeps
+// a model of the path's bottleneck bandwidth and round-trip propagation delay, and sizes cwnd to a small multiple of
+// their product. Until it has a bandwidth estimate, it has no pacing rate and only cwnd limits how fast it sends.
+#[derive(Debug)]
+pub struct Bbr {
+    pub mss: u32,
+    pub initial_cwnd: u32,
+    pub cwnd: WatchedValue<u32>,
+    pub mode: Cell<BbrMode>,
+    pub pacing_gain: Cell<f64>,
+    pub cwnd_gain: Cell<f64>,
+
+    // Path model
+    pub btl_bw: Cell<f64>,  // Bytes/sec, the highest delivery rate sampled over the last few rounds
+    pub btl_bw_samples: RefCell<VecDeque<(u64, f64)>>,  // (round, delivery rate) samples behind the max filter
+    pub min_rtt: Cell<Option<Duration>>,
+    pub min_rtt_stamp: Cell<Option<Instant>>,   // When min_rtt was last lowered or refreshed
+    pub min_rtt_window: Duration,
+
+    // Delivery rate sampling
+    pub delivered: Cell<u64>,   // Total bytes acknowledged on the connection
+    pub last_acked: Cell<u32>,  // Bytes acknowledged by the ACK being processed
+    pub delivered_history: RefCell<VecDeque<(Instant, u64)>>,  // What `delivered` was as of each ACK
+
+    // Round trip counting
+    pub round_count: Cell<u64>,
+    pub round_end: Cell<SeqNumber>, // The current round ends once this is acknowledged
+    pub round_start: Cell<bool>,    // Whether a round has ended since the model was last updated
+
+    // STARTUP exit
+    pub full_bw: Cell<f64>,
+    pub full_bw_count: Cell<u32>,
+    pub filled_pipe: Cell<bool>,
+
+    // PROBE_BW gain cycling
+    pub cycle_index: Cell<usize>,
+    pub cycle_stamp: Cell<Option<Instant>>,
+
+    // PROBE_RTT
+    pub probe_rtt_done_stamp: Cell<Option<Instant>>,
+    pub prior_cwnd: Cell<u32>,
+
+    // Fast Retransmit State
+    pub duplicate_ack_count: WatchedValue<u32>,
+    pub fast_retransmit_now: WatchedValue<bool>,
+
+    pub cwnd_observer: CwndObserverSlot,
+}
+
+impl CongestionControl for Bbr {
+    #[allow(clippy::new_ret_no_self)]
+    fn new(mss: usize, seq_no: SeqNumber, options: Option<Options>) -> Box<dyn CongestionControl> {
+        Box::new(Self::new_unboxed(mss, seq_no, options))
+    }
+}
+
+impl Bbr {
+    const DUP_ACK_THRESHOLD: u32 = 3;
+    // 2/ln(2), the smallest gain that still doubles the sending rate every round during STARTUP.
+    const HIGH_GAIN: f64 = 2.885;
+    const PROBE_BW_CWND_GAIN: f64 = 2.;
+    const PROBE_BW_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1., 1., 1., 1., 1., 1.];
+    const BTL_BW_FILTER_ROUNDS: u64 = 10;
+    const DEFAULT_MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+    const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+    // STARTUP ends once the bandwidth estimate has grown by less than this for `FULL_BW_ROUNDS` rounds running.
+    const FULL_BW_GROWTH: f64 = 1.25;
+    const FULL_BW_ROUNDS: u32 = 3;
+    const MIN_CWND_SEGMENTS: u32 = 4;
+
+    pub fn validate_options(options: &Options) -> Result<(), Fail> {
+        if let Some(ms) = options.get_int("min_rtt_window_ms") {
+            if ms <= 0 {
+                return Err(Fail::OutOfRange { details: "min_rtt_window_ms" });
+            }
+        }
+        Ok(())
+    }
+
+    fn new_unboxed(mss: usize, seq_no: SeqNumber, options: Option<Options>) -> Self {
+        let mss: u32 = mss.try_into().unwrap();
+        // The initial value of cwnd is set according to RFC5681, section 3.1, page 7
+        let initial_cwnd = match mss {
+            0..=1095 => 4 * mss,
+            1096..=2190 => 3 * mss,
+            _ => 2 * mss
+        };
+
+        let options: Options = options.unwrap_or_default();
+        let min_rtt_window = options.get_int("min_rtt_window_ms")
+            .map(|ms| Duration::from_millis(ms as u64))
+            .unwrap_or(Self::DEFAULT_MIN_RTT_WINDOW);
+
+        Self {
+            mss,
+            initial_cwnd,
+            cwnd: WatchedValue::new(initial_cwnd),
+            mode: Cell::new(BbrMode::Startup),
+            pacing_gain: Cell::new(Self::HIGH_GAIN),
+            cwnd_gain: Cell::new(Self::HIGH_GAIN),
+
+            btl_bw: Cell::new(0.),
+            btl_bw_samples: RefCell::new(VecDeque::new()),
+            min_rtt: Cell::new(None),
+            min_rtt_stamp: Cell::new(None),
+            min_rtt_window,
+
+            delivered: Cell::new(0),
+            last_acked: Cell::new(0),
+            delivered_history: RefCell::new(VecDeque::new()),
+
+            round_count: Cell::new(0),
+            round_end: Cell::new(seq_no),
+            round_start: Cell::new(false),
+
+            full_bw: Cell::new(0.),
+            full_bw_count: Cell::new(0),
+            filled_pipe: Cell::new(false),
+
+            cycle_index: Cell::new(0),
+            cycle_stamp: Cell::new(None),
+
+            probe_rtt_done_stamp: Cell::new(None),
+            prior_cwnd: Cell::new(initial_cwnd),
+
+            duplicate_ack_count: WatchedValue::new(0),
+            fast_retransmit_now: WatchedValue::new(false),
+
+            cwnd_observer: CwndObserverSlot::default(),
+        }
+    }
+
+    fn set_cwnd(&self, cwnd: u32, reason: CwndChangeReason) {
+        // Never below the loss window, or we'd stop sending altogether.
+        let cwnd = max(cwnd, self.mss);
+        let old_cwnd = self.cwnd.get();
+        self.cwnd.set(cwnd);
+        if cwnd != old_cwnd {
+            self.cwnd_observer.notify(|| CwndChange {
+                cwnd,
+                // BBR doesn't use a slow start threshold.
+                ssthresh: u32::MAX,
+                reason,
+            });
+        }
+    }
+
+    fn min_cwnd(&self) -> u32 {
+        Self::MIN_CWND_SEGMENTS * self.mss
+    }
+
+    // The bandwidth-delay product scaled by `gain`, once we've got samples of both.
+    fn inflight(&self, gain: f64) -> Option<u32> {
+        let min_rtt = self.min_rtt.get()?;
+        let btl_bw = self.btl_bw.get();
+        if btl_bw == 0. {
+            return None;
+        }
+        let bdp = btl_bw * min_rtt.as_secs_f64();
+        Some((gain * bdp) as u32)
+    }
+
+    fn pacing_rate(&self) -> f64 {
+        self.pacing_gain.get() * self.btl_bw.get()
+    }
+
+    // Draft Section 4.1.1.2: The delivery rate over the interval between the last ACK before the acknowledged data
+    // was sent and now. If nothing had been acknowledged yet when it was sent, the interval starts at the send.
+    fn sample_delivery_rate(&self, now: Instant, rtt: Duration) -> Option<f64> {
+        let send_time = now.checked_sub(rtt)?;
+        let mut history = self.delivered_history.borrow_mut();
+        let (start, delivered_at_start) = match history.iter().rposition(|&(t, _)| t <= send_time) {
+            Some(i) => {
+                // Anything we send from here on goes out after this ACK, so the older ones aren't needed again.
+                history.drain(..i);
+                history[0]
+            },
+            None => (send_time, 0),
+        };
+        let interval = now.checked_duration_since(start)?;
+        if interval == Duration::from_secs(0) {
+            return None;
+        }
+        Some((self.delivered.get() - delivered_at_start) as f64 / interval.as_secs_f64())
+    }
+
+    // Windowed max filter over the last `BTL_BW_FILTER_ROUNDS` rounds.
+    fn update_btl_bw(&self, rate: f64) {
+        let round_count = self.round_count.get();
+        let mut samples = self.btl_bw_samples.borrow_mut();
+        samples.push_back((round_count, rate));
+        while let Some(&(round, _)) = samples.front() {
+            if round + Self::BTL_BW_FILTER_ROUNDS > round_count {
+                break;
+            }
+            samples.pop_front();
+        }
+        let btl_bw = samples.iter().map(|&(_, rate)| rate).fold(0., f64::max);
+        self.btl_bw.set(btl_bw);
+    }
+
+    fn check_full_pipe(&self) {
+        if self.filled_pipe.get() {
+            return;
+        }
+        let btl_bw = self.btl_bw.get();
+        if btl_bw >= self.full_bw.get() * Self::FULL_BW_GROWTH {
+            self.full_bw.set(btl_bw);
+            self.full_bw_count.set(0);
+            return;
+        }
+        let full_bw_count = self.full_bw_count.get() + 1;
+        self.full_bw_count.set(full_bw_count);
+        if full_bw_count >= Self::FULL_BW_ROUNDS {
+            self.filled_pipe.set(true);
+        }
+    }
+
+    fn enter_startup(&self) {
+        self.mode.set(BbrMode::Startup);
+        self.pacing_gain.set(Self::HIGH_GAIN);
+        self.cwnd_gain.set(Self::HIGH_GAIN);
+    }
+
+    fn enter_drain(&self) {
+        self.mode.set(BbrMode::Drain);
+        // Drain the queue STARTUP built up in one round.
+        self.pacing_gain.set(1. / Self::HIGH_GAIN);
+        self.cwnd_gain.set(Self::HIGH_GAIN);
+    }
+
+    fn enter_probe_bw(&self, now: Instant) {
+        self.mode.set(BbrMode::ProbeBw);
+        self.cwnd_gain.set(Self::PROBE_BW_CWND_GAIN);
+        // The draft picks the starting phase at random to keep flows from synchronising. Starting just after the
+        // 0.75 phase is the deterministic choice that avoids draining right after DRAIN did.
+        self.cycle_index.set(2);
+        self.cycle_stamp.set(Some(now));
+        self.pacing_gain.set(Self::PROBE_BW_GAIN_CYCLE[2]);
+    }
+
+    fn enter_probe_rtt(&self) {
+        self.mode.set(BbrMode::ProbeRtt);
+        self.pacing_gain.set(1.);
+        self.cwnd_gain.set(1.);
+        self.prior_cwnd.set(self.cwnd.get());
+        self.probe_rtt_done_stamp.set(None);
+    }
+
+    fn exit_probe_rtt(&self, now: Instant) {
+        self.min_rtt_stamp.set(Some(now));
+        self.set_cwnd(max(self.cwnd.get(), self.prior_cwnd.get()), CwndChangeReason::Ack);
+        if self.filled_pipe.get() {
+            self.enter_probe_bw(now);
+        } else {
+            self.enter_startup();
+        }
+    }
+
+    // Each phase of the gain cycle lasts about one min_rtt.
+    fn advance_cycle_phase(&self, now: Instant) {
+        let (min_rtt, cycle_stamp) = match (self.min_rtt.get(), self.cycle_stamp.get()) {
+            (Some(min_rtt), Some(cycle_stamp)) => (min_rtt, cycle_stamp),
+            _ => return,
+        };
+        if now.duration_since(cycle_stamp) > min_rtt {
+            let cycle_index = (self.cycle_index.get() + 1) % Self::PROBE_BW_GAIN_CYCLE.len();
+            self.cycle_index.set(cycle_index);
+            self.cycle_stamp.set(Some(now));
+            self.pacing_gain.set(Self::PROBE_BW_GAIN_CYCLE[cycle_index]);
+        }
+    }
+
+    fn update_cwnd(&self, bytes_acknowledged: u32) {
+        let cwnd = self.cwnd.get();
+        let new_cwnd = if self.mode.get() == BbrMode::ProbeRtt {
+            min(cwnd, self.min_cwnd())
+        } else {
+            let target = self.inflight(self.cwnd_gain.get());
+            let grown = cwnd + bytes_acknowledged;
+            match target {
+                Some(target) if self.filled_pipe.get() => min(grown, target),
+                Some(target) if cwnd >= target && self.delivered.get() >= self.initial_cwnd as u64 => cwnd,
+                _ => grown,
+            }
+        };
+        self.set_cwnd(max(new_cwnd, self.min_cwnd()), CwndChangeReason::Ack);
+    }
+}
+
+impl SlowStartCongestionAvoidance for Bbr {
+    fn get_cwnd(&self) -> u32 { self.cwnd.get() }
+    fn watch_cwnd(&self) -> (u32, WatchFuture<'_, u32>) { self.cwnd.watch() }
+    fn get_pacing_rate(&self) -> Option<f64> { Some(self.pacing_rate()).filter(|&rate| rate > 0.) }
+    fn set_cwnd_observer(&self, observer: CwndObserver) { self.cwnd_observer.set(observer) }
+
+    fn on_ack_received(&self, sender: &Sender, ack_seq_no: SeqNumber) {
+        let Wrapping(bytes_acknowledged) = ack_seq_no - sender.base_seq_no.get();
+        if bytes_acknowledged == 0 {
+            let duplicate_ack_count = self.duplicate_ack_count.get() + 1;
+            self.duplicate_ack_count.set(duplicate_ack_count);
+            // BBR doesn't take loss as a sign of congestion, so this only triggers the retransmission.
+            if duplicate_ack_count == Self::DUP_ACK_THRESHOLD {
+                self.fast_retransmit_now.set(true);
+            }
+            return;
+        }
+        self.duplicate_ack_count.set(0);
+        self.delivered.set(self.delivered.get() + bytes_acknowledged as u64);
+        self.last_acked.set(bytes_acknowledged);
+    }
+
+    fn on_ack_timing(&self, sender: &Sender, now: Instant, rtt: Option<Duration>) {
+        if !seq_after(self.round_end.get(), sender.base_seq_no.get()) {
+            self.round_count.set(self.round_count.get() + 1);
+            self.round_end.set(sender.sent_seq_no.get());
+            self.round_start.set(true);
+        }
+
+        if let Some(rtt) = rtt {
+            if let Some(rate) = self.sample_delivery_rate(now, rtt) {
+                self.update_btl_bw(rate);
+            }
+
+            let min_rtt_expired = match self.min_rtt_stamp.get() {
+                Some(stamp) => now.duration_since(stamp) > self.min_rtt_window,
+                None => false,
+            };
+            if min_rtt_expired || self.min_rtt.get().map(|min_rtt| rtt <= min_rtt).unwrap_or(true) {
+                self.min_rtt.set(Some(rtt));
+                self.min_rtt_stamp.set(Some(now));
+            }
+            // A minimum that hasn't dropped in a whole window may just be queueing delay: drain the queue and look
+            // again.
+            if min_rtt_expired && self.mode.get() != BbrMode::ProbeRtt {
+                self.enter_probe_rtt();
+            }
+        }
+        self.delivered_history.borrow_mut().push_back((now, self.delivered.get()));
+
+        if self.round_start.replace(false) && self.mode.get() == BbrMode::Startup {
+            self.check_full_pipe();
+            if self.filled_pipe.get() {
+                self.enter_drain();
+            }
+        }
+        if self.mode.get() == BbrMode::Drain {
+            if let Some(bdp) = self.inflight(1.) {
+                if sender.bytes_in_flight() <= bdp {
+                    self.enter_probe_bw(now);
+                }
+            }
+        }
+        if self.mode.get() == BbrMode::ProbeBw {
+            self.advance_cycle_phase(now);
+        }
+        if self.mode.get() == BbrMode::ProbeRtt {
+            match self.probe_rtt_done_stamp.get() {
+                None if sender.bytes_in_flight() <= self.min_cwnd() => {
+                    self.probe_rtt_done_stamp.set(Some(now + Self::PROBE_RTT_DURATION));
+                },
+                Some(done) if now >= done => self.exit_probe_rtt(now),
+                _ => (),
+            }
+        }
+
+        self.update_cwnd(self.last_acked.replace(0));
+    }
+
+    fn on_rto(&self, _sender: &Sender) {
+        self.prior_cwnd.set(self.cwnd.get());
+        self.set_cwnd(self.mss, CwndChangeReason::Rto);
+    }
+
+    fn on_connection_reset(&self, sender: &Sender) {
+        self.enter_startup();
+        self.btl_bw.set(0.);
+        self.btl_bw_samples.borrow_mut().clear();
+        self.min_rtt.set(None);
+        self.min_rtt_stamp.set(None);
+
+        self.delivered.set(0);
+        self.last_acked.set(0);
+        self.delivered_history.borrow_mut().clear();
+
+        self.round_count.set(0);
+        self.round_end.set(sender.sent_seq_no.get());
+        self.round_start.set(false);
+
+        self.full_bw.set(0.);
+        self.full_bw_count.set(0);
+        self.filled_pipe.set(false);
+
+        self.cycle_index.set(0);
+        self.cycle_stamp.set(None);
+        self.probe_rtt_done_stamp.set(None);
+        self.prior_cwnd.set(self.initial_cwnd);
+
+        self.duplicate_ack_count.set(0);
+        self.fast_retransmit_now.set_without_notify(false);
+
+        self.set_cwnd(self.initial_cwnd, CwndChangeReason::Reset);
+    }
+}
+
+impl FastRetransmitRecovery for Bbr {
+    fn get_duplicate_ack_count(&self) -> u32 { self.duplicate_ack_count.get() }
+    fn watch_duplicate_ack_count(&self) -> (u32, WatchFuture<'_, u32>) { self.duplicate_ack_count.watch() }
+
+    fn get_retransmit_now_flag(&self) -> bool { self.fast_retransmit_now.get() }
+    fn watch_retransmit_now_flag(&self) -> (bool, WatchFuture<'_, bool>) { self.fast_retransmit_now.watch() }
+
+    fn on_fast_retransmit(&self, _sender: &Sender) {
+        self.fast_retransmit_now.set_without_notify(false);
+    }
+}
+
+impl LimitedTransmit for Bbr {}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            super::sender::Sender,
+            None as NoCongestionCtrl,
+            Options,
+        },
+        Bbr,
+        BbrMode,
+    };
+    use crate::protocols::tcp::{
+        congestion_ctrl::{
+            CongestionControl,
+            SlowStartCongestionAvoidance,
+        },
+        tests::cc_harness::{
+            CcHarness,
+            MSS,
+        },
+    };
+    use std::{
+        num::Wrapping,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    // Feed `bbr` an ACK the way `Sender::remote_ack` would.
+    fn ack(bbr: &Bbr, sender: &Sender, num_bytes: u32, now: Instant, rtt: Duration) {
+        let ack_seq_no = sender.base_seq_no.get() + Wrapping(num_bytes);
+        bbr.on_ack_received(sender, ack_seq_no);
+        sender.base_seq_no.set(ack_seq_no);
+        bbr.on_ack_timing(sender, now, Some(rtt));
+    }
+
+    #[test]
+    fn test_bbr_min_rtt_windowed() {
+        let mss = MSS as u32;
+        let harness = CcHarness::new(NoCongestionCtrl::new, None);
+        let sender = &harness.sender;
+        let mut options = Options::default();
+        options.insert_int("min_rtt_window_ms".to_string(), 1000);
+        let bbr = Bbr::new_unboxed(MSS, Wrapping(0), Some(options));
+        let t0 = Instant::now();
+        harness.send(10);
+
+        ack(&bbr, sender, mss, t0 + Duration::from_millis(100), Duration::from_millis(100));
+        ack(&bbr, sender, mss, t0 + Duration::from_millis(200), Duration::from_millis(50));
+        ack(&bbr, sender, mss, t0 + Duration::from_millis(300), Duration::from_millis(80));
+        assert_eq!(bbr.min_rtt.get(), Some(Duration::from_millis(50)));
+
+        // Once the minimum is older than the window, the next sample replaces it and we go to measure it afresh.
+        ack(&bbr, sender, mss, t0 + Duration::from_millis(1300), Duration::from_millis(80));
+        assert_eq!(bbr.min_rtt.get(), Some(Duration::from_millis(80)));
+        assert_eq!(bbr.mode.get(), BbrMode::ProbeRtt);
+        assert_eq!(bbr.cwnd.get(), 4 * mss);
+    }
+
+    #[test]
+    fn test_bbr_startup_to_probe_bw() {
+        let mss = MSS as u32;
+        let harness = CcHarness::new(NoCongestionCtrl::new, None);
+        let sender = &harness.sender;
+        let bbr = Bbr::new_unboxed(MSS, Wrapping(0), None);
+        let t0 = Instant::now();
+        let rtt = Duration::from_millis(10);
+        // There's nothing to pace at until the first delivery rate sample.
+        assert_eq!(bbr.get_pacing_rate(), None);
+
+        // Keep 10 segments in flight and acknowledge one every millisecond: a steady 1 MSS/ms over a 10ms path.
+        harness.send(10);
+        let mut now = t0;
+        for _ in 0..100 {
+            now += Duration::from_millis(1);
+            ack(&bbr, sender, mss, now, rtt);
+            harness.send(1);
+            if bbr.mode.get() == BbrMode::ProbeBw {
+                break;
+            }
+        }
+        assert_eq!(bbr.mode.get(), BbrMode::ProbeBw);
+        assert!(bbr.filled_pipe.get());
+        assert_eq!(bbr.min_rtt.get(), Some(rtt));
+        let btl_bw = mss as f64 * 1000.;
+        assert!((bbr.btl_bw.get() - btl_bw).abs() < 1.);
+        assert!((bbr.get_pacing_rate().unwrap() - btl_bw).abs() < 1.);
+
+        // In PROBE_BW, cwnd settles at twice the bandwidth-delay product.
+        for _ in 0..5 {
+            now += Duration::from_millis(1);
+            ack(&bbr, sender, mss, now, rtt);
+            harness.send(1);
+        }
+        let bdp = btl_bw * rtt.as_secs_f64();
+        assert!((bbr.cwnd.get() as f64 - 2. * bdp).abs() <= 1.);
+    }
+}
@@ -1,5 +1,9 @@
 use super::{
     CongestionControl,
+    CwndChange,
+    CwndChangeReason,
+    CwndObserver,
+    CwndObserverSlot,
     Options,
     SlowStartCongestionAvoidance,
     FastRetransmitRecovery,
@@ -8,7 +12,12 @@ use super::{
 use super::super::sender::Sender;
 use crate::{
     collections::watched::{WatchedValue, WatchFuture},
-    protocols::tcp::SeqNumber,
+    fail::Fail,
+    protocols::tcp::{
+        seq_after,
+        seq_before,
+        SeqNumber,
+    },
 };
 use std::{
     cell::Cell,
@@ -19,9 +28,33 @@ use std::{
     time::{Duration, Instant},
 };
 
+// Where we are in F-RTO (RFC5682), which tells a spurious timeout apart from real loss by sending new data after the
+// retransmission and watching the ACKs that come back.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrtoState {
+    Inactive,
+    // We've retransmitted after a timeout and are waiting for the first ACK (step 2).
+    AwaitingFirstAck,
+    // The first ACK advanced the window, so we've let up to two new segments out and are waiting on the next ACK
+    // (step 3).
+    AwaitingSecondAck,
+}
+
+// What cwnd and friends were before a reduction, so it can be undone if the reduction turns out to be spurious.
+#[derive(Clone, Copy, Debug)]
+pub struct UndoState {
+    pub cwnd: u32,
+    pub ssthresh: u32,
+    pub w_max: u32,
+    pub w_last_max: u32,
+    pub last_congestion_was_rto: bool,
+}
+
 #[derive(Debug)]
 pub struct Cubic {
     pub mss: u32, // Just for convenience, otherwise we have `as u32` or `.try_into().unwrap()` scattered everywhere...
+    pub c: f32,                     // Scales how quickly cwnd grows back after a congestion event
+    pub beta_cubic: f32,            // The multiplicative decrease factor applied to cwnd on congestion
     // Slow Start / Congestion Avoidance State
     pub ca_start: Cell<Instant>,    // The time we started the current congestion avoidance
     pub cwnd: WatchedValue<u32>,    // Congestion window: Maximum number of bytes that may be in flight ot prevent congestion
@@ -31,21 +64,68 @@ pub struct Cubic {
     pub last_congestion_was_rto: Cell<bool>,    // A flag for whether the last congestion event was detected by RTO
     pub retransmitted_packets_in_flight: Cell<u32>, // A flag for if there is currently a retransmitted packet in flight
     pub rtt_at_last_send: Cell<Duration>,    // The RTT at the moment we last sent data
-    pub ssthresh: Cell<u32>,        // The size of cwnd at which we will change from using slow start to congestion avoidance
-    pub w_max: Cell<u32>,           // The size of cwnd before the previous congestion event
+    pub ssthresh: WatchedValue<u32>,        // The size of cwnd (in bytes) at which we will change from using slow start to congestion avoidance
+    pub w_max: Cell<u32>,           // The size of cwnd (in bytes) before the previous congestion event
+    pub w_last_max: Cell<u32>,      // cwnd (in bytes) at the previous congestion event, before fast convergence reduced w_max
 
     // Fast Recovery / Fast Retransmit State
-    pub duplicate_ack_count: Cell<u32>,             // The number of consecutive duplicate ACKs we've received
+    pub duplicate_ack_count: WatchedValue<u32>,             // The number of consecutive duplicate ACKs we've received
+    pub dup_ack_threshold: Cell<u32>,               // How many duplicate ACKs it takes before we treat a segment as lost
+    pub reordering_detection: bool,                 // Should spurious fast retransmits raise dup_ack_threshold
     pub fast_retransmit_now: WatchedValue<bool>,    // Flag to cause the retransmitter to retransmit a segment now
-    pub in_fast_recovery: Cell<bool>,               // Are we currently in the `fast recovery` algorithm
+    pub in_fast_recovery: WatchedValue<bool>,       // Are we currently in the `fast recovery` algorithm
     pub prev_ack_seq_no: Cell<SeqNumber>,           // The previous highest ACK sequence number
     pub recover: Cell<SeqNumber>,                   // If we receive dup ACKs with sequence numbers greater than this we'll attempt fast recovery
     
     pub limited_transmit_cwnd_increase: WatchedValue<u32>, // The amount by which cwnd should be increased due to the limited transit algorithm
+
+    // F-RTO State
+    pub frto: bool,                         // Should we use F-RTO (RFC5682) to detect spurious timeouts
+    pub frto_state: Cell<FrtoState>,        // Which step of F-RTO we're in, if any
+    pub frto_recover: Cell<SeqNumber>,      // The highest sequence number we'd sent when the timeout fired
+    pub undo_state: Cell<Option<UndoState>>, // What to restore if the last reduction was spurious
+    pub undo_retransmits: Cell<u32>,        // Retransmissions since the last reduction that haven't been D-SACKed
+
+    // Congestion Window Validation State
+    pub cwnd_validation: bool,              // Should we hold back cwnd while the application isn't using it (RFC7661)
+    pub cwnd_validated_at: Cell<Instant>,   // The last time cwnd was in full use, or was last decayed
+    pub max_in_flight: Cell<u32>,           // The most bytes we've had in flight since then
+
+    pub cwnd_observer: CwndObserverSlot,    // Told about every change to cwnd, if the application registered for it
 }
 
 impl CongestionControl for Cubic {
+    #[allow(clippy::new_ret_no_self)]
     fn new(mss: usize, seq_no: SeqNumber, options: Option<Options>) -> Box<dyn CongestionControl> {
+        Box::new(Self::new_unboxed(mss, seq_no, options))
+    }
+}
+
+impl Cubic {
+    // RFC8312's defaults for the Cubic parameters, which can be overridden with the `c` and `beta` options.
+    const DEFAULT_C: f64 = 0.4;
+    const DEFAULT_BETA_CUBIC: f64 = 0.7;
+
+    // RFC5681 waits for three duplicate ACKs. Reordering can raise that, up to the same cap Linux puts on
+    // `tcp_max_reordering`.
+    const DEFAULT_DUP_ACK_THRESHOLD: u32 = 3;
+    const MAX_DUP_ACK_THRESHOLD: u32 = 300;
+
+    pub fn validate_options(options: &Options) -> Result<(), Fail> {
+        if let Some(c) = options.get_float("c") {
+            if c <= 0. {
+                return Err(Fail::OutOfRange { details: "c" });
+            }
+        }
+        if let Some(beta) = options.get_float("beta") {
+            if beta <= 0. || beta >= 1. {
+                return Err(Fail::OutOfRange { details: "beta" });
+            }
+        }
+        Ok(())
+    }
+
+    fn new_unboxed(mss: usize, seq_no: SeqNumber, options: Option<Options>) -> Self {
         let mss: u32 = mss.try_into().unwrap();
         // The initial value of cwnd is set according to RFC5681, section 3.1, page 7
         let initial_cwnd = match mss {
@@ -56,9 +136,16 @@ impl CongestionControl for Cubic {
         
         let options: Options = options.unwrap_or_default();
         let fast_convergence = options.get_bool("fast_convergence").unwrap_or(true);
+        let frto = options.get_bool("frto").unwrap_or(true);
+        let cwnd_validation = options.get_bool("cwnd_validation").unwrap_or(true);
+        let reordering_detection = options.get_bool("reordering_detection").unwrap_or(true);
+        let c = options.get_float("c").unwrap_or(Self::DEFAULT_C);
+        let beta_cubic = options.get_float("beta").unwrap_or(Self::DEFAULT_BETA_CUBIC);
 
-        Box::new(Self {
+        Self {
             mss,
+            c: c as f32,
+            beta_cubic: beta_cubic as f32,
             // Slow Start / Congestion Avoidance State
             ca_start: Cell::new(Instant::now()), // record the start time of the congestion avoidance period
             cwnd: WatchedValue::new(initial_cwnd),
@@ -67,44 +154,227 @@ impl CongestionControl for Cubic {
             last_send_time: Cell::new(Instant::now()),
             retransmitted_packets_in_flight: Cell::new(0),
             rtt_at_last_send: Cell::new(Duration::new(1, 0)), // The default RTT is 1 sec
-            ssthresh: Cell::new(u32::MAX), // According to RFC5681 ssthresh should be initialised 'arbitrarily high'
+            ssthresh: WatchedValue::new(u32::MAX), // According to RFC5681 ssthresh should be initialised 'arbitrarily high'
             w_max: Cell::new(0), // Because ssthresh is u32::MAX, this will be set appropriately during the 1st congestion event
+            w_last_max: Cell::new(0),
             last_congestion_was_rto: Cell::new(false),
 
-            in_fast_recovery: Cell::new(false),
+            in_fast_recovery: WatchedValue::new(false),
             fast_retransmit_now: WatchedValue::new(false),
             recover: Cell::new(seq_no), // Recover set to initial send sequence number according to RFC6582
             prev_ack_seq_no: Cell::new(seq_no), // RFC6582 doesn't specify the initial value, but this seems sensible
-            duplicate_ack_count: Cell::new(0),
+            duplicate_ack_count: WatchedValue::new(0),
+            dup_ack_threshold: Cell::new(Self::DEFAULT_DUP_ACK_THRESHOLD),
+            reordering_detection,
 
             limited_transmit_cwnd_increase: WatchedValue::new(0),
-        })
+
+            frto,
+            frto_state: Cell::new(FrtoState::Inactive),
+            frto_recover: Cell::new(seq_no),
+            undo_state: Cell::new(None),
+            undo_retransmits: Cell::new(0),
+
+            cwnd_validation,
+            cwnd_validated_at: Cell::new(Instant::now()),
+            max_in_flight: Cell::new(0),
+
+            cwnd_observer: CwndObserverSlot::default(),
+        }
     }
-}
 
-impl Cubic {
-    // Cubic const parameters
-    const C: f32 =  0.4;
-    const BETA_CUBIC: f32 = 0.7;
+    // cwnd, ssthresh and w_max are all stored in bytes, while RFC8312's equations work in segments. Every conversion
+    // goes through these helpers so it's clear which unit each value is in.
+    fn bytes_to_segments(&self, bytes: u32) -> f32 {
+        bytes as f32 / self.mss as f32
+    }
+
+    fn segments_to_bytes(&self, segments: f32) -> u32 {
+        (segments * self.mss as f32) as u32
+    }
+
+    // The number of full-sized segments that fit in `bytes`, rounding down.
+    fn whole_segments(&self, bytes: u32) -> u32 {
+        bytes / self.mss
+    }
 
-    const DUP_ACK_THRESHOLD: u32 = 3;
+    pub fn cwnd_in_segments(&self) -> f32 {
+        self.bytes_to_segments(self.cwnd.get())
+    }
+
+    pub fn ssthresh_in_segments(&self) -> f32 {
+        self.bytes_to_segments(self.ssthresh.get())
+    }
+
+    pub fn w_max_in_segments(&self) -> f32 {
+        self.bytes_to_segments(self.w_max.get())
+    }
+
+    // All changes to cwnd go through here so the observer sees them. Update ssthresh first so it's reported too.
+    fn set_cwnd_bytes(&self, cwnd: u32, reason: CwndChangeReason) {
+        // However many reductions pile up, never go below the loss window, or we'd stop sending altogether.
+        let cwnd = max(cwnd, self.mss);
+        let old_cwnd = self.cwnd.get();
+        self.cwnd.set(cwnd);
+        if cwnd != old_cwnd {
+            self.cwnd_observer.notify(|| CwndChange {
+                cwnd,
+                ssthresh: self.ssthresh.get(),
+                reason,
+            });
+        }
+    }
+
+    // Grows cwnd towards `cwnd`, but no further than the remote's receive window: we could never have that much in
+    // flight anyway, and a window-limited connection would otherwise keep inflating cwnd and then burst once the
+    // window opens up. This never shrinks cwnd.
+    fn grow_cwnd_bytes(&self, sender: &Sender, cwnd: u32, reason: CwndChangeReason) {
+        let limit = max(self.cwnd.get(), sender.window_size.get());
+        self.set_cwnd_bytes(min(cwnd, limit), reason);
+    }
 
     fn fast_convergence(&self) {
-        // The fast convergence algorithm assumes that w_max and cwnd are stored in units of mss, so we do this
-        // integer division to prevent it being applied too often
+        // RFC8312 Section 4.6 compares cwnd with where it stood at the last congestion event, W_last_max, not with
+        // w_max, which fast convergence may already have reduced. Comparing against that would let a flow that keeps
+        // losing ground skip every other reduction.
+        //
+        // The comparison is in segments. We only count whole segments, so cwnd has to have fallen a full segment
+        // short before we release bandwidth, rather than on any byte-sized shortfall.
         let cwnd = self.cwnd.get();
+        let w_last_max = self.w_last_max.get();
+        self.w_last_max.set(cwnd);
 
-        if (cwnd / self.mss) < self.w_max.get() / self.mss {
-            self.w_max.set((cwnd as f32 * (1. + Self::BETA_CUBIC) / 2.) as u32);
+        if self.whole_segments(cwnd) < self.whole_segments(w_last_max) {
+            self.w_max.set((cwnd as f32 * (1. + self.beta_cubic) / 2.) as u32);
         } else {
             self.w_max.set(cwnd);
         }
     }
 
+    fn save_undo_state(&self) {
+        self.undo_state.set(Some(UndoState {
+            cwnd: self.cwnd.get(),
+            ssthresh: self.ssthresh.get(),
+            w_max: self.w_max.get(),
+            w_last_max: self.w_last_max.get(),
+            last_congestion_was_rto: self.last_congestion_was_rto.get(),
+        }));
+        self.undo_retransmits.set(0);
+    }
+
+    // Counts a retransmission against the reduction it's part of, so we only undo once every one of them has
+    // turned out to be spurious.
+    fn count_undo_retransmit(&self) {
+        if self.undo_state.get().is_some() {
+            self.undo_retransmits.set(self.undo_retransmits.get() + 1);
+        }
+    }
+
+    // Puts back what we had before the last reduction. cwnd only ever grows here, in case it has already recovered
+    // past where it was.
+    fn undo_cwnd_reduction(&self) {
+        if let Some(undo) = self.undo_state.take() {
+            self.ssthresh.set(undo.ssthresh);
+            self.w_max.set(undo.w_max);
+            self.w_last_max.set(undo.w_last_max);
+            self.last_congestion_was_rto.set(undo.last_congestion_was_rto);
+            self.set_cwnd_bytes(max(self.cwnd.get(), undo.cwnd), CwndChangeReason::Undo);
+        }
+    }
+
+    fn end_frto(&self) {
+        self.frto_state.set(FrtoState::Inactive);
+        self.undo_state.set(None);
+    }
+
+    // F-RTO's handling of the ACKs that follow a timeout, called once they've been through the usual processing.
+    fn on_ack_received_frto(&self, sender: &Sender, ack_seq_no: SeqNumber, bytes_acknowledged: u32) {
+        let mss = self.mss;
+        match self.frto_state.get() {
+            FrtoState::Inactive => (),
+            FrtoState::AwaitingFirstAck => {
+                // RFC5682 step 2a: A duplicate ACK, or one covering everything we'd sent before the timeout, could
+                // be down to the retransmission alone, so it doesn't tell us anything.
+                if bytes_acknowledged == 0 || !seq_before(ack_seq_no, self.frto_recover.get()) {
+                    self.end_frto();
+                    return;
+                }
+                // Step 2b: The ACK is for data we sent before the timeout. Send up to two new segments to see whether
+                // the rest of it is still getting through.
+                let Wrapping(unsent) = sender.unsent_seq_no.get() - sender.sent_seq_no.get();
+                if unsent == 0 {
+                    self.end_frto();
+                    return;
+                }
+                let Wrapping(in_flight) = sender.sent_seq_no.get() - ack_seq_no;
+                self.set_cwnd_bytes(max(self.cwnd.get(), in_flight + 2 * mss), CwndChangeReason::Rto);
+                self.frto_state.set(FrtoState::AwaitingSecondAck);
+            },
+            FrtoState::AwaitingSecondAck => {
+                if bytes_acknowledged == 0 {
+                    // Step 3a: The new segments only drew a duplicate ACK, so the timeout was real. Carry on with
+                    // slow start from no more than three segments.
+                    self.set_cwnd_bytes(min(self.cwnd.get(), 3 * mss), CwndChangeReason::Rto);
+                    self.end_frto();
+                } else {
+                    // Step 3b: More of the data we sent before the timeout arrived, so the timeout was spurious.
+                    self.frto_state.set(FrtoState::Inactive);
+                    self.undo_cwnd_reduction();
+                }
+            },
+        }
+    }
+
+    // Whether the last flight was big enough that cwnd, rather than the application, held us back. Like Linux, in
+    // slow start it's enough to have used half of cwnd, since cwnd doubles each RTT there.
+    fn is_cwnd_limited(&self, sender: &Sender) -> bool {
+        if sender.bytes_unsent() > 0 {
+            return true;
+        }
+        let in_flight = sender.bytes_in_flight();
+        let cwnd = self.cwnd.get();
+        if cwnd < self.ssthresh.get() {
+            cwnd < 2 * in_flight
+        } else {
+            in_flight + self.mss > cwnd
+        }
+    }
+
+    // RFC7661: Called with each ACK, before it's processed. Returns whether cwnd may grow from it.
+    fn validate_cwnd(&self, sender: &Sender) -> bool {
+        if !self.cwnd_validation {
+            return true;
+        }
+        self.max_in_flight.set(max(self.max_in_flight.get(), sender.bytes_in_flight()));
+        if self.is_cwnd_limited(sender) {
+            self.cwnd_validated_at.set(sender.now());
+            return true;
+        }
+        false
+    }
+
+    // Once the application has left cwnd partly unused for an RTO, cwnd no longer reflects anything we've seen the
+    // path carry. Move it halfway towards what we did use (RFC2861 Section 3, which RFC7661 builds on), but no
+    // lower than the initial window, and remember the old cwnd in ssthresh so slow start can get back to it.
+    fn decay_unused_cwnd(&self, sender: &Sender) {
+        if !self.cwnd_validation || sender.now() - self.cwnd_validated_at.get() <= sender.current_rto() {
+            return;
+        }
+        let cwnd = self.cwnd.get();
+        let used = self.max_in_flight.get();
+        if used < cwnd {
+            self.ssthresh.set(max(self.ssthresh.get(), cwnd / 4 * 3));
+            let decayed = max((cwnd + used) / 2, min(self.initial_cwnd, cwnd));
+            self.set_cwnd_bytes(decayed, CwndChangeReason::AppLimited);
+        }
+        self.cwnd_validated_at.set(sender.now());
+        self.max_in_flight.set(0);
+    }
+
     fn increment_dup_ack_count(&self) -> u32 {
         let duplicate_ack_count = self.duplicate_ack_count.get() + 1;
         self.duplicate_ack_count.set(duplicate_ack_count);
-        if duplicate_ack_count < Self::DUP_ACK_THRESHOLD {
+        if duplicate_ack_count < self.dup_ack_threshold.get() {
             self.limited_transmit_cwnd_increase.modify(|ltci| ltci + self.mss);
         }
         duplicate_ack_count
@@ -116,34 +386,34 @@ impl Cubic {
         let duplicate_ack_count = self.increment_dup_ack_count();
 
         let prev_ack_seq_no = self.prev_ack_seq_no.get();
-        let ack_seq_no_diff = if ack_seq_no > prev_ack_seq_no {
+        let ack_seq_no_diff = if seq_after(ack_seq_no, prev_ack_seq_no) {
             (ack_seq_no - prev_ack_seq_no).0
         } else {
-            // Handle the case where the current ack_seq_no has wrapped and the previous hasn't
             (prev_ack_seq_no - ack_seq_no).0
         };
         let cwnd = self.cwnd.get();
-        let ack_covers_recover = ack_seq_no - Wrapping(1) > self.recover.get();
+        let ack_covers_recover = seq_after(ack_seq_no - Wrapping(1), self.recover.get());
         let retransmitted_packet_dropped_heuristic = cwnd > self.mss && ack_seq_no_diff as u32 <= 4 * self.mss;
         
-        if duplicate_ack_count == Self::DUP_ACK_THRESHOLD && (ack_covers_recover || retransmitted_packet_dropped_heuristic) { 
+        if duplicate_ack_count == self.dup_ack_threshold.get() && (ack_covers_recover || retransmitted_packet_dropped_heuristic) { 
             // Check against recover specified in RFC6582
             self.in_fast_recovery.set(true);
             self.recover.set(sender.sent_seq_no.get());
-            let reduced_cwnd = (cwnd as f32 * Self::BETA_CUBIC) as u32;
+            let reduced_cwnd = (cwnd as f32 * self.beta_cubic) as u32;
 
             if self.fast_convergence {
                 self.fast_convergence();
             } else {
                 self.w_max.set(cwnd);
             }
+            self.save_undo_state();
             self.ssthresh.set(max(reduced_cwnd, 2 * self.mss));
-            self.cwnd.set(reduced_cwnd);
+            self.set_cwnd_bytes(reduced_cwnd, CwndChangeReason::FastRecovery);
             self.fast_retransmit_now.set(true);
             // We don't reset ca_start here even though cwnd has been shrunk because we aren't going
             // straight back into congestion avoidance.
-        } else if duplicate_ack_count > Self::DUP_ACK_THRESHOLD || self.in_fast_recovery.get() {
-            self.cwnd.modify(|c| c + self.mss);
+        } else if duplicate_ack_count > self.dup_ack_threshold.get() || self.in_fast_recovery.get() {
+            self.set_cwnd_bytes(cwnd + self.mss, CwndChangeReason::FastRecovery);
         }
     }
 
@@ -152,21 +422,24 @@ impl Cubic {
         let bytes_acknowledged = ack_seq_no - sender.base_seq_no.get();
         let mss = self.mss;
 
-        if ack_seq_no > self.recover.get() {
+        if seq_after(ack_seq_no, self.recover.get()) {
             // Full acknowledgement
-            self.cwnd.set(min(self.ssthresh.get(), max(bytes_outstanding.0, mss) + mss));
+            self.set_cwnd_bytes(min(self.ssthresh.get(), max(bytes_outstanding.0, mss) + mss), CwndChangeReason::FastRecovery);
             // Record the time we go back into congestion avoidance
-            self.ca_start.set(Instant::now());
+            self.ca_start.set(sender.now());
             // Record that we didn't enter CA from a timeout
             self.last_congestion_was_rto.set(false);
             self.in_fast_recovery.set(false);
         } else {
             // Partial acknowledgement
             self.fast_retransmit_now.set(true);
+            // Deflate cwnd by the amount of new data acknowledged, saturating so a partial ACK covering more
+            // than cwnd (e.g. under reordering) can't wrap it around to a huge window.
+            let cwnd = self.cwnd.get();
             if bytes_acknowledged.0 >= mss {
-                self.cwnd.modify(|c| c - bytes_acknowledged.0 + mss);
+                self.set_cwnd_bytes(cwnd.saturating_sub(bytes_acknowledged.0) + mss, CwndChangeReason::FastRecovery);
             } else {
-                self.cwnd.modify(|c| c - bytes_acknowledged.0);
+                self.set_cwnd_bytes(max(cwnd.saturating_sub(bytes_acknowledged.0), mss), CwndChangeReason::FastRecovery);
             }
             // We stay in fast recovery mode here because we haven't acknowledged all data up to `recovery`
             // Thus, we don't reset ca_start here either.
@@ -174,25 +447,22 @@ impl Cubic {
     }
 
     fn k(&self, w_max: f32) -> f32 {
-        // While we store w_max in terms of bytes, we have pre-normalised it to units of MSS
-        // for compatibility with RFC8312
+        // `w_max` is in segments, as in RFC8312.
         if self.last_congestion_was_rto.get() {
             0.0
         } else {
-            (w_max * (1.-Self::BETA_CUBIC)/Self::C).cbrt()
+            (w_max * (1.-self.beta_cubic)/self.c).cbrt()
         }
     }
 
     fn w_cubic(&self, w_max: f32, t: f32, k: f32) -> f32 {
-        // While we store w_max in terms of bytes, we have pre-normalised it to units of MSS
-        // for compatibility with RFC8312
-        (Self::C)*(t-k).powi(3) + w_max
+        // `w_max` is in segments, as in RFC8312.
+        self.c*(t-k).powi(3) + w_max
     }
 
     fn w_est(&self, w_max: f32, t: f32, rtt: f32) -> f32 {
-        // While we store w_max in terms of bytes, we have pre-normalised it to units of MSS
-        // for compatibility with RFC8312
-        let bc = Self::BETA_CUBIC;
+        // `w_max` is in segments, as in RFC8312.
+        let bc = self.beta_cubic;
         w_max * bc + ((3. * (1. - bc) / (1. + bc)) * t / rtt)
     }
 
@@ -204,24 +474,22 @@ impl Cubic {
 
         if cwnd < ssthresh {
             // Slow start
-            self.cwnd.modify(|c| c + min(bytes_acknowledged.0, mss));
+            self.grow_cwnd_bytes(sender, cwnd + min(bytes_acknowledged.0, mss), CwndChangeReason::Ack);
         } else {
             // Congestion avoidance
-            let t = self.ca_start.get().elapsed().as_secs_f32();
+            let t = (sender.now() - self.ca_start.get()).as_secs_f32();
             let rtt = sender.current_rto().as_secs_f32();
-            let mss_f32 = mss as f32;
-            let normalised_w_max = self.w_max.get() as f32 / mss_f32;
-            let k = self.k(normalised_w_max);
-            let w_est = self.w_est(normalised_w_max, t, rtt);
-            if self.w_cubic(normalised_w_max, t, k) < w_est {
-                // w_est return units of MSS which we multiply back up to get bytes
-                self.cwnd.set((w_est * mss_f32) as u32); 
+            // Everything from here on is in segments until we convert back to set cwnd.
+            let w_max_segments = self.w_max_in_segments();
+            let cwnd_segments = self.cwnd_in_segments();
+            let k = self.k(w_max_segments);
+            let w_est = self.w_est(w_max_segments, t, rtt);
+            if self.w_cubic(w_max_segments, t, k) < w_est {
+                self.grow_cwnd_bytes(sender, self.segments_to_bytes(w_est), CwndChangeReason::Ack);
             } else {
-                let cwnd_f32 = cwnd as f32;
-                // Again, do everythin in terms of units of MSS
-                let normalised_cwnd = cwnd_f32 / mss_f32;
-                let cwnd_inc = ((self.w_cubic(normalised_w_max, t + rtt, k) - normalised_cwnd) / normalised_cwnd) * mss_f32;
-                self.cwnd.modify(|c| c + cwnd_inc as u32);
+                let target = self.w_cubic(w_max_segments, t + rtt, k);
+                let cwnd_inc_segments = (target - cwnd_segments) / cwnd_segments;
+                self.grow_cwnd_bytes(sender, cwnd + self.segments_to_bytes(cwnd_inc_segments), CwndChangeReason::Ack);
             }
         }
     }
@@ -234,15 +502,15 @@ impl Cubic {
         } else {
             self.w_max.set(cwnd);
         }
-        self.cwnd.set(self.mss);
 
         let rpif = self.retransmitted_packets_in_flight.get();
         if rpif == 0 {
             // If we lost a retransmitted packet, we don't shrink ssthresh.
             // So we have to check if a retransmitted packet was in flight before we shrink it.
-            self.ssthresh.set(max((cwnd as f32 * Self::BETA_CUBIC) as u32, 2 * self.mss));
+            self.ssthresh.set(max((cwnd as f32 * self.beta_cubic) as u32, 2 * self.mss));
 
         }
+        self.set_cwnd_bytes(self.mss, CwndChangeReason::Rto);
 
         // Used to decide whether to shrink ssthresh on rto
         // We're just about to retransmit a packet, so increment the counter
@@ -252,6 +520,22 @@ impl Cubic {
         self.last_congestion_was_rto.set(true);
     }
 
+    fn retransmitted_packets_acked(&self, sender: &Sender, bytes_acknowledged: u32) -> u32 {
+        // Retransmitted segments have had their `initial_tx` cleared, so count those this ACK fully covers.
+        let mut bytes_remaining = bytes_acknowledged as usize;
+        let mut num_acked = 0;
+        for segment in sender.unacked_queue.borrow().iter() {
+            if segment.bytes.len() > bytes_remaining {
+                break;
+            }
+            bytes_remaining -= segment.bytes.len();
+            if segment.initial_tx.is_none() {
+                num_acked += 1;
+            }
+        }
+        num_acked
+    }
+
     fn on_rto_fast_recovery(&self, sender: &Sender) {
         // Exit fast recovery/retransmit
         self.recover.set(sender.sent_seq_no.get());
@@ -262,18 +546,23 @@ impl Cubic {
 impl SlowStartCongestionAvoidance for Cubic {
     fn get_cwnd(&self) -> u32 { self.cwnd.get() }
     fn watch_cwnd(&self) -> (u32, WatchFuture<'_, u32>) { self.cwnd.watch() }
+    fn get_ssthresh(&self) -> u32 { self.ssthresh.get() }
+    fn watch_ssthresh(&self) -> (u32, WatchFuture<'_, u32>) { self.ssthresh.watch() }
+    fn set_cwnd_observer(&self, observer: CwndObserver) { self.cwnd_observer.set(observer) }
 
-    fn on_cwnd_check_before_send(&self, _sender: &Sender) {
-        let long_time_since_send = Instant::now().duration_since(self.last_send_time.get()) > self.rtt_at_last_send.get();
+    fn on_cwnd_check_before_send(&self, sender: &Sender) {
+        let long_time_since_send = sender.now() - self.last_send_time.get() > self.rtt_at_last_send.get();
         if long_time_since_send {
             let restart_window = min(self.initial_cwnd, self.cwnd.get());
-            self.cwnd.set(restart_window);
+            self.set_cwnd_bytes(restart_window, CwndChangeReason::Idle);
             self.limited_transmit_cwnd_increase.set_without_notify(0);
+        } else {
+            self.decay_unused_cwnd(sender);
         }
     }
 
     fn on_send(&self, sender: &Sender, num_bytes_sent: u32) {
-        self.last_send_time.set(Instant::now());
+        self.last_send_time.set(sender.now());
         self.rtt_at_last_send.set(sender.current_rto());
         self.limited_transmit_cwnd_increase.set_without_notify(
             self.limited_transmit_cwnd_increase.get().saturating_sub(num_bytes_sent)
@@ -281,50 +570,123 @@ impl SlowStartCongestionAvoidance for Cubic {
     }
 
     fn on_ack_received(&self, sender: &Sender, ack_seq_no: SeqNumber) {
+        let cwnd_limited = self.validate_cwnd(sender);
         let bytes_acknowledged = ack_seq_no - sender.base_seq_no.get();
         if bytes_acknowledged.0 == 0 {
             // ACK is a duplicate
             self.on_dup_ack_received(sender, ack_seq_no);
-            // We attempt to keep track of the number of retransmitted packets in flight because we do not alter
-            // ssthresh if a packet is lost when it has been retransmitted. There is almost certainly a better way.
-            self.retransmitted_packets_in_flight.set(self.retransmitted_packets_in_flight.get().saturating_sub(1));
+            self.on_ack_received_frto(sender, ack_seq_no, 0);
         } else {
             self.duplicate_ack_count.set(0);
+            // We attempt to keep track of the number of retransmitted packets in flight because we do not alter
+            // ssthresh if a packet is lost when it has been retransmitted. There is almost certainly a better way.
+            let retransmitted_packets_acked = self.retransmitted_packets_acked(sender, bytes_acknowledged.0);
+            self.retransmitted_packets_in_flight.set(
+                self.retransmitted_packets_in_flight.get().saturating_sub(retransmitted_packets_acked)
+            );
 
             if self.in_fast_recovery.get() {
                 // Fast Recovery response to new data
                 self.on_ack_received_fast_recovery(sender, ack_seq_no);
-            } else {
+            } else if cwnd_limited {
                 self.on_ack_received_ss_ca(sender, ack_seq_no);
             }
             // Used to handle dup ACKs after timeout
             self.prev_ack_seq_no.set(ack_seq_no);
+            self.on_ack_received_frto(sender, ack_seq_no, bytes_acknowledged.0);
         }
     }
 
     fn on_rto(&self, sender: &Sender) {
+        // F-RTO only starts from a first timeout outside of fast recovery. Timing out again while it's running means
+        // the first one was real.
+        if self.frto && self.frto_state.get() == FrtoState::Inactive && !self.in_fast_recovery.get() {
+            self.save_undo_state();
+            self.frto_state.set(FrtoState::AwaitingFirstAck);
+            self.frto_recover.set(sender.sent_seq_no.get());
+        } else {
+            self.end_frto();
+        }
+
         // Handle timeout for any of the algorithms we could currently be using
         self.on_rto_ss_ca();
         self.on_rto_fast_recovery(sender);
+        self.count_undo_retransmit();
+    }
+
+    fn on_connection_reset(&self, sender: &Sender) {
+        // Everything goes back to how `new` left it, except that sequence numbers start from where we are now.
+        let seq_no = sender.sent_seq_no.get();
+        self.ca_start.set(sender.now());
+        self.last_send_time.set(sender.now());
+        self.retransmitted_packets_in_flight.set(0);
+        self.rtt_at_last_send.set(Duration::new(1, 0));
+        self.ssthresh.set(u32::MAX);
+        self.set_cwnd_bytes(self.initial_cwnd, CwndChangeReason::Reset);
+        self.w_max.set(0);
+        self.w_last_max.set(0);
+        self.last_congestion_was_rto.set(false);
+
+        self.in_fast_recovery.set(false);
+        self.fast_retransmit_now.set_without_notify(false);
+        self.recover.set(seq_no);
+        self.prev_ack_seq_no.set(seq_no);
+        self.duplicate_ack_count.set(0);
+        self.dup_ack_threshold.set(Self::DEFAULT_DUP_ACK_THRESHOLD);
+
+        self.limited_transmit_cwnd_increase.set(0);
+
+        self.frto_state.set(FrtoState::Inactive);
+        self.frto_recover.set(seq_no);
+        self.undo_state.set(None);
+        self.undo_retransmits.set(0);
+
+        self.cwnd_validated_at.set(sender.now());
+        self.max_in_flight.set(0);
     }
 }
 
 impl FastRetransmitRecovery for Cubic {
     fn get_duplicate_ack_count(&self) -> u32 { self.duplicate_ack_count.get() }
+    fn watch_duplicate_ack_count(&self) -> (u32, WatchFuture<'_, u32>) { self.duplicate_ack_count.watch() }
 
     fn get_retransmit_now_flag(&self) -> bool { self.fast_retransmit_now.get() }
     fn watch_retransmit_now_flag(&self) -> (bool, WatchFuture<'_, bool>) { self.fast_retransmit_now.watch() }
 
+    fn get_fast_recovery_flag(&self) -> bool { self.in_fast_recovery.get() }
+    fn watch_fast_recovery_flag(&self) -> (bool, WatchFuture<'_, bool>) { self.in_fast_recovery.watch() }
+
     fn on_fast_retransmit(&self, _sender: &Sender) {
         // NOTE: Could we potentially miss FastRetransmit requests with just a flag?
         // I suspect it doesn't matter because we only retransmit on the 3rd repeat ACK precisely...
         // I should really use some other mechanism here just because it would be nicer...
         self.fast_retransmit_now.set_without_notify(false);
+        self.count_undo_retransmit();
+    }
+
+    fn on_reordering_detected(&self, _sender: &Sender, degree: u32) {
+        if !self.reordering_detection {
+            return;
+        }
+        // Each of the `degree` segments that overtook the original drew a duplicate ACK, so waiting for one more than
+        // that would have let it arrive. We never lower the threshold again short of a reset.
+        let threshold = max(self.dup_ack_threshold.get(), degree + 1);
+        self.dup_ack_threshold.set(min(threshold, Self::MAX_DUP_ACK_THRESHOLD));
     }
 
-    fn on_base_seq_no_wraparound(&self, _sender: &Sender) {
-        // This still won't let us enter fast recovery if base_seq_no wraps to precisely 0, but there's nothing to be done in that case.
-        self.recover.set(Wrapping(0)); 
+    fn on_spurious_retransmit(&self, _sender: &Sender) {
+        let undo_retransmits = self.undo_retransmits.get();
+        if self.undo_state.get().is_none() || undo_retransmits == 0 {
+            return;
+        }
+        self.undo_retransmits.set(undo_retransmits - 1);
+        if undo_retransmits == 1 {
+            // Everything we retransmitted for the last reduction had already arrived, so the loss was only
+            // reordering or a delayed ACK.
+            self.in_fast_recovery.set(false);
+            self.frto_state.set(FrtoState::Inactive);
+            self.undo_cwnd_reduction();
+        }
     }
 }
 
@@ -332,3 +694,160 @@ impl LimitedTransmit for Cubic {
     fn get_limited_transmit_cwnd_increase(&self) -> u32 { self.limited_transmit_cwnd_increase.get() }
     fn watch_limited_transmit_cwnd_increase(&self) -> (u32, WatchFuture<'_, u32>) { self.limited_transmit_cwnd_increase.watch() }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::None as NoCongestionCtrl,
+        Cubic,
+    };
+    use crate::protocols::tcp::{
+        congestion_ctrl::{
+            CongestionControl,
+            SlowStartCongestionAvoidance,
+        },
+        tests::cc_harness::{
+            CcHarness,
+            MSS,
+        },
+    };
+    use std::{
+        num::Wrapping,
+        time::Instant,
+    };
+
+    #[test]
+    fn test_unit_conversions() {
+        let base_seq_no = Wrapping(0);
+
+        // The initial window is a whole number of segments on either side of RFC5681's MSS thresholds.
+        for &(mss, segments) in &[(1095, 4.), (1096, 3.), (2190, 3.), (2191, 2.)] {
+            let cubic = Cubic::new_unboxed(mss, base_seq_no, None);
+            assert!((cubic.cwnd_in_segments() - segments).abs() < f32::EPSILON);
+            assert_eq!(cubic.get_cwnd(), segments as u32 * mss as u32);
+        }
+
+        let cubic = Cubic::new_unboxed(MSS, base_seq_no, None);
+        let mss = MSS as u32;
+        assert_eq!(cubic.whole_segments(mss - 1), 0);
+        assert_eq!(cubic.whole_segments(mss), 1);
+        assert_eq!(cubic.whole_segments(2 * mss - 1), 1);
+        assert_eq!(cubic.segments_to_bytes(cubic.bytes_to_segments(3 * mss)), 3 * mss);
+        assert!((cubic.bytes_to_segments(mss / 2) - 0.5).abs() < f32::EPSILON);
+        // A shrinking window never converts to a huge number of bytes.
+        assert_eq!(cubic.segments_to_bytes(-1.), 0);
+
+        cubic.ssthresh.set(4 * mss);
+        cubic.w_max.set(10 * mss);
+        assert!((cubic.ssthresh_in_segments() - 4.).abs() < f32::EPSILON);
+        assert!((cubic.w_max_in_segments() - 10.).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_fast_convergence_counts_whole_segments() {
+        let base_seq_no = Wrapping(0);
+        let cubic = Cubic::new_unboxed(MSS, base_seq_no, None);
+        let mss = MSS as u32;
+
+        // Falling short of the last maximum by less than a segment doesn't count as losing ground to another flow.
+        cubic.w_last_max.set(10 * mss + mss / 2);
+        cubic.cwnd.set(10 * mss);
+        cubic.fast_convergence();
+        assert_eq!(cubic.w_max.get(), 10 * mss);
+        assert_eq!(cubic.w_last_max.get(), 10 * mss);
+
+        // A full segment short, w_max is pulled below cwnd to release bandwidth.
+        cubic.cwnd.set(9 * mss);
+        cubic.fast_convergence();
+        assert_eq!(cubic.w_max.get(), ((9 * mss) as f32 * (1. + cubic.beta_cubic) / 2.) as u32);
+        assert!(cubic.w_max.get() < cubic.cwnd.get());
+    }
+
+    #[test]
+    fn test_fast_convergence_tracks_last_max() {
+        let cubic = Cubic::new_unboxed(MSS, Wrapping(0), None);
+        let mss = MSS as u32;
+        let reduced = |cwnd: u32| (cwnd as f32 * (1. + cubic.beta_cubic) / 2.) as u32;
+
+        // The first congestion event has nothing to compare against.
+        cubic.cwnd.set(100 * mss);
+        cubic.on_rto_ss_ca();
+        assert_eq!(cubic.w_max.get(), 100 * mss);
+
+        // The next comes at a smaller cwnd, so we're losing ground and release some.
+        cubic.cwnd.set(90 * mss);
+        cubic.on_rto_ss_ca();
+        assert_eq!(cubic.w_max.get(), reduced(90 * mss));
+
+        // And the one after at smaller still. That's above the reduced w_max, but what counts is that it's below
+        // where cwnd was last time.
+        let cwnd = (cubic.w_max.get() / mss + 1) * mss;
+        assert!(cwnd < 90 * mss);
+        cubic.cwnd.set(cwnd);
+        cubic.on_rto_ss_ca();
+        assert_eq!(cubic.w_max.get(), reduced(cwnd));
+
+        // Holding steady isn't losing ground.
+        cubic.cwnd.set(cwnd);
+        cubic.on_rto_ss_ca();
+        assert_eq!(cubic.w_max.get(), cwnd);
+    }
+
+    #[test]
+    fn test_retransmitted_packets_in_flight_doesnt_underflow() {
+        let base_seq_no = Wrapping(0);
+        // The harness's own controller is irrelevant here, we drive `cubic` by hand.
+        let harness = CcHarness::new(NoCongestionCtrl::new, None);
+        let sender = &harness.sender;
+        let cubic = Cubic::new_unboxed(MSS, base_seq_no, None);
+
+        // ACKing new data that was never retransmitted leaves the counter at zero.
+        harness.send(1);
+        cubic.on_ack_received(sender, base_seq_no + Wrapping(MSS as u32));
+        assert_eq!(cubic.retransmitted_packets_in_flight.get(), 0);
+    }
+
+    #[test]
+    fn test_retransmitted_packets_in_flight_decremented_on_retransmit_acked() {
+        let base_seq_no = Wrapping(0);
+        let harness = CcHarness::new(NoCongestionCtrl::new, None);
+        let sender = &harness.sender;
+        let cubic = Cubic::new_unboxed(MSS, base_seq_no, None);
+
+        // Time out and retransmit the first segment.
+        harness.send(2);
+        harness.time_out();
+        cubic.on_rto(sender);
+        assert_eq!(cubic.retransmitted_packets_in_flight.get(), 1);
+
+        // Duplicate ACKs don't acknowledge the retransmission.
+        cubic.on_ack_received(sender, base_seq_no);
+        assert_eq!(cubic.retransmitted_packets_in_flight.get(), 1);
+
+        cubic.on_ack_received(sender, base_seq_no + Wrapping(MSS as u32));
+        assert_eq!(cubic.retransmitted_packets_in_flight.get(), 0);
+    }
+
+    #[test]
+    fn test_unused_cwnd_decays() {
+        let base_seq_no = Wrapping(0);
+        let mss = MSS as u32;
+        let harness = CcHarness::new(NoCongestionCtrl::new, None);
+        let sender = &harness.sender;
+        let cubic = Cubic::new_unboxed(MSS, base_seq_no, None);
+        let ssthresh = cubic.ssthresh.get();
+
+        // We've been sending steadily (so this isn't an idle restart), but with no more than 4 segments in flight
+        // against a 20 segment cwnd, for longer than an RTO.
+        cubic.cwnd.set(20 * mss);
+        cubic.max_in_flight.set(4 * mss);
+        cubic.cwnd_validated_at.set(Instant::now() - 2 * sender.current_rto());
+        cubic.on_cwnd_check_before_send(sender);
+        assert_eq!(cubic.get_cwnd(), 12 * mss);
+        assert_eq!(cubic.ssthresh.get(), ssthresh.max(15 * mss));
+
+        // The next decay needs another RTO to pass first.
+        cubic.on_cwnd_check_before_send(sender);
+        assert_eq!(cubic.get_cwnd(), 12 * mss);
+    }
+}
@@ -0,0 +1,364 @@
+use super::{
+    CongestionControl,
+    CwndChange,
+    CwndChangeReason,
+    CwndObserver,
+    CwndObserverSlot,
+    Options,
+    SlowStartCongestionAvoidance,
+    FastRetransmitRecovery,
+    LimitedTransmit,
+};
+use super::super::sender::Sender;
+use crate::{
+    collections::watched::{WatchedValue, WatchFuture},
+    fail::Fail,
+    protocols::tcp::{
+        seq_after,
+        SeqNumber,
+    },
+};
+use std::{
+    cell::Cell,
+    cmp::{max, min},
+    convert::TryInto,
+    fmt::Debug,
+    num::Wrapping,
+};
+
+// Data Center TCP (RFC 8257). Outside of ECN this is NewReno, but rather than halving cwnd when the network marks
+// congestion, it shrinks it in proportion to the fraction of bytes that were marked over the last window.
+//
+// Note that the stack doesn't negotiate ECN yet: our SYNs don't carry ECE/CWR, outgoing datagrams aren't sent with an
+// ECT codepoint, and our receiver never echoes CE marks back as ECE. So against another catnip peer, or any path
+// that only marks ECT traffic, no ACK arrives with ECE set and this behaves exactly like NewReno. Alpha only moves
+// when the remote sets ECE on its own, e.g. in tests that craft the ACKs directly.
+#[derive(Debug)]
+pub struct Dctcp {
+    pub mss: u32,
+    pub initial_cwnd: u32,
+    pub cwnd: WatchedValue<u32>,
+    pub ssthresh: WatchedValue<u32>,
+    pub bytes_acked_ca: Cell<u32>,  // Bytes acknowledged towards the next MSS of growth during congestion avoidance
+
+    // ECN state
+    pub alpha: Cell<f32>,           // Moving estimate of the fraction of bytes marked
+    pub alpha_gain: f32,            // `g` in RFC 8257, the weight given to the latest window when updating alpha
+    pub window_end: Cell<SeqNumber>,    // Alpha is updated and the counts below reset once this is acknowledged
+    pub bytes_acked: Cell<u32>,     // Bytes acknowledged in the current window
+    pub bytes_marked: Cell<u32>,    // Bytes acknowledged with ECE set in the current window
+    pub reduced_this_window: Cell<bool>,    // We only react to ECE once per window
+
+    // Fast Recovery / Fast Retransmit State
+    pub duplicate_ack_count: WatchedValue<u32>,
+    pub fast_retransmit_now: WatchedValue<bool>,
+    pub in_fast_recovery: WatchedValue<bool>,
+    pub recover: Cell<SeqNumber>,
+
+    pub cwnd_observer: CwndObserverSlot,
+}
+
+impl CongestionControl for Dctcp {
+    #[allow(clippy::new_ret_no_self)]
+    fn new(mss: usize, seq_no: SeqNumber, options: Option<Options>) -> Box<dyn CongestionControl> {
+        Box::new(Self::new_unboxed(mss, seq_no, options))
+    }
+}
+
+impl Dctcp {
+    const DUP_ACK_THRESHOLD: u32 = 3;
+    // RFC 8257 Section 4.2 recommends 1/16.
+    const DEFAULT_ALPHA_GAIN: f64 = 1. / 16.;
+
+    pub fn validate_options(options: &Options) -> Result<(), Fail> {
+        if let Some(alpha_gain) = options.get_float("alpha_gain") {
+            if alpha_gain <= 0. || alpha_gain > 1. {
+                return Err(Fail::OutOfRange { details: "alpha_gain" });
+            }
+        }
+        Ok(())
+    }
+
+    fn new_unboxed(mss: usize, seq_no: SeqNumber, options: Option<Options>) -> Self {
+        let mss: u32 = mss.try_into().unwrap();
+        // The initial value of cwnd is set according to RFC5681, section 3.1, page 7
+        let initial_cwnd = match mss {
+            0..=1095 => 4 * mss,
+            1096..=2190 => 3 * mss,
+            _ => 2 * mss
+        };
+
+        let options: Options = options.unwrap_or_default();
+        let alpha_gain = options.get_float("alpha_gain").unwrap_or(Self::DEFAULT_ALPHA_GAIN);
+
+        Self {
+            mss,
+            initial_cwnd,
+            cwnd: WatchedValue::new(initial_cwnd),
+            ssthresh: WatchedValue::new(u32::MAX),
+            bytes_acked_ca: Cell::new(0),
+
+            // RFC 8257 Section 3.3: Start out assuming everything's marked, so early marks get a full reaction.
+            alpha: Cell::new(1.),
+            alpha_gain: alpha_gain as f32,
+            window_end: Cell::new(seq_no),
+            bytes_acked: Cell::new(0),
+            bytes_marked: Cell::new(0),
+            reduced_this_window: Cell::new(false),
+
+            duplicate_ack_count: WatchedValue::new(0),
+            fast_retransmit_now: WatchedValue::new(false),
+            in_fast_recovery: WatchedValue::new(false),
+            recover: Cell::new(seq_no),
+
+            cwnd_observer: CwndObserverSlot::default(),
+        }
+    }
+
+    fn set_cwnd(&self, cwnd: u32, reason: CwndChangeReason) {
+        // Never below the loss window, or we'd stop sending altogether.
+        let cwnd = max(cwnd, self.mss);
+        let old_cwnd = self.cwnd.get();
+        self.cwnd.set(cwnd);
+        if cwnd != old_cwnd {
+            self.cwnd_observer.notify(|| CwndChange {
+                cwnd,
+                ssthresh: self.ssthresh.get(),
+                reason,
+            });
+        }
+    }
+
+    // RFC 5681 Equation 4, used for both fast retransmit and RTOs.
+    fn halved_flight_size(&self, sender: &Sender) -> u32 {
+        let Wrapping(flight_size) = sender.sent_seq_no.get() - sender.base_seq_no.get();
+        max(flight_size / 2, 2 * self.mss)
+    }
+
+    fn on_dup_ack_received(&self, sender: &Sender) {
+        let duplicate_ack_count = self.duplicate_ack_count.get() + 1;
+        self.duplicate_ack_count.set(duplicate_ack_count);
+
+        if duplicate_ack_count == Self::DUP_ACK_THRESHOLD && !self.in_fast_recovery.get() {
+            self.ssthresh.set(self.halved_flight_size(sender));
+            self.recover.set(sender.sent_seq_no.get());
+            self.in_fast_recovery.set(true);
+            self.set_cwnd(self.ssthresh.get() + Self::DUP_ACK_THRESHOLD * self.mss, CwndChangeReason::FastRecovery);
+            self.fast_retransmit_now.set(true);
+        } else if self.in_fast_recovery.get() {
+            // Each further duplicate ACK means another segment has left the network.
+            self.set_cwnd(self.cwnd.get() + self.mss, CwndChangeReason::FastRecovery);
+        }
+    }
+}
+
+impl SlowStartCongestionAvoidance for Dctcp {
+    fn get_cwnd(&self) -> u32 { self.cwnd.get() }
+    fn watch_cwnd(&self) -> (u32, WatchFuture<'_, u32>) { self.cwnd.watch() }
+    fn get_ssthresh(&self) -> u32 { self.ssthresh.get() }
+    fn watch_ssthresh(&self) -> (u32, WatchFuture<'_, u32>) { self.ssthresh.watch() }
+    fn set_cwnd_observer(&self, observer: CwndObserver) { self.cwnd_observer.set(observer) }
+
+    fn on_ecn_feedback(&self, sender: &Sender, ack_seq_no: SeqNumber, ece: bool) {
+        let Wrapping(bytes_outstanding) = sender.sent_seq_no.get() - sender.base_seq_no.get();
+        let Wrapping(bytes_acknowledged) = ack_seq_no - sender.base_seq_no.get();
+        if bytes_acknowledged > bytes_outstanding {
+            return;
+        }
+
+        // RFC 8257 Section 3.3: Count the bytes each ACK covers, and how many of those the receiver saw marked.
+        self.bytes_acked.set(self.bytes_acked.get() + bytes_acknowledged);
+        if ece {
+            self.bytes_marked.set(self.bytes_marked.get() + bytes_acknowledged);
+        }
+
+        // Once a window's worth of data is acknowledged, fold the fraction marked into alpha and start over.
+        if seq_after(ack_seq_no, self.window_end.get()) {
+            let bytes_acked = self.bytes_acked.get();
+            if bytes_acked > 0 {
+                let marked_fraction = self.bytes_marked.get() as f32 / bytes_acked as f32;
+                let alpha = (1. - self.alpha_gain) * self.alpha.get() + self.alpha_gain * marked_fraction;
+                self.alpha.set(alpha);
+            }
+            self.bytes_acked.set(0);
+            self.bytes_marked.set(0);
+            self.window_end.set(sender.sent_seq_no.get());
+            self.reduced_this_window.set(false);
+        }
+
+        // React to marks at most once per window, and leave loss recovery to its own reduction.
+        if ece && !self.reduced_this_window.get() && !self.in_fast_recovery.get() {
+            self.reduced_this_window.set(true);
+            let reduced_cwnd = (self.cwnd.get() as f32 * (1. - self.alpha.get() / 2.)) as u32;
+            let reduced_cwnd = max(reduced_cwnd, 2 * self.mss);
+            self.ssthresh.set(reduced_cwnd);
+            self.set_cwnd(reduced_cwnd, CwndChangeReason::Ecn);
+        }
+    }
+
+    fn on_ack_received(&self, sender: &Sender, ack_seq_no: SeqNumber) {
+        let Wrapping(bytes_acknowledged) = ack_seq_no - sender.base_seq_no.get();
+        if bytes_acknowledged == 0 {
+            self.on_dup_ack_received(sender);
+            return;
+        }
+        self.duplicate_ack_count.set(0);
+        let mss = self.mss;
+
+        if self.in_fast_recovery.get() {
+            if seq_after(ack_seq_no, self.recover.get()) {
+                // Full acknowledgement (RFC 6582 Section 3.2, step 3)
+                self.in_fast_recovery.set(false);
+                let Wrapping(flight_size) = sender.sent_seq_no.get() - ack_seq_no;
+                self.set_cwnd(min(self.ssthresh.get(), max(flight_size, mss) + mss), CwndChangeReason::FastRecovery);
+            } else {
+                // Partial acknowledgement: retransmit the next hole and deflate by what was acknowledged.
+                self.fast_retransmit_now.set(true);
+                let cwnd = self.cwnd.get();
+                self.set_cwnd(max(cwnd.saturating_sub(bytes_acknowledged) + mss, mss), CwndChangeReason::FastRecovery);
+            }
+            return;
+        }
+
+        let cwnd = self.cwnd.get();
+        if cwnd < self.ssthresh.get() {
+            // Slow start
+            self.set_cwnd(cwnd + min(bytes_acknowledged, mss), CwndChangeReason::Ack);
+        } else {
+            // Congestion avoidance: one MSS per cwnd's worth of acknowledged data (RFC 5681 Section 3.1).
+            let bytes_acked_ca = self.bytes_acked_ca.get() + bytes_acknowledged;
+            if bytes_acked_ca >= cwnd {
+                self.bytes_acked_ca.set(bytes_acked_ca - cwnd);
+                self.set_cwnd(cwnd + mss, CwndChangeReason::Ack);
+            } else {
+                self.bytes_acked_ca.set(bytes_acked_ca);
+            }
+        }
+    }
+
+    fn on_rto(&self, sender: &Sender) {
+        self.ssthresh.set(self.halved_flight_size(sender));
+        self.bytes_acked_ca.set(0);
+        self.recover.set(sender.sent_seq_no.get());
+        self.in_fast_recovery.set(false);
+        self.set_cwnd(self.mss, CwndChangeReason::Rto);
+    }
+
+    fn on_connection_reset(&self, sender: &Sender) {
+        let seq_no = sender.sent_seq_no.get();
+        self.ssthresh.set(u32::MAX);
+        self.bytes_acked_ca.set(0);
+
+        self.alpha.set(1.);
+        self.window_end.set(seq_no);
+        self.bytes_acked.set(0);
+        self.bytes_marked.set(0);
+        self.reduced_this_window.set(false);
+
+        self.duplicate_ack_count.set(0);
+        self.fast_retransmit_now.set_without_notify(false);
+        self.in_fast_recovery.set(false);
+        self.recover.set(seq_no);
+
+        self.set_cwnd(self.initial_cwnd, CwndChangeReason::Reset);
+    }
+}
+
+impl FastRetransmitRecovery for Dctcp {
+    fn get_duplicate_ack_count(&self) -> u32 { self.duplicate_ack_count.get() }
+    fn watch_duplicate_ack_count(&self) -> (u32, WatchFuture<'_, u32>) { self.duplicate_ack_count.watch() }
+
+    fn get_retransmit_now_flag(&self) -> bool { self.fast_retransmit_now.get() }
+    fn watch_retransmit_now_flag(&self) -> (bool, WatchFuture<'_, bool>) { self.fast_retransmit_now.watch() }
+
+    fn get_fast_recovery_flag(&self) -> bool { self.in_fast_recovery.get() }
+    fn watch_fast_recovery_flag(&self) -> (bool, WatchFuture<'_, bool>) { self.in_fast_recovery.watch() }
+
+    fn on_fast_retransmit(&self, _sender: &Sender) {
+        self.fast_retransmit_now.set_without_notify(false);
+    }
+}
+
+impl LimitedTransmit for Dctcp {}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            super::sender::Sender,
+            None as NoCongestionCtrl,
+            Options,
+        },
+        Dctcp,
+    };
+    use crate::protocols::tcp::{
+        congestion_ctrl::{
+            CongestionControl,
+            SlowStartCongestionAvoidance,
+        },
+        tests::cc_harness::{
+            CcHarness,
+            MSS,
+        },
+    };
+    use std::num::Wrapping;
+
+    // Feed `dctcp` an ACK the way the control block would, then advance the sender past it.
+    fn ack(dctcp: &Dctcp, sender: &Sender, num_bytes: u32, ece: bool) {
+        let ack_seq_no = sender.base_seq_no.get() + Wrapping(num_bytes);
+        dctcp.on_ecn_feedback(sender, ack_seq_no, ece);
+        dctcp.on_ack_received(sender, ack_seq_no);
+        sender.base_seq_no.set(ack_seq_no);
+    }
+
+    #[test]
+    fn test_dctcp_alpha_updated_once_per_window() {
+        let mss = MSS as u32;
+        let harness = CcHarness::new(NoCongestionCtrl::new, None);
+        let sender = &harness.sender;
+        let mut options = Options::default();
+        options.insert_float("alpha_gain".to_string(), 0.5);
+        let dctcp = Dctcp::new_unboxed(MSS, Wrapping(0), Some(options));
+
+        // The first ACK closes the initial, empty window. None of it was marked, so alpha halves.
+        harness.send(4);
+        ack(&dctcp, sender, mss, false);
+        assert!((dctcp.alpha.get() - 0.5).abs() < f32::EPSILON);
+
+        // Alpha doesn't move again until an ACK goes past the end of the next window, here the 4 MSS that were
+        // in flight when the first one closed.
+        ack(&dctcp, sender, mss, false);
+        ack(&dctcp, sender, mss, true);
+        ack(&dctcp, sender, mss, false);
+        assert!((dctcp.alpha.get() - 0.5).abs() < f32::EPSILON);
+        harness.send(1);
+        ack(&dctcp, sender, mss, false);
+
+        // One MSS out of the four acknowledged since was marked.
+        assert!((dctcp.alpha.get() - (0.5 * 0.5 + 0.5 * 0.25)).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_dctcp_reduces_cwnd_by_alpha() {
+        let mss = MSS as u32;
+        let harness = CcHarness::new(NoCongestionCtrl::new, None);
+        let sender = &harness.sender;
+        let dctcp = Dctcp::new_unboxed(MSS, Wrapping(0), None);
+        for _ in 0..8 {
+            dctcp.cwnd.modify(|c| c + mss);
+        }
+        harness.send(20);
+
+        // A marked ACK shrinks cwnd by alpha / 2 rather than halving it.
+        dctcp.alpha.set(0.25);
+        ack(&dctcp, sender, mss, true);
+        let alpha = dctcp.alpha.get();
+        let reduced = ((11 * mss) as f32 * (1. - alpha / 2.)) as u32;
+        assert_eq!(dctcp.cwnd.get(), reduced);
+        assert_eq!(dctcp.ssthresh.get(), reduced);
+
+        // Further marks in the same window leave it alone, apart from congestion avoidance growth.
+        ack(&dctcp, sender, mss, true);
+        assert!(dctcp.cwnd.get() <= reduced + mss);
+    }
+}
@@ -1,15 +1,33 @@
 use super::sender::Sender;
 use crate::{
     collections::watched::WatchFuture,
+    fail::Fail,
     protocols::tcp::SeqNumber,
 };
-use std::fmt::Debug;
+use std::{
+    cell::RefCell,
+    fmt::{
+        self,
+        Debug,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
 
+mod bbr;
 mod cubic;
+mod dctcp;
 mod none;
 mod options;
 pub use self::{
+    bbr::{
+        Bbr,
+        BbrMode,
+    },
     cubic::Cubic,
+    dctcp::Dctcp,
     none::None,
     options::{
         Options,
@@ -17,30 +35,109 @@ pub use self::{
     },
 };
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CwndChangeReason {
+    Ack,
+    FastRecovery,
+    Rto,
+    // The receiver echoed congestion marks (RFC 3168) to an algorithm that reacts to them.
+    Ecn,
+    // The connection sat idle for longer than an RTO, so cwnd restarts (RFC 5681 Section 4.1).
+    Idle,
+    // The application left part of cwnd unused for longer than an RTO, so cwnd decays towards what it did use
+    // (RFC 7661).
+    AppLimited,
+    Reset,
+    // An earlier reduction turned out to be spurious and was undone.
+    Undo,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CwndChange {
+    // Both in bytes.
+    pub cwnd: u32,
+    pub ssthresh: u32,
+    pub reason: CwndChangeReason,
+}
+
+pub type CwndObserver = Box<dyn Fn(CwndChange)>;
+
+// Holds the observer registered for a connection, if any. Without one, notifying is just a check for `None`.
+#[derive(Default)]
+pub struct CwndObserverSlot {
+    observer: RefCell<Option<CwndObserver>>,
+}
+
+impl CwndObserverSlot {
+    pub fn set(&self, observer: CwndObserver) {
+        *self.observer.borrow_mut() = Some(observer);
+    }
+
+    pub fn notify(&self, change: impl FnOnce() -> CwndChange) {
+        if let Some(ref observer) = *self.observer.borrow() {
+            observer(change());
+        }
+    }
+}
+
+impl Debug for CwndObserverSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CwndObserverSlot({})", if self.observer.borrow().is_some() { "set" } else { "unset" })
+    }
+}
+
 pub trait SlowStartCongestionAvoidance { 
     fn get_cwnd(&self) -> u32 { u32::MAX }
     fn watch_cwnd(&self) -> (u32,  WatchFuture<'_, u32>) { (u32::MAX, WatchFuture::Pending) }
+    fn get_ssthresh(&self) -> u32 { u32::MAX }
+    fn watch_ssthresh(&self) -> (u32, WatchFuture<'_, u32>) { (u32::MAX, WatchFuture::Pending) }
+
+    // The rate in bytes/sec the algorithm would like data paced at, for algorithms that model one. The background
+    // sender waits `len / rate` after each segment of `len` bytes before sending the next. Must be positive.
+    fn get_pacing_rate(&self) -> Option<f64> { None }
+
+    // Registers a callback for every change to cwnd. Algorithms without a congestion window never call it.
+    fn set_cwnd_observer(&self, _observer: CwndObserver) {}
 
     // Called immediately before the cwnd check is performed before data is sent
     fn on_cwnd_check_before_send(&self, _sender: &Sender) {}
 
+    // Called with every ACK's ECE flag, before `on_ack_received`, for algorithms that react to ECN marks.
+    fn on_ecn_feedback(&self, _sender: &Sender, _ack_seq_no: SeqNumber, _ece: bool) {}
+
+    // `sender.window_size` already holds the window the remote advertised along with this ACK.
     fn on_ack_received(&self, _sender: &Sender, _ack_seq_no: SeqNumber) {}
+
+    // Called after an ACK of new data has been processed, with when it arrived and the RTT sample taken from it (if
+    // Karn's algorithm allowed one), for algorithms that estimate delivery rate.
+    fn on_ack_timing(&self, _sender: &Sender, _now: Instant, _rtt: Option<Duration>) {}
     
     // Called immediately before retransmit after RTO
     fn on_rto(&self, _sender: &Sender) {}
 
     // Called immediately before a segment is sent for the 1st time
     fn on_send(&self, _sender: &Sender, _num_sent_bytes: u32) {}
+
+    // Called when the connection is reused or otherwise needs to start over, to return to the initial state
+    fn on_connection_reset(&self, _sender: &Sender) {}
 }
 
 pub trait FastRetransmitRecovery where Self: SlowStartCongestionAvoidance {
     fn get_duplicate_ack_count(&self) -> u32 { 0 }
+    fn watch_duplicate_ack_count(&self) -> (u32, WatchFuture<'_, u32>) { (0, WatchFuture::Pending) }
 
     fn get_retransmit_now_flag(&self) -> bool { false }
     fn watch_retransmit_now_flag(&self) -> (bool, WatchFuture<'_, bool>) { (false, WatchFuture::Pending) }
 
+    fn get_fast_recovery_flag(&self) -> bool { false }
+    fn watch_fast_recovery_flag(&self) -> (bool, WatchFuture<'_, bool>) { (false, WatchFuture::Pending) }
+
     fn on_fast_retransmit(&self, _sender: &Sender) {}
-    fn on_base_seq_no_wraparound(&self, _sender: &Sender) {}
+    // Called when the remote reports (with a D-SACK) that a segment we retransmitted had already arrived.
+    fn on_spurious_retransmit(&self, _sender: &Sender) {}
+    // Called, before `on_spurious_retransmit`, when that retransmission was a fast retransmit: the segment we took for
+    // lost had really been overtaken by `degree` others.
+    fn on_reordering_detected(&self, _sender: &Sender, _degree: u32) {}
 }
 
 pub trait LimitedTransmit where Self: SlowStartCongestionAvoidance {
@@ -57,3 +154,23 @@ pub trait CongestionControl: SlowStartCongestionAvoidance +
 }
 
 pub type CongestionControlConstructor = fn(usize, SeqNumber, Option<options::Options>) -> Box<dyn CongestionControl>;
+
+// Looks up a congestion control algorithm by the name a config file would use for it.
+pub fn constructor_by_name(name: &str) -> Option<CongestionControlConstructor> {
+    match name {
+        "bbr" => Some(Bbr::new),
+        "cubic" => Some(Cubic::new),
+        "dctcp" => Some(Dctcp::new),
+        "none" => Some(None::new),
+        _ => Option::None,
+    }
+}
+
+// Checks every option the algorithms read is in range. No two of them read the same option, so this doesn't need to
+// know which algorithm the options are for. Doing it when the options are set means a bad value is turned away
+// there, rather than panicking the engine when a connection is set up with it.
+pub fn validate_options(options: &options::Options) -> Result<(), Fail> {
+    Bbr::validate_options(options)?;
+    Cubic::validate_options(options)?;
+    Dctcp::validate_options(options)
+}
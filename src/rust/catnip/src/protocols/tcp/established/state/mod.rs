@@ -1,13 +1,28 @@
+#[cfg(feature = "fault-injection")]
+pub mod ack_faults;
+mod checksum;
 pub mod congestion_ctrl;
 pub mod receiver;
+mod rate_limit;
 mod rto;
 pub mod sender;
 
 use self::{
-    receiver::Receiver,
-    sender::Sender,
+    checksum::PayloadChecksums,
+    receiver::{
+        Receiver,
+        ReceiverState,
+    },
+    sender::{
+        Sender,
+        SenderState,
+    },
 };
 use crate::{
+    collections::watched::{
+        WatchFuture,
+        WatchedValue,
+    },
     fail::Fail,
     protocols::{
         arp,
@@ -23,80 +38,401 @@ use crate::{
             Ipv4Header,
             Ipv4Protocol2,
         },
-        tcp::segment::{
-            TcpHeader,
-            TcpSegment,
+        tcp::{
+            segment::{
+                SelectiveAcknowlegement,
+                TcpHeader,
+                TcpOptions2,
+                TcpSegment,
+            },
+            seq_after,
+            seq_before,
+            SeqNumber,
         },
     },
     runtime::Runtime,
     sync::Bytes,
 };
-use std::time::Duration;
+use std::{
+    cell::Cell,
+    collections::hash_map::DefaultHasher,
+    hash::{
+        Hash,
+        Hasher,
+    },
+    num::Wrapping,
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    // Both sides sent FINs and had them acknowledged.
+    Graceful,
+    ResetReceived,
+    ResetSent,
+    // We ran out of retries retransmitting unacknowledged data.
+    RetransmitTimeout,
+    // The remote never answered our SYNs.
+    HandshakeTimeout,
+    // We were told over ICMP that the remote host or port couldn't be reached while we were connecting.
+    Unreachable,
+    // We closed our side, but the remote didn't finish closing its own within `fin_wait_timeout`.
+    FinWaitTimeout,
+}
+
+// Where the connection is in RFC 793's state machine. The sender and receiver each only track their own half of
+// the close, so this puts the two together, along with which side closed first.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionState {
+    Established,
+    // We've closed our side, and our FIN is queued or hasn't been acknowledged yet.
+    FinWait1,
+    // The remote has acknowledged our FIN, but not closed its own side.
+    FinWait2,
+    // Both sides closed at around the same time, and our FIN hasn't been acknowledged yet.
+    Closing,
+    // We closed first, and both FINs are in. We don't wait out 2*MSL yet, so this only lasts until our ACK of the
+    // remote's FIN goes out.
+    TimeWait,
+    // The remote closed first, and we're waiting on the application to close our side.
+    CloseWait,
+    // The remote closed first, and we've sent our FIN after it and are waiting for it to be acknowledged.
+    LastAck,
+    Closed,
+}
 
 pub struct ControlBlock<RT: Runtime> {
-    pub local: ipv4::Endpoint,
-    pub remote: ipv4::Endpoint,
+    local: Cell<ipv4::Endpoint>,
+    remote: Cell<ipv4::Endpoint>,
 
     pub rt: RT,
     pub arp: arp::Peer<RT>,
 
     pub sender: Sender,
     pub receiver: Receiver,
+
+    // Only tracked if `payload_checksums` is set in the TCP options.
+    pub payload_checksums: Option<PayloadChecksums>,
+    // Whether both sides offered SACK-permitted in the handshake.
+    sack_permitted: bool,
+
+    connection_id: u64,
+    close_reason: WatchedValue<Option<CloseReason>>,
+    // Whether the remote's FIN got here while our side was still open, i.e. we're the passive closer.
+    remote_closed_first: Cell<bool>,
 }
 
 impl<RT: Runtime> ControlBlock<RT> {
-    pub fn receive(&self, header: &TcpHeader, data: Bytes) {
+    pub fn new(
+        local: ipv4::Endpoint,
+        remote: ipv4::Endpoint,
+        rt: RT,
+        arp: arp::Peer<RT>,
+        sender: Sender,
+        receiver: Receiver,
+        sack_permitted: bool,
+    ) -> Self {
+        sender.rto.borrow_mut().set_fixed(rt.tcp_options().fixed_rto);
+        let clock_rt = rt.clone();
+        sender.set_clock(Rc::new(move || clock_rt.now()));
+        // The congestion controller took its starting times from the wall clock when the sender was built.
+        sender.reset_congestion_ctrl();
+        let payload_checksums = if rt.tcp_options().payload_checksums {
+            Some(PayloadChecksums::new())
+        } else {
+            None
+        };
+        // Mix in our initial sequence number so a later connection reusing the same four-tuple gets a new id.
+        let mut hasher = DefaultHasher::new();
+        (local, remote, sender.base_seq_no.get()).hash(&mut hasher);
+        let connection_id = hasher.finish();
+        Self {
+            local: Cell::new(local),
+            remote: Cell::new(remote),
+            rt,
+            arp,
+            sender,
+            receiver,
+            payload_checksums,
+            sack_permitted,
+            connection_id,
+            close_reason: WatchedValue::new(None),
+            remote_closed_first: Cell::new(false),
+        }
+    }
+
+    pub fn four_tuple(&self) -> (ipv4::Endpoint, ipv4::Endpoint) {
+        (self.local.get(), self.remote.get())
+    }
+
+    pub fn local(&self) -> ipv4::Endpoint {
+        self.local.get()
+    }
+
+    pub fn remote(&self) -> ipv4::Endpoint {
+        self.remote.get()
+    }
+
+    // Moves the connection to a new four-tuple without touching its sequence state. Everything we send from here
+    // on, including retransmissions of what's already in flight, uses the new addresses, and the remote's link
+    // address is looked up again for them. The new path may have nothing in common with the old one, so
+    // congestion control starts over.
+    pub fn rebind(&self, local: ipv4::Endpoint, remote: ipv4::Endpoint) {
+        debug!("[{:016x}] Rebinding from {:?} to {:?}", self.connection_id, self.four_tuple(), (local, remote));
+        self.local.set(local);
+        self.remote.set(remote);
+        self.sender.reset_congestion_ctrl();
+    }
+
+    // Whether the remote's FIN got here while our side was still open, i.e. we're the passive closer.
+    pub fn remote_closed_first(&self) -> bool {
+        self.remote_closed_first.get()
+    }
+
+    // Identifies this connection in logs and stats. Stable for the connection's lifetime.
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    // Why the connection was torn down, if it has been.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason.get()
+    }
+
+    pub fn watch_close_reason(&self) -> (Option<CloseReason>, WatchFuture<'_, Option<CloseReason>>) {
+        self.close_reason.watch()
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        if self.close_reason.get().is_some() {
+            return ConnectionState::Closed;
+        }
+        let remote_closed = self.receiver.state.get() != ReceiverState::Open;
+        let remote_closed_first = self.remote_closed_first.get();
+        match (self.sender.state.get(), remote_closed) {
+            (SenderState::Reset, _) => ConnectionState::Closed,
+            (SenderState::Open, false) => ConnectionState::Established,
+            (SenderState::Closed, false) | (SenderState::SentFin, false) => ConnectionState::FinWait1,
+            (SenderState::FinAckd, false) => ConnectionState::FinWait2,
+            (SenderState::Open, true) => ConnectionState::CloseWait,
+            // Our FIN only goes out once the last of our data has, so the application may have closed in
+            // CLOSE_WAIT without us having moved on to LAST_ACK yet.
+            (SenderState::Closed, true) if remote_closed_first => ConnectionState::CloseWait,
+            (SenderState::Closed, true) => ConnectionState::Closing,
+            (SenderState::SentFin, true) if remote_closed_first => ConnectionState::LastAck,
+            (SenderState::SentFin, true) => ConnectionState::Closing,
+            // As the passive closer we're done once our FIN is acknowledged, and only waiting to be torn down.
+            (SenderState::FinAckd, true) if remote_closed_first => ConnectionState::Closed,
+            (SenderState::FinAckd, true) => ConnectionState::TimeWait,
+        }
+    }
+
+    // Records why the connection is being torn down. Only the first reason sticks, since anything after it is
+    // fallout from the teardown itself. Unless both sides closed gracefully, whatever the application is still
+    // waiting on (sends, flushes and reads alike) fails with `ConnectionAborted`.
+    pub fn set_close_reason(&self, reason: CloseReason) {
+        if self.close_reason.get().is_some() {
+            return;
+        }
+        debug!("[{:016x}] Connection closed: {:?}", self.connection_id, reason);
+        self.close_reason.set(Some(reason));
+        if reason != CloseReason::Graceful {
+            self.sender.abort();
+            self.receiver.abort();
+        }
+    }
+
+    // `arrival` is when the runtime took the segment off the wire, which can be a little before we get to
+    // processing it. RTT samples are taken against it so they don't pick up our own queueing delay.
+    pub fn receive(&self, header: &TcpHeader, data: Bytes, arrival: Instant) {
         let now = self.rt.now();
+        if self.sender.state.get() == SenderState::Reset {
+            warn!("Dropping segment for aborted connection: {:?}", header);
+            return;
+        }
         if header.syn {
             warn!("Ignoring duplicate SYN on established connection");
         }
         if header.rst {
-            unimplemented!();
-        }
-        if header.fin {
-            self.receiver.receive_fin();
-        }
-        if header.ack {
-            if let Err(e) = self.sender.remote_ack(header.ack_num, now) {
-                warn!("Ignoring remote ack for {:?}: {:?}", header, e);
+            // RFC 5961 Section 3.2: Only a RST at exactly the next sequence number we expect resets the connection,
+            // since anywhere else in the window it may be a guess. Those get a challenge ACK instead, which a remote
+            // that really did reset answers with a RST we'll take. Anything outside the window is dropped.
+            let expected_seq_no = self.receiver.expected_seq_no();
+            let window_end = expected_seq_no + Wrapping(self.receiver.window_size());
+            if header.seq_num == expected_seq_no {
+                self.set_close_reason(CloseReason::ResetReceived);
+            } else if seq_after(header.seq_num, expected_seq_no) && seq_before(header.seq_num, window_end) {
+                warn!("Sending challenge ACK for RST in window: {:?}", header);
+                self.send_ack();
+            } else {
+                warn!("Dropping RST outside the window: {:?}", header);
             }
+            return;
+        }
+        if header.urg {
+            self.receiver.receive_urgent(header.seq_num, header.urgent_pointer);
         }
+        // Take the window first, so the congestion controller sees the one advertised along with this ACK.
         if let Err(e) = self.sender.update_remote_window(header.window_size as u16) {
             warn!("Invalid window size update for {:?}: {:?}", header, e);
         }
+        if header.ack {
+            self.sender.congestion_ctrl.on_ecn_feedback(&self.sender, header.ack_num, header.ece);
+            match self.sender.remote_ack(header.ack_num, arrival) {
+                Ok(()) => (),
+                // RFC 793 Section 3.9: An ACK for something we haven't sent gets the remote an ACK telling it where
+                // we really are, and the rest of the segment is dropped.
+                Err(Fail::Invalid { .. }) => {
+                    warn!("Dropping segment acknowledging unsent data: {:?}", header);
+                    self.send_ack();
+                    return;
+                },
+                Err(e) => warn!("Ignoring remote ack for {:?}: {:?}", header, e),
+            }
+            if self.sack_permitted {
+                if let Some((begin, end)) = dsack_block(header) {
+                    self.sender.receive_dsack(begin, end);
+                }
+            }
+        }
+        let fin_seq_no = header.seq_num + Wrapping(data.len() as u32);
         if !data.is_empty() {
+            let past_gap = seq_after(header.seq_num, self.receiver.recv_seq_no.get());
             if let Err(e) = self.receiver.receive_data(header.seq_num, data, now) {
                 warn!("Ignoring remote data for {:?}: {:?}", header, e);
             }
+            // RFC 5681 Section 4.2: Each segment that arrives past a gap gets its own duplicate ACK right away,
+            // rather than waiting on the delayed ACK timer, so the sender can count them towards fast retransmit.
+            if past_gap {
+                self.send_ack();
+            }
+        }
+        // A FIN may come on the same segment as the last of the data, which has to be taken in first.
+        if header.fin {
+            match self.receiver.receive_fin(fin_seq_no) {
+                Ok(()) if self.sender.state.get() == SenderState::Open => self.remote_closed_first.set(true),
+                Ok(()) => (),
+                Err(e) => warn!("Ignoring remote FIN for {:?}: {:?}", header, e),
+            }
         }
     }
 
+    fn send_ack(&self) {
+        // A delayed ACK can't go right away, so leave it to the acknowledger to hold it back.
+        #[cfg(feature = "fault-injection")]
+        {
+            if self.receiver.ack_faults.delay() > Duration::new(0, 0) {
+                self.receiver.ack_deadline.set(Some(self.rt.now()));
+                return;
+            }
+        }
+        match self.arp.try_query(self.remote.get().address()) {
+            Some(remote_link_addr) => {
+                let header = self.tcp_header();
+                self.emit_ack(header, remote_link_addr);
+            },
+            None => self.receiver.ack_deadline.set(Some(self.rt.now())),
+        }
+    }
+
+    // Sends a segment that only carries an ACK. These all come through here so fault injection can drop them.
+    pub fn emit_ack(&self, header: TcpHeader, remote_link_addr: MacAddress) {
+        #[cfg(feature = "fault-injection")]
+        {
+            if self.receiver.ack_faults.should_drop() {
+                // As far as the receiver's concerned it went out, or it'd only try again.
+                self.receiver.ack_sent(header.ack_num);
+                return;
+            }
+        }
+        self.emit(header, Bytes::empty(), remote_link_addr);
+    }
+
     pub fn close(&self) -> Result<(), Fail> {
         self.sender.close()
     }
 
+    // Tears the connection down right away rather than closing it: both directions drop whatever they're holding,
+    // and the closer sends the remote a RST.
+    pub fn abort(&self) -> Result<(), Fail> {
+        if self.sender.state.get() == SenderState::Reset || self.close_reason.get().is_some() {
+            return Err(Fail::Ignored {
+                details: "Connection already closed",
+            });
+        }
+        self.sender.abort();
+        self.receiver.abort();
+        Ok(())
+    }
+
     pub fn tcp_header(&self) -> TcpHeader {
-        let mut header = TcpHeader::new(self.local.port, self.remote.port);
-        // TODO: Support window scaling here.
-        header.window_size = self.receiver.window_size() as u16;
+        let mut header = TcpHeader::new(self.local.get().port, self.remote.get().port);
+        // Segments carrying data or a FIN set their own sequence number. Anything else, like a pure ACK, still has
+        // to carry the next one we'd send, or the remote will take it for out of window.
+        header.seq_num = self.sender.next_seq_no();
+        header.window_size = self.receiver.advertised_window();
         if let Some(ack_seq_no) = self.receiver.current_ack() {
             header.ack_num = ack_seq_no;
             header.ack = true;
         }
+        if let Some((begin, end)) = self.receiver.duplicate_range.take() {
+            if self.sack_permitted && self.rt.tcp_options().dsack {
+                // Only the first block is sent, the rest are padding.
+                let sacks = [SelectiveAcknowlegement { begin, end }; 4];
+                header.push_option(TcpOptions2::SelectiveAcknowlegement { num_sacks: 1, sacks });
+            }
+        }
         header
     }
 
+    // Sends a segment that bypasses the sender entirely, so nothing is queued for retransmission and the sender's
+    // sequence numbers don't move. The header starts out as `tcp_header()` at the next sequence number we'd send,
+    // and `craft` can then change anything in it. Only for reproducing specific (even bogus) sequences in tests.
+    #[cfg(test)]
+    pub fn emit_crafted(&self, craft: impl FnOnce(&mut TcpHeader), data: Bytes) -> Result<(), Fail> {
+        let remote_link_addr = match self.arp.try_query(self.remote.get().address()) {
+            Some(addr) => addr,
+            None => {
+                return Err(Fail::ResourceNotFound {
+                    details: "Remote link address not cached",
+                })
+            },
+        };
+        let mut header = self.tcp_header();
+        craft(&mut header);
+        // Only an ACK we'd have sent anyway counts as sending one, since a crafted one may be bogus.
+        if header.ack && Some(header.ack_num) == self.receiver.current_ack() {
+            self.receiver.ack_sent(header.ack_num);
+        }
+        self.transmit(header, data, remote_link_addr);
+        Ok(())
+    }
+
     pub fn emit(&self, header: TcpHeader, data: Bytes, remote_link_addr: MacAddress) {
         if header.ack {
             self.receiver.ack_sent(header.ack_num);
         }
+        self.transmit(header, data, remote_link_addr);
+    }
+
+    fn transmit(&self, mut header: TcpHeader, data: Bytes, remote_link_addr: MacAddress) {
+        if !data.is_empty() {
+            if let Some(urgent_pointer) = self.sender.urgent_pointer(header.seq_num) {
+                header.urg = true;
+                header.urgent_pointer = urgent_pointer;
+            }
+        }
         let segment = TcpSegment {
             ethernet2_hdr: Ethernet2Header {
                 dst_addr: remote_link_addr,
                 src_addr: self.rt.local_link_addr(),
                 ether_type: EtherType2::Ipv4,
             },
-            ipv4_hdr: Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp),
+            ipv4_hdr: Ipv4Header::new(self.local.get().addr, self.remote.get().addr, Ipv4Protocol2::Tcp),
             tcp_hdr: header,
             data,
         };
@@ -110,4 +446,32 @@ impl<RT: Runtime> ControlBlock<RT> {
     pub fn current_rto(&self) -> Duration {
         self.sender.current_rto()
     }
+
+    pub fn rto_estimate(&self) -> Duration {
+        self.sender.rto_estimate()
+    }
+}
+
+// RFC 2883 Section 4: The first SACK block reports a duplicate rather than data past a gap if it's below the
+// cumulative ACK, or if it sits inside the second block.
+fn dsack_block(header: &TcpHeader) -> Option<(SeqNumber, SeqNumber)> {
+    let (num_sacks, sacks) = header.iter_options().find_map(|option| match option {
+        TcpOptions2::SelectiveAcknowlegement { num_sacks, sacks } if *num_sacks > 0 => Some((*num_sacks, sacks)),
+        _ => None,
+    })?;
+    let first = sacks[0];
+    let Wrapping(below_ack) = header.ack_num - first.end;
+    if below_ack < 1 << 31 {
+        return Some((first.begin, first.end));
+    }
+    if num_sacks > 1 {
+        let second = sacks[1];
+        let Wrapping(begin_offset) = first.begin - second.begin;
+        let Wrapping(end_offset) = first.end - second.begin;
+        let Wrapping(second_len) = second.end - second.begin;
+        if begin_offset <= end_offset && end_offset <= second_len {
+            return Some((first.begin, first.end));
+        }
+    }
+    None
 }
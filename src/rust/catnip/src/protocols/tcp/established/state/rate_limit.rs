@@ -0,0 +1,91 @@
+use crate::fail::Fail;
+use std::{
+    cell::RefCell,
+    cmp,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// Token bucket refilled at `rate` bytes per second, holding at most `burst` bytes' worth of tokens.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    burst: usize,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last_refill {
+            return;
+        }
+        let elapsed = now - self.last_refill;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.burst as f64);
+        self.last_refill = now;
+    }
+
+    fn try_take(&mut self, len: usize, now: Instant) -> Result<usize, Instant> {
+        self.refill(now);
+        // Wait for enough tokens to cover `len`, or a full bucket if that's smaller, rather than trickling out
+        // whatever little has built up.
+        let wanted = cmp::min(len, self.burst) as f64;
+        if self.tokens < wanted {
+            let nanos = ((wanted - self.tokens) * 1e9 / self.rate as f64).ceil() as u64;
+            return Err(now + Duration::from_nanos(nanos));
+        }
+        let taken = cmp::min(len, self.tokens as usize);
+        self.tokens -= taken as f64;
+        Ok(taken)
+    }
+}
+
+// Optional limit on how fast one direction of a connection moves data, in bytes per second.
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    bucket: RefCell<Option<TokenBucket>>,
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        Self {
+            bucket: RefCell::new(None),
+        }
+    }
+
+    // Sets the rate, or removes the limit if it's `None`. The bucket starts out full and holds `burst` bytes, so
+    // up to that much can go at once.
+    pub fn set(&self, rate: Option<u64>, burst: usize, now: Instant) -> Result<(), Fail> {
+        let bucket = match rate {
+            Some(0) => {
+                return Err(Fail::OutOfRange {
+                    details: "Rate limit must be positive",
+                })
+            },
+            Some(rate) => Some(TokenBucket {
+                rate,
+                burst,
+                tokens: burst as f64,
+                last_refill: now,
+            }),
+            None => None,
+        };
+        *self.bucket.borrow_mut() = bucket;
+        Ok(())
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.bucket.borrow().is_some()
+    }
+
+    // How many of `len` bytes may go right now, taking them out of the bucket, or when enough will have built up
+    // if too few have.
+    pub fn try_take(&self, len: usize, now: Instant) -> Result<usize, Instant> {
+        match *self.bucket.borrow_mut() {
+            Some(ref mut bucket) => bucket.try_take(len, now),
+            None => Ok(len),
+        }
+    }
+}
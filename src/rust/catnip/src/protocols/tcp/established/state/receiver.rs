@@ -1,12 +1,25 @@
+#[cfg(feature = "fault-injection")]
+use super::ack_faults::AckFaultInjector;
+use super::rate_limit::RateLimit;
 use crate::{
     collections::watched::WatchedValue,
     fail::Fail,
-    protocols::tcp::SeqNumber,
-    sync::Bytes,
+    protocols::tcp::{
+        seq_before,
+        SeqNumber,
+    },
+    sync::{
+        Bytes,
+        BytesMut,
+    },
 };
 use std::{
     cell::{Cell, RefCell},
-    collections::VecDeque,
+    cmp,
+    collections::{
+        vec_deque,
+        VecDeque,
+    },
     num::Wrapping,
     task::{
         Context,
@@ -41,21 +54,39 @@ pub struct Receiver {
     pub ack_seq_no: WatchedValue<SeqNumber>,
     pub recv_seq_no: WatchedValue<SeqNumber>,
     pub available: Cell<usize>,
+    // Sequence number of the last byte of urgent data the remote has pointed us at (RFC 1122 Section 4.2.2.4).
+    pub urgent_seq_no: Cell<Option<SeqNumber>>,
+    // The most recent range of data the remote sent us again after we'd already received it, to be reported back in
+    // a D-SACK block (RFC 2883) on our next ACK.
+    pub duplicate_range: Cell<Option<(SeqNumber, SeqNumber)>>,
 
     pub ack_deadline: WatchedValue<Option<Instant>>,
     // According to RFC1122, even when using delayed ACKs, we must ACK at least every second
-    // full segment immediately, so we track if the last segment was full-size
-    pub last_segment_was_full_size: Cell<bool>,
-    pub acked_last_full_size_segment: Cell<bool>,
+    // full segment immediately, so we count the consecutive full-size segments we haven't ACKed yet
+    pub full_segments_unacked: Cell<usize>,
+    // How many full-size segments we let go unacknowledged before ACKing immediately.
+    pub ack_ratio: usize,
     pub mss: usize,
 
     pub max_window_size: u32,
+    // Our shift for the windows we advertise, which is zero unless the remote agreed to window scaling.
+    pub window_scale: u8,
+    // The most unread data we'll buffer. The window we advertise shrinks as unread data approaches this, so it
+    // closes at the limit rather than offering room we'd then have to drop.
+    pub recv_buffer_limit: u32,
+
+    // Caps how fast the application can read data out, if set.
+    pub rate_limit: RateLimit,
+    #[cfg(feature = "fault-injection")]
+    pub ack_faults: AckFaultInjector,
 
+    // Set once the connection's been aborted, after which there's nothing left to read.
+    aborted: Cell<bool>,
     waker: RefCell<Option<Waker>>,
 }
 
 impl Receiver {
-    pub fn new(seq_no: SeqNumber, max_window_size: u32, mss: usize) -> Self {
+    pub fn new(seq_no: SeqNumber, max_window_size: u32, window_scale: u8, recv_buffer_limit: u32, mss: usize, ack_ratio: usize) -> Self {
         Self {
             state: WatchedValue::new(ReceiverState::Open),
             base_seq_no: WatchedValue::new(seq_no),
@@ -63,18 +94,33 @@ impl Receiver {
             ack_seq_no: WatchedValue::new(seq_no),
             recv_seq_no: WatchedValue::new(seq_no),
             available: Cell::new(0),
+            urgent_seq_no: Cell::new(None),
+            duplicate_range: Cell::new(None),
             ack_deadline: WatchedValue::new(None),
-            last_segment_was_full_size: Cell::new(false),
-            acked_last_full_size_segment: Cell::new(false),
+            full_segments_unacked: Cell::new(0),
+            ack_ratio,
             mss,
-            max_window_size,
+            // We can't offer more than the window field can describe at our scale.
+            max_window_size: cmp::min(max_window_size, (u16::max_value() as u32) << window_scale),
+            window_scale,
+            recv_buffer_limit,
+            rate_limit: RateLimit::new(),
+            #[cfg(feature = "fault-injection")]
+            ack_faults: AckFaultInjector::new(),
+            aborted: Cell::new(false),
             waker: RefCell::new(None),
         }
     }
 
     pub fn window_size(&self) -> u32 {
         let Wrapping(bytes_outstanding) = self.recv_seq_no.get() - self.base_seq_no.get();
-        self.max_window_size - bytes_outstanding
+        cmp::min(self.max_window_size, self.recv_buffer_limit).saturating_sub(bytes_outstanding)
+    }
+
+    // The window to put in the header, scaled down by our shift. This rounds down, so we never offer more room than
+    // we have.
+    pub fn advertised_window(&self) -> u16 {
+        (self.window_size() >> self.window_scale) as u16
     }
 
     pub fn current_ack(&self) -> Option<SeqNumber> {
@@ -84,13 +130,53 @@ impl Receiver {
             Some(recv_seq_no)
     }
 
+    // RCV.NXT: the next sequence number we expect from the remote, which is one past its FIN once we've taken that.
+    pub fn expected_seq_no(&self) -> SeqNumber {
+        match self.state.get() {
+            ReceiverState::Open => self.recv_seq_no.get(),
+            _ => self.recv_seq_no.get() + Wrapping(1),
+        }
+    }
+
     pub fn ack_sent(&self, seq_no: SeqNumber) {
-        assert_eq!(seq_no, self.recv_seq_no.get());
+        let recv_seq_no = self.recv_seq_no.get();
+        // Our ACK of the remote's FIN also covers the sequence number the FIN consumed.
+        if self.state.get() != ReceiverState::Open && seq_no == recv_seq_no + Wrapping(1) {
+            self.ack_deadline.set(None);
+            return;
+        }
+        assert_eq!(seq_no, recv_seq_no);
         self.ack_deadline.set(None);
         self.ack_seq_no.set(seq_no);
+        self.full_segments_unacked.set(0);
+    }
+
+    // Whether a read would complete right away, with either data or the end of the stream.
+    pub fn is_readable(&self) -> bool {
+        self.available.get() > 0 || self.state.get() != ReceiverState::Open || self.aborted.get()
+    }
+
+    // Drops everything the application hasn't read yet. Reads from then on, including one that's already waiting,
+    // fail with `ConnectionAborted`.
+    pub fn abort(&self) {
+        self.recv_queue.borrow_mut().clear();
+        self.available.set(0);
+        self.ack_deadline.set(None);
+        self.aborted.set(true);
+        if let Some(w) = self.waker.borrow_mut().take() {
+            w.wake()
+        }
+    }
+
+    fn check_aborted(&self) -> Result<(), Fail> {
+        if self.aborted.get() {
+            return Err(Fail::ConnectionAborted {});
+        }
+        Ok(())
     }
 
     pub fn peek(&self) -> Result<Bytes, Fail> {
+        self.check_aborted()?;
         if self.base_seq_no.get() == self.recv_seq_no.get() {
             if self.state.get() != ReceiverState::Open {
                 return Err(Fail::ResourceNotFound {
@@ -112,7 +198,115 @@ impl Receiver {
         Ok(segment)
     }
 
+    // Like `peek`, but merges consecutive segments so the result holds at least `amount` bytes.
+    pub fn peek_amount(&self, amount: usize) -> Result<Bytes, Fail> {
+        self.check_aborted()?;
+        let available = self.available.get();
+        if available == 0 && amount == 0 && self.state.get() == ReceiverState::Open {
+            return Ok(Bytes::empty());
+        }
+        if available < amount || available == 0 {
+            if self.state.get() == ReceiverState::Open {
+                return Err(Fail::ResourceExhausted {
+                    details: "Not enough available data",
+                });
+            }
+            if available == 0 {
+                return Err(Fail::ResourceNotFound {
+                    details: "Receiver closed",
+                });
+            }
+        }
+
+        let recv_queue = self.recv_queue.borrow();
+        let front = recv_queue
+            .front()
+            .expect("recv_seq > base_seq without data in queue?");
+        if front.len() >= amount {
+            return Ok(front.clone());
+        }
+
+        let mut segments_len = 0;
+        let mut num_segments = 0;
+        for segment in recv_queue.iter() {
+            if segments_len >= amount {
+                break;
+            }
+            segments_len += segment.len();
+            num_segments += 1;
+        }
+        let mut buf = BytesMut::zeroed(segments_len);
+        let mut offset = 0;
+        for segment in recv_queue.iter().take(num_segments) {
+            buf[offset..(offset + segment.len())].copy_from_slice(&segment[..]);
+            offset += segment.len();
+        }
+        Ok(buf.freeze())
+    }
+
+    // Every segment available to the application, in order, without removing them from the queue. The segments
+    // share their storage with the queue, so this doesn't copy any data.
+    pub fn peek_segments(&self) -> Result<vec_deque::IntoIter<Bytes>, Fail> {
+        self.check_aborted()?;
+        if self.base_seq_no.get() == self.recv_seq_no.get() && self.state.get() != ReceiverState::Open {
+            return Err(Fail::ResourceNotFound {
+                details: "Receiver closed",
+            });
+        }
+        Ok(self.recv_queue.borrow().clone().into_iter())
+    }
+
+    // Removes the first `amount` bytes from the queue, splitting a segment if it ends partway through one.
+    pub fn consume(&self, amount: usize) -> Result<(), Fail> {
+        self.check_aborted()?;
+        if amount > self.available.get() {
+            return Err(Fail::OutOfRange {
+                details: "Consumed more than available",
+            });
+        }
+        let mut recv_queue = self.recv_queue.borrow_mut();
+        let mut remaining = amount;
+        while remaining > 0 {
+            let segment = recv_queue
+                .pop_front()
+                .expect("Available data without a segment in queue?");
+            if segment.len() > remaining {
+                let (_, tail) = segment.split(remaining);
+                recv_queue.push_front(tail);
+                break;
+            }
+            remaining -= segment.len();
+        }
+        self.base_seq_no.modify(|b| b + Wrapping(amount as u32));
+        self.available.set(self.available.get() - amount);
+        Ok(())
+    }
+
+    pub fn poll_peek(&self, ctx: &mut Context) -> Poll<Result<Bytes, Fail>> {
+        if let Err(e) = self.check_aborted() {
+            return Poll::Ready(Err(e));
+        }
+        if self.base_seq_no.get() == self.recv_seq_no.get() {
+            if self.state.get() != ReceiverState::Open {
+                return Poll::Ready(Err(Fail::ResourceNotFound {
+                    details: "Receiver closed",
+                }));
+            }
+            *self.waker.borrow_mut() = Some(ctx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let segment = self
+            .recv_queue
+            .borrow()
+            .front()
+            .expect("recv_seq > base_seq without data in queue?")
+            .clone();
+        Poll::Ready(Ok(segment))
+    }
+
     pub fn recv(&self) -> Result<Option<Bytes>, Fail> {
+        self.check_aborted()?;
         if self.base_seq_no.get() == self.recv_seq_no.get() {
             if self.state.get() != ReceiverState::Open {
                 return Err(Fail::ResourceNotFound {
@@ -134,6 +328,9 @@ impl Receiver {
     }
 
     pub fn poll_recv(&self, ctx: &mut Context) -> Poll<Result<Bytes, Fail>> {
+        if let Err(e) = self.check_aborted() {
+            return Poll::Ready(Err(e));
+        }
         if self.base_seq_no.get() == self.recv_seq_no.get() {
             if self.state.get() != ReceiverState::Open {
                 return Poll::Ready(Err(Fail::ResourceNotFound {
@@ -155,36 +352,102 @@ impl Receiver {
         Poll::Ready(Ok(segment))
     }
 
-    pub fn receive_fin(&self) {
+    // Called after the application reads with the window from before the read. If that reopened a window that was
+    // too small for a full segment, the sender has probably stopped, so let it know right away.
+    pub fn schedule_window_update(&self, window_before: u32, now: Instant) {
+        let mss = self.mss as u32;
+        if window_before < mss && self.window_size() >= mss {
+            self.ack_deadline.set(Some(now));
+        }
+    }
+
+    pub fn receive_urgent(&self, seq_no: SeqNumber, urgent_pointer: u16) {
+        let urgent_seq_no = seq_no + Wrapping(urgent_pointer as u32);
+        // Only ever move the pointer forward, since segments may arrive out of order.
+        if let Some(current) = self.urgent_seq_no.get() {
+            if seq_before(urgent_seq_no, current) {
+                return;
+            }
+        }
+        self.urgent_seq_no.set(Some(urgent_seq_no));
+    }
+
+    // Offset from the next byte the application will read to the last byte of urgent data, or `None` if there's no
+    // urgent data it hasn't read yet.
+    pub fn urgent_offset(&self) -> Option<usize> {
+        let urgent_seq_no = self.urgent_seq_no.get()?;
+        let base_seq_no = self.base_seq_no.get();
+        if seq_before(urgent_seq_no, base_seq_no) {
+            return None;
+        }
+        let Wrapping(offset) = urgent_seq_no - base_seq_no;
+        Some(offset as usize)
+    }
+
+    // `seq_no` is the sequence number the FIN takes, just past any data on the same segment. A FIN that gets here
+    // ahead of some of the data before it can't close the receiver yet, or that data would be turned away when it
+    // does arrive, so it's dropped and the remote retransmits it.
+    pub fn receive_fin(&self, seq_no: SeqNumber) -> Result<(), Fail> {
+        if seq_no != self.recv_seq_no.get() {
+            return Err(Fail::Ignored {
+                details: "FIN ahead of missing data",
+            });
+        }
         // Even if we've already ACKd the FIN, we need to resend the ACK if we receive another FIN.
         self.state.set(ReceiverState::ReceivedFin);
+        // A reader waiting on more data gets whatever is left in the queue, then the end of the stream.
+        if let Some(w) = self.waker.borrow_mut().take() {
+            w.wake()
+        }
+        Ok(())
     }
 
-    pub fn receive_data(&self, seq_no: SeqNumber, buf: Bytes, now: Instant) -> Result<(), Fail> {
-        let buf_len = buf.len();
+    pub fn receive_data(&self, seq_no: SeqNumber, mut buf: Bytes, now: Instant) -> Result<(), Fail> {
         if self.state.get() != ReceiverState::Open {
             return Err(Fail::ResourceNotFound {
                 details: "Receiver closed",
             });
         }
 
-        if self.recv_seq_no.get() != seq_no {
-            return Err(Fail::Ignored {
-                details: "Out of order segment",
-            });
+        let recv_seq_no = self.recv_seq_no.get();
+        if recv_seq_no != seq_no {
+            // If the segment starts before `recv_seq_no`, it overlaps data we've already received, most likely
+            // because the peer retransmitted it. Otherwise it's from the future, which we don't buffer yet.
+            if !seq_before(seq_no, recv_seq_no) {
+                return Err(Fail::Ignored {
+                    details: "Out of order segment",
+                });
+            }
+            let Wrapping(overlap) = recv_seq_no - seq_no;
+            if overlap as usize >= buf.len() {
+                // The peer may have missed our ACK, so resend it right away.
+                self.duplicate_range.set(Some((seq_no, seq_no + Wrapping(buf.len() as u32))));
+                self.ack_deadline.set(Some(now));
+                return Err(Fail::Ignored {
+                    details: "Duplicate segment",
+                });
+            }
+            self.duplicate_range.set(Some((seq_no, recv_seq_no)));
+            let (_, new_data) = buf.split(overlap as usize);
+            buf = new_data;
         }
 
-        let unread_bytes = self
-            .recv_queue
-            .borrow()
-            .iter()
-            .map(|b| b.len())
-            .sum::<usize>();
-        if unread_bytes + buf_len > self.max_window_size as usize {
+        // The window we advertise shrinks as unread data builds up, so a well-behaved sender stops before we
+        // have to drop anything. If it sends into a closed window anyway (e.g. to probe it), remind it where the
+        // window is.
+        let window_size = self.window_size() as usize;
+        if window_size == 0 {
+            self.ack_deadline.set(Some(now));
             return Err(Fail::Ignored {
                 details: "Full receive window",
             });
         }
+        // As a last resort, keep what fits and let the sender retransmit the rest.
+        if buf.len() > window_size {
+            let (fits, _) = buf.split(window_size);
+            buf = fits;
+        }
+        let buf_len = buf.len();
 
         self.recv_seq_no.modify(|r| r + Wrapping(buf_len as u32));
         self.available.set(self.available.get() + buf_len);
@@ -192,26 +455,22 @@ impl Receiver {
         self.waker.borrow_mut().take().map(|w| w.wake());
 
         // TODO: How do we handle when the other side is in PERSIST state here?
-        // According to RFC1122, we ACK every 2nd consecutive full-size segment no matter what
-        // If the last segment had size MSS, this has size MSS and we have at least 2 * MSS bytes to ACK, ACK now
-        if buf_len == self.mss && self.last_segment_was_full_size.get() && !self.acked_last_full_size_segment.get() {
-            self.acked_last_full_size_segment.set(true);
-                    self.ack_deadline.set(Some(now));
-        } else if buf_len == self.mss {
-                self.last_segment_was_full_size.set(true);
-            self.acked_last_full_size_segment.set(false);
-            if self.ack_deadline.get().is_none() {
-                // TODO: Configure this value (and also maybe just have an RT pointer here.)
-                self.ack_deadline
-                    .set(Some(now + Duration::from_millis(500)));
+        // According to RFC1122, we ACK every 2nd consecutive full-size segment no matter what. `ack_ratio` is that
+        // 2 unless it's been stretched for experiments.
+        if buf_len == self.mss {
+            let full_segments_unacked = self.full_segments_unacked.get() + 1;
+            self.full_segments_unacked.set(full_segments_unacked);
+            if full_segments_unacked >= self.ack_ratio {
+                self.ack_deadline.set(Some(now));
+                return Ok(());
             }
-        } else if self.ack_deadline.get().is_none() {
-            self.last_segment_was_full_size.set(false);
+        } else {
+            self.full_segments_unacked.set(0);
+        }
+        if self.ack_deadline.get().is_none() {
             // TODO: Configure this value (and also maybe just have an RT pointer here.)
             self.ack_deadline
                 .set(Some(now + Duration::from_millis(500)));
-        } else {
-            self.last_segment_was_full_size.set(false);
         }
 
         Ok(())
@@ -12,6 +12,9 @@ pub struct RtoCalculator {
     rto: f64,
 
     received_sample: bool,
+
+    // Overrides the estimate when set, without affecting how it's computed.
+    fixed: Option<Duration>,
 }
 
 impl RtoCalculator {
@@ -22,9 +25,15 @@ impl RtoCalculator {
             rto: 1.0,
 
             received_sample: false,
+
+            fixed: None,
         }
     }
 
+    pub fn set_fixed(&mut self, rto: Option<Duration>) {
+        self.fixed = rto;
+    }
+
     pub fn add_sample(&mut self, rtt: Duration) {
         const ALPHA: f64 = 0.125;
         const BETA: f64 = 0.25;
@@ -67,7 +76,12 @@ impl RtoCalculator {
         self.update_rto(self.rto * 2.0);
     }
 
+    // The RTO to use for retransmission timers: the fixed one if we've been given one, or else our estimate.
     pub fn estimate(&self) -> Duration {
+        self.fixed.unwrap_or_else(|| self.adaptive_estimate())
+    }
+
+    pub fn adaptive_estimate(&self) -> Duration {
         FloatDuration::seconds(self.rto).to_std().unwrap()
     }
 }
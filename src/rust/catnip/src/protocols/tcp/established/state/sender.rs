@@ -1,26 +1,52 @@
 use super::{
+    rate_limit::RateLimit,
     rto::RtoCalculator,
     congestion_ctrl as cc
 };
 use crate::{
-    collections::watched::WatchedValue,
+    collections::{
+        waker_set::WakerSet,
+        watched::WatchedValue,
+    },
     fail::Fail,
-    protocols::tcp::SeqNumber,
-    sync::Bytes,
+    protocols::tcp::{
+        seq_after,
+        SeqNumber,
+    },
+    sync::{
+        Bytes,
+        BytesMut,
+    },
 };
 use std::{
     boxed::Box,
-    cell::RefCell,
+    cell::{
+        Cell,
+        RefCell,
+    },
+    cmp,
     collections::VecDeque,
     convert::TryInto,
     fmt,
+    mem,
     num::Wrapping,
+    rc::Rc,
+    task::{
+        Context,
+        Poll,
+    },
     time::{
         Duration,
         Instant,
     },
 };
 
+// How many of our most recent retransmissions we remember, to match D-SACK blocks against.
+const MAX_RETRANSMITTED_RANGES: usize = 16;
+
+// How many of the most recent send-to-ACK latencies we keep around for the application to look at.
+const MAX_ACK_LATENCY_SAMPLES: usize = 1024;
+
 pub struct UnackedSegment {
     pub bytes: Bytes,
     // Set to `None` on retransmission to implement Karn's algorithm.
@@ -33,11 +59,12 @@ pub enum SenderState {
     Closed,
     SentFin,
     FinAckd,
-
-    #[allow(unused)]
     Reset,
 }
 
+// Where a sender and its congestion controller get the current time from.
+pub type Clock = Rc<dyn Fn() -> Instant>;
+
 pub struct Sender {
     pub state: WatchedValue<SenderState>,
 
@@ -55,6 +82,15 @@ pub struct Sender {
     pub sent_seq_no: WatchedValue<SeqNumber>,
     pub unsent_queue: RefCell<VecDeque<Bytes>>,
     pub unsent_seq_no: WatchedValue<SeqNumber>,
+    // RFC 1122 Section 4.2.2.4: Sequence number of the last byte of urgent data, until it's acknowledged.
+    pub urgent_seq_no: Cell<Option<SeqNumber>>,
+
+    // Maximum number of bytes that may be unacknowledged or unsent at once.
+    pub send_buffer_size: usize,
+    // Waiting to send, or for everything sent to be acknowledged.
+    waiters: WakerSet,
+    // Waiting in `poll_sent` for data to go out, rather than to be acknowledged.
+    sent_waiters: WakerSet,
 
     pub window_size: WatchedValue<u32>,
     // RFC 1323: Number of bits to shift advertised window, defaults to zero.
@@ -64,8 +100,21 @@ pub struct Sender {
 
     pub retransmit_deadline: WatchedValue<Option<Instant>>,
     pub rto: RefCell<RtoCalculator>,
+    // Sequence ranges we've recently retransmitted, oldest first, and whether duplicate ACKs (rather than a timeout)
+    // were behind each. A D-SACK for one of them means the retransmission wasn't needed.
+    pub retransmitted_ranges: RefCell<VecDeque<(SeqNumber, SeqNumber, bool)>>,
+    // How long each recently acknowledged segment took from being sent to being acknowledged, oldest first. Unlike
+    // the RTO estimator, this keeps every sample rather than a smoothed average, so the distribution is visible.
+    pub ack_latencies: RefCell<VecDeque<Duration>>,
 
     pub congestion_ctrl: Box<dyn cc::CongestionControl>,
+
+    // Caps how fast the background sender puts data on the wire, if set.
+    pub rate_limit: RateLimit,
+    // When the congestion controller's pacing rate lets the next segment go, if it has one.
+    pub next_paced_send: Cell<Option<Instant>>,
+
+    clock: RefCell<Clock>,
 }
 
 impl fmt::Debug for Sender {
@@ -74,6 +123,7 @@ impl fmt::Debug for Sender {
             .field("base_seq_no", &self.base_seq_no)
             .field("sent_seq_no", &self.sent_seq_no)
             .field("unsent_seq_no", &self.unsent_seq_no)
+            .field("send_buffer_size", &self.send_buffer_size)
             .field("window_size", &self.window_size)
             .field("window_scale", &self.window_scale)
             .field("mss", &self.mss)
@@ -84,7 +134,7 @@ impl fmt::Debug for Sender {
 }
 
 impl Sender {
-    pub fn new(seq_no: SeqNumber, window_size: u32, window_scale: u8, mss: usize, cc_constructor: cc::CongestionControlConstructor, congestion_control_options: Option<cc::Options>) -> Self {
+    pub fn new(seq_no: SeqNumber, window_size: u32, window_scale: u8, mss: usize, send_buffer_size: usize, cc_constructor: cc::CongestionControlConstructor, congestion_control_options: Option<cc::Options>) -> Self {
         Self {
             state: WatchedValue::new(SenderState::Open),
 
@@ -93,6 +143,11 @@ impl Sender {
             sent_seq_no: WatchedValue::new(seq_no),
             unsent_queue: RefCell::new(VecDeque::new()),
             unsent_seq_no: WatchedValue::new(seq_no),
+            urgent_seq_no: Cell::new(None),
+
+            send_buffer_size,
+            waiters: WakerSet::new(),
+            sent_waiters: WakerSet::new(),
 
             window_size: WatchedValue::new(window_size),
             window_scale,
@@ -100,27 +155,56 @@ impl Sender {
 
             retransmit_deadline: WatchedValue::new(None),
             rto: RefCell::new(RtoCalculator::new()),
+            retransmitted_ranges: RefCell::new(VecDeque::new()),
+            ack_latencies: RefCell::new(VecDeque::new()),
 
             congestion_ctrl: cc_constructor(mss, seq_no, congestion_control_options),
+
+            rate_limit: RateLimit::new(),
+            next_paced_send: Cell::new(None),
+
+            clock: RefCell::new(Rc::new(Instant::now)),
         }
     }
 
-    pub fn send<RT: crate::runtime::Runtime>(&self, buf: Bytes, cb: &super::ControlBlock<RT>) -> Result<(), Fail> {
-        if self.state.get() != SenderState::Open {
-            return Err(Fail::Ignored {
+    // The sender starts out on the wall clock. Connections switch it over to their runtime's, so that a simulated
+    // clock drives congestion control as well. Congestion control keeps whatever times it took from the old clock,
+    // so callers switching clocks on a sender that's been used should follow up with `reset_congestion_ctrl`.
+    pub fn set_clock(&self, clock: Clock) {
+        *self.clock.borrow_mut() = clock;
+    }
+
+    pub fn now(&self) -> Instant {
+        (self.clock.borrow())()
+    }
+
+    fn check_open(&self) -> Result<(), Fail> {
+        match self.state.get() {
+            SenderState::Open => Ok(()),
+            SenderState::Reset => Err(Fail::ConnectionAborted {}),
+            _ => Err(Fail::Ignored {
                 details: "Sender closed",
-            });
+            }),
         }
+    }
+
+    pub fn send<RT: crate::runtime::Runtime>(&self, buf: Bytes, cb: &super::ControlBlock<RT>) -> Result<(), Fail> {
+        self.check_open()?;
         let buf_len: u32 = buf.len().try_into().map_err(|_| Fail::Ignored {
             details: "Buffer too large",
         })?;
+        self.check_send_buffer(buf.len())?;
+        if let Some(ref checksums) = cb.payload_checksums {
+            checksums.record_sent(&buf[..]);
+        }
 
         let win_sz = self.window_size.get();
         let base_seq = self.base_seq_no.get();
         let sent_seq = self.sent_seq_no.get();
         let Wrapping(sent_data) = sent_seq - base_seq;
         
-        // Fast path: Try to send the data immediately.
+        // Fast path: Try to send the data immediately, as long as it wouldn't jump ahead of already queued data.
+        let nothing_unsent = sent_seq == self.unsent_seq_no.get();
         let in_flight_after_send = sent_data + buf_len;
 
         // Before we get cwnd for the check, we prompt it to shrink it if the connection has been idle
@@ -129,8 +213,11 @@ impl Sender {
         // The limited transmit algorithm can increase the effective size of cwnd by up to 2MSS
         let effective_cwnd = cwnd + self.congestion_ctrl.get_limited_transmit_cwnd_increase();
 
-        if win_sz > 0 && win_sz >= in_flight_after_send && effective_cwnd >= in_flight_after_send {
-            if let Some(remote_link_addr) = cb.arp.try_query(cb.remote.address()) {
+        // Rate limited or paced connections always leave it to the background sender to space the data out.
+        let paced = self.rate_limit.is_set() || self.congestion_ctrl.get_pacing_rate().is_some();
+
+        if nothing_unsent && !paced && win_sz > 0 && win_sz >= in_flight_after_send && effective_cwnd >= in_flight_after_send {
+            if let Some(remote_link_addr) = cb.arp.try_query(cb.remote().address()) {
                 // This hook is primarily intended to record the last time we sent data, so we can later tell if the connection has been idle
                 self.congestion_ctrl.on_send(&self, sent_data);
 
@@ -139,7 +226,7 @@ impl Sender {
                 cb.emit(header, buf.clone(), remote_link_addr);
 
                 self.unsent_seq_no.modify(|s| s + Wrapping(buf_len));
-                self.sent_seq_no.modify(|s| s + Wrapping(buf_len));
+                self.record_sent(buf_len);
                 let unacked_segment = UnackedSegment {
                     bytes: buf,
                     initial_tx: Some(cb.rt.now()),
@@ -159,22 +246,180 @@ impl Sender {
         Ok(())
     }
 
-    pub fn close(&self) -> Result<(), Fail> {
-        if self.state.get() != SenderState::Open {
-            return Err(Fail::Ignored {
-                details: "Sender closed",
+    // Everything we hold on to, sent or not, counts against the send buffer. The `poll_*` functions wait for room
+    // before getting here, so this only catches callers that don't, rather than letting a stalled peer's backlog
+    // grow without bound.
+    fn check_send_buffer(&self, len: usize) -> Result<(), Fail> {
+        if len > self.send_buffer_available() {
+            return Err(Fail::ResourceExhausted {
+                details: "Send buffer full",
             });
         }
+        Ok(())
+    }
+
+    // Queue as much of `buf` as fits in the send buffer, leaving the remainder in `buf` and waiting for
+    // acknowledgements to free up space if it doesn't all fit.
+    pub fn poll_send<RT: crate::runtime::Runtime>(&self, buf: &mut Bytes, cb: &super::ControlBlock<RT>, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        let available = self.send_buffer_available();
+        if buf.len() <= available {
+            let buf = mem::replace(buf, Bytes::empty());
+            return Poll::Ready(self.send(buf, cb));
+        }
+        if available > 0 {
+            let (chunk, remainder) = mem::replace(buf, Bytes::empty()).split(available);
+            *buf = remainder;
+            if let Err(e) = self.send(chunk, cb) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        self.waiters.register(ctx.waker());
+        Poll::Pending
+    }
+
+    // Waits until everything before `seq_no` has been sent at least once, whether or not it's been acknowledged.
+    pub fn poll_sent(&self, seq_no: SeqNumber, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        if self.state.get() == SenderState::Reset {
+            return Poll::Ready(Err(Fail::ConnectionAborted {}));
+        }
+        let Wrapping(unsent) = seq_no - self.sent_seq_no.get();
+        if unsent == 0 || unsent > i32::max_value() as u32 {
+            return Poll::Ready(Ok(()));
+        }
+        self.sent_waiters.register(ctx.waker());
+        Poll::Pending
+    }
+
+    // Moves `sent_seq_no` past `len` bytes we've just sent for the first time.
+    pub fn record_sent(&self, len: u32) {
+        self.sent_seq_no.modify(|s| s + Wrapping(len));
+        self.sent_waiters.wake_all();
+    }
+
+    // Ready once everything handed to `send` has been acknowledged by the remote.
+    pub fn poll_flush(&self, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        if self.state.get() == SenderState::Reset {
+            return Poll::Ready(Err(Fail::ConnectionAborted {}));
+        }
+        if self.bytes_unsent() == 0 && self.bytes_in_flight() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        self.waiters.register(ctx.waker());
+        Poll::Pending
+    }
+
+    // Like `poll_send`, but for a message built from several buffers. Rather than sending each one as its own
+    // segment, they go onto the unsent queue back to back for `pop_unsent` to coalesce into full segments.
+    pub fn poll_sendv<RT: crate::runtime::Runtime>(&self, bufs: &mut VecDeque<Bytes>, cb: &super::ControlBlock<RT>, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        if bufs.len() == 1 {
+            let r = self.poll_send(&mut bufs[0], cb, ctx);
+            if let Poll::Ready(..) = r {
+                bufs.clear();
+            }
+            return r;
+        }
+        while let Some(buf) = bufs.pop_front() {
+            if buf.is_empty() {
+                continue;
+            }
+            let available = self.send_buffer_available();
+            if available == 0 {
+                bufs.push_front(buf);
+                self.waiters.register(ctx.waker());
+                return Poll::Pending;
+            }
+            let chunk = if buf.len() > available {
+                let (chunk, remainder) = buf.split(available);
+                bufs.push_front(remainder);
+                chunk
+            } else {
+                buf
+            };
+            if let Err(e) = self.queue_unsent(chunk, cb) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn queue_unsent<RT: crate::runtime::Runtime>(&self, buf: Bytes, cb: &super::ControlBlock<RT>) -> Result<(), Fail> {
+        self.check_open()?;
+        let buf_len: u32 = buf.len().try_into().map_err(|_| Fail::Ignored {
+            details: "Buffer too large",
+        })?;
+        self.check_send_buffer(buf.len())?;
+        if let Some(ref checksums) = cb.payload_checksums {
+            checksums.record_sent(&buf[..]);
+        }
+        self.unsent_queue.borrow_mut().push_back(buf);
+        self.unsent_seq_no.modify(|s| s + Wrapping(buf_len));
+        Ok(())
+    }
+
+    // Like `poll_send`, but marks the data as urgent so every segment sent until it's acknowledged points at it.
+    pub fn poll_send_urgent<RT: crate::runtime::Runtime>(&self, buf: &mut Bytes, cb: &super::ControlBlock<RT>, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        if !buf.is_empty() && self.state.get() == SenderState::Open {
+            let last_byte = self.unsent_seq_no.get() + Wrapping(buf.len() as u32 - 1);
+            self.urgent_seq_no.set(Some(last_byte));
+        }
+        self.poll_send(buf, cb, ctx)
+    }
+
+    // The urgent pointer for a segment starting at `seq_no`, if there's urgent data at or after it. Following
+    // RFC 1122 rather than RFC 793, the pointer is the offset of the last urgent byte, not the one after it.
+    pub fn urgent_pointer(&self, seq_no: SeqNumber) -> Option<u16> {
+        let Wrapping(offset) = self.urgent_seq_no.get()? - seq_no;
+        if offset > i32::max_value() as u32 {
+            return None;
+        }
+        // RFC 6093 Section 4: If the urgent data is further away than we can point, point as far as we can.
+        Some(cmp::min(offset, u16::max_value() as u32) as u16)
+    }
+
+    pub fn close(&self) -> Result<(), Fail> {
+        self.check_open()?;
         self.state.set(SenderState::Closed);
         Ok(())
     }
 
+    // The sequence number for a segment that doesn't set its own, like a pure ACK. Our FIN takes `sent_seq_no`
+    // without moving it, so once the FIN has gone out this is one past it.
+    pub fn next_seq_no(&self) -> SeqNumber {
+        match self.state.get() {
+            SenderState::SentFin | SenderState::FinAckd => self.sent_seq_no.get() + Wrapping(1),
+            _ => self.sent_seq_no.get(),
+        }
+    }
+
+    // Gives up on everything we haven't sent or had acknowledged yet, and wakes anyone waiting to send so they find
+    // out the connection's been aborted. The closer then sends the remote a RST, which has to go after our FIN if
+    // that's already out.
+    pub fn abort(&self) {
+        self.unsent_queue.borrow_mut().clear();
+        self.unacked_queue.borrow_mut().clear();
+        let sent_seq = self.next_seq_no();
+        self.base_seq_no.set(sent_seq);
+        self.unsent_seq_no.set(sent_seq);
+        self.sent_seq_no.set(sent_seq);
+        self.retransmit_deadline.set(None);
+        self.state.set(SenderState::Reset);
+        self.waiters.wake_all();
+        self.sent_waiters.wake_all();
+    }
+
     pub fn remote_ack(&self, ack_seq_no: SeqNumber, now: Instant) -> Result<(), Fail> {
         if self.state.get() == SenderState::SentFin {
-            assert_eq!(self.base_seq_no.get(), self.sent_seq_no.get());
-            assert_eq!(self.sent_seq_no.get(), self.unsent_seq_no.get());
-            self.state.set(SenderState::FinAckd);
-            return Ok(());
+            // Our FIN takes the sequence number after the last of our data, so an ACK of it covers all the data
+            // too. Anything short of that can still be for data the FIN went out with.
+            let sent_seq_no = self.sent_seq_no.get();
+            if ack_seq_no == sent_seq_no + Wrapping(1) {
+                if self.base_seq_no.get() != sent_seq_no {
+                    self.remote_ack(sent_seq_no, now)?;
+                }
+                assert_eq!(self.sent_seq_no.get(), self.unsent_seq_no.get());
+                self.state.set(SenderState::FinAckd);
+                return Ok(());
+            }
         }
 
         let base_seq_no = self.base_seq_no.get();
@@ -184,11 +429,24 @@ impl Sender {
         let bytes_acknowledged = ack_seq_no - base_seq_no;
 
         if bytes_acknowledged > bytes_outstanding {
-            return Err(Fail::Ignored {
-                details: "ACK is outside of send window",
+            // An ACK from before `base_seq_no` is an old one that later ACKs have overtaken, e.g. after being
+            // reordered or duplicated on the way, and tells us nothing new (RFC 793 Section 3.9). So is another ACK
+            // of our FIN once it's been acknowledged.
+            let Wrapping(behind) = base_seq_no - ack_seq_no;
+            let fin_reacked = self.state.get() == SenderState::FinAckd && ack_seq_no == sent_seq_no + Wrapping(1);
+            if behind < 1 << 31 || fin_reacked {
+                return Ok(());
+            }
+            return Err(Fail::Invalid {
+                details: "ACK is for data that hasn't been sent",
             });
         }
 
+        if bytes_outstanding.0 == 0 {
+            // With nothing in flight this is just the ACK field of the remote's own data, not a duplicate ACK.
+            return Ok(());
+        }
+
         self.congestion_ctrl.on_ack_received(&self, ack_seq_no);
         if bytes_acknowledged.0 == 0 {
             return Ok(());
@@ -203,36 +461,106 @@ impl Sender {
             self.retransmit_deadline.set(Some(deadline));
         }
 
-        // TODO: Do acks need to be on segment boundaries? How does this interact with repacketization?
         let mut bytes_remaining = bytes_acknowledged.0 as usize;
-        while let Some(segment) = self.unacked_queue.borrow_mut().pop_front() {
+        // Karn's algorithm: If any segment covered by this ACK was retransmitted, we can't tell whether the ACK
+        // was for the original transmission or the retransmission, so we don't take an RTT sample from it at all.
+        let mut acked_retransmission = false;
+        let mut latest_initial_tx = None;
+        let mut unacked_queue = self.unacked_queue.borrow_mut();
+        while let Some(mut segment) = unacked_queue.pop_front() {
+            match segment.initial_tx {
+                Some(initial_tx) => latest_initial_tx = Some(initial_tx),
+                None => acked_retransmission = true,
+            }
             if segment.bytes.len() > bytes_remaining {
-                // TODO: We need to close the connection in this case.
-                return Err(Fail::Ignored {
-                    details: "ACK isn't on segment boundary",
-                });
+                // The ACK only covers part of this segment, so trim off the acknowledged prefix and leave the rest
+                // at the front of the queue.
+                let (_, remainder) = segment.bytes.split(bytes_remaining);
+                segment.bytes = remainder;
+                unacked_queue.push_front(segment);
+                break;
             }
             bytes_remaining -= segment.bytes.len();
-
-            // Add sample for RTO if not a retransmission
-            // TODO: TCP timestamp support.
+            // A retransmitted segment no longer has a send time to measure from.
             if let Some(initial_tx) = segment.initial_tx {
-                self.rto.borrow_mut().add_sample(now - initial_tx);
+                self.record_ack_latency(now - initial_tx);
             }
             if bytes_remaining == 0 {
                 break;
             }
         }
+        drop(unacked_queue);
+
+        // Add sample for RTO from the most recently sent segment if none were retransmissions
+        // TODO: TCP timestamp support.
+        let rtt = match (acked_retransmission, latest_initial_tx) {
+            (false, Some(initial_tx)) => Some(now - initial_tx),
+            _ => None,
+        };
+        if let Some(rtt) = rtt {
+            self.rto.borrow_mut().add_sample(rtt);
+        }
         self.base_seq_no.modify(|b| b + bytes_acknowledged);
-        let new_base_seq_no = self.base_seq_no.get();
-        if new_base_seq_no < base_seq_no {
-            // We've wrapped around, and so we need to do some bookkeeping
-            self.congestion_ctrl.on_base_seq_no_wraparound(&self);
+        self.congestion_ctrl.on_ack_timing(&self, now, rtt);
+        if let Some(urgent_seq_no) = self.urgent_seq_no.get() {
+            let Wrapping(offset) = urgent_seq_no - self.base_seq_no.get();
+            if offset > i32::max_value() as u32 {
+                self.urgent_seq_no.set(None);
+            }
         }
+        self.waiters.wake_all();
 
         Ok(())
     }
 
+    fn record_ack_latency(&self, latency: Duration) {
+        let mut latencies = self.ack_latencies.borrow_mut();
+        if latencies.len() == MAX_ACK_LATENCY_SAMPLES {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+
+    pub fn ack_latencies(&self) -> Vec<Duration> {
+        self.ack_latencies.borrow().iter().copied().collect()
+    }
+
+    pub fn record_retransmit(&self, seq_no: SeqNumber, len: usize, fast_retransmit: bool) {
+        let mut ranges = self.retransmitted_ranges.borrow_mut();
+        if ranges.len() == MAX_RETRANSMITTED_RANGES {
+            ranges.pop_front();
+        }
+        ranges.push_back((seq_no, seq_no + Wrapping(len as u32), fast_retransmit));
+    }
+
+    // The remote reported receiving `begin..end` twice. If that's data we retransmitted, the retransmission was
+    // spurious, so tell congestion control. A duplicate the network made on its own says nothing about our
+    // retransmissions, and each one only counts once.
+    //
+    // A spurious fast retransmission also tells us how badly the network reorders: the original was overtaken by
+    // everything the remote has acknowledged since, each segment of which drew one of the duplicate ACKs we took for
+    // a loss.
+    pub fn receive_dsack(&self, begin: SeqNumber, end: SeqNumber) {
+        let mut ranges = self.retransmitted_ranges.borrow_mut();
+        let position = ranges.iter().position(|&(rtx_begin, rtx_end, _)| {
+            let Wrapping(rtx_len) = rtx_end - rtx_begin;
+            let Wrapping(begin_offset) = begin - rtx_begin;
+            let Wrapping(end_offset) = end - rtx_begin;
+            begin_offset < end_offset && end_offset <= rtx_len
+        });
+        if let Some(ix) = position {
+            let (_, rtx_end, fast_retransmit) = ranges.remove(ix).unwrap();
+            drop(ranges);
+            let base_seq_no = self.base_seq_no.get();
+            if fast_retransmit && seq_after(base_seq_no, rtx_end) {
+                let Wrapping(overtaken) = base_seq_no - rtx_end;
+                let mss = self.mss as u32;
+                self.congestion_ctrl.on_reordering_detected(self, (overtaken + mss - 1) / mss);
+            }
+            self.congestion_ctrl.on_spurious_retransmit(self);
+        }
+    }
+
     pub fn pop_one_unsent_byte(&self) -> Option<Bytes> {
         let mut queue = self.unsent_queue.borrow_mut();
         let buf = queue.pop_front()?;
@@ -242,15 +570,39 @@ impl Sender {
     }
 
     pub fn pop_unsent(&self, max_bytes: usize) -> Option<Bytes> {
-        // TODO: Use a scatter/gather array to coalesce multiple buffers into a single segment.
         let mut unsent_queue = self.unsent_queue.borrow_mut();
-        let mut buf = unsent_queue.pop_front()?;
-        if buf.len() > max_bytes {
+        let buf = unsent_queue.pop_front()?;
+        if buf.len() >= max_bytes {
             let (head, tail) = buf.split(max_bytes);
-            buf = head;
-            unsent_queue.push_front(tail);
+            if !tail.is_empty() {
+                unsent_queue.push_front(tail);
+            }
+            return Some(head);
+        }
+
+        // Coalesce as many of the following buffers as fit into a single segment. We only pay for the copy when
+        // there's more than one buffer to send.
+        let queued_bytes = unsent_queue.iter().fold(buf.len(), |n, b| n + b.len());
+        let segment_len = cmp::min(queued_bytes, max_bytes);
+        if segment_len == buf.len() {
+            return Some(buf);
+        }
+        let mut segment = BytesMut::zeroed(segment_len);
+        segment[..buf.len()].copy_from_slice(&buf[..]);
+        let mut offset = buf.len();
+        while offset < segment_len {
+            let next = unsent_queue
+                .pop_front()
+                .expect("Unsent queue shrank while coalescing");
+            let split_ix = cmp::min(next.len(), segment_len - offset);
+            let (head, tail) = next.split(split_ix);
+            segment[offset..(offset + head.len())].copy_from_slice(&head[..]);
+            offset += head.len();
+            if !tail.is_empty() {
+                unsent_queue.push_front(tail);
+            }
         }
-        Some(buf)
+        Some(segment.freeze())
     }
 
     pub fn update_remote_window(&self, window_size_hdr: u16) -> Result<(), Fail> {
@@ -271,6 +623,33 @@ impl Sender {
         Ok(())
     }
 
+    // Number of bytes sent but not yet acknowledged.
+    pub fn bytes_in_flight(&self) -> u32 {
+        let Wrapping(in_flight) = self.sent_seq_no.get() - self.base_seq_no.get();
+        in_flight
+    }
+
+    // Number of bytes queued by the application that haven't been sent yet.
+    pub fn bytes_unsent(&self) -> u32 {
+        let Wrapping(unsent) = self.unsent_seq_no.get() - self.sent_seq_no.get();
+        unsent
+    }
+
+    // Number of bytes held in the send buffer, whether they're waiting to be sent or to be acknowledged.
+    pub fn send_buffer_used(&self) -> usize {
+        self.bytes_in_flight() as usize + self.bytes_unsent() as usize
+    }
+
+    // Number of bytes the application may still queue before the send buffer is full.
+    pub fn send_buffer_available(&self) -> usize {
+        self.send_buffer_size.saturating_sub(self.send_buffer_used())
+    }
+
+    // Number of bytes the remote's advertised window will still accept.
+    pub fn send_window_available(&self) -> u32 {
+        self.window_size.get().saturating_sub(self.bytes_in_flight())
+    }
+
     pub fn remote_mss(&self) -> usize {
         self.mss
     }
@@ -278,4 +657,19 @@ impl Sender {
     pub fn current_rto(&self) -> Duration {
         self.rto.borrow().estimate()
     }
+
+    // What the RTO would be from our RTT samples, even if `current_rto` is fixed.
+    pub fn rto_estimate(&self) -> Duration {
+        self.rto.borrow().adaptive_estimate()
+    }
+
+    // Puts congestion control back into its initial state. Everything that makes the connection start over (a new
+    // clock, a new path) comes through here.
+    pub fn reset_congestion_ctrl(&self) {
+        self.congestion_ctrl.on_connection_reset(self);
+    }
+
+    pub fn set_cwnd_observer(&self, observer: cc::CwndObserver) {
+        self.congestion_ctrl.set_cwnd_observer(observer);
+    }
 }
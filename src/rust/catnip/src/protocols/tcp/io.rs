@@ -0,0 +1,114 @@
+use super::peer::Peer;
+use crate::{
+    fail::Fail,
+    file_table::FileDescriptor,
+    runtime::Runtime,
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+};
+use futures::io::{
+    AsyncRead,
+    AsyncWrite,
+};
+use std::{
+    cmp,
+    io,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+// Adapts an established connection to the `futures` I/O traits, so code written against them can run over catnip.
+// Flushing waits for the remote to acknowledge everything written so far, and closing flushes before sending our
+// FIN. The engine still has to be driven (frames received, clock advanced) for any of these to make progress.
+pub struct TcpStream<RT: Runtime> {
+    fd: FileDescriptor,
+    peer: Peer<RT>,
+
+    // Whatever's left of the last segment popped that didn't fit in the caller's buffer.
+    read_buf: Bytes,
+    closed: bool,
+}
+
+impl<RT: Runtime> TcpStream<RT> {
+    pub fn new(peer: &Peer<RT>, fd: FileDescriptor) -> Self {
+        Self {
+            fd,
+            peer: Peer {
+                inner: peer.inner.clone(),
+            },
+            read_buf: Bytes::empty(),
+            closed: false,
+        }
+    }
+
+    pub fn fd(&self) -> FileDescriptor {
+        self.fd
+    }
+}
+
+impl<RT: Runtime> AsyncRead for TcpStream<RT> {
+    fn poll_read(self: Pin<&mut Self>, ctx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let self_ = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if self_.read_buf.is_empty() {
+            self_.read_buf = match self_.peer.poll_recv(self_.fd, ctx) {
+                Poll::Ready(Ok(segment)) => segment,
+                // The remote has sent its FIN and we've read everything before it.
+                Poll::Ready(Err(Fail::ResourceNotFound { .. })) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                Poll::Pending => return Poll::Pending,
+            };
+        }
+        let n = cmp::min(buf.len(), self_.read_buf.len());
+        buf[..n].copy_from_slice(&self_.read_buf[..n]);
+        let (_, remainder) = std::mem::replace(&mut self_.read_buf, Bytes::empty()).split(n);
+        self_.read_buf = remainder;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<RT: Runtime> AsyncWrite for TcpStream<RT> {
+    fn poll_write(self: Pin<&mut Self>, ctx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let self_ = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut remaining = BytesMut::from(buf).freeze();
+        match self_.peer.poll_push(self_.fd, &mut remaining, ctx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.len())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
+            // The send buffer only had room for part of `buf`: report a short write rather than holding onto the
+            // rest, since the caller is free to change what it writes next.
+            Poll::Pending if remaining.len() < buf.len() => Poll::Ready(Ok(buf.len() - remaining.len())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<io::Result<()>> {
+        let self_ = self.get_mut();
+        self_.peer.poll_flush(self_.fd, ctx).map_err(|e| e.into())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<io::Result<()>> {
+        let self_ = self.get_mut();
+        if self_.closed {
+            return Poll::Ready(Ok(()));
+        }
+        // Hold the FIN back until the remote has everything we've written, so an error there still surfaces here.
+        match self_.peer.poll_flush(self_.fd, ctx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+            Poll::Pending => return Poll::Pending,
+        }
+        self_.peer.close(self_.fd)?;
+        self_.closed = true;
+        Poll::Ready(Ok(()))
+    }
+}
@@ -5,38 +5,41 @@ use crate::protocols::{
     ipv4,
     tcp::SeqNumber,
 };
-use crc::{
-    crc32,
-    Hasher32,
-};
+use siphasher::sip::SipHasher24;
 use std::{
     hash::Hasher,
     num::Wrapping,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
+// RFC 6528 Section 3: ISN = M + F(localip, localport, remoteip, remoteport, secretkey), where M ticks every 4
+// microseconds and F is a keyed hash of the four-tuple. The clock keeps successive connections between the same
+// endpoints from reusing sequence numbers, while the hash keeps the ISN unguessable to anyone without the key. F is
+// SipHash-2-4 rather than std's `DefaultHasher`, whose algorithm may change between Rust releases.
+const CLOCK_TICK: Duration = Duration::from_micros(4);
+
 pub struct IsnGenerator {
-    nonce: u32,
-    counter: Wrapping<u16>,
+    key: u128,
+    epoch: Instant,
 }
 
 impl IsnGenerator {
-    pub fn new(nonce: u32) -> Self {
-        Self {
-            nonce,
-            counter: Wrapping(0),
-        }
+    pub fn new(key: u128, epoch: Instant) -> Self {
+        Self { key, epoch }
     }
 
-    pub fn generate(&mut self, local: &ipv4::Endpoint, remote: &ipv4::Endpoint) -> SeqNumber {
-        let mut hash = crc32::Digest::new(crc32::IEEE);
-        hash.write_u32(remote.address().into());
-        hash.write_u16(remote.port().into());
+    pub fn generate(&self, local: &ipv4::Endpoint, remote: &ipv4::Endpoint, now: Instant) -> SeqNumber {
+        let mut hash = SipHasher24::new_with_keys(self.key as u64, (self.key >> 64) as u64);
         hash.write_u32(local.address().into());
         hash.write_u16(local.port().into());
-        hash.write_u32(self.nonce);
-        let hash = hash.sum32();
-        let isn = Wrapping(hash) + Wrapping(u32::from(self.counter.0));
-        self.counter += Wrapping(1);
-        isn
+        hash.write_u32(remote.address().into());
+        hash.write_u16(remote.port().into());
+        let hash = hash.finish() as u32;
+
+        let ticks = now.saturating_duration_since(self.epoch).as_nanos() / CLOCK_TICK.as_nanos();
+        Wrapping(hash) + Wrapping(ticks as u32)
     }
 }
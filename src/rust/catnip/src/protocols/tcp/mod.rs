@@ -1,6 +1,10 @@
 mod active_open;
+#[cfg(feature = "async-io")]
+pub mod blocking;
 pub mod constants;
 mod established;
+#[cfg(feature = "async-io")]
+pub mod io;
 mod isn_generator;
 pub mod operations;
 mod options;
@@ -15,8 +19,25 @@ use std::num::Wrapping;
 
 pub type SeqNumber = Wrapping<u32>;
 
+// Whether `a` comes after `b` in sequence space. Sequence numbers wrap around, so there's no answer for numbers half
+// the space or more apart, and this treats them as unordered (RFC 1982 Section 3.2).
+pub fn seq_after(a: SeqNumber, b: SeqNumber) -> bool {
+    let Wrapping(diff) = a - b;
+    diff > 0 && diff < 1 << 31
+}
+
+pub fn seq_before(a: SeqNumber, b: SeqNumber) -> bool {
+    seq_after(b, a)
+}
+
+#[cfg(feature = "fault-injection")]
+pub use self::established::state::ack_faults::AckFaults;
 pub use self::{
     options::TcpOptions as Options,
     peer::Peer,
-    established::state::congestion_ctrl as congestion_ctrl
+    established::state::congestion_ctrl as congestion_ctrl,
+    established::state::{
+        CloseReason,
+        ConnectionState,
+    },
 };
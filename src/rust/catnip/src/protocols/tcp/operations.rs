@@ -9,11 +9,16 @@ use crate::{
         OperationResult,
         ResultFuture,
     },
+    protocols::{
+        ipv4,
+        tcp::SeqNumber,
+    },
     runtime::Runtime,
     sync::Bytes,
 };
 use std::{
     cell::RefCell,
+    collections::VecDeque,
     fmt,
     future::Future,
     pin::Pin,
@@ -84,8 +89,8 @@ impl<RT: Runtime> TcpOperation<RT> {
 
             Accept(ResultFuture {
                 future,
-                done: Some(Ok(fd)),
-            }) => (future.fd, OperationResult::Accept(fd)),
+                done: Some(Ok((fd, remote))),
+            }) => (future.fd, OperationResult::Accept(fd, remote)),
             Accept(ResultFuture {
                 future,
                 done: Some(Err(e)),
@@ -158,7 +163,7 @@ impl<RT: Runtime> fmt::Debug for AcceptFuture<RT> {
 }
 
 impl<RT: Runtime> Future for AcceptFuture<RT> {
-    type Output = Result<FileDescriptor, Fail>;
+    type Output = Result<(FileDescriptor, ipv4::Endpoint), Fail>;
 
     fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
         let self_ = self.get_mut();
@@ -171,8 +176,10 @@ impl<RT: Runtime> Future for AcceptFuture<RT> {
 
 pub struct PushFuture<RT: Runtime> {
     pub fd: FileDescriptor,
-    pub err: Option<Fail>,
-    pub _marker: std::marker::PhantomData<RT>,
+    // The part of the pushed buffer that hasn't made it into the send buffer yet.
+    pub buf: Bytes,
+    pub urgent: bool,
+    pub inner: Rc<RefCell<Inner<RT>>>,
 }
 
 impl<RT: Runtime> fmt::Debug for PushFuture<RT> {
@@ -184,11 +191,69 @@ impl<RT: Runtime> fmt::Debug for PushFuture<RT> {
 impl<RT: Runtime> Future for PushFuture<RT> {
     type Output = Result<(), Fail>;
 
-    fn poll(self: Pin<&mut Self>, _context: &mut Context) -> Poll<Self::Output> {
-        match self.get_mut().err.take() {
-            None => Poll::Ready(Ok(())),
-            Some(e) => Poll::Ready(Err(e)),
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let self_ = self.get_mut();
+        let peer = Peer {
+            inner: self_.inner.clone(),
+        };
+        if self_.urgent {
+            return peer.poll_push_urgent(self_.fd, &mut self_.buf, ctx);
         }
+        peer.poll_push(self_.fd, &mut self_.buf, ctx)
+    }
+}
+
+pub struct PushvFuture<RT: Runtime> {
+    pub fd: FileDescriptor,
+    // The buffers, or what's left of them, that haven't made it into the send buffer yet.
+    pub bufs: VecDeque<Bytes>,
+    pub inner: Rc<RefCell<Inner<RT>>>,
+}
+
+impl<RT: Runtime> fmt::Debug for PushvFuture<RT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PushvFuture({})", self.fd)
+    }
+}
+
+impl<RT: Runtime> Future for PushvFuture<RT> {
+    type Output = Result<(), Fail>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let self_ = self.get_mut();
+        let peer = Peer {
+            inner: self_.inner.clone(),
+        };
+        peer.poll_pushv(self_.fd, &mut self_.bufs, ctx)
+    }
+}
+
+pub struct FlushFuture<RT: Runtime> {
+    pub fd: FileDescriptor,
+    // Where the data pushed before the flush ends, or why we couldn't find out.
+    pub seq_no: Result<SeqNumber, Fail>,
+    pub inner: Rc<RefCell<Inner<RT>>>,
+}
+
+impl<RT: Runtime> fmt::Debug for FlushFuture<RT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FlushFuture({})", self.fd)
+    }
+}
+
+impl<RT: Runtime> Future for FlushFuture<RT> {
+    type Output = Result<(), Fail>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let self_ = self.get_mut();
+        let seq_no = match self_.seq_no {
+            Ok(seq_no) => seq_no,
+            Err(ref e) => return Poll::Ready(Err(e.clone())),
+        };
+        let peer = Peer {
+            inner: self_.inner.clone(),
+        };
+        peer.poll_sent(self_.fd, seq_no, ctx)
     }
 }
 
@@ -1,84 +1,358 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
-use crate::protocols::tcp::{
-    constants::{
-        DEFAULT_MSS,
-        MAX_MSS,
-        MIN_MSS,
+use crate::{
+    fail::Fail,
+    protocols::{
+        ip::port::{
+            FIRST_EPHEMERAL_PORT,
+            LAST_EPHEMERAL_PORT,
+        },
+        tcp::{
+            constants::{
+                DEFAULT_MSS,
+                MAX_MSS,
+                MAX_WINDOW_SIZE,
+                MIN_MSS,
+            },
+            established::state::congestion_ctrl::{self as cc, CongestionControl},
+        },
     },
-    established::state::congestion_ctrl::{self as cc, CongestionControl},
 };
-use std::time::Duration;
+use std::{
+    cmp,
+    time::Duration,
+};
 
 pub use crate::protocols::tcp::established::state::congestion_ctrl::CongestionControlConstructor;
 
 #[derive(Clone, Debug)]
 pub struct TcpOptions {
+    // Number of consecutive full-size segments we receive before ACKing them right away, rather than waiting on the
+    // delayed ACK timer. RFC 1122 requires at least every second one to be ACKed, so larger values are clamped to 2
+    // unless `ack_ratio_unclamped` is set. Note that sparser ACKs also mean fewer RTT samples for the remote's RTO
+    // estimate, and more data retransmitted on each timeout.
+    pub ack_ratio: usize,
+    pub ack_ratio_unclamped: bool,
     pub advertised_mss: usize,
     pub congestion_ctrl_type: CongestionControlConstructor,
     pub congestion_ctrl_options: Option<cc::Options>,
+    // Report segments we receive twice in a SACK block on the ACK that follows (RFC 2883), so the remote can tell a
+    // needless retransmission from a lost segment. Only used on connections that negotiated SACK (see
+    // `sack_enabled`).
+    pub dsack: bool,
+    // Inclusive range of local ports picked for connections that weren't explicitly bound.
+    pub ephemeral_port_range: (u16, u16),
+    // How long after sending our FIN we wait for the remote to finish closing, i.e. to ACK our FIN and send its own,
+    // before giving up on the connection and releasing it. The wait starts over whenever the remote sends us more
+    // data, since it may still have plenty left to send after we've finished.
+    pub fin_wait_timeout: Duration,
+    // Use this RTO on every connection instead of estimating it from RTT samples, e.g. to keep RTO dynamics out of a
+    // congestion control experiment. Timeouts don't back off either. Samples are still taken, so the estimate they'd
+    // have given stays visible through `Engine::tcp_rto_estimate`.
+    pub fixed_rto: Option<Duration>,
     pub handshake_retries: usize,
     pub handshake_timeout: Duration,
+    // Maximum segment lifetime (RFC 793 Section 3.3). Having closed first, a connection stays in TIME_WAIT for twice
+    // this long after both FINs have been acknowledged, unless `release_closed_ports` is set.
+    pub msl: Duration,
+    pub payload_checksums: bool,
+    // Caps the unread data buffered per connection. Defaults to `receive_window_size` if unset.
+    pub receive_buffer_limit: Option<usize>,
+    // Windows over 64KB are only advertised on connections where the remote agrees to window scaling (RFC 7323), and
+    // are capped to 64KB on the rest.
     pub receive_window_size: usize,
+    // Hands the local port of a connection that closed gracefully back for reuse right away, instead of holding it
+    // for as long as the socket exists. This skips TIME_WAIT: a stray segment from the old connection can land on a
+    // new one with the same endpoints, so it's only meant for quickly rerunning tests against the same peer.
+    pub release_closed_ports: bool,
     pub retries: usize,
+    // Offer SACK-permitted in our SYNs, and accept it in the remote's (RFC 2018). SACK blocks are only sent or acted
+    // on when both sides offered it, and otherwise we stick to cumulative ACKs.
+    pub sack_enabled: bool,
+    pub send_buffer_size: usize,
+    // How long to wait before acknowledging the remote's FIN. Zero sends the ACK as soon as we've received it.
     pub trailing_ack_delay: Duration,
 }
 
 impl Default for TcpOptions {
     fn default() -> Self {
         TcpOptions {
+            ack_ratio: 2,
+            ack_ratio_unclamped: false,
             advertised_mss: DEFAULT_MSS,
             congestion_ctrl_type: cc::Cubic::new,
             congestion_ctrl_options: None,
+            dsack: false,
+            ephemeral_port_range: (FIRST_EPHEMERAL_PORT, LAST_EPHEMERAL_PORT),
+            fin_wait_timeout: Duration::from_secs(60),
+            fixed_rto: None,
             handshake_retries: 5,
             handshake_timeout: Duration::from_secs(3),
+            msl: Duration::from_secs(30),
+            payload_checksums: false,
+            receive_buffer_limit: None,
             receive_window_size: 0xffff,
+            release_closed_ports: false,
             retries: 5,
-            trailing_ack_delay: Duration::from_micros(1),
+            sack_enabled: true,
+            send_buffer_size: 1 << 20,
+            trailing_ack_delay: Duration::new(0, 0),
         }
     }
 }
 
 impl TcpOptions {
-    pub fn advertised_mss(mut self, value: usize) -> Self {
-        assert!(value >= MIN_MSS);
-        assert!(value <= MAX_MSS);
-        self.advertised_mss = value;
+    // Checks the options as a whole, since the fields can also be set directly.
+    pub fn validate(&self) -> Result<(), Fail> {
+        self.clone()
+            .try_ack_ratio(self.ack_ratio)?
+            .try_advertised_mss(self.advertised_mss)?
+            .try_ephemeral_port_range(self.ephemeral_port_range.0, self.ephemeral_port_range.1)?
+            .try_fin_wait_timeout(self.fin_wait_timeout)?
+            .try_handshake_retries(self.handshake_retries)?
+            .try_handshake_timeout(self.handshake_timeout)?
+            .try_msl(self.msl)?
+            .try_receive_window_size(self.receive_window_size)?
+            .try_retries(self.retries)?
+            .try_send_buffer_size(self.send_buffer_size)?;
+        if let Some(limit) = self.receive_buffer_limit {
+            self.clone().try_receive_buffer_limit(limit)?;
+        }
+        if let Some(rto) = self.fixed_rto {
+            self.clone().try_fixed_rto(rto)?;
+        }
+        if let Some(ref options) = self.congestion_ctrl_options {
+            self.clone().try_congestion_control_options(options.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn ack_ratio(self, value: usize) -> Self {
+        self.try_ack_ratio(value).unwrap()
+    }
+
+    pub fn try_ack_ratio(mut self, value: usize) -> Result<Self, Fail> {
+        if value == 0 {
+            return Err(Fail::OutOfRange { details: "ack_ratio" });
+        }
+        self.ack_ratio = value;
+        Ok(self)
+    }
+
+    pub fn ack_ratio_unclamped(mut self, value: bool) -> Self {
+        self.ack_ratio_unclamped = value;
         self
     }
 
+    // The ACK ratio connections actually use, once clamped to what RFC 1122 allows.
+    pub fn effective_ack_ratio(&self) -> usize {
+        if self.ack_ratio_unclamped {
+            self.ack_ratio
+        } else {
+            cmp::min(self.ack_ratio, 2)
+        }
+    }
+
+    // The window scale we offer in our SYNs: the smallest shift that fits `receive_window_size` in the 16-bit window
+    // field.
+    pub fn receive_window_scale(&self) -> u8 {
+        let mut scale = 0;
+        while self.receive_window_size >> scale > u16::max_value() as usize {
+            scale += 1;
+        }
+        scale
+    }
+
+    pub fn advertised_mss(self, value: usize) -> Self {
+        self.try_advertised_mss(value).unwrap()
+    }
+
+    pub fn try_advertised_mss(mut self, value: usize) -> Result<Self, Fail> {
+        if value < MIN_MSS || value > MAX_MSS {
+            return Err(Fail::OutOfRange {
+                details: "advertised_mss",
+            });
+        }
+        self.advertised_mss = value;
+        Ok(self)
+    }
+
     pub fn congestion_ctrl_type(mut self, value: CongestionControlConstructor) -> Self {
         self.congestion_ctrl_type = value;
         self
     }
 
-    pub fn congestion_control_options(mut self, value: cc::Options) -> Self {
+    pub fn congestion_ctrl_by_name(mut self, name: &str) -> Result<Self, Fail> {
+        self.congestion_ctrl_type = cc::constructor_by_name(name).ok_or(Fail::Unsupported {
+            details: "Unknown congestion control algorithm",
+        })?;
+        Ok(self)
+    }
+
+    pub fn congestion_control_options(self, value: cc::Options) -> Self {
+        self.try_congestion_control_options(value).unwrap()
+    }
+
+    pub fn try_congestion_control_options(mut self, value: cc::Options) -> Result<Self, Fail> {
+        cc::validate_options(&value)?;
         self.congestion_ctrl_options = Some(value);
+        Ok(self)
+    }
+
+    pub fn dsack(mut self, value: bool) -> Self {
+        self.dsack = value;
         self
     }
 
-    pub fn handshake_retries(mut self, value: usize) -> Self {
-        assert!(value > 0);
-        self.handshake_retries = value;
+    pub fn sack_enabled(mut self, value: bool) -> Self {
+        self.sack_enabled = value;
         self
     }
 
-    pub fn handshake_timeout(mut self, value: Duration) -> Self {
-        assert!(value > Duration::new(0, 0));
+    pub fn ephemeral_port_range(self, first: u16, last: u16) -> Self {
+        self.try_ephemeral_port_range(first, last).unwrap()
+    }
+
+    pub fn try_ephemeral_port_range(mut self, first: u16, last: u16) -> Result<Self, Fail> {
+        if first == 0 || first > last {
+            return Err(Fail::OutOfRange {
+                details: "ephemeral_port_range",
+            });
+        }
+        self.ephemeral_port_range = (first, last);
+        Ok(self)
+    }
+
+    pub fn fin_wait_timeout(self, value: Duration) -> Self {
+        self.try_fin_wait_timeout(value).unwrap()
+    }
+
+    pub fn try_fin_wait_timeout(mut self, value: Duration) -> Result<Self, Fail> {
+        if value == Duration::new(0, 0) {
+            return Err(Fail::OutOfRange {
+                details: "fin_wait_timeout",
+            });
+        }
+        self.fin_wait_timeout = value;
+        Ok(self)
+    }
+
+    pub fn fixed_rto(self, value: Duration) -> Self {
+        self.try_fixed_rto(value).unwrap()
+    }
+
+    pub fn try_fixed_rto(mut self, value: Duration) -> Result<Self, Fail> {
+        if value == Duration::new(0, 0) {
+            return Err(Fail::OutOfRange { details: "fixed_rto" });
+        }
+        self.fixed_rto = Some(value);
+        Ok(self)
+    }
+
+    pub fn handshake_retries(self, value: usize) -> Self {
+        self.try_handshake_retries(value).unwrap()
+    }
+
+    pub fn try_handshake_retries(mut self, value: usize) -> Result<Self, Fail> {
+        if value == 0 {
+            return Err(Fail::OutOfRange {
+                details: "handshake_retries",
+            });
+        }
+        self.handshake_retries = value;
+        Ok(self)
+    }
+
+    pub fn handshake_timeout(self, value: Duration) -> Self {
+        self.try_handshake_timeout(value).unwrap()
+    }
+
+    pub fn try_handshake_timeout(mut self, value: Duration) -> Result<Self, Fail> {
+        if value == Duration::new(0, 0) {
+            return Err(Fail::OutOfRange {
+                details: "handshake_timeout",
+            });
+        }
         self.handshake_timeout = value;
+        Ok(self)
+    }
+
+    pub fn msl(self, value: Duration) -> Self {
+        self.try_msl(value).unwrap()
+    }
+
+    pub fn try_msl(mut self, value: Duration) -> Result<Self, Fail> {
+        if value == Duration::new(0, 0) {
+            return Err(Fail::OutOfRange {
+                details: "msl",
+            });
+        }
+        self.msl = value;
+        Ok(self)
+    }
+
+    pub fn payload_checksums(mut self, value: bool) -> Self {
+        self.payload_checksums = value;
         self
     }
 
-    pub fn receive_window_size(mut self, value: usize) -> Self {
-        assert!(value > 0);
+    pub fn receive_buffer_limit(self, value: usize) -> Self {
+        self.try_receive_buffer_limit(value).unwrap()
+    }
+
+    pub fn try_receive_buffer_limit(mut self, value: usize) -> Result<Self, Fail> {
+        if value == 0 {
+            return Err(Fail::OutOfRange {
+                details: "receive_buffer_limit",
+            });
+        }
+        self.receive_buffer_limit = Some(value);
+        Ok(self)
+    }
+
+    pub fn receive_window_size(self, value: usize) -> Self {
+        self.try_receive_window_size(value).unwrap()
+    }
+
+    pub fn try_receive_window_size(mut self, value: usize) -> Result<Self, Fail> {
+        if value == 0 || value > MAX_WINDOW_SIZE {
+            return Err(Fail::OutOfRange {
+                details: "receive_window_size",
+            });
+        }
         self.receive_window_size = value;
+        Ok(self)
+    }
+
+    pub fn release_closed_ports(mut self, value: bool) -> Self {
+        self.release_closed_ports = value;
         self
     }
 
-    pub fn retries(mut self, value: usize) -> Self {
-        assert!(value > 0);
+    pub fn retries(self, value: usize) -> Self {
+        self.try_retries(value).unwrap()
+    }
+
+    pub fn try_retries(mut self, value: usize) -> Result<Self, Fail> {
+        if value == 0 {
+            return Err(Fail::OutOfRange { details: "retries" });
+        }
         self.retries = value;
-        self
+        Ok(self)
+    }
+
+    pub fn send_buffer_size(self, value: usize) -> Self {
+        self.try_send_buffer_size(value).unwrap()
+    }
+
+    pub fn try_send_buffer_size(mut self, value: usize) -> Result<Self, Fail> {
+        if value == 0 {
+            return Err(Fail::OutOfRange {
+                details: "send_buffer_size",
+            });
+        }
+        self.send_buffer_size = value;
+        Ok(self)
     }
 
     pub fn trailing_ack_delay(mut self, value: Duration) -> Self {
@@ -1,11 +1,19 @@
 use super::{
-    constants::{DEFAULT_MSS, FALLBACK_MSS},
+    constants::{
+        FALLBACK_MSS,
+        MAX_WINDOW_SCALE,
+    },
     established::state::{
+        congestion_ctrl::{
+            self as cc,
+            CongestionControlConstructor,
+        },
         receiver::Receiver,
         sender::Sender,
         ControlBlock,
     },
     isn_generator::IsnGenerator,
+    peer::RstSender,
 };
 use crate::{
     fail::Fail,
@@ -39,8 +47,8 @@ use hashbrown::{
 };
 use std::{
     cell::RefCell,
+    cmp,
     collections::VecDeque,
-    convert::TryInto,
     future::Future,
     num::Wrapping,
     rc::Rc,
@@ -49,15 +57,28 @@ use std::{
         Poll,
         Waker,
     },
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
-struct InflightAccept {
+// What the remote's SYN and our SYN+ACK settle for the connection.
+#[derive(Clone, Copy)]
+struct Handshake {
     local_isn: SeqNumber,
     remote_isn: SeqNumber,
+    // The window in a SYN is never scaled.
     window_size: u32,
     window_scale: u8,
+    // Our own shift, for the windows we advertise, if the remote offered window scaling.
+    receive_window_scale: Option<u8>,
     mss: usize,
+    sack_permitted: bool,
+}
+
+struct InflightAccept {
+    handshake: Handshake,
 
     #[allow(unused)]
     handle: SchedulerHandle,
@@ -71,7 +92,7 @@ struct ReadySockets<RT: Runtime> {
 
 impl<RT: Runtime> ReadySockets<RT> {
     fn push_ok(&mut self, cb: ControlBlock<RT>) {
-        assert!(self.endpoints.insert(cb.remote));
+        assert!(self.endpoints.insert(cb.remote()));
         self.ready.push_back(Ok(cb));
         self.waker.take().map(|w| w.wake());
     }
@@ -90,7 +111,7 @@ impl<RT: Runtime> ReadySockets<RT> {
             },
         };
         if let Ok(ref cb) = r {
-            assert!(self.endpoints.remove(&cb.remote));
+            assert!(self.endpoints.remove(&cb.remote()));
         }
         Poll::Ready(r)
     }
@@ -98,6 +119,18 @@ impl<RT: Runtime> ReadySockets<RT> {
     fn len(&self) -> usize {
         self.ready.len()
     }
+
+    // Hands a segment to a connection that's waiting to be accepted.
+    fn receive(&self, remote: &ipv4::Endpoint, header: &TcpHeader, data: Bytes, arrival: Instant) {
+        for r in &self.ready {
+            if let Ok(ref cb) = r {
+                if cb.remote() == *remote {
+                    cb.receive(header, data, arrival);
+                    return;
+                }
+            }
+        }
+    }
 }
 
 pub struct PassiveSocket<RT: Runtime> {
@@ -110,25 +143,41 @@ pub struct PassiveSocket<RT: Runtime> {
     local: ipv4::Endpoint,
     rt: RT,
     arp: arp::Peer<RT>,
+    rst_sender: RstSender<RT>,
+
+    // Every connection accepted on this socket uses these.
+    congestion_ctrl_type: CongestionControlConstructor,
+    congestion_ctrl_options: Option<cc::Options>,
 }
 
 impl<RT: Runtime> PassiveSocket<RT> {
-    pub fn new(local: ipv4::Endpoint, max_backlog: usize, rt: RT, arp: arp::Peer<RT>) -> Self {
+    pub fn new(
+        local: ipv4::Endpoint,
+        max_backlog: usize,
+        rt: RT,
+        arp: arp::Peer<RT>,
+        rst_sender: RstSender<RT>,
+        congestion_ctrl_type: CongestionControlConstructor,
+        congestion_ctrl_options: Option<cc::Options>,
+    ) -> Self {
         let ready = ReadySockets {
             ready: VecDeque::new(),
             endpoints: HashSet::new(),
             waker: None,
         };
         let ready = Rc::new(RefCell::new(ready));
-        let nonce = rt.rng_gen();
+        let isn_key = rt.rng_gen();
         Self {
             inflight: HashMap::new(),
             ready,
             max_backlog,
-            isn_generator: IsnGenerator::new(nonce),
+            isn_generator: IsnGenerator::new(isn_key, rt.now()),
             local,
             rt,
             arp,
+            rst_sender,
+            congestion_ctrl_type,
+            congestion_ctrl_options,
         }
     }
 
@@ -136,11 +185,18 @@ impl<RT: Runtime> PassiveSocket<RT> {
         self.ready.borrow_mut().poll(ctx)
     }
 
-    pub fn receive(&mut self, ip_header: &Ipv4Header, header: &TcpHeader) -> Result<(), Fail> {
+    pub fn receive(
+        &mut self,
+        ip_header: &Ipv4Header,
+        header: &TcpHeader,
+        data: Bytes,
+        arrival: Instant,
+    ) -> Result<(), Fail> {
         let remote = ipv4::Endpoint::new(ip_header.src_addr, header.src_port);
         if self.ready.borrow().endpoints.contains(&remote) {
-            // TODO: What should we do if a packet shows up for a connection that hasn't been
-            // `accept`ed yet?
+            // The connection's established but hasn't been `accept`ed yet. Its control block can take the segment
+            // anyway, and the data will be waiting once it is.
+            self.ready.borrow().receive(&remote, header, data, arrival);
             return Ok(());
         }
         let inflight_len = self.inflight.len();
@@ -153,34 +209,43 @@ impl<RT: Runtime> PassiveSocket<RT> {
                 });
             }
             // TODO: Add entry API.
-            let &InflightAccept {
+            let Handshake {
                 local_isn,
                 remote_isn,
                 window_size,
                 window_scale,
+                receive_window_scale,
                 mss,
-                ..
-            } = self.inflight.get(&remote).unwrap();
+                sack_permitted,
+            } = self.inflight.get(&remote).unwrap().handshake;
             if header.ack_num != local_isn + Wrapping(1) {
                 return Err(Fail::Malformed {
                     details: "Invalid SYN+ACK seq num",
                 });
             }
-            let sender = Sender::new(local_isn + Wrapping(1), window_size, window_scale, mss, self.rt.tcp_options().congestion_ctrl_type, self.rt.tcp_options().congestion_ctrl_options);
+            let sender = Sender::new(local_isn + Wrapping(1), window_size, window_scale, mss, self.rt.tcp_options().send_buffer_size, self.congestion_ctrl_type, self.congestion_ctrl_options.clone());
             let receiver = Receiver::new(
                 remote_isn + Wrapping(1),
                 self.rt.tcp_options().receive_window_size as u32,
-                mss
+                receive_window_scale.unwrap_or(0),
+                self.rt.tcp_options().receive_buffer_limit.unwrap_or(self.rt.tcp_options().receive_window_size) as u32,
+                mss,
+                self.rt.tcp_options().effective_ack_ratio(),
             );
             self.inflight.remove(&remote);
-            let cb = ControlBlock {
-                local: self.local.clone(),
-                remote: remote.clone(),
-                rt: self.rt.clone(),
-                arp: self.arp.clone(),
+            let cb = ControlBlock::new(
+                self.local.clone(),
+                remote.clone(),
+                self.rt.clone(),
+                self.arp.clone(),
                 sender,
                 receiver,
-            };
+                sack_permitted,
+            );
+            // The remote may have started sending data with the ACK that completed the handshake.
+            if !data.is_empty() {
+                cb.receive(header, data, arrival);
+            }
             self.ready.borrow_mut().push_ok(cb);
             return Ok(());
         }
@@ -192,63 +257,62 @@ impl<RT: Runtime> PassiveSocket<RT> {
             });
         }
         if inflight_len + self.ready.borrow().len() >= self.max_backlog {
-            // TODO: Should we send a RST here?
+            // Refuse the connection outright rather than leaving the remote to retry its SYN until it times out.
+            self.rst_sender.send(&self.local, &remote, header, data.len())?;
             return Err(Fail::ConnectionRefused {});
         }
-        let mut window_scale = 1;
-        let mut mss = FALLBACK_MSS;
+        let mut remote_mss = FALLBACK_MSS;
+        let mut remote_window_scale = None;
+        let mut sack_permitted = false;
         for option in header.iter_options() {
             match option {
-                TcpOptions2::WindowScale(w) => {
-                    window_scale = *w;
-                },
                 TcpOptions2::MaximumSegmentSize(m) => {
-                    if *m as usize <= DEFAULT_MSS {
-                        mss = *m as usize;
-                    }
+                    remote_mss = *m as usize;
+                },
+                TcpOptions2::WindowScale(s) => {
+                    remote_window_scale = Some(*s);
+                },
+                TcpOptions2::SelectiveAcknowlegementPermitted => {
+                    sack_permitted = self.rt.tcp_options().sack_enabled;
                 },
                 _ => continue,
             }
         }
+        // Neither side may send segments larger than the other advertised, so both directions use the smaller.
+        let mss = cmp::min(remote_mss, self.rt.tcp_options().advertised_mss);
+        // RFC 7323 Section 2.2: We only offer window scaling back if the remote offered it in its SYN, and then each
+        // side's shift applies to the windows it advertises from here on.
+        let (receive_window_scale, window_scale) = match remote_window_scale {
+            Some(s) => (Some(self.rt.tcp_options().receive_window_scale()), cmp::min(s, MAX_WINDOW_SCALE)),
+            None => (None, 0),
+        };
 
-        let local_isn = self.isn_generator.generate(&self.local, &remote);
-        let remote_isn = header.seq_num;
+        let handshake = Handshake {
+            local_isn: self.isn_generator.generate(&self.local, &remote, self.rt.now()),
+            remote_isn: header.seq_num,
+            window_size: header.window_size as u32,
+            window_scale,
+            receive_window_scale,
+            mss,
+            sack_permitted,
+        };
         let future = Self::background(
-            local_isn,
-            remote_isn,
+            handshake,
             self.local,
             remote.clone(),
-            mss,
             self.rt.clone(),
             self.arp.clone(),
             self.ready.clone(),
         );
         let handle = self.rt.spawn(future);
-
-        let window_size = header
-            .window_size
-            .checked_shl(window_scale as u32)
-            .expect("TODO: Window size overflow")
-            .try_into()
-            .expect("TODO: Window size overflow");
-        let accept = InflightAccept {
-            local_isn,
-            remote_isn,
-            window_size,
-            window_scale,
-            mss,
-            handle,
-        };
-        self.inflight.insert(remote, accept);
+        self.inflight.insert(remote, InflightAccept { handshake, handle });
         Ok(())
     }
 
     fn background(
-        local_isn: SeqNumber,
-        remote_isn: SeqNumber,
+        handshake: Handshake,
         local: ipv4::Endpoint,
         remote: ipv4::Endpoint,
-        mss: usize,
         rt: RT,
         arp: arp::Peer<RT>,
         ready: Rc<RefCell<ReadySockets<RT>>>,
@@ -268,11 +332,20 @@ impl<RT: Runtime> PassiveSocket<RT> {
                 };
                 let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
                 tcp_hdr.syn = true;
-                tcp_hdr.seq_num = local_isn;
+                tcp_hdr.seq_num = handshake.local_isn;
                 tcp_hdr.ack = true;
-                tcp_hdr.ack_num = remote_isn + Wrapping(1);
+                tcp_hdr.ack_num = handshake.remote_isn + Wrapping(1);
                 tcp_hdr.window_size = max_window_size;
-                tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(mss as u16));
+                // Advertise what we're willing to receive, independent of what the remote advertised.
+                let mss = rt.tcp_options().advertised_mss as u16;
+                tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(mss));
+                // RFC 2018 Section 2: Only offer SACK back if the remote offered it in its SYN.
+                if handshake.sack_permitted {
+                    tcp_hdr.push_option(TcpOptions2::SelectiveAcknowlegementPermitted);
+                }
+                if let Some(window_scale) = handshake.receive_window_scale {
+                    tcp_hdr.push_option(TcpOptions2::WindowScale(window_scale));
+                }
 
                 let segment = TcpSegment {
                     ethernet2_hdr: Ethernet2Header {
@@ -1,9 +1,22 @@
 use super::{
     active_open::ActiveOpenSocket,
-    established::EstablishedSocket,
+    congestion_ctrl::{
+        self as cc,
+        CongestionControlConstructor,
+        CwndObserver,
+    },
+    established::{
+        state::{
+            CloseReason,
+            ConnectionState,
+        },
+        EstablishedSocket,
+    },
     isn_generator::IsnGenerator,
     passive_open::PassiveSocket,
 };
+#[cfg(feature = "fault-injection")]
+use super::AckFaults;
 use crate::{
     fail::Fail,
     file_table::{
@@ -13,12 +26,18 @@ use crate::{
     },
     protocols::{
         arp,
-        ethernet2::frame::{
-            EtherType2,
-            Ethernet2Header,
+        ethernet2::{
+            frame::{
+                EtherType2,
+                Ethernet2Header,
+            },
+            MacAddress,
+        },
+        icmpv4,
+        ip::{
+            self,
+            port::EphemeralPorts,
         },
-        ip,
-        ip::port::EphemeralPorts,
         ipv4,
         ipv4::datagram::{
             Ipv4Header,
@@ -29,27 +48,51 @@ use crate::{
                 AcceptFuture,
                 ConnectFuture,
                 ConnectFutureState,
+                FlushFuture,
                 PopFuture,
                 PushFuture,
+                PushvFuture,
             },
             segment::{
                 TcpHeader,
                 TcpSegment,
             },
+            SeqNumber,
         },
     },
     runtime::Runtime,
+    scheduler::SchedulerHandle,
     sync::Bytes,
 };
+use futures_intrusive::{
+    buffer::GrowingHeapBuf,
+    channel::shared::{
+        generic_channel,
+        GenericReceiver,
+        GenericSender,
+    },
+    NoopLock,
+};
 use hashbrown::HashMap;
 use std::{
     cell::RefCell,
+    collections::{
+        vec_deque,
+        VecDeque,
+    },
+    convert::TryFrom,
+    future::Future,
+    net::Ipv4Addr,
+    num::Wrapping,
     rc::Rc,
     task::{
         Context,
         Poll,
     },
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 pub struct Peer<RT: Runtime> {
@@ -75,9 +118,15 @@ impl<RT: Runtime> Peer<RT> {
 
     pub fn bind(&self, fd: FileDescriptor, addr: ipv4::Endpoint) -> Result<(), Fail> {
         let mut inner = self.inner.borrow_mut();
-        if addr.port() >= ip::Port::first_private_port() {
+        inner.release_closed_ports();
+        if inner.ephemeral_ports.contains(addr.port()) {
             return Err(Fail::Malformed {
-                details: "Port number in private port range",
+                details: "Port number in ephemeral port range",
+            });
+        }
+        if inner.sockets.values().any(|s| s.local() == Some(addr)) {
+            return Err(Fail::ResourceBusy {
+                details: "Port already in use",
             });
         }
         match inner.sockets.get_mut(&fd) {
@@ -91,8 +140,12 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
-    pub fn receive(&self, ip_header: &Ipv4Header, buf: Bytes) -> Result<(), Fail> {
-        self.inner.borrow_mut().receive(ip_header, buf)
+    pub fn receive(&self, ip_header: &Ipv4Header, buf: Bytes, arrival: Instant) -> Result<(), Fail> {
+        self.inner.borrow_mut().receive(ip_header, buf, arrival)
+    }
+
+    pub fn receive_icmp_error(&self, error: &icmpv4::Error) -> Result<(), Fail> {
+        self.inner.borrow_mut().receive_icmp_error(error)
     }
 
     pub fn listen(&self, fd: FileDescriptor, backlog: usize) -> Result<(), Fail> {
@@ -112,7 +165,16 @@ impl<RT: Runtime> Peer<RT> {
             });
         }
 
-        let socket = PassiveSocket::new(local, backlog, inner.rt.clone(), inner.arp.clone());
+        let (congestion_ctrl_type, congestion_ctrl_options) = inner.take_congestion_ctrl(fd);
+        let socket = PassiveSocket::new(
+            local,
+            backlog,
+            inner.rt.clone(),
+            inner.arp.clone(),
+            inner.rst_sender.clone(),
+            congestion_ctrl_type,
+            congestion_ctrl_options,
+        );
         assert!(inner.passive.insert(local.clone(), socket).is_none());
         inner.sockets.insert(fd, Socket::Listening { local });
         Ok(())
@@ -122,7 +184,7 @@ impl<RT: Runtime> Peer<RT> {
         &self,
         fd: FileDescriptor,
         ctx: &mut Context,
-    ) -> Poll<Result<FileDescriptor, Fail>> {
+    ) -> Poll<Result<(FileDescriptor, ipv4::Endpoint), Fail>> {
         let mut inner_ = self.inner.borrow_mut();
         let inner = &mut *inner_;
 
@@ -145,18 +207,21 @@ impl<RT: Runtime> Peer<RT> {
             Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
         };
         let established = EstablishedSocket::new(cb);
+        // A connection that was torn down may still hold the same endpoints.
+        inner.release_closed_ports();
 
         let fd = inner.file_table.alloc(File::TcpSocket);
-        let key = (established.cb.local.clone(), established.cb.remote.clone());
+        let remote = established.cb.remote();
+        let key = (established.cb.local(), remote.clone());
 
         let socket = Socket::Established {
-            local: established.cb.local.clone(),
-            remote: established.cb.remote.clone(),
+            local: established.cb.local(),
+            remote: remote.clone(),
         };
         assert!(inner.sockets.insert(fd, socket).is_none());
         assert!(inner.established.insert(key, established).is_none());
 
-        Poll::Ready(Ok(fd))
+        Poll::Ready(Ok((fd, remote)))
     }
 
     pub fn accept(&self, fd: FileDescriptor) -> AcceptFuture<RT> {
@@ -170,31 +235,47 @@ impl<RT: Runtime> Peer<RT> {
         let mut inner = self.inner.borrow_mut();
 
         let r = try {
-            match inner.sockets.get_mut(&fd) {
-                Some(Socket::Inactive { .. }) => (),
+            let bound = match inner.sockets.get_mut(&fd) {
+                Some(Socket::Inactive { local }) => *local,
                 _ => Err(Fail::Malformed {
                     details: "Invalid file descriptor",
                 })?,
-            }
+            };
 
-            // TODO: We need to free these!
-            let local_port = inner.ephemeral_ports.alloc()?;
-            let local = ipv4::Endpoint::new(inner.rt.local_ipv4_addr(), local_port);
+            // Use the bound local endpoint if there is one, and otherwise pick an ephemeral port.
+            let local = match bound {
+                Some(local) => local,
+                None => {
+                    inner.release_closed_ports();
+                    let offset = inner.rt.rng_gen::<u16>() as usize;
+                    let local_ipv4_addr = inner.rt.local_ipv4_addr();
+                    let Inner { ref mut ephemeral_ports, ref sockets, .. } = *inner;
+                    let local_port = ephemeral_ports.alloc(offset, |port| {
+                        let local = ipv4::Endpoint::new(local_ipv4_addr, port);
+                        sockets.values().any(|s| s.local() == Some(local))
+                    })?;
+                    ipv4::Endpoint::new(local_ipv4_addr, local_port)
+                },
+            };
 
             let socket = Socket::Connecting {
                 local: local.clone(),
                 remote: remote.clone(),
             };
             inner.sockets.insert(fd, socket);
+            inner.close_reasons.remove(&fd);
 
-            let local_isn = inner.isn_generator.generate(&local, &remote);
+            let local_isn = inner.isn_generator.generate(&local, &remote, inner.rt.now());
             let key = (local.clone(), remote.clone());
+            let (congestion_ctrl_type, congestion_ctrl_options) = inner.take_congestion_ctrl(fd);
             let socket = ActiveOpenSocket::new(
                 local_isn,
                 local,
                 remote,
                 inner.rt.clone(),
                 inner.arp.clone(),
+                congestion_ctrl_type,
+                congestion_ctrl_options,
             );
             assert!(inner.connecting.insert(key, socket).is_none());
             fd
@@ -212,70 +293,79 @@ impl<RT: Runtime> Peer<RT> {
 
     pub fn peek(&self, fd: FileDescriptor) -> Result<Bytes, Fail> {
         let inner = self.inner.borrow_mut();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            },
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => s.peek(),
-            None => Err(Fail::Malformed {
-                details: "Socket not established",
-            }),
+        inner.established_socket(fd)?.peek()
+    }
+
+    pub fn peek_segments(&self, fd: FileDescriptor) -> Result<vec_deque::IntoIter<Bytes>, Fail> {
+        let inner = self.inner.borrow();
+        inner.established_socket(fd)?.peek_segments()
+    }
+
+    pub fn consume(&self, fd: FileDescriptor, amount: usize) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        inner.established_socket(fd)?.consume(amount)
+    }
+
+    pub fn peek_amount(&self, fd: FileDescriptor, amount: usize) -> Result<Bytes, Fail> {
+        let inner = self.inner.borrow_mut();
+        inner.established_socket(fd)?.peek_amount(amount)
+    }
+
+    pub fn poll_peek(&self, fd: FileDescriptor, ctx: &mut Context) -> Poll<Result<Bytes, Fail>> {
+        let inner = self.inner.borrow_mut();
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_peek(ctx),
+            Err(e) => Poll::Ready(Err(e)),
         }
     }
 
     pub fn recv(&self, fd: FileDescriptor) -> Result<Option<Bytes>, Fail> {
         let inner = self.inner.borrow_mut();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Recv: Socket not established",
-                })
-            },
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => s.recv(),
-            None => Err(Fail::Malformed {
-                details: "Socket not established",
-            }),
-        }
+        inner.established_socket(fd)?.recv()
     }
 
     pub fn poll_recv(&self, fd: FileDescriptor, ctx: &mut Context) -> Poll<Result<Bytes, Fail>> {
         let inner = self.inner.borrow_mut();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Poll::Ready(Err(Fail::Malformed {
-                    details: "Recv: Socket not established",
-                }))
-            },
-            None => return Poll::Ready(Err(Fail::Malformed { details: "Bad FD" })),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => s.poll_recv(ctx),
-            None => Poll::Ready(Err(Fail::Malformed {
-                details: "Socket not established",
-            })),
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_recv(ctx),
+            Err(e) => Poll::Ready(Err(e)),
         }
     }
 
     pub fn push(&self, fd: FileDescriptor, buf: Bytes) -> PushFuture<RT> {
-        let err = match self.send(fd, buf) {
-            Ok(()) => None,
-            Err(e) => Some(e),
-        };
         PushFuture {
             fd,
-            err,
-            _marker: std::marker::PhantomData,
+            buf,
+            urgent: false,
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn push_urgent(&self, fd: FileDescriptor, buf: Bytes) -> PushFuture<RT> {
+        PushFuture {
+            fd,
+            buf,
+            urgent: true,
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn pushv(&self, fd: FileDescriptor, bufs: &[Bytes]) -> PushvFuture<RT> {
+        PushvFuture {
+            fd,
+            bufs: bufs.iter().cloned().collect(),
+            inner: self.inner.clone(),
+        }
+    }
+
+    // The future resolves once everything pushed before this call has been sent, not necessarily acknowledged.
+    pub fn flush(&self, fd: FileDescriptor) -> FlushFuture<RT> {
+        let inner = self.inner.borrow();
+        let seq_no = inner.established_socket(fd).map(|s| s.unsent_seq_no());
+        FlushFuture {
+            fd,
+            seq_no,
+            inner: self.inner.clone(),
         }
     }
 
@@ -286,40 +376,74 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
-    fn send(&self, fd: FileDescriptor, buf: Bytes) -> Result<(), Fail> {
+    pub fn poll_push(&self, fd: FileDescriptor, buf: &mut Bytes, ctx: &mut Context) -> Poll<Result<(), Fail>> {
         let inner = self.inner.borrow_mut();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            },
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => s.send(buf),
-            None => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            },
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_send(buf, ctx),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    pub fn poll_push_urgent(&self, fd: FileDescriptor, buf: &mut Bytes, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        let inner = self.inner.borrow_mut();
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_send_urgent(buf, ctx),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    pub fn urgent_offset(&self, fd: FileDescriptor) -> Result<Option<usize>, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.urgent_offset())
+    }
+
+    pub fn bytes_available(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.bytes_available())
+    }
+
+    pub fn is_readable(&self, fd: FileDescriptor) -> Result<bool, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.is_readable())
+    }
+
+    pub fn send_buffer_used(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.send_buffer_used())
+    }
+
+    pub fn poll_pushv(&self, fd: FileDescriptor, bufs: &mut VecDeque<Bytes>, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        let inner = self.inner.borrow_mut();
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_sendv(bufs, ctx),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    pub fn poll_sent(&self, fd: FileDescriptor, seq_no: SeqNumber, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        let inner = self.inner.borrow();
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_sent(seq_no, ctx),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    pub fn poll_flush(&self, fd: FileDescriptor, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        let inner = self.inner.borrow();
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_flush(ctx),
+            Err(e) => Poll::Ready(Err(e)),
         }
     }
 
     pub fn close(&self, fd: FileDescriptor) -> Result<(), Fail> {
         let inner = self.inner.borrow_mut();
         match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => {
-                let key = (local.clone(), remote.clone());
-                match inner.established.get(&key) {
-                    Some(ref s) => s.close()?,
-                    None => {
-                        return Err(Fail::Malformed {
-                            details: "Socket not established",
-                        })
-                    },
-                }
+            Some(Socket::Established { .. }) => inner.established_socket(fd)?.close()?,
+            Some(Socket::Closed) => {
+                return Err(Fail::Ignored {
+                    details: "Socket already closed",
+                })
             },
             Some(..) => {
                 // TODO: Implement close for listening sockets.
@@ -330,50 +454,118 @@ impl<RT: Runtime> Peer<RT> {
         Ok(())
     }
 
-    pub fn remote_mss(&self, fd: FileDescriptor) -> Result<usize, Fail> {
-        let inner = self.inner.borrow();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
+    // Overrides the engine's congestion control algorithm and options for a socket that hasn't connected or started
+    // listening yet. A listening socket passes them on to every connection it accepts.
+    pub fn set_congestion_ctrl(
+        &self,
+        fd: FileDescriptor,
+        congestion_ctrl_type: CongestionControlConstructor,
+        congestion_ctrl_options: Option<cc::Options>,
+    ) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Inactive { .. }) => (),
             Some(..) => {
                 return Err(Fail::Malformed {
-                    details: "Socket not established",
+                    details: "Socket already connecting or listening",
                 })
             },
             None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => Ok(s.remote_mss()),
-            None => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            },
         }
+        if let Some(ref options) = congestion_ctrl_options {
+            cc::validate_options(options)?;
+        }
+        inner.congestion_ctrl.insert(fd, (congestion_ctrl_type, congestion_ctrl_options));
+        Ok(())
     }
 
-    pub fn current_rto(&self, fd: FileDescriptor) -> Result<Duration, Fail> {
+    // Resets the socket's connection instead of closing it gracefully: anything still queued is dropped and a RST
+    // goes to the remote.
+    pub fn abort(&self, fd: FileDescriptor) -> Result<(), Fail> {
         let inner = self.inner.borrow();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            },
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => Ok(s.current_rto()),
-            None => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            },
+        inner.established_socket(fd)?.abort()
+    }
+
+    // Why the socket's connection was torn down, or `None` if it's still up (or never got as far as connecting).
+    pub fn close_reason(&self, fd: FileDescriptor) -> Result<Option<CloseReason>, Fail> {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Established { .. }) => Ok(inner.established_socket(fd)?.close_reason()),
+            Some(..) => Ok(inner.close_reasons.get(&fd).copied()),
+            None => Err(Fail::Malformed { details: "Bad FD" }),
         }
     }
 
+    pub fn connection_state(&self, fd: FileDescriptor) -> Result<ConnectionState, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.connection_state())
+    }
+
+    pub fn set_cwnd_observer(&self, fd: FileDescriptor, observer: CwndObserver) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        let s = inner.established_socket(fd)?;
+        s.set_cwnd_observer(observer);
+        Ok(())
+    }
+
+    pub fn set_send_rate_limit(&self, fd: FileDescriptor, rate: Option<u64>) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        inner.established_socket(fd)?.set_send_rate_limit(rate)
+    }
+
+    #[cfg(feature = "fault-injection")]
+    pub fn set_ack_faults(&self, fd: FileDescriptor, faults: Option<AckFaults>) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        let s = inner.established_socket(fd)?;
+        s.set_ack_faults(faults);
+        Ok(())
+    }
+
+    pub fn set_recv_rate_limit(&self, fd: FileDescriptor, rate: Option<u64>) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        inner.established_socket(fd)?.set_recv_rate_limit(rate)
+    }
+
+    pub fn remote_mss(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.remote_mss())
+    }
+
+    #[cfg(test)]
+    pub fn emit_crafted(&self, fd: FileDescriptor, craft: impl FnOnce(&mut TcpHeader), data: Bytes) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        inner.established_socket(fd)?.emit_crafted(craft, data)
+    }
+
+    pub fn ack_latencies(&self, fd: FileDescriptor) -> Result<Vec<Duration>, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.ack_latencies())
+    }
+
+    pub fn current_rto(&self, fd: FileDescriptor) -> Result<Duration, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.current_rto())
+    }
+
+    pub fn rto_estimate(&self, fd: FileDescriptor) -> Result<Duration, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.rto_estimate())
+    }
+
+    pub fn payload_checksums(&self, fd: FileDescriptor) -> Result<(u32, u32), Fail> {
+        let inner = self.inner.borrow();
+        inner.established_socket(fd)?.payload_checksums()
+    }
+
     pub fn endpoints(&self, fd: FileDescriptor) -> Result<(ipv4::Endpoint, ipv4::Endpoint), Fail> {
         let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.endpoints())
+    }
+
+    // Moves an established connection over to new local and remote addresses, keeping its ports. The runtime has
+    // to be taking in frames for the new local address by the time the remote starts sending to it.
+    pub fn rebind(&self, fd: FileDescriptor, local_addr: Ipv4Addr, remote_addr: Ipv4Addr) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
         let key = match inner.sockets.get(&fd) {
             Some(Socket::Established { local, remote }) => (*local, *remote),
             Some(..) => {
@@ -383,14 +575,30 @@ impl<RT: Runtime> Peer<RT> {
             },
             None => return Err(Fail::Malformed { details: "Bad FD" }),
         };
-        match inner.established.get(&key) {
-            Some(ref s) => Ok(s.endpoints()),
+        let new_key = (ipv4::Endpoint::new(local_addr, key.0.port), ipv4::Endpoint::new(remote_addr, key.1.port));
+        if new_key == key {
+            return Ok(());
+        }
+        if inner.established.contains_key(&new_key) || inner.connecting.contains_key(&new_key) {
+            return Err(Fail::ResourceBusy {
+                details: "Connection already exists",
+            });
+        }
+        let socket = match inner.established.remove(&key) {
+            Some(s) => s,
             None => {
                 return Err(Fail::Malformed {
                     details: "Socket not established",
                 })
             },
-        }
+        };
+        socket.rebind(new_key.0, new_key.1);
+        inner.established.insert(new_key, socket);
+        inner.sockets.insert(fd, Socket::Established {
+            local: new_key.0,
+            remote: new_key.1,
+        });
+        Ok(())
     }
 }
 
@@ -409,6 +617,20 @@ enum Socket {
         local: ipv4::Endpoint,
         remote: ipv4::Endpoint,
     },
+    // The connection closed and its local port was released (see `TcpOptions::release_closed_ports`).
+    Closed,
+}
+
+impl Socket {
+    fn local(&self) -> Option<ipv4::Endpoint> {
+        match self {
+            Socket::Inactive { local } => *local,
+            Socket::Listening { local } => Some(*local),
+            Socket::Connecting { local, .. } => Some(*local),
+            Socket::Established { local, .. } => Some(*local),
+            Socket::Closed => None,
+        }
+    }
 }
 
 pub struct Inner<RT: Runtime> {
@@ -419,6 +641,10 @@ pub struct Inner<RT: Runtime> {
 
     // FD -> local port
     sockets: HashMap<FileDescriptor, Socket>,
+    // FD -> congestion control to use instead of the engine's, until the socket connects or listens
+    congestion_ctrl: HashMap<FileDescriptor, (CongestionControlConstructor, Option<cc::Options>)>,
+    // FD -> why connecting failed, for sockets that never got as far as a control block
+    close_reasons: HashMap<FileDescriptor, CloseReason>,
 
     passive: HashMap<ipv4::Endpoint, PassiveSocket<RT>>,
     connecting: HashMap<(ipv4::Endpoint, ipv4::Endpoint), ActiveOpenSocket<RT>>,
@@ -426,24 +652,66 @@ pub struct Inner<RT: Runtime> {
 
     rt: RT,
     arp: arp::Peer<RT>,
+    rst_sender: RstSender<RT>,
+    #[allow(unused)]
+    rst_handle: SchedulerHandle,
 }
 
 impl<RT: Runtime> Inner<RT> {
     fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Self {
+        let (first_ephemeral_port, last_ephemeral_port) = rt.tcp_options().ephemeral_port_range;
+        let (rst_sender, rst_background) = RstSender::new(rt.clone(), arp.clone());
+        let rst_handle = rt.spawn(rst_background);
         Self {
-            isn_generator: IsnGenerator::new(rt.rng_gen()),
+            isn_generator: IsnGenerator::new(rt.rng_gen(), rt.now()),
             file_table,
-            ephemeral_ports: EphemeralPorts::new(),
+            ephemeral_ports: EphemeralPorts::new(first_ephemeral_port, last_ephemeral_port),
             sockets: HashMap::new(),
+            congestion_ctrl: HashMap::new(),
+            close_reasons: HashMap::new(),
             passive: HashMap::new(),
             connecting: HashMap::new(),
             established: HashMap::new(),
             rt,
             arp,
+            rst_sender,
+            rst_handle,
         }
     }
 
-    fn receive(&mut self, ip_hdr: &Ipv4Header, buf: Bytes) -> Result<(), Fail> {
+    // The connection on `fd`, for the operations that only make sense once it's established.
+    fn established_socket(&self, fd: FileDescriptor) -> Result<&EstablishedSocket<RT>, Fail> {
+        let key = match self.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            // The connection was torn down and released. Anything still waiting on it finds out how it ended.
+            Some(Socket::Closed) => match self.close_reasons.get(&fd) {
+                None | Some(CloseReason::Graceful) => {
+                    return Err(Fail::Malformed {
+                        details: "Socket not established",
+                    })
+                },
+                Some(..) => return Err(Fail::ConnectionAborted {}),
+            },
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        self.established.get(&key).ok_or(Fail::Malformed {
+            details: "Socket not established",
+        })
+    }
+
+    fn take_congestion_ctrl(&mut self, fd: FileDescriptor) -> (CongestionControlConstructor, Option<cc::Options>) {
+        self.congestion_ctrl.remove(&fd).unwrap_or_else(|| {
+            let options = self.rt.tcp_options();
+            (options.congestion_ctrl_type, options.congestion_ctrl_options)
+        })
+    }
+
+    fn receive(&mut self, ip_hdr: &Ipv4Header, buf: Bytes, arrival: Instant) -> Result<(), Fail> {
         let (tcp_hdr, data) = TcpHeader::parse(ip_hdr, buf)?;
         let local = ipv4::Endpoint::new(ip_hdr.dst_addr, tcp_hdr.dst_port);
         let remote = ipv4::Endpoint::new(ip_hdr.src_addr, tcp_hdr.src_port);
@@ -457,50 +725,119 @@ impl<RT: Runtime> Inner<RT> {
         let key = (local, remote);
 
         if let Some(s) = self.established.get(&key) {
-            s.receive(&tcp_hdr, data);
-            return Ok(());
+            // Without TIME_WAIT, a SYN for a connection that's already closed is the remote starting a new one from
+            // the same port.
+            let reopened = tcp_hdr.syn
+                && !tcp_hdr.ack
+                && self.rt.tcp_options().release_closed_ports
+                && s.close_reason() == Some(CloseReason::Graceful);
+            // Once a connection's been reset or given up on, whatever the remote sends is for one that's gone.
+            let aborted = match s.close_reason() {
+                None | Some(CloseReason::Graceful) => false,
+                Some(..) => true,
+            };
+            if !reopened && !aborted {
+                s.receive(&tcp_hdr, data, arrival);
+                if s.close_reason() == Some(CloseReason::ResetReceived) {
+                    self.release_connection(key);
+                }
+                return Ok(());
+            }
+            self.release_connection(key);
         }
         if let Some(s) = self.connecting.get_mut(&key) {
-            s.receive(&tcp_hdr);
+            s.receive(&tcp_hdr, data, arrival);
             return Ok(());
         }
         let (local, _) = key;
         if let Some(s) = self.passive.get_mut(&local) {
-            return s.receive(ip_hdr, &tcp_hdr);
+            return s.receive(ip_hdr, &tcp_hdr, data, arrival);
         }
 
-        // The packet isn't for an open port; send a RST segment.
-        self.send_rst(&local, &remote)?;
+        // The packet isn't for any connection we know of, e.g. one from before we restarted, so send a RST to let
+        // the remote know it's gone. Never in answer to a RST, though, or two stacks could keep resetting each other.
+        if tcp_hdr.rst {
+            return Err(Fail::Ignored {
+                details: "RST for unknown connection",
+            });
+        }
+        self.rst_sender.send(&local, &remote, &tcp_hdr, data.len())?;
         Ok(())
     }
 
-    fn send_rst(&mut self, local: &ipv4::Endpoint, remote: &ipv4::Endpoint) -> Result<(), Fail> {
-        // TODO: Make this work pending on ARP resolution if needed.
-        let remote_link_addr =
-            self.arp
-                .try_query(remote.addr)
-                .ok_or_else(|| Fail::ResourceNotFound {
-                    details: "RST destination not in ARP cache",
-                })?;
-
-        let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
-        tcp_hdr.rst = true;
-
-        let segment = TcpSegment {
-            ethernet2_hdr: Ethernet2Header {
-                dst_addr: remote_link_addr,
-                src_addr: self.rt.local_link_addr(),
-                ether_type: EtherType2::Ipv4,
+    // RFC 1122 Section 4.2.3.9 only has port unreachable abort a connection, but while we're still waiting on the
+    // SYN+ACK, either code means there's nothing there to connect to. So rather than waiting out the handshake
+    // timeout, the connect fails right away. Connections that are already established are left alone.
+    fn receive_icmp_error(&mut self, error: &icmpv4::Error) -> Result<(), Fail> {
+        let local = ipv4::Endpoint::new(error.src_addr, ip::Port::try_from(error.src_port)?);
+        let remote = ipv4::Endpoint::new(error.dst_addr, ip::Port::try_from(error.dst_port)?);
+        let key = (local, remote);
+        let socket = match self.connecting.get_mut(&key) {
+            Some(s) => s,
+            None => {
+                return Err(Fail::Ignored {
+                    details: "ICMP error for a socket that isn't connecting",
+                })
             },
-            ipv4_hdr: Ipv4Header::new(local.addr, remote.addr, Ipv4Protocol2::Tcp),
-            tcp_hdr,
-            data: Bytes::empty(),
         };
-        self.rt.transmit(segment);
-
+        let e = match error.unreachable {
+            icmpv4::Unreachable::Host => Fail::HostUnreachable {},
+            icmpv4::Unreachable::Port => Fail::ConnectionRefused {},
+        };
+        if !socket.receive_unreachable(Wrapping(error.seq_num), e)? {
+            return Ok(());
+        }
+        let fd = self.sockets.iter().find_map(|(fd, s)| match s {
+            Socket::Connecting { local: l, remote: r } if (*l, *r) == key => Some(*fd),
+            _ => None,
+        });
+        if let Some(fd) = fd {
+            self.close_reasons.insert(fd, CloseReason::Unreachable);
+        }
         Ok(())
     }
 
+    // Drops every connection that was torn down, so their local ports can be reused. Those that closed gracefully
+    // are only dropped if the options allow it.
+    fn release_closed_ports(&mut self) {
+        let release_graceful = self.rt.tcp_options().release_closed_ports;
+        let closed = self
+            .established
+            .iter()
+            .filter(|(_, s)| match s.close_reason() {
+                Some(CloseReason::Graceful) => release_graceful,
+                Some(..) => true,
+                None => false,
+            })
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in closed {
+            self.release_connection(key);
+        }
+    }
+
+    fn release_connection(&mut self, key: (ipv4::Endpoint, ipv4::Endpoint)) {
+        let reason = match self.established.remove(&key) {
+            Some(s) => s.close_reason(),
+            None => return,
+        };
+        let (local, remote) = key;
+        if self.ephemeral_ports.contains(local.port()) {
+            self.ephemeral_ports.free(local.port());
+        }
+        let fd = self.sockets.iter().find_map(|(fd, s)| match s {
+            Socket::Established { local: l, remote: r } if (*l, *r) == key => Some(*fd),
+            _ => None,
+        });
+        if let Some(fd) = fd {
+            self.sockets.insert(fd, Socket::Closed);
+            if let Some(reason) = reason {
+                self.close_reasons.insert(fd, reason);
+            }
+        }
+        debug!("Released {:?} after its connection to {:?} closed", local, remote);
+    }
+
     pub(super) fn poll_connect_finished(
         &mut self,
         fd: FileDescriptor,
@@ -532,7 +869,22 @@ impl<RT: Runtime> Inner<RT> {
         };
         self.connecting.remove(&key);
 
-        let cb = result?;
+        let cb = match result {
+            Ok(cb) => cb,
+            Err(e) => {
+                let reason = match e {
+                    Fail::Timeout {} => Some(CloseReason::HandshakeTimeout),
+                    Fail::ConnectionRefused {} => Some(CloseReason::ResetReceived),
+                    Fail::HostUnreachable {} => Some(CloseReason::Unreachable),
+                    _ => None,
+                };
+                // An ICMP error will already have recorded its own reason.
+                if let Some(reason) = reason {
+                    self.close_reasons.entry(fd).or_insert(reason);
+                }
+                return Poll::Ready(Err(e));
+            },
+        };
         assert!(self
             .established
             .insert(key, EstablishedSocket::new(cb))
@@ -544,3 +896,86 @@ impl<RT: Runtime> Inner<RT> {
         Poll::Ready(Ok(()))
     }
 }
+
+type RstRequest = (ipv4::Endpoint, ipv4::Endpoint, TcpHeader);
+type RstQueueSender = GenericSender<NoopLock, RstRequest, GrowingHeapBuf<RstRequest>>;
+type RstQueueReceiver = GenericReceiver<NoopLock, RstRequest, GrowingHeapBuf<RstRequest>>;
+
+// Answers segments that don't belong to any connection with a RST. The remote isn't necessarily in the ARP cache,
+// e.g. when we've just restarted, so those RSTs wait on an ARP query in the background.
+#[derive(Clone)]
+pub struct RstSender<RT: Runtime> {
+    rt: RT,
+    arp: arp::Peer<RT>,
+    queue: RstQueueSender,
+}
+
+impl<RT: Runtime> RstSender<RT> {
+    // The returned future sends the queued RSTs, so it has to be kept running for as long as this is in use.
+    fn new(rt: RT, arp: arp::Peer<RT>) -> (Self, impl Future<Output = ()>) {
+        let (tx, rx) = generic_channel(16);
+        let background = Self::background(rt.clone(), arp.clone(), rx);
+        (Self { rt, arp, queue: tx }, background)
+    }
+
+    // Sends a RST in reply to the segment with `header`, which carried `data_len` bytes of data.
+    pub fn send(
+        &self,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        header: &TcpHeader,
+        data_len: usize,
+    ) -> Result<(), Fail> {
+        // RFC 793 Section 3.4: The reset takes its sequence number from the segment's ACK if it has one, so the
+        // remote will accept it. Otherwise it starts from zero and acknowledges everything the segment took up.
+        let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
+        tcp_hdr.rst = true;
+        if header.ack {
+            tcp_hdr.seq_num = header.ack_num;
+        } else {
+            tcp_hdr.ack = true;
+            tcp_hdr.ack_num = header.seq_num + Wrapping(data_len as u32 + header.syn as u32 + header.fin as u32);
+        }
+
+        // First, try to send it right away.
+        if let Some(remote_link_addr) = self.arp.try_query(remote.addr) {
+            Self::transmit(&self.rt, local, remote, tcp_hdr, remote_link_addr);
+            return Ok(());
+        }
+        // Otherwise defer to the async path. If that's backed up, the remote will try again anyway.
+        self.queue
+            .try_send((*local, *remote, tcp_hdr))
+            .map_err(|_| Fail::ResourceExhausted {
+                details: "Too many RSTs waiting on ARP",
+            })
+    }
+
+    async fn background(rt: RT, arp: arp::Peer<RT>, rx: RstQueueReceiver) {
+        while let Some((local, remote, tcp_hdr)) = rx.receive().await {
+            match arp.query(remote.addr).await {
+                Ok(remote_link_addr) => Self::transmit(&rt, &local, &remote, tcp_hdr, remote_link_addr),
+                Err(e) => warn!("Failed to send RST to {:?}: {:?}", remote, e),
+            }
+        }
+    }
+
+    fn transmit(
+        rt: &RT,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        tcp_hdr: TcpHeader,
+        remote_link_addr: MacAddress,
+    ) {
+        let segment = TcpSegment {
+            ethernet2_hdr: Ethernet2Header {
+                dst_addr: remote_link_addr,
+                src_addr: rt.local_link_addr(),
+                ether_type: EtherType2::Ipv4,
+            },
+            ipv4_hdr: Ipv4Header::new(local.addr, remote.addr, Ipv4Protocol2::Tcp),
+            tcp_hdr,
+            data: Bytes::empty(),
+        };
+        rt.transmit(segment);
+    }
+}
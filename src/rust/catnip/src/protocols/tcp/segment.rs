@@ -20,7 +20,6 @@ use crate::{
 use byteorder::{
     ByteOrder,
     NetworkEndian,
-    ReadBytesExt,
 };
 use std::{
     cmp,
@@ -28,7 +27,6 @@ use std::{
         TryFrom,
         TryInto,
     },
-    io::Cursor,
     num::Wrapping,
 };
 
@@ -176,6 +174,134 @@ impl TcpOptions2 {
     }
 }
 
+// Walks the options field of a TCP header (RFC 793 Section 3.1), yielding each option other than padding in the
+// order they appear. Options we don't know are skipped over using their length, as RFC 1122 Section 4.2.2.5 asks,
+// but an option whose length is wrong for what it holds, or that runs past the end of the field, is `Malformed`
+// and ends the walk.
+pub fn parse_options(buf: &[u8]) -> impl Iterator<Item = Result<TcpOptions2, Fail>> + '_ {
+    OptionsParser { buf }
+}
+
+struct OptionsParser<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for OptionsParser<'a> {
+    type Item = Result<TcpOptions2, Fail>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (&kind, rest) = self.buf.split_first()?;
+            match kind {
+                // End of option list. Whatever follows is padding.
+                0 => {
+                    self.buf = &[];
+                    return None;
+                },
+                1 => {
+                    self.buf = rest;
+                    continue;
+                },
+                _ => (),
+            }
+            match self.parse_option() {
+                Ok(Some(option)) => return Some(Ok(option)),
+                Ok(None) => continue,
+                Err(e) => {
+                    self.buf = &[];
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
+
+impl<'a> OptionsParser<'a> {
+    // Takes the option at the front of `buf`, which has a kind and length byte, returning `None` if it's one we
+    // don't know.
+    fn parse_option(&mut self) -> Result<Option<TcpOptions2>, Fail> {
+        if self.buf.len() < 2 {
+            return Err(Fail::Malformed {
+                details: "TCP option runs past the end of the header",
+            });
+        }
+        let kind = self.buf[0];
+        let option_length = self.buf[1] as usize;
+        if option_length < 2 {
+            return Err(Fail::Malformed {
+                details: "TCP option length too small",
+            });
+        }
+        if option_length > self.buf.len() {
+            return Err(Fail::Malformed {
+                details: "TCP option runs past the end of the header",
+            });
+        }
+        let (option_buf, rest) = self.buf.split_at(option_length);
+        self.buf = rest;
+        let data = &option_buf[2..];
+
+        let option = match kind {
+            2 => {
+                if option_length != 4 {
+                    return Err(Fail::Malformed {
+                        details: "MSS size was not 4",
+                    });
+                }
+                TcpOptions2::MaximumSegmentSize(NetworkEndian::read_u16(data))
+            },
+            3 => {
+                if option_length != 3 {
+                    return Err(Fail::Malformed {
+                        details: "Window scale size was not 3",
+                    });
+                }
+                TcpOptions2::WindowScale(data[0])
+            },
+            4 => {
+                if option_length != 2 {
+                    return Err(Fail::Malformed {
+                        details: "SACK permitted size was not 2",
+                    });
+                }
+                TcpOptions2::SelectiveAcknowlegementPermitted
+            },
+            5 => {
+                let num_sacks = match option_length {
+                    10 | 18 | 26 | 34 => (option_length - 2) / 8,
+                    _ => {
+                        return Err(Fail::Malformed {
+                            details: "Invalid SACK size",
+                        })
+                    },
+                };
+                let mut sacks = [SelectiveAcknowlegement {
+                    begin: Wrapping(0),
+                    end: Wrapping(0),
+                }; 4];
+                for (i, block) in data.chunks_exact(8).enumerate().take(num_sacks) {
+                    sacks[i].begin = Wrapping(NetworkEndian::read_u32(&block[0..4]));
+                    sacks[i].end = Wrapping(NetworkEndian::read_u32(&block[4..8]));
+                }
+                TcpOptions2::SelectiveAcknowlegement { num_sacks, sacks }
+            },
+            8 => {
+                if option_length != 10 {
+                    return Err(Fail::Malformed {
+                        details: "TCP timestamp size was not 10",
+                    });
+                }
+                TcpOptions2::Timestamp {
+                    sender_timestamp: NetworkEndian::read_u32(&data[0..4]),
+                    echo_timestamp: NetworkEndian::read_u32(&data[4..8]),
+                }
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(option))
+    }
+}
+
 #[derive(Debug)]
 pub struct TcpHeader {
     pub src_port: ip::Port,
@@ -287,100 +413,14 @@ impl TcpHeader {
 
         let mut num_options = 0;
         let mut option_list = [TcpOptions2::NoOperation; MAX_TCP_OPTIONS];
-
-        if data_offset > MIN_TCP_HEADER2_SIZE {
-            let mut option_rdr = Cursor::new(&hdr_buf[MIN_TCP_HEADER2_SIZE..data_offset]);
-            loop {
-                // Sometimes we read off the end of the options for some reason, in which case we
-                // stop parsing and continue rather than panic, as everything else works.
-                // Since this was necessary to make a connection to a Linux TCP, I'm confident(ish)
-                // it's the right thing to do not to just error out.
-                // I haven't the time to figure out precisely what's wrong with the option reader, but
-                // this should be investigated further.
-                let option_kind = option_rdr.read_u8().unwrap_or_else(|_| {
-                    println!("Reading TCP option kind failed, defaulting to 0 (stop parsing)");
-                    0
+        for option in parse_options(&hdr_buf[MIN_TCP_HEADER2_SIZE..data_offset]) {
+            if num_options >= option_list.len() {
+                return Err(Fail::Malformed {
+                    details: "Too many TCP options provided",
                 });
-                let option = match option_kind {
-                    0 => break,
-                    1 => continue,
-                    2 => {
-                        let option_length = option_rdr.read_u8()?;
-                        if option_length != 4 {
-                            return Err(Fail::Malformed {
-                                details: "MSS size was not 4",
-                            });
-                        }
-                        let mss = option_rdr.read_u16::<NetworkEndian>()?;
-                        TcpOptions2::MaximumSegmentSize(mss)
-                    },
-                    3 => {
-                        let option_length = option_rdr.read_u8()?;
-                        if option_length != 3 {
-                            return Err(Fail::Malformed {
-                                details: "Window scale size was not 3",
-                            });
-                        }
-                        let window_scale = option_rdr.read_u8()?;
-                        TcpOptions2::WindowScale(window_scale)
-                    },
-                    4 => {
-                        let option_length = option_rdr.read_u8()?;
-                        if option_length != 2 {
-                            return Err(Fail::Malformed {
-                                details: "SACK permitted size was not 2",
-                            });
-                        }
-                        TcpOptions2::SelectiveAcknowlegementPermitted
-                    },
-                    5 => {
-                        let option_length = option_rdr.read_u8()?;
-                        let num_sacks = match option_length {
-                            10 | 18 | 26 | 34 => (option_length as usize - 2) / 8,
-                            _ => {
-                                return Err(Fail::Malformed {
-                                    details: "Invalid SACK size",
-                                })
-                            },
-                        };
-                        let mut sacks = [SelectiveAcknowlegement {
-                            begin: Wrapping(0),
-                            end: Wrapping(0),
-                        }; 4];
-                        for i in 0..num_sacks {
-                            sacks[i].begin = Wrapping(option_rdr.read_u32::<NetworkEndian>()?);
-                            sacks[i].end = Wrapping(option_rdr.read_u32::<NetworkEndian>()?);
-                        }
-                        TcpOptions2::SelectiveAcknowlegement { num_sacks, sacks }
-                    },
-                    8 => {
-                        let option_length = option_rdr.read_u8()?;
-                        if option_length != 10 {
-                            return Err(Fail::Malformed {
-                                details: "TCP timestamp size was not 10",
-                            });
-                        }
-                        let sender_timestamp = option_rdr.read_u32::<NetworkEndian>()?;
-                        let echo_timestamp = option_rdr.read_u32::<NetworkEndian>()?;
-                        TcpOptions2::Timestamp {
-                            sender_timestamp,
-                            echo_timestamp,
-                        }
-                    },
-                    _ => {
-                        return Err(Fail::Malformed {
-                            details: "Invalid TCP option",
-                        })
-                    },
-                };
-                if num_options >= option_list.len() {
-                    return Err(Fail::Malformed {
-                        details: "Too many TCP options provided",
-                    });
-                }
-                option_list[num_options] = option;
-                num_options += 1;
             }
+            option_list[num_options] = option?;
+            num_options += 1;
         }
 
         let header = Self {
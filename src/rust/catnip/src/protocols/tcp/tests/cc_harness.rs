@@ -0,0 +1,174 @@
+use crate::{
+    protocols::tcp::{
+        congestion_ctrl::{
+            self as cc,
+            CwndChange,
+        },
+        established::state::sender::{
+            Sender,
+            UnackedSegment,
+        },
+        SeqNumber,
+    },
+    sync::BytesMut,
+};
+use std::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    num::Wrapping,
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+pub const MSS: usize = 1450;
+pub const BUFFER_SIZE: usize = 1 << 20;
+
+// Queues up a full segment on `sender` as if it had been sent at `initial_tx`, or retransmitted if `initial_tx` is
+// `None`. For tests that need a `Sender` of their own rather than a whole harness.
+pub fn push_unacked(sender: &Sender, initial_tx: Option<Instant>) {
+    let bytes = BytesMut::from(&vec![0x5a; MSS][..]).freeze();
+    sender.unacked_queue.borrow_mut().push_back(UnackedSegment { bytes, initial_tx });
+    sender.sent_seq_no.modify(|s| s + Wrapping(MSS as u32));
+    sender.unsent_seq_no.modify(|s| s + Wrapping(MSS as u32));
+}
+
+// Drives a congestion control algorithm through a `Sender` without a control block or a peer, so tests can feed it
+// synthetic ACK sequences and check how cwnd and ssthresh move. Sending only fills the unacknowledged queue and
+// moves the sequence numbers, while ACKs go through `Sender::remote_ack` just as they would off the wire. Every
+// cwnd change is recorded.
+//
+// The harness keeps its own clock for when segments are sent and ACKs arrive, which is all RTT samples (and so
+// the RTO) are taken from. Anything an algorithm times off `Instant::now()` itself still sees real time.
+pub struct CcHarness {
+    pub sender: Sender,
+    now: Cell<Instant>,
+    changes: Rc<RefCell<Vec<CwndChange>>>,
+}
+
+impl CcHarness {
+    pub fn new(cc_constructor: cc::CongestionControlConstructor, options: Option<cc::Options>) -> Self {
+        Self::starting_at(Wrapping(0), cc_constructor, options)
+    }
+
+    pub fn starting_at(
+        seq_no: SeqNumber,
+        cc_constructor: cc::CongestionControlConstructor,
+        options: Option<cc::Options>,
+    ) -> Self {
+        // The remote's window is big enough that it never gets in the way.
+        let sender = Sender::new(seq_no, BUFFER_SIZE as u32, 0, MSS, BUFFER_SIZE, cc_constructor, options);
+        let changes = Rc::new(RefCell::new(vec![]));
+        let changes_ = changes.clone();
+        sender.set_cwnd_observer(Box::new(move |change| changes_.borrow_mut().push(change)));
+        Self {
+            sender,
+            now: Cell::new(Instant::now()),
+            changes,
+        }
+    }
+
+    pub fn advance_clock(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+
+    pub fn cwnd(&self) -> u32 {
+        self.sender.congestion_ctrl.get_cwnd()
+    }
+
+    // Every change to cwnd so far, oldest first. Algorithms without a congestion window never report any.
+    pub fn changes(&self) -> Vec<CwndChange> {
+        self.changes.borrow().clone()
+    }
+
+    // The most recent change, for checking on a single step.
+    pub fn last_change(&self) -> Option<CwndChange> {
+        self.changes.borrow().last().cloned()
+    }
+
+    // Gives the application more data waiting to go out, so the connection counts as limited by cwnd rather than
+    // by the application.
+    pub fn queue(&self, num_bytes: u32) {
+        self.sender.unsent_seq_no.modify(|s| s + Wrapping(num_bytes));
+    }
+
+    // Sends `num_segments` full segments, prompting the algorithm the same way the sender does before each one.
+    // Doesn't check cwnd, so tests are free to send more than it allows.
+    pub fn send(&self, num_segments: usize) {
+        for _ in 0..num_segments {
+            let Wrapping(sent_data) = self.sender.sent_seq_no.get() - self.sender.base_seq_no.get();
+            self.sender.congestion_ctrl.on_cwnd_check_before_send(&self.sender);
+            self.sender.congestion_ctrl.on_send(&self.sender, sent_data);
+            push_unacked(&self.sender, Some(self.now.get()));
+        }
+    }
+
+    // Sends as many segments as cwnd has room for.
+    pub fn fill_cwnd(&self) -> usize {
+        let in_flight = self.sender.bytes_in_flight() as usize;
+        let room = (self.cwnd() as usize).saturating_sub(in_flight) / MSS;
+        self.send(room);
+        room
+    }
+
+    // Acknowledges the next `num_segments` segments, one ACK per segment.
+    pub fn ack(&self, num_segments: usize) {
+        for _ in 0..num_segments {
+            let ack_seq_no = self.sender.base_seq_no.get() + Wrapping(MSS as u32);
+            self.sender.remote_ack(ack_seq_no, self.now.get()).unwrap();
+        }
+    }
+
+    // Acknowledges the next `num_segments` segments with a single ACK.
+    pub fn stretch_ack(&self, num_segments: usize) {
+        let ack_seq_no = self.sender.base_seq_no.get() + Wrapping((num_segments * MSS) as u32);
+        self.sender.remote_ack(ack_seq_no, self.now.get()).unwrap();
+    }
+
+    // Acknowledges everything sent so far with a single ACK.
+    pub fn ack_all(&self) {
+        let ack_seq_no = self.sender.sent_seq_no.get();
+        self.sender.remote_ack(ack_seq_no, self.now.get()).unwrap();
+    }
+
+    pub fn dup_ack(&self, count: usize) {
+        for _ in 0..count {
+            let ack_seq_no = self.sender.base_seq_no.get();
+            self.sender.remote_ack(ack_seq_no, self.now.get()).unwrap();
+        }
+    }
+
+    // Runs the algorithm through a retransmission timeout and retransmits the first unacknowledged segment, as the
+    // retransmitter would.
+    pub fn time_out(&self) {
+        self.sender.congestion_ctrl.on_rto(&self.sender);
+        self.sender.rto.borrow_mut().record_failure();
+        self.retransmit_first(false);
+    }
+
+    // If the algorithm asked for a fast retransmit, does it the way the retransmitter would. Returns whether it
+    // did.
+    pub fn fast_retransmit(&self) -> bool {
+        if !self.sender.congestion_ctrl.get_retransmit_now_flag() {
+            return false;
+        }
+        self.sender.congestion_ctrl.on_fast_retransmit(&self.sender);
+        self.retransmit_first(true);
+        true
+    }
+
+    fn retransmit_first(&self, fast_retransmit: bool) {
+        let len = match self.sender.unacked_queue.borrow_mut().front_mut() {
+            Some(segment) => {
+                segment.initial_tx.take();
+                segment.bytes.len()
+            },
+            None => return,
+        };
+        self.sender.record_retransmit(self.sender.base_seq_no.get(), len, fast_retransmit);
+    }
+}
@@ -0,0 +1,552 @@
+use super::cc_harness::{
+    CcHarness,
+    MSS,
+};
+use crate::{
+    fail::Fail,
+    protocols::tcp::{
+        congestion_ctrl::{
+            self as cc,
+            CongestionControl,
+            CwndChange,
+            CwndChangeReason,
+        },
+        Options as TcpOptions,
+    },
+};
+use futures::task::noop_waker_ref;
+use must_let::must_let;
+use std::{
+    future::Future,
+    num::Wrapping,
+    task::{
+        Context,
+        Poll,
+    },
+    time::Duration,
+};
+
+#[test]
+fn test_cubic_partial_ack_doesnt_underflow_cwnd() {
+    let mss = MSS as u32;
+    let harness = CcHarness::new(cc::Cubic::new, None);
+    let cc = &harness.sender.congestion_ctrl;
+    harness.send(10);
+
+    // Three duplicate ACKs put us into fast recovery with `recover` set to the highest sent sequence number.
+    harness.dup_ack(3);
+    assert_eq!(cc.get_duplicate_ack_count(), 3);
+    assert!(cc.get_retransmit_now_flag());
+    assert!(harness.cwnd() < 9 * mss);
+
+    // A partial ACK covering much more data than the reduced cwnd must not wrap it around.
+    harness.stretch_ack(9);
+    assert!(harness.cwnd() >= mss);
+    assert!(harness.cwnd() <= 10 * mss);
+}
+
+// `recover` has to be compared in sequence space: here it lies past the wraparound, so a partial ACK from before it
+// is numerically larger.
+#[test]
+fn test_cubic_partial_ack_across_wraparound() {
+    let mss = MSS as u32;
+    let harness = CcHarness::starting_at(Wrapping(0) - Wrapping(5 * mss), cc::Cubic::new, None);
+    let cc = &harness.sender.congestion_ctrl;
+    harness.send(10);
+    harness.dup_ack(3);
+    assert!(cc.get_fast_recovery_flag());
+
+    harness.stretch_ack(2);
+    assert!(cc.get_fast_recovery_flag());
+    harness.send(1);
+    harness.ack_all();
+    assert!(!cc.get_fast_recovery_flag());
+}
+
+#[test]
+fn test_cubic_connection_reset() {
+    let harness = CcHarness::new(cc::Cubic::new, None);
+    let cc = &harness.sender.congestion_ctrl;
+    let initial_cwnd = harness.cwnd();
+    harness.send(10);
+
+    // Enter fast recovery, which shrinks cwnd.
+    harness.dup_ack(3);
+    assert!(cc.get_fast_recovery_flag());
+
+    harness.sender.reset_congestion_ctrl();
+    assert!(!cc.get_fast_recovery_flag());
+    assert!(!cc.get_retransmit_now_flag());
+    assert_eq!(cc.get_duplicate_ack_count(), 0);
+    assert_eq!(cc.get_limited_transmit_cwnd_increase(), 0);
+    assert_eq!(harness.cwnd(), initial_cwnd);
+}
+
+// Each step into and out of fast recovery can be awaited, rather than checked for after every ACK.
+#[test]
+fn test_cubic_watch_fast_recovery() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let harness = CcHarness::new(cc::Cubic::new, None);
+    let cc = &harness.sender.congestion_ctrl;
+    harness.send(10);
+
+    let (count, count_changed) = cc.watch_duplicate_ack_count();
+    let (ssthresh, ssthresh_changed) = cc.watch_ssthresh();
+    let (in_fast_recovery, fast_recovery_changed) = cc.watch_fast_recovery_flag();
+    futures::pin_mut!(count_changed, ssthresh_changed, fast_recovery_changed);
+    assert_eq!((count, ssthresh, in_fast_recovery), (0, u32::MAX, false));
+    must_let!(let Poll::Pending = Future::poll(count_changed.as_mut(), &mut ctx));
+    must_let!(let Poll::Pending = Future::poll(ssthresh_changed.as_mut(), &mut ctx));
+    must_let!(let Poll::Pending = Future::poll(fast_recovery_changed.as_mut(), &mut ctx));
+
+    // The first duplicate ACK only bumps the count.
+    harness.dup_ack(1);
+    must_let!(let Poll::Ready(()) = Future::poll(count_changed.as_mut(), &mut ctx));
+    must_let!(let Poll::Pending = Future::poll(ssthresh_changed.as_mut(), &mut ctx));
+    must_let!(let Poll::Pending = Future::poll(fast_recovery_changed.as_mut(), &mut ctx));
+
+    // The third starts fast recovery with a lower ssthresh.
+    harness.dup_ack(2);
+    must_let!(let Poll::Ready(()) = Future::poll(ssthresh_changed.as_mut(), &mut ctx));
+    must_let!(let Poll::Ready(()) = Future::poll(fast_recovery_changed.as_mut(), &mut ctx));
+    assert_eq!(cc.get_duplicate_ack_count(), 3);
+    assert!(cc.get_ssthresh() < u32::MAX);
+    assert!(cc.get_fast_recovery_flag());
+
+    // An ACK past everything outstanding when it started ends it.
+    let (_, fast_recovery_changed) = cc.watch_fast_recovery_flag();
+    futures::pin_mut!(fast_recovery_changed);
+    must_let!(let Poll::Pending = Future::poll(fast_recovery_changed.as_mut(), &mut ctx));
+    harness.send(1);
+    harness.ack_all();
+    must_let!(let Poll::Ready(()) = Future::poll(fast_recovery_changed.as_mut(), &mut ctx));
+    assert!(!cc.get_fast_recovery_flag());
+    assert_eq!(cc.get_duplicate_ack_count(), 0);
+}
+
+// BBR's duplicate ACK count can be awaited the same way, though it only ever triggers the retransmission.
+#[test]
+fn test_bbr_watch_duplicate_ack_count() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let harness = CcHarness::new(cc::Bbr::new, None);
+    let cc = &harness.sender.congestion_ctrl;
+    harness.send(10);
+
+    let (count, count_changed) = cc.watch_duplicate_ack_count();
+    futures::pin_mut!(count_changed);
+    assert_eq!(count, 0);
+    must_let!(let Poll::Pending = Future::poll(count_changed.as_mut(), &mut ctx));
+
+    harness.dup_ack(1);
+    must_let!(let Poll::Ready(()) = Future::poll(count_changed.as_mut(), &mut ctx));
+    assert_eq!(cc.get_duplicate_ack_count(), 1);
+    assert!(!cc.get_retransmit_now_flag());
+
+    let (_, count_changed) = cc.watch_duplicate_ack_count();
+    futures::pin_mut!(count_changed);
+    must_let!(let Poll::Pending = Future::poll(count_changed.as_mut(), &mut ctx));
+    harness.dup_ack(2);
+    must_let!(let Poll::Ready(()) = Future::poll(count_changed.as_mut(), &mut ctx));
+    assert_eq!(cc.get_duplicate_ack_count(), 3);
+    assert!(cc.get_retransmit_now_flag());
+
+    // New data being acknowledged starts the count over.
+    let (_, count_changed) = cc.watch_duplicate_ack_count();
+    futures::pin_mut!(count_changed);
+    must_let!(let Poll::Pending = Future::poll(count_changed.as_mut(), &mut ctx));
+    harness.ack_all();
+    must_let!(let Poll::Ready(()) = Future::poll(count_changed.as_mut(), &mut ctx));
+    assert_eq!(cc.get_duplicate_ack_count(), 0);
+}
+
+#[test]
+fn test_congestion_ctrl_by_name() {
+    let options = TcpOptions::default().congestion_ctrl_by_name("none").unwrap();
+    let harness = CcHarness::new(options.congestion_ctrl_type, None);
+    assert_eq!(harness.cwnd(), u32::MAX);
+
+    let options = options.congestion_ctrl_by_name("cubic").unwrap();
+    let harness = CcHarness::new(options.congestion_ctrl_type, None);
+    assert!(harness.cwnd() < u32::MAX);
+
+    let options = options.congestion_ctrl_by_name("dctcp").unwrap();
+    let harness = CcHarness::new(options.congestion_ctrl_type, None);
+    assert!(harness.cwnd() < u32::MAX);
+
+    let options = options.congestion_ctrl_by_name("bbr").unwrap();
+    let harness = CcHarness::new(options.congestion_ctrl_type, None);
+    assert!(harness.cwnd() < u32::MAX);
+
+    must_let!(let Err(Fail::Unsupported { .. }) = TcpOptions::default().congestion_ctrl_by_name("reno"));
+}
+
+#[test]
+fn test_cwnd_observer() {
+    let mss = MSS as u32;
+    let harness = CcHarness::new(cc::Cubic::new, None);
+    let initial_cwnd = harness.cwnd();
+    harness.send(10);
+
+    // Slow start grows cwnd by one MSS per ACK.
+    harness.ack(1);
+    assert_eq!(
+        harness.last_change(),
+        Some(CwndChange {
+            cwnd: initial_cwnd + mss,
+            ssthresh: u32::MAX,
+            reason: CwndChangeReason::Ack,
+        })
+    );
+
+    // Entering fast recovery reports the reduced cwnd along with the new ssthresh.
+    harness.dup_ack(3);
+    let change = harness.last_change().unwrap();
+    assert_eq!(change.reason, CwndChangeReason::FastRecovery);
+    assert_eq!(change.cwnd, harness.cwnd());
+    assert!(change.ssthresh < u32::MAX);
+
+    harness.sender.reset_congestion_ctrl();
+    assert_eq!(harness.last_change().unwrap().reason, CwndChangeReason::Reset);
+    assert_eq!(harness.changes().len(), 3);
+}
+
+#[test]
+fn test_cubic_cwnd_limited_by_receive_window() {
+    let mss = MSS as u32;
+    let window_size = 5 * mss;
+    let harness = CcHarness::new(cc::Cubic::new, None);
+    harness.sender.window_size.set(window_size);
+    assert!(harness.cwnd() < window_size);
+    // The application always has more queued than the window lets out, so it's never what holds cwnd back.
+    harness.queue(mss);
+
+    // A long window-limited stretch of slow start stops growing cwnd once it reaches the receive window.
+    let ack_one_segment = || {
+        harness.send(1);
+        harness.ack(1);
+    };
+    for _ in 0..20 {
+        ack_one_segment();
+    }
+    assert_eq!(harness.cwnd(), window_size);
+
+    // Once the window opens up, cwnd picks up growing from there rather than having run ahead of it.
+    harness.sender.window_size.set(0xffff);
+    ack_one_segment();
+    assert_eq!(harness.cwnd(), window_size + mss);
+
+    // A window smaller than cwnd caps further growth but doesn't shrink cwnd.
+    harness.sender.window_size.set(2 * mss);
+    ack_one_segment();
+    assert_eq!(harness.cwnd(), window_size + mss);
+}
+
+// Only a connection that's using cwnd gets to grow it.
+#[test]
+fn test_cubic_app_limited_cwnd_doesnt_grow() {
+    let mss = MSS as u32;
+    let harness = CcHarness::new(cc::Cubic::new, None);
+    let mut options = cc::Options::default();
+    options.insert_bool("cwnd_validation".to_owned(), false);
+    let unvalidated = CcHarness::new(cc::Cubic::new, Some(options));
+    let initial_cwnd = harness.cwnd();
+
+    // A single segment in flight with nothing queued behind it uses well under half of the initial window.
+    for harness in &[&harness, &unvalidated] {
+        harness.send(1);
+        harness.ack(1);
+    }
+    assert_eq!(harness.cwnd(), initial_cwnd);
+    assert_eq!(unvalidated.cwnd(), initial_cwnd + mss);
+}
+
+// Slow starts until cwnd covers `num_segments` full segments and sends that many, times out, then has the remote
+// acknowledge the first segment. Returns cwnd from before the timeout.
+fn time_out_then_ack_first_segment(harness: &CcHarness, num_segments: usize) -> u32 {
+    let mss = MSS as u32;
+    // Keep some data queued so cwnd is what limits us, and so grows.
+    harness.queue(mss);
+    while harness.cwnd() < num_segments as u32 * mss {
+        harness.send(1);
+        harness.ack(1);
+    }
+    let cwnd = harness.cwnd();
+    harness.send(num_segments);
+    harness.time_out();
+    assert_eq!(harness.cwnd(), mss);
+
+    // There's more data queued up behind what was in flight.
+    harness.queue(4 * mss);
+    harness.ack(1);
+
+    // F-RTO opens cwnd just enough to let two new segments out past what's still in flight.
+    assert_eq!(harness.cwnd(), (num_segments as u32 + 1) * mss);
+    harness.send(2);
+    cwnd
+}
+
+#[test]
+fn test_cubic_frto_spurious_timeout() {
+    let mss = MSS as u32;
+    let harness = CcHarness::new(cc::Cubic::new, None);
+
+    // An RTT spike delays the ACKs past the RTO. When they turn up, the next one also acknowledges data sent before
+    // the timeout, so cwnd and ssthresh go back to where they were.
+    let cwnd = time_out_then_ack_first_segment(&harness, 8);
+    harness.ack(1);
+    let restored_cwnd = harness.cwnd();
+    assert!(restored_cwnd >= cwnd);
+
+    // Back in slow start, since ssthresh was restored too.
+    harness.ack(1);
+    assert_eq!(harness.cwnd(), restored_cwnd + mss);
+}
+
+#[test]
+fn test_cubic_frto_real_timeout() {
+    let mss = MSS as u32;
+    let harness = CcHarness::new(cc::Cubic::new, None);
+
+    // The new segments only draw a duplicate ACK, so the rest really was lost and cwnd stays collapsed.
+    time_out_then_ack_first_segment(&harness, 8);
+    harness.dup_ack(1);
+    assert_eq!(harness.cwnd(), 3 * mss);
+
+    // Without F-RTO, cwnd just slow starts from one segment.
+    let mut options = cc::Options::default();
+    options.insert_bool("frto".to_owned(), false);
+    let harness = CcHarness::new(cc::Cubic::new, Some(options));
+    harness.send(8);
+    harness.time_out();
+    harness.queue(4 * mss);
+    harness.ack(1);
+    assert_eq!(harness.cwnd(), 2 * mss);
+}
+
+// The same as `test_cubic_frto_spurious_timeout`, but the timeout fires just before the sequence numbers wrap, so the
+// first ACK after it is numerically larger than anything we'd sent.
+#[test]
+fn test_cubic_frto_across_wraparound() {
+    let mss = MSS as u32;
+    let harness = CcHarness::starting_at(Wrapping(0) - Wrapping(7 * mss), cc::Cubic::new, None);
+
+    let cwnd = time_out_then_ack_first_segment(&harness, 8);
+    harness.ack(1);
+    assert!(harness.cwnd() >= cwnd);
+}
+
+#[test]
+fn test_cubic_dsack_undoes_fast_recovery() {
+    let mss = MSS as u32;
+    let base_seq_no = Wrapping(0);
+    let harness = CcHarness::new(cc::Cubic::new, None);
+    let sender = &harness.sender;
+    let cwnd = harness.cwnd();
+    harness.send(10);
+
+    // Reordering draws three duplicate ACKs, so we reduce cwnd and retransmit the first segment.
+    harness.dup_ack(3);
+    assert!(harness.cwnd() < cwnd);
+    assert!(harness.fast_retransmit());
+
+    // A D-SACK for data we never retransmitted doesn't tell us anything.
+    sender.receive_dsack(base_seq_no + Wrapping(mss), base_seq_no + Wrapping(2 * mss));
+    assert!(sender.congestion_ctrl.get_fast_recovery_flag());
+    assert!(harness.cwnd() < cwnd);
+
+    // The original turns up after all, and the remote reports the retransmission as a duplicate.
+    sender.receive_dsack(base_seq_no, base_seq_no + Wrapping(mss));
+    assert!(!sender.congestion_ctrl.get_fast_recovery_flag());
+    assert_eq!(harness.cwnd(), cwnd);
+
+    // Each retransmission can only be reported once.
+    assert!(sender.retransmitted_ranges.borrow().is_empty());
+}
+
+#[test]
+fn test_cubic_dsack_needs_every_retransmit() {
+    let mss = MSS as u32;
+    let base_seq_no = Wrapping(0);
+    let harness = CcHarness::new(cc::Cubic::new, None);
+    let sender = &harness.sender;
+    let cwnd = harness.cwnd();
+    harness.send(10);
+    harness.dup_ack(3);
+    assert!(harness.fast_retransmit());
+
+    // A partial ACK retransmits the next hole too, which was really lost.
+    harness.ack(1);
+    assert!(harness.fast_retransmit());
+
+    // Only one of the two retransmissions was spurious, so the reduction stands.
+    sender.receive_dsack(base_seq_no, base_seq_no + Wrapping(mss));
+    assert!(sender.congestion_ctrl.get_fast_recovery_flag());
+    assert!(harness.cwnd() < cwnd);
+}
+
+// Sends ten segments and delivers the first behind the next three, which draws a spurious fast retransmit. The
+// remote then acknowledges those four.
+fn reorder_first_segment(harness: &CcHarness) {
+    let mss = MSS as u32;
+    let base_seq_no = harness.sender.base_seq_no.get();
+    harness.send(10);
+    harness.dup_ack(3);
+    assert!(harness.sender.congestion_ctrl.get_fast_recovery_flag());
+    assert!(harness.fast_retransmit());
+
+    harness.stretch_ack(4);
+    harness.sender.receive_dsack(base_seq_no, base_seq_no + Wrapping(mss));
+    assert!(!harness.sender.congestion_ctrl.get_fast_recovery_flag());
+}
+
+#[test]
+fn test_cubic_reordering_raises_dup_ack_threshold() {
+    let harness = CcHarness::new(cc::Cubic::new, None);
+    let cc = &harness.sender.congestion_ctrl;
+    reorder_first_segment(&harness);
+
+    // Three segments overtook the original, so three duplicate ACKs are no longer enough to call a loss...
+    harness.dup_ack(3);
+    assert!(!cc.get_fast_recovery_flag());
+
+    // ...but a fourth is.
+    harness.dup_ack(1);
+    assert!(cc.get_fast_recovery_flag());
+
+    // A reset forgets what we learned.
+    cc.on_connection_reset(&harness.sender);
+    harness.ack_all();
+    harness.send(10);
+    harness.dup_ack(3);
+    assert!(cc.get_fast_recovery_flag());
+}
+
+#[test]
+fn test_cubic_reordering_detection_disabled() {
+    let mut options = cc::Options::default();
+    options.insert_bool("reordering_detection".to_owned(), false);
+    let harness = CcHarness::new(cc::Cubic::new, Some(options));
+    reorder_first_segment(&harness);
+
+    harness.dup_ack(3);
+    assert!(harness.sender.congestion_ctrl.get_fast_recovery_flag());
+}
+
+#[test]
+fn test_harness_drives_every_algorithm() {
+    let mss = MSS as u32;
+    for &name in &["bbr", "cubic", "dctcp", "none"] {
+        let harness = CcHarness::new(cc::constructor_by_name(name).unwrap(), None);
+        harness.queue(64 * mss);
+
+        // A round of slow start, a loss repaired by fast retransmit, then a timeout.
+        harness.send(3);
+        harness.ack(3);
+        harness.send(6);
+        harness.dup_ack(3);
+        harness.fast_retransmit();
+        harness.ack_all();
+        harness.send(4);
+        harness.time_out();
+        harness.ack_all();
+        assert_eq!(harness.sender.bytes_in_flight(), 0, "{}", name);
+
+        // Whatever else they do, none of them shut the window completely.
+        assert!(harness.cwnd() >= mss, "{}", name);
+        for change in harness.changes() {
+            assert!(change.cwnd >= mss, "{}: {:?}", name, change);
+        }
+    }
+}
+
+#[test]
+fn test_cubic_harness_trajectory() {
+    let mss = MSS as u32;
+    let harness = CcHarness::new(cc::Cubic::new, None);
+    harness.queue(64 * mss);
+
+    // Slow start: each ACK during the first round grows cwnd by a segment.
+    assert_eq!(harness.fill_cwnd(), 3);
+    harness.ack(3);
+    let cwnds: Vec<u32> = harness.changes().iter().map(|change| change.cwnd).collect();
+    assert_eq!(cwnds, vec![4 * mss, 5 * mss, 6 * mss]);
+    assert!(harness.changes().iter().all(|change| change.reason == CwndChangeReason::Ack));
+
+    // The third duplicate ACK takes cwnd and ssthresh down to 0.7 (the default beta) of what it was.
+    assert_eq!(harness.fill_cwnd(), 6);
+    harness.dup_ack(2);
+    assert!(!harness.fast_retransmit());
+    harness.dup_ack(1);
+    let change = harness.last_change().unwrap();
+    assert_eq!(change.reason, CwndChangeReason::FastRecovery);
+    assert_eq!(change.cwnd, ((6 * mss) as f32 * 0.7) as u32);
+    assert_eq!(change.ssthresh, change.cwnd);
+    assert!(harness.fast_retransmit());
+
+    // A timeout collapses cwnd to a single segment.
+    harness.time_out();
+    let change = harness.last_change().unwrap();
+    assert_eq!(change.reason, CwndChangeReason::Rto);
+    assert_eq!(change.cwnd, mss);
+}
+
+#[test]
+fn test_cubic_beta_option() {
+    let mss = MSS as u32;
+    let mut options = cc::Options::default();
+    options.insert_float("beta".to_string(), 0.5);
+    let harness = CcHarness::new(cc::Cubic::new, Some(options));
+    harness.queue(64 * mss);
+    harness.fill_cwnd();
+    harness.ack(3);
+
+    // Same as above, except the reduction on the third duplicate ACK is down to half.
+    assert_eq!(harness.fill_cwnd(), 6);
+    harness.dup_ack(3);
+    let change = harness.last_change().unwrap();
+    assert_eq!(change.reason, CwndChangeReason::FastRecovery);
+    assert_eq!(change.cwnd, 3 * mss);
+    assert_eq!(change.ssthresh, 3 * mss);
+}
+
+#[test]
+fn test_harness_clock_drives_rtt() {
+    let harness = CcHarness::new(cc::Cubic::new, None);
+    harness.send(1);
+    harness.advance_clock(Duration::from_millis(10));
+    harness.ack(1);
+    assert!(harness.sender.current_rto() < Duration::from_millis(100));
+}
+
+#[test]
+fn test_cwnd_floor() {
+    let mss = MSS as u32;
+    let mut cubic_options = cc::Options::default();
+    cubic_options.insert_float("beta".to_string(), 0.1);
+    let algorithms: Vec<(&str, cc::CongestionControlConstructor, Option<cc::Options>)> = vec![
+        ("bbr", cc::Bbr::new, None),
+        ("cubic", cc::Cubic::new, None),
+        ("cubic with a small beta", cc::Cubic::new, Some(cubic_options)),
+        ("dctcp", cc::Dctcp::new, None),
+    ];
+    for (name, constructor, options) in algorithms {
+        let harness = CcHarness::new(constructor, options);
+        harness.queue(1024 * mss);
+        harness.fill_cwnd();
+
+        // Time out over and over, with losses repaired by fast retransmit in between, so every reduction lands on
+        // a window that's already been cut right down.
+        for _ in 0..20 {
+            harness.time_out();
+            assert!(harness.cwnd() >= mss, "{}", name);
+            harness.send(4);
+            harness.dup_ack(3);
+            harness.fast_retransmit();
+            assert!(harness.cwnd() >= mss, "{}", name);
+            harness.ack(1);
+        }
+        for change in harness.changes() {
+            assert!(change.cwnd >= mss, "{}: {:?}", name, change);
+        }
+    }
+}
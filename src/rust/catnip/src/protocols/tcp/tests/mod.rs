@@ -1,22 +1,69 @@
+pub mod cc_harness;
+mod congestion_ctrl;
+mod receiver;
+mod segment;
+mod sender;
+
 use crate::{
+    fail::Fail,
     protocols::{
+        ethernet2::frame::{
+            EtherType2,
+            Ethernet2Header,
+        },
+        icmpv4::datagram::{
+            Icmpv4Header,
+            Icmpv4Message,
+            Icmpv4Type2,
+        },
         ip,
         ipv4,
+        ipv4::datagram::{
+            Ipv4Header,
+            Ipv4Protocol2,
+        },
+        tcp,
+        tcp::congestion_ctrl::{
+            self as cc,
+            CongestionControl,
+        },
+        tcp::isn_generator::IsnGenerator,
+        tcp::segment::{
+            TcpHeader,
+            TcpOptions2,
+        },
+    },
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+    file_table::FileDescriptor,
+    runtime::Runtime,
+    test_helpers::{
+        self,
+        FaultInjection,
+        TestEngine,
     },
-    sync::BytesMut,
-    test_helpers,
 };
 use futures::task::noop_waker_ref;
+use hashbrown::HashMap;
 use must_let::must_let;
 use std::{
+    cell::RefCell,
+    cmp,
     convert::TryFrom,
     future::Future,
+    num::Wrapping,
     pin::Pin,
+    rc::Rc,
     task::{
         Context,
         Poll,
     },
-    time::Instant,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 #[test]
@@ -51,8 +98,9 @@ fn test_connect() {
     alice.rt().poll_scheduler();
     bob.receive(alice.rt().pop_frame()).unwrap();
 
-    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok((bob_fd, alice_endpoint))) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
     must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    assert_eq!(alice_endpoint.address(), test_helpers::ALICE_IPV4);
 
     // Send data from Alice to Bob
     let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
@@ -66,3 +114,2186 @@ fn test_connect() {
     must_let!(let Poll::Ready(Ok(received_buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
     assert_eq!(received_buf, buf);
 }
+
+#[test]
+fn test_connect_timeout() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let options = alice
+        .rt()
+        .tcp_options()
+        .handshake_retries(3)
+        .handshake_timeout(Duration::from_secs(1));
+    alice.rt().set_tcp_options(options);
+
+    // Carrie's in Alice's ARP cache but never answers.
+    let remote = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, ip::Port::try_from(80).unwrap());
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, remote);
+
+    // The SYN is retransmitted with the timeout doubling each time: at 0s, 1s and 3s.
+    let mut syn_times = vec![];
+    let start = now;
+    while now < start + Duration::from_secs(7) {
+        alice.rt().poll_scheduler();
+        while let Some(frame) = alice.rt().try_pop_frame() {
+            assert!(parse_tcp_header(frame).syn);
+            syn_times.push(now - start);
+        }
+        assert!(Future::poll(Pin::new(&mut connect_future), &mut ctx).is_pending());
+        now += Duration::from_millis(100);
+        alice.rt().advance_clock(now);
+    }
+    assert_eq!(syn_times, vec![Duration::from_secs(0), Duration::from_secs(1), Duration::from_secs(3)]);
+
+    // After the last retry's timeout runs out, the connect fails.
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), None);
+    alice.rt().poll_scheduler();
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::HandshakeTimeout));
+}
+
+#[test]
+fn test_connect_unreachable() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    for &(code, host_unreachable) in &[(3, false), (1, true)] {
+        let mut alice = test_helpers::new_alice(now);
+        let bob = test_helpers::new_bob(now);
+        let remote = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, ip::Port::try_from(80).unwrap());
+        let alice_fd = alice.tcp_socket();
+        let mut connect_future = alice.tcp_connect(alice_fd, remote);
+        alice.rt().poll_scheduler();
+        let syn = alice.rt().pop_frame();
+
+        // Bob's the router on the way to Carrie, and sends back the SYN's IPv4 header and the start of its TCP header.
+        let (_, ipv4_datagram) = syn.split(14);
+        let (quoted, _) = ipv4_datagram.split(28);
+        let message = |data| Icmpv4Message {
+            ethernet2_hdr: Ethernet2Header {
+                dst_addr: test_helpers::ALICE_MAC,
+                src_addr: test_helpers::BOB_MAC,
+                ether_type: EtherType2::Ipv4,
+            },
+            ipv4_hdr: Ipv4Header::new(test_helpers::BOB_IPV4, test_helpers::ALICE_IPV4, Ipv4Protocol2::Icmpv4),
+            icmpv4_hdr: Icmpv4Header {
+                icmpv4_type: Icmpv4Type2::DestinationUnreachable,
+                code,
+            },
+            data,
+        };
+
+        // An error quoting some other sequence number than the SYN's is ignored, and the connect carries on.
+        let mut forged = BytesMut::from(&quoted[..]);
+        forged[24] ^= 0xFF;
+        bob.rt().transmit(message(forged.freeze()));
+        must_let!(let Err(Fail::Ignored { .. }) = alice.receive(bob.rt().pop_frame()));
+        assert!(Future::poll(Pin::new(&mut connect_future), &mut ctx).is_pending());
+        assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), None);
+
+        bob.rt().transmit(message(quoted));
+        alice.receive(bob.rt().pop_frame()).unwrap();
+
+        // The connect fails right away, without waiting out the handshake timeout.
+        if host_unreachable {
+            must_let!(let Poll::Ready(Err(Fail::HostUnreachable {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+        } else {
+            must_let!(let Poll::Ready(Err(Fail::ConnectionRefused {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+        }
+        assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::Unreachable));
+    }
+}
+
+fn parse_tcp_header(frame: Bytes) -> TcpHeader {
+    let (_, ipv4_payload) = Ethernet2Header::parse(frame).unwrap();
+    let (ipv4_hdr, tcp_payload) = Ipv4Header::parse(ipv4_payload).unwrap();
+    let (tcp_hdr, _) = TcpHeader::parse(&ipv4_hdr, tcp_payload).unwrap();
+    tcp_hdr
+}
+
+#[test]
+fn test_listen_backlog_full() {
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+
+    // The first SYN fills Bob's backlog.
+    let alice_fd1 = alice.tcp_socket();
+    let _connect_future1 = alice.tcp_connect(alice_fd1, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // So the second one is refused with a RST.
+    let alice_fd2 = alice.tcp_socket();
+    let _connect_future2 = alice.tcp_connect(alice_fd2, listen_addr);
+    alice.rt().poll_scheduler();
+    let syn = alice.rt().pop_frame();
+    let syn_hdr = parse_tcp_header(syn.clone());
+    must_let!(let Err(Fail::ConnectionRefused {}) = bob.receive(syn));
+
+    let rst_hdr = parse_tcp_header(bob.rt().pop_frame());
+    assert!(rst_hdr.rst);
+    assert_eq!(rst_hdr.dst_port, syn_hdr.src_port);
+    assert_eq!(rst_hdr.ack_num, syn_hdr.seq_num + Wrapping(1));
+}
+
+// Establish a connection from Alice to Bob, returning their respective file descriptors.
+fn establish_connection(alice: &mut TestEngine, bob: &mut TestEngine) -> (FileDescriptor, FileDescriptor) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    must_let!(let Poll::Ready(Ok((bob_fd, _))) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    (alice_fd, bob_fd)
+}
+
+// Run both peers' background work and deliver every frame they emit to the other side.
+fn exchange_frames(alice: &mut TestEngine, bob: &mut TestEngine) {
+    alice.rt().poll_scheduler();
+    while let Some(frame) = alice.rt().try_pop_frame() {
+        let _ = bob.receive(frame);
+    }
+    bob.rt().poll_scheduler();
+    while let Some(frame) = bob.rt().try_pop_frame() {
+        let _ = alice.receive(frame);
+    }
+}
+
+// Read everything currently available on `fd` into `received`.
+fn pop_all(engine: &mut TestEngine, fd: FileDescriptor, received: &mut Vec<u8>) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    loop {
+        let mut pop_future = engine.tcp_pop(fd);
+        match Future::poll(Pin::new(&mut pop_future), &mut ctx) {
+            Poll::Ready(Ok(segment)) => received.extend_from_slice(&segment[..]),
+            _ => break,
+        }
+    }
+}
+
+#[test]
+fn test_push_larger_than_send_buffer() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let send_buffer_size = 1000;
+    let options = alice.rt().tcp_options().send_buffer_size(send_buffer_size);
+    alice.rt().set_tcp_options(options);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    let buf: Vec<u8> = (0..(4 * send_buffer_size + 100)).map(|i| i as u8).collect();
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&buf[..]).freeze());
+    let mut pushed = false;
+    let mut received = vec![];
+
+    for _ in 0..100 {
+        if !pushed {
+            match Future::poll(Pin::new(&mut push_future), &mut ctx) {
+                Poll::Ready(r) => {
+                    r.unwrap();
+                    pushed = true;
+                },
+                Poll::Pending => (),
+            }
+        }
+        exchange_frames(&mut alice, &mut bob);
+        loop {
+            let mut pop_future = bob.tcp_pop(bob_fd);
+            match Future::poll(Pin::new(&mut pop_future), &mut ctx) {
+                Poll::Ready(Ok(segment)) => received.extend_from_slice(&segment[..]),
+                _ => break,
+            }
+        }
+        if pushed && received.len() == buf.len() {
+            break;
+        }
+        // Let Bob's delayed ACKs fire.
+        now += Duration::from_millis(500);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    assert_eq!(received, buf);
+}
+
+#[test]
+fn test_payload_checksums() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    for engine in &[&alice, &bob] {
+        let options = engine.rt().tcp_options().payload_checksums(true);
+        engine.rt().set_tcp_options(options);
+    }
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    let buf = BytesMut::from(&b"length preserving corruption"[..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    exchange_frames(&mut alice, &mut bob);
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+
+    let (alice_sent, _) = alice.tcp_payload_checksums(alice_fd).unwrap();
+    let (_, bob_received) = bob.tcp_payload_checksums(bob_fd).unwrap();
+    assert_eq!(alice_sent, crc::crc32::checksum_ieee(&buf[..]));
+    assert_eq!(alice_sent, bob_received);
+}
+
+#[test]
+fn test_dropped_frames() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice.rt().set_fault_injection(FaultInjection {
+        drop_probability: 1.0,
+        ..FaultInjection::default()
+    });
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let alice_fd = alice.tcp_socket();
+    let _connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+    let metrics = alice.rt().metrics();
+    assert_eq!(metrics.tx_packets, 1);
+    assert_eq!(metrics.tx_dropped, 1);
+}
+
+#[test]
+fn test_delayed_frames_released_by_clock() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice.rt().set_fault_injection(FaultInjection {
+        delay: Some(Duration::from_millis(10)),
+        ..FaultInjection::default()
+    });
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let alice_fd = alice.tcp_socket();
+    let _connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    // The SYN is held back until the delay has elapsed.
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+    alice.rt().advance_clock(now + Duration::from_millis(10));
+    let header = parse_tcp_header(alice.rt().pop_frame());
+    assert!(header.syn);
+}
+
+// Pushes 8000 bytes from Alice to Bob with `faults` applied to both sides' frames, and checks they all arrive intact.
+// Each side's faults are drawn from its own seed.
+fn push_over_faulty_link(faults: FaultInjection) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+    for (i, engine) in [&alice, &bob].iter().enumerate() {
+        engine.rt().set_fault_injection(FaultInjection {
+            seed: [i as u8 + 1; 16],
+            ..faults.clone()
+        });
+    }
+
+    let buf: Vec<u8> = (0..8000).map(|i| i as u8).collect();
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&buf[..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    let mut received = vec![];
+
+    for _ in 0..1000 {
+        exchange_frames(&mut alice, &mut bob);
+        pop_all(&mut bob, bob_fd, &mut received);
+        if received.len() == buf.len() {
+            break;
+        }
+        // Let delayed ACKs and retransmission timeouts fire.
+        now += Duration::from_millis(500);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    assert_eq!(received, buf);
+}
+
+#[test]
+fn test_push_over_reordering_link() {
+    push_over_faulty_link(FaultInjection {
+        reorder_probability: 0.3,
+        ..FaultInjection::default()
+    });
+}
+
+#[test]
+fn test_push_over_lossy_link() {
+    push_over_faulty_link(FaultInjection {
+        drop_probability: 0.2,
+        reorder_probability: 0.3,
+        ..FaultInjection::default()
+    });
+}
+
+#[test]
+fn test_connect_from_bound_port() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+
+    let local_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(12345).unwrap());
+    let alice_fd = alice.tcp_socket();
+    alice.tcp_bind(alice_fd, local_addr).unwrap();
+    let _connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    // A second socket can't bind to the port we're connecting from.
+    let other_fd = alice.tcp_socket();
+    must_let!(let Err(Fail::ResourceBusy { .. }) = alice.tcp_bind(other_fd, local_addr));
+
+    alice.rt().poll_scheduler();
+    let header = parse_tcp_header(alice.rt().pop_frame());
+    assert!(header.syn);
+    assert_eq!(header.src_port, local_addr.port());
+}
+
+#[test]
+fn test_ephemeral_port_range() {
+    let now = Instant::now();
+    let rt = test_helpers::TestRuntime::new("alice", now, test_helpers::ALICE_MAC, test_helpers::ALICE_IPV4);
+    rt.set_tcp_options(rt.tcp_options().ephemeral_port_range(5000, 5001));
+    let mut alice = TestEngine::new(rt).unwrap();
+
+    let remote = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut ports = (0..2)
+        .map(|_| {
+            let fd = alice.tcp_socket();
+            let _connect_future = alice.tcp_connect(fd, remote);
+            alice.rt().poll_scheduler();
+            let port: u16 = parse_tcp_header(alice.rt().pop_frame()).src_port.into();
+            port
+        })
+        .collect::<Vec<_>>();
+    ports.sort_unstable();
+    assert_eq!(ports, vec![5000, 5001]);
+
+    // The range is exhausted, so the next connect fails.
+    let fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(fd, remote);
+    must_let!(let Poll::Ready(Err(Fail::ResourceExhausted { .. })) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+}
+
+#[test]
+fn test_release_closed_ports() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+
+    for &release in &[false, true] {
+        // With a single ephemeral port, a second connection only works if closing the first gave the port back.
+        let rt = test_helpers::TestRuntime::new("alice", now, test_helpers::ALICE_MAC, test_helpers::ALICE_IPV4);
+        rt.set_tcp_options(rt.tcp_options().ephemeral_port_range(5000, 5000).release_closed_ports(release));
+        let mut alice = TestEngine::new(rt).unwrap();
+        let mut bob = test_helpers::new_bob(now);
+        let options = bob.rt().tcp_options().release_closed_ports(release);
+        bob.rt().set_tcp_options(options);
+
+        let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+        alice.tcp_close(alice_fd).unwrap();
+        bob.tcp_close(bob_fd).unwrap();
+        for _ in 0..3 {
+            exchange_frames(&mut alice, &mut bob);
+            now += Duration::from_millis(1);
+            alice.rt().advance_clock(now);
+            bob.rt().advance_clock(now);
+        }
+
+        let fd = alice.tcp_socket();
+        let mut connect_future = alice.tcp_connect(fd, listen_addr);
+        if !release {
+            must_let!(let Poll::Ready(Err(Fail::ResourceExhausted { .. })) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+            continue;
+        }
+        // Bob still has the old connection to this port, and has to let the new SYN through to his listener.
+        exchange_frames(&mut alice, &mut bob);
+        exchange_frames(&mut alice, &mut bob);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+        // The old socket still reports how it closed.
+        assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::Graceful));
+        must_let!(let Err(Fail::Ignored { .. }) = alice.tcp_close(alice_fd));
+    }
+}
+
+#[test]
+fn test_options_validation() {
+    let options = tcp::Options::default();
+    options.validate().unwrap();
+    must_let!(let Err(Fail::OutOfRange { .. }) = options.clone().try_advertised_mss(1));
+    must_let!(let Err(Fail::OutOfRange { .. }) = options.clone().try_retries(0));
+    must_let!(let Err(Fail::OutOfRange { .. }) = options.clone().try_ephemeral_port_range(6000, 5000));
+    must_let!(let Err(Fail::OutOfRange { .. }) = options.clone().try_ack_ratio(0));
+    must_let!(let Err(Fail::OutOfRange { .. }) = options.clone().try_fin_wait_timeout(Duration::new(0, 0)));
+    must_let!(let Err(Fail::OutOfRange { .. }) = options.clone().try_msl(Duration::new(0, 0)));
+
+    // So are congestion control options, whichever algorithm reads them.
+    for &(key, value) in &[("c", 0.), ("beta", 1.), ("alpha_gain", 1.5)] {
+        let mut cc_options = cc::Options::default();
+        cc_options.insert_float(key.to_owned(), value);
+        must_let!(let Err(Fail::OutOfRange { .. }) = options.clone().try_congestion_control_options(cc_options));
+    }
+    let mut cc_options = cc::Options::default();
+    cc_options.insert_int("min_rtt_window_ms".to_owned(), 0);
+    must_let!(let Err(Fail::OutOfRange { .. }) = options.clone().try_congestion_control_options(cc_options.clone()));
+    let mut bad_cc_options = options.clone();
+    bad_cc_options.congestion_ctrl_options = Some(cc_options);
+    must_let!(let Err(Fail::OutOfRange { .. }) = bad_cc_options.validate());
+
+    // ACK ratios past what RFC 1122 allows only apply when asked for explicitly.
+    let sparse_acks = options.clone().ack_ratio(4);
+    assert_eq!(sparse_acks.effective_ack_ratio(), 2);
+    assert_eq!(sparse_acks.ack_ratio_unclamped(true).effective_ack_ratio(), 4);
+
+    // Windows past 64KB need window scaling, up to the largest the maximum shift allows.
+    assert_eq!(options.receive_window_scale(), 0);
+    let options = options.try_receive_window_size(1 << 20).unwrap();
+    options.validate().unwrap();
+    assert_eq!(options.receive_window_scale(), 5);
+    let options = options.try_receive_window_size(0xffff << 14).unwrap();
+    assert_eq!(options.receive_window_scale(), 14);
+    must_let!(let Err(Fail::OutOfRange { .. }) = options.clone().try_receive_window_size((0xffff << 14) + 1));
+
+    // Fields set directly skip those checks, so the engine refuses to start with them.
+    let rt = test_helpers::TestRuntime::new("alice", Instant::now(), test_helpers::ALICE_MAC, test_helpers::ALICE_IPV4);
+    let mut options = tcp::Options::default();
+    options.ephemeral_port_range = (6000, 5000);
+    rt.set_tcp_options(options);
+    must_let!(let Err(Fail::OutOfRange { .. }) = TestEngine::new(rt));
+}
+
+#[test]
+fn test_mss_negotiation() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let alice_mss = 600;
+    let options = alice.rt().tcp_options().advertised_mss(alice_mss);
+    alice.rt().set_tcp_options(options);
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // Bob advertises his own MSS rather than echoing Alice's.
+    bob.rt().poll_scheduler();
+    let syn_ack = bob.rt().pop_frame();
+    let header = parse_tcp_header(syn_ack.clone());
+    let advertised = header.iter_options().find_map(|o| match o {
+        TcpOptions2::MaximumSegmentSize(m) => Some(*m as usize),
+        _ => None,
+    });
+    assert_eq!(advertised, Some(bob.rt().tcp_options().advertised_mss));
+    alice.receive(syn_ack).unwrap();
+
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok((bob_fd, _))) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // Both sides segment at the smaller of the two.
+    assert_eq!(alice.tcp_mss(alice_fd).unwrap(), alice_mss);
+    assert_eq!(bob.tcp_mss(bob_fd).unwrap(), alice_mss);
+}
+
+#[test]
+fn test_sack_negotiation() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let sack_permitted = |frame: Bytes| {
+        parse_tcp_header(frame)
+            .iter_options()
+            .any(|option| matches!(option, TcpOptions2::SelectiveAcknowlegementPermitted))
+    };
+
+    for &(alice_sack, bob_sack) in &[(true, true), (true, false), (false, true)] {
+        let mut alice = test_helpers::new_alice(now);
+        let mut bob = test_helpers::new_bob(now);
+        let options = alice.rt().tcp_options().sack_enabled(alice_sack);
+        alice.rt().set_tcp_options(options);
+        let options = bob.rt().tcp_options().sack_enabled(bob_sack).dsack(true);
+        bob.rt().set_tcp_options(options);
+
+        let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+        let listen_fd = bob.tcp_socket();
+        bob.tcp_bind(listen_fd, listen_addr).unwrap();
+        bob.tcp_listen(listen_fd, 1).unwrap();
+        let mut accept_future = bob.tcp_accept(listen_fd);
+        let alice_fd = alice.tcp_socket();
+        let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+        // Alice offers SACK if she has it enabled, and Bob only offers it back if both of them do.
+        alice.rt().poll_scheduler();
+        let syn = alice.rt().pop_frame();
+        assert_eq!(sack_permitted(syn.clone()), alice_sack);
+        bob.receive(syn).unwrap();
+        bob.rt().poll_scheduler();
+        let syn_ack = bob.rt().pop_frame();
+        assert_eq!(sack_permitted(syn_ack.clone()), alice_sack && bob_sack);
+        alice.receive(syn_ack).unwrap();
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+        // So Bob only reports a duplicate in a D-SACK block if SACK was negotiated.
+        let buf = BytesMut::from(&[7u8; 10][..]).freeze();
+        let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        alice.tcp_emit_crafted(alice_fd, |header| header.seq_num -= Wrapping(10), buf).unwrap();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        bob.rt().poll_scheduler();
+        let ack = parse_tcp_header(bob.rt().pop_frame());
+        let reported_dsack = ack
+            .iter_options()
+            .any(|option| matches!(option, TcpOptions2::SelectiveAcknowlegement { .. }));
+        assert_eq!(reported_dsack, alice_sack && bob_sack);
+    }
+}
+
+#[test]
+fn test_slow_reader() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let receive_window_size = 3000;
+    let options = bob.rt().tcp_options().receive_window_size(receive_window_size);
+    bob.rt().set_tcp_options(options);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    let buf: Vec<u8> = (0..(8 * receive_window_size)).map(|i| i as u8).collect();
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&buf[..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+
+    // While Bob isn't reading, Alice fills his window and then stops.
+    for _ in 0..10 {
+        exchange_frames(&mut alice, &mut bob);
+        now += Duration::from_millis(500);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    let mut received = vec![];
+    pop_all(&mut bob, bob_fd, &mut received);
+    assert!(!received.is_empty());
+    assert!(received.len() <= receive_window_size);
+
+    // Once Bob reads, his window update lets the rest through.
+    for _ in 0..100 {
+        exchange_frames(&mut alice, &mut bob);
+        pop_all(&mut bob, bob_fd, &mut received);
+        if received.len() == buf.len() {
+            break;
+        }
+        now += Duration::from_millis(500);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    assert_eq!(received, buf);
+}
+
+#[test]
+fn test_large_window() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let rtt = Duration::from_millis(50);
+    let receive_window_size = 1 << 20;
+    let len = 4 * receive_window_size;
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let options = alice.rt().tcp_options().send_buffer_size(len);
+    alice.rt().set_tcp_options(options);
+    let options = bob.rt().tcp_options().receive_window_size(receive_window_size);
+    bob.rt().set_tcp_options(options);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    let buf: Vec<u8> = (0..len).map(|i| i as u8).collect();
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&buf[..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+
+    // Each round, Alice sends what she can and Bob's ACKs for it arrive a round trip later. Once her congestion
+    // window has opened up, Bob's scaled window lets her have more than 64KB in flight at once.
+    let mut received = vec![];
+    let mut max_flight = 0;
+    let mut max_window = 0;
+    for _ in 0..100 {
+        alice.rt().poll_scheduler();
+        let received_before = received.len();
+        while let Some(frame) = alice.rt().try_pop_frame() {
+            bob.receive(frame).unwrap();
+        }
+        pop_all(&mut bob, bob_fd, &mut received);
+        max_flight = cmp::max(max_flight, received.len() - received_before);
+
+        now += rtt;
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+        bob.rt().poll_scheduler();
+        while let Some(frame) = bob.rt().try_pop_frame() {
+            let header = parse_tcp_header(frame.clone());
+            max_window = cmp::max(max_window, (header.window_size as usize) << 5);
+            alice.receive(frame).unwrap();
+        }
+        if received.len() == len {
+            break;
+        }
+    }
+    assert!(received == buf);
+    assert!(max_window > 0xffff && max_window <= receive_window_size);
+    assert!(max_flight > 0xffff && max_flight <= receive_window_size, "{}", max_flight);
+}
+
+// Bob takes in a large transfer while reading only every other round, so his window, delayed ACKs and receive queue
+// stay under load for the whole of it.
+#[test]
+fn test_bulk_receive() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let receive_window_size = 16 * 1024;
+    let len = 256 * 1024;
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let options = alice.rt().tcp_options().send_buffer_size(len);
+    alice.rt().set_tcp_options(options);
+    let options = bob.rt().tcp_options().receive_window_size(receive_window_size);
+    bob.rt().set_tcp_options(options);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    let buf: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&buf[..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+
+    let mut received = vec![];
+    let mut data_segments = 0;
+    let mut acks = 0;
+    let mut last_ack = None;
+    let mut bob_seq_no = None;
+    for round in 0..1000 {
+        alice.rt().poll_scheduler();
+        while let Some(frame) = alice.rt().try_pop_frame() {
+            let (_, ipv4_payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+            let (ipv4_hdr, tcp_payload) = Ipv4Header::parse(ipv4_payload).unwrap();
+            let (header, data) = TcpHeader::parse(&ipv4_hdr, tcp_payload).unwrap();
+            if !data.is_empty() {
+                data_segments += 1;
+            }
+            bob_seq_no = Some(header.ack_num);
+            bob.receive(frame).unwrap();
+        }
+        if round % 2 == 0 {
+            pop_all(&mut bob, bob_fd, &mut received);
+        }
+
+        now += Duration::from_millis(10);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+        bob.rt().poll_scheduler();
+        while let Some(frame) = bob.rt().try_pop_frame() {
+            let header = parse_tcp_header(frame.clone());
+            // Bob sends nothing of his own, so every ACK carries the sequence number Alice expects next. He never
+            // offers more than his buffer, and never takes back an ACK.
+            assert_eq!(Some(header.seq_num), bob_seq_no);
+            assert!(header.window_size as usize <= receive_window_size);
+            if let Some(last_ack) = last_ack {
+                assert!(!tcp::seq_before(header.ack_num, last_ack));
+            }
+            last_ack = Some(header.ack_num);
+            acks += 1;
+            alice.receive(frame).unwrap();
+        }
+        if received.len() == len {
+            break;
+        }
+    }
+    assert!(received == buf);
+    // Delayed ACKs cover more than one segment each.
+    assert!(acks < data_segments, "{} ACKs for {} segments", acks, data_segments);
+}
+
+#[test]
+fn test_rate_limit() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+    let rate = 10000;
+    must_let!(let Err(Fail::OutOfRange { .. }) = alice.tcp_set_send_rate_limit(alice_fd, Some(0)));
+    alice.tcp_set_send_rate_limit(alice_fd, Some(rate)).unwrap();
+
+    let buf: Vec<u8> = (0..(5 * rate)).map(|i| i as u8).collect();
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&buf[..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+
+    // Over a second, Alice sends no more than a second's worth, plus the segment her bucket starts out with. (She
+    // sends less, since she has to wait for Bob's first ACK to open his window.)
+    let mut received = vec![];
+    let run = |alice: &mut TestEngine, bob: &mut TestEngine, now: &mut Instant, received: &mut Vec<u8>| {
+        for _ in 0..100 {
+            exchange_frames(alice, bob);
+            pop_all(bob, bob_fd, received);
+            *now += Duration::from_millis(10);
+            alice.rt().advance_clock(*now);
+            bob.rt().advance_clock(*now);
+        }
+        exchange_frames(alice, bob);
+        pop_all(bob, bob_fd, received);
+    };
+    run(&mut alice, &mut bob, &mut now, &mut received);
+    assert!(received.len() as u64 >= rate / 2, "{}", received.len());
+    assert!(received.len() as u64 <= rate + 1460, "{}", received.len());
+
+    // Lifting the limit lets the rest through, but then limiting Bob's reads slows it back down.
+    alice.tcp_set_send_rate_limit(alice_fd, None).unwrap();
+    bob.tcp_set_recv_rate_limit(bob_fd, Some(rate / 2)).unwrap();
+    let before = received.len();
+    run(&mut alice, &mut bob, &mut now, &mut received);
+    let read = (received.len() - before) as u64;
+    assert!(read >= rate / 2 * 9 / 10, "{}", read);
+    assert!(read <= rate / 2 + 1460, "{}", read);
+
+    bob.tcp_set_recv_rate_limit(bob_fd, None).unwrap();
+    run(&mut alice, &mut bob, &mut now, &mut received);
+    assert_eq!(received, buf);
+}
+
+#[test]
+fn test_rebind() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    // Alice sends something that gets lost on the way, and then moves to Carrie's addresses.
+    let buf = BytesMut::from(&b"hello"[..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    alice.rt().pop_frame();
+    alice.rt().set_local_addrs(test_helpers::CARRIE_MAC, test_helpers::CARRIE_IPV4);
+    alice.tcp_rebind(alice_fd, test_helpers::CARRIE_IPV4, test_helpers::BOB_IPV4).unwrap();
+    bob.tcp_rebind(bob_fd, test_helpers::BOB_IPV4, test_helpers::CARRIE_IPV4).unwrap();
+
+    // The retransmission comes from the new address, and the connection carries on from there.
+    now += alice.tcp_rto(alice_fd).unwrap();
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    let (ethernet2_hdr, ipv4_payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+    let (ipv4_hdr, _) = Ipv4Header::parse(ipv4_payload).unwrap();
+    assert_eq!(ethernet2_hdr.src_addr, test_helpers::CARRIE_MAC);
+    assert_eq!(ipv4_hdr.src_addr, test_helpers::CARRIE_IPV4);
+    bob.receive(frame).unwrap();
+    let mut received = vec![];
+    pop_all(&mut bob, bob_fd, &mut received);
+    assert_eq!(&received[..], &buf[..]);
+
+    let mut push_future = bob.tcp_push(bob_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    exchange_frames(&mut alice, &mut bob);
+    let mut received = vec![];
+    pop_all(&mut alice, alice_fd, &mut received);
+    assert_eq!(&received[..], &buf[..]);
+}
+
+#[test]
+fn test_stalled_reader_bounds_send_buffer() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let send_buffer_size = 10000;
+    let options = alice.rt().tcp_options().send_buffer_size(send_buffer_size);
+    alice.rt().set_tcp_options(options);
+    let receive_window_size = 3000;
+    let options = bob.rt().tcp_options().receive_window_size(receive_window_size);
+    bob.rt().set_tcp_options(options);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    // Bob never reads, so once his window closes nothing Alice pushes gets acknowledged. She keeps pushing anyway,
+    // but the pushes start waiting instead of buffering more.
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&[0u8; 1000][..]).freeze());
+    let mut pushes_completed = 0;
+    for _ in 0..50 {
+        while let Poll::Ready(r) = Future::poll(Pin::new(&mut push_future), &mut ctx) {
+            r.unwrap();
+            pushes_completed += 1;
+            push_future = alice.tcp_push(alice_fd, BytesMut::from(&[0u8; 1000][..]).freeze());
+        }
+        exchange_frames(&mut alice, &mut bob);
+        now += Duration::from_millis(500);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    assert!(pushes_completed * 1000 >= send_buffer_size);
+    let used = alice.tcp_send_buffer_used(alice_fd).unwrap();
+    assert!(used <= send_buffer_size);
+    assert!(used > send_buffer_size - 1000);
+
+    // Reading frees up Bob's window, and with it Alice's send buffer.
+    let mut received = vec![];
+    let mut pushed = false;
+    for _ in 0..100 {
+        pop_all(&mut bob, bob_fd, &mut received);
+        exchange_frames(&mut alice, &mut bob);
+        if let Poll::Ready(r) = Future::poll(Pin::new(&mut push_future), &mut ctx) {
+            r.unwrap();
+            pushed = true;
+            break;
+        }
+        now += Duration::from_millis(500);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    assert!(pushed);
+}
+
+#[cfg(feature = "async-io")]
+#[test]
+fn test_async_io_stream() {
+    use futures::io::{
+        AsyncRead,
+        AsyncWrite,
+    };
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let options = alice.rt().tcp_options().send_buffer_size(1000);
+    alice.rt().set_tcp_options(options);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+    let mut alice_stream = alice.tcp_stream(alice_fd);
+    let mut bob_stream = bob.tcp_stream(bob_fd);
+
+    // Only part of the buffer fits in the send buffer, and none of it is acknowledged yet.
+    let buf: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+    must_let!(let Poll::Ready(Ok(1000)) = Pin::new(&mut alice_stream).poll_write(&mut ctx, &buf[..]));
+    assert!(Pin::new(&mut alice_stream).poll_flush(&mut ctx).is_pending());
+    let mut written = 1000;
+
+    let mut closed = false;
+    let mut eof = false;
+    let mut received = vec![];
+    let mut read_buf = [0u8; 300];
+    for _ in 0..100 {
+        if written < buf.len() {
+            match Pin::new(&mut alice_stream).poll_write(&mut ctx, &buf[written..]) {
+                Poll::Ready(r) => written += r.unwrap(),
+                Poll::Pending => (),
+            }
+        } else if !closed {
+            match Pin::new(&mut alice_stream).poll_close(&mut ctx) {
+                Poll::Ready(r) => {
+                    r.unwrap();
+                    closed = true;
+                },
+                Poll::Pending => (),
+            }
+        }
+        exchange_frames(&mut alice, &mut bob);
+        loop {
+            match Pin::new(&mut bob_stream).poll_read(&mut ctx, &mut read_buf) {
+                Poll::Ready(Ok(0)) => {
+                    eof = true;
+                    break;
+                },
+                Poll::Ready(Ok(n)) => received.extend_from_slice(&read_buf[..n]),
+                _ => break,
+            }
+        }
+        if eof {
+            break;
+        }
+        now += Duration::from_millis(500);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    assert!(closed);
+    assert!(eof);
+    assert_eq!(received, buf);
+}
+
+#[test]
+fn test_bytes_available() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+    assert_eq!(bob.tcp_bytes_available(bob_fd).unwrap(), 0);
+    assert!(!bob.tcp_is_readable(bob_fd).unwrap());
+
+    for i in 0..2u8 {
+        let buf = BytesMut::from(&vec![i; 100][..]).freeze();
+        let mut push_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        exchange_frames(&mut alice, &mut bob);
+    }
+    assert_eq!(bob.tcp_bytes_available(bob_fd).unwrap(), 200);
+    assert!(bob.tcp_is_readable(bob_fd).unwrap());
+
+    let mut received = vec![];
+    pop_all(&mut bob, bob_fd, &mut received);
+    assert_eq!(received.len(), 200);
+    assert_eq!(bob.tcp_bytes_available(bob_fd).unwrap(), 0);
+    assert!(!bob.tcp_is_readable(bob_fd).unwrap());
+
+    // Once Alice closes, a pop returns the end of the stream right away, so the socket is readable with nothing
+    // available.
+    alice.tcp_close(alice_fd).unwrap();
+    for _ in 0..2 {
+        exchange_frames(&mut alice, &mut bob);
+        now += Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    assert_eq!(bob.tcp_bytes_available(bob_fd).unwrap(), 0);
+    assert!(bob.tcp_is_readable(bob_fd).unwrap());
+}
+
+#[test]
+fn test_flush_waits_for_send_not_ack() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, _) = establish_connection(&mut alice, &mut bob);
+
+    // More than fits in the initial congestion window, so some of it has to wait on ACKs before it goes out.
+    let buf = BytesMut::from(&vec![0x5a; 8 * alice.tcp_mss(alice_fd).unwrap()][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    let mut flush_future = alice.tcp_flush(alice_fd);
+
+    let mut flushed = false;
+    for _ in 0..20 {
+        alice.rt().poll_scheduler();
+        if let Poll::Ready(r) = Future::poll(Pin::new(&mut flush_future), &mut ctx) {
+            r.unwrap();
+            flushed = true;
+            break;
+        }
+        exchange_frames(&mut alice, &mut bob);
+        now += Duration::from_millis(100);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    assert!(flushed);
+    // The last of it is on its way, but Bob hasn't acknowledged it yet.
+    assert!(alice.tcp_send_buffer_used(alice_fd).unwrap() > 0);
+
+    // Data pushed after the flush started doesn't hold it up, but a new flush waits for it too.
+    let mut push_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut flush_future), &mut ctx));
+    let mut flush_future = alice.tcp_flush(alice_fd);
+    assert!(Future::poll(Pin::new(&mut flush_future), &mut ctx).is_pending());
+}
+
+#[test]
+fn test_pushv_coalesces_buffers() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    let header = BytesMut::from(&b"header"[..]).freeze();
+    let body = BytesMut::from(&b"and the body that follows it"[..]).freeze();
+    let mut push_future = alice.tcp_pushv(alice_fd, &[header.clone(), body.clone()]);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    exchange_frames(&mut alice, &mut bob);
+
+    // Both buffers went out in a single segment.
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(segment)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&segment[..header.len()], &header[..]);
+    assert_eq!(&segment[header.len()..], &body[..]);
+}
+
+#[test]
+fn test_pushv_larger_than_send_buffer() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let options = alice.rt().tcp_options().send_buffer_size(1000);
+    alice.rt().set_tcp_options(options);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    let buf: Vec<u8> = (0..1800).map(|i| i as u8).collect();
+    let bufs: Vec<Bytes> = buf.chunks(600).map(|c| BytesMut::from(c).freeze()).collect();
+    let mut push_future = alice.tcp_pushv(alice_fd, &bufs[..]);
+    assert!(Future::poll(Pin::new(&mut push_future), &mut ctx).is_pending());
+
+    let mut pushed = false;
+    let mut received = vec![];
+    for _ in 0..100 {
+        if !pushed {
+            match Future::poll(Pin::new(&mut push_future), &mut ctx) {
+                Poll::Ready(r) => {
+                    r.unwrap();
+                    pushed = true;
+                },
+                Poll::Pending => (),
+            }
+        }
+        exchange_frames(&mut alice, &mut bob);
+        pop_all(&mut bob, bob_fd, &mut received);
+        if pushed && received.len() == buf.len() {
+            break;
+        }
+        now += Duration::from_millis(500);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    assert_eq!(received, buf);
+}
+
+#[test]
+fn test_isn_generator() {
+    let now = Instant::now();
+    let alice = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(50000).unwrap());
+    let bob = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let other_bob = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(81).unwrap());
+    let generator = IsnGenerator::new(0x1234, now);
+
+    // Reproducible given the same key, but different for a different key or four-tuple.
+    let isn = generator.generate(&alice, &bob, now);
+    assert_eq!(IsnGenerator::new(0x1234, now).generate(&alice, &bob, now), isn);
+    assert_ne!(IsnGenerator::new(0x4321, now).generate(&alice, &bob, now), isn);
+    assert_ne!(IsnGenerator::new(0x1234 << 64, now).generate(&alice, &bob, now), isn);
+    assert_ne!(generator.generate(&alice, &other_bob, now), isn);
+
+    // The same four-tuple gets a later ISN as time passes, one tick every 4us.
+    let later = now + Duration::from_millis(1);
+    assert_eq!(generator.generate(&alice, &bob, later), isn + Wrapping(250));
+
+    // A clock reading from before the generator was created counts as no time at all.
+    let earlier = now - Duration::from_millis(1);
+    assert_eq!(generator.generate(&alice, &bob, earlier), isn);
+}
+
+#[test]
+fn test_urgent_data() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&b"abc"[..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    let mut push_future = alice.tcp_push_urgent(alice_fd, BytesMut::from(&b"XYZ"[..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+
+    // The urgent pointer is relative to each segment and points at the last urgent byte, per RFC 1122. The segment
+    // sent before the urgent data was queued isn't marked.
+    let first = alice.rt().pop_frame();
+    let second = alice.rt().pop_frame();
+    assert!(!parse_tcp_header(first.clone()).urg);
+    let header = parse_tcp_header(second.clone());
+    assert!(header.urg);
+    assert_eq!(header.urgent_pointer, 2);
+
+    assert_eq!(bob.tcp_urgent_offset(bob_fd).unwrap(), None);
+    bob.receive(first).unwrap();
+    bob.receive(second).unwrap();
+
+    // Bob sees the urgent data coming before he's read up to it.
+    assert_eq!(bob.tcp_urgent_offset(bob_fd).unwrap(), Some(5));
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(bob.tcp_urgent_offset(bob_fd).unwrap(), Some(2));
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(bob.tcp_urgent_offset(bob_fd).unwrap(), None);
+}
+
+#[test]
+fn test_trailing_ack_delay() {
+    // `None` leaves the option at its default, which sends the ACK right away.
+    for &delay in &[None, Some(Duration::from_millis(0)), Some(Duration::from_millis(100))] {
+        let mut now = Instant::now();
+        let mut alice = test_helpers::new_alice(now);
+        let mut bob = test_helpers::new_bob(now);
+        if let Some(delay) = delay {
+            let options = bob.rt().tcp_options().trailing_ack_delay(delay);
+            bob.rt().set_tcp_options(options);
+        }
+        let (alice_fd, _) = establish_connection(&mut alice, &mut bob);
+
+        alice.tcp_close(alice_fd).unwrap();
+        alice.rt().poll_scheduler();
+        let fin = alice.rt().pop_frame();
+        assert!(parse_tcp_header(fin.clone()).fin);
+        bob.receive(fin).unwrap();
+        bob.rt().poll_scheduler();
+
+        // The ACK of Alice's FIN waits out the delay, unless there isn't one.
+        if let Some(delay) = delay.filter(|&d| d > Duration::from_millis(0)) {
+            assert!(bob.rt().try_pop_frame().is_none());
+            now += delay;
+            bob.rt().advance_clock(now);
+            bob.rt().poll_scheduler();
+        }
+        let ack = parse_tcp_header(bob.rt().pop_frame());
+        assert!(ack.ack && !ack.fin);
+    }
+}
+
+#[test]
+fn test_passive_close() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+    assert_eq!(alice.tcp_connection_state(alice_fd).unwrap(), tcp::ConnectionState::Established);
+
+    // Bob closes first, and Alice acknowledges his FIN but stays open until she closes too.
+    bob.tcp_close(bob_fd).unwrap();
+    bob.rt().poll_scheduler();
+    let bob_fin = bob.rt().pop_frame();
+    let bob_fin_seq_num = parse_tcp_header(bob_fin.clone()).seq_num;
+    alice.receive(bob_fin).unwrap();
+    assert_eq!(alice.tcp_connection_state(alice_fd).unwrap(), tcp::ConnectionState::CloseWait);
+    assert!(alice.tcp_is_readable(alice_fd).unwrap());
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(bob.tcp_connection_state(bob_fd).unwrap(), tcp::ConnectionState::FinWait2);
+    assert_eq!(alice.tcp_connection_state(alice_fd).unwrap(), tcp::ConnectionState::CloseWait);
+
+    alice.tcp_close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let fin = alice.rt().pop_frame();
+    let fin_seq_num = parse_tcp_header(fin.clone()).seq_num;
+    assert!(parse_tcp_header(fin.clone()).fin);
+    assert_eq!(alice.tcp_connection_state(alice_fd).unwrap(), tcp::ConnectionState::LastAck);
+
+    // Bob's ACK of Alice's FIN is lost, so she sends it again once the retransmission timer fires.
+    bob.receive(fin).unwrap();
+    bob.rt().poll_scheduler();
+    bob.rt().advance_clock(now);
+    bob.rt().poll_scheduler();
+    let last_ack = bob.rt().pop_frame();
+    // Bob's FIN took a sequence number, so his final ACK comes after it.
+    let last_ack_hdr = parse_tcp_header(last_ack.clone());
+    assert_eq!(last_ack_hdr.seq_num, bob_fin_seq_num + Wrapping(1));
+    assert_eq!(last_ack_hdr.ack_num, fin_seq_num + Wrapping(1));
+    // Having closed first, Bob waits out TIME_WAIT (see `test_time_wait`).
+    assert_eq!(bob.tcp_connection_state(bob_fd).unwrap(), tcp::ConnectionState::TimeWait);
+    let rto = alice.tcp_rto(alice_fd).unwrap();
+    now += rto;
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    let header = parse_tcp_header(alice.rt().pop_frame());
+    assert!(header.fin);
+    assert_eq!(header.seq_num, fin_seq_num);
+    assert_eq!(alice.tcp_connection_state(alice_fd).unwrap(), tcp::ConnectionState::LastAck);
+
+    alice.receive(last_ack).unwrap();
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.tcp_connection_state(alice_fd).unwrap(), tcp::ConnectionState::Closed);
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::Graceful));
+}
+
+#[test]
+fn test_time_wait() {
+    let msl = Duration::from_secs(1);
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let options = alice.rt().tcp_options().msl(msl);
+    alice.rt().set_tcp_options(options);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    // Alice closes first, so once Bob has closed too, she's the one left in TIME_WAIT and he's done right away.
+    alice.tcp_close(alice_fd).unwrap();
+    exchange_frames(&mut alice, &mut bob);
+    bob.tcp_close(bob_fd).unwrap();
+    bob.rt().poll_scheduler();
+    let bob_fin = bob.rt().pop_frame();
+    assert!(parse_tcp_header(bob_fin.clone()).fin);
+    alice.receive(bob_fin.clone()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    assert_eq!(bob.tcp_close_reason(bob_fd).unwrap(), Some(tcp::CloseReason::Graceful));
+    assert_eq!(alice.tcp_connection_state(alice_fd).unwrap(), tcp::ConnectionState::TimeWait);
+
+    // Bob's FIN turns up again just before her 2*MSL is up, so she ACKs it again and starts the wait over.
+    now += 2 * msl - Duration::from_millis(1);
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.tcp_connection_state(alice_fd).unwrap(), tcp::ConnectionState::TimeWait);
+    alice.receive(bob_fin).unwrap();
+    alice.rt().poll_scheduler();
+    assert!(parse_tcp_header(alice.rt().pop_frame()).ack);
+
+    now += 2 * msl - Duration::from_millis(1);
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), None);
+    now += Duration::from_millis(1);
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::Graceful));
+    assert_eq!(alice.tcp_connection_state(alice_fd).unwrap(), tcp::ConnectionState::Closed);
+}
+
+#[test]
+fn test_dup_acks_after_drop() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, _) = establish_connection(&mut alice, &mut bob);
+
+    let mut frames = vec![];
+    for i in 0..4u8 {
+        let buf = BytesMut::from(&vec![i; 100][..]).freeze();
+        let mut push_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        frames.push(alice.rt().pop_frame());
+    }
+    let lost_seq_num = parse_tcp_header(frames[0].clone()).seq_num;
+
+    // The first segment is lost, and each one after it gets an immediate duplicate ACK for the gap.
+    for frame in frames.drain(..).skip(1) {
+        let _ = bob.receive(frame);
+    }
+    let mut dup_acks = vec![];
+    while let Some(frame) = bob.rt().try_pop_frame() {
+        let header = parse_tcp_header(frame.clone());
+        assert!(header.ack);
+        assert_eq!(header.ack_num, lost_seq_num);
+        dup_acks.push(frame);
+    }
+    assert_eq!(dup_acks.len(), 3);
+
+    // Which is enough for Alice to fast retransmit the lost segment.
+    for frame in dup_acks {
+        let _ = alice.receive(frame);
+    }
+    alice.rt().poll_scheduler();
+    let retransmitted = alice.rt().pop_frame();
+    assert_eq!(parse_tcp_header(retransmitted).seq_num, lost_seq_num);
+}
+
+#[test]
+fn test_per_socket_congestion_ctrl() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 2).unwrap();
+
+    // One of Alice's connections opts out of congestion control, the other keeps the engine's default.
+    let default_fd = alice.tcp_socket();
+    let uncontrolled_fd = alice.tcp_socket();
+    let mut bad_options = cc::Options::default();
+    bad_options.insert_float("beta".to_owned(), 0.);
+    let result = alice.tcp_set_congestion_ctrl(default_fd, cc::Cubic::new, Some(bad_options));
+    must_let!(let Err(Fail::OutOfRange { .. }) = result);
+    alice.tcp_set_congestion_ctrl(uncontrolled_fd, cc::None::new, None).unwrap();
+    let mut default_connect = alice.tcp_connect(default_fd, listen_addr);
+    let mut uncontrolled_connect = alice.tcp_connect(uncontrolled_fd, listen_addr);
+    for _ in 0..3 {
+        exchange_frames(&mut alice, &mut bob);
+    }
+    for _ in 0..2 {
+        let mut accept_future = bob.tcp_accept(listen_fd);
+        must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    }
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut default_connect), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut uncontrolled_connect), &mut ctx));
+
+    // It's too late to change once the connection's been made.
+    must_let!(let Err(Fail::Malformed { .. }) = alice.tcp_set_congestion_ctrl(default_fd, cc::None::new, None));
+
+    // Bob's SYN+ACK only opens a small window, so get an ACK from him on each connection to open it fully.
+    for &fd in &[default_fd, uncontrolled_fd] {
+        let mut push_future = alice.tcp_push(fd, BytesMut::from(&[0u8][..]).freeze());
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    }
+    exchange_frames(&mut alice, &mut bob);
+    let now = now + Duration::from_millis(500);
+    alice.rt().advance_clock(now);
+    bob.rt().advance_clock(now);
+    exchange_frames(&mut alice, &mut bob);
+
+    // Count how many MSS-sized segments each connection sends before hearing anything back.
+    let mut segments_sent = |fd| {
+        let mss = alice.tcp_mss(fd).unwrap();
+        for _ in 0..10 {
+            let buf = BytesMut::from(&vec![0u8; mss][..]).freeze();
+            let mut push_future = alice.tcp_push(fd, buf);
+            must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        }
+        alice.rt().poll_scheduler();
+        let mut count = 0;
+        while alice.rt().try_pop_frame().is_some() {
+            count += 1;
+        }
+        count
+    };
+    assert_eq!(segments_sent(uncontrolled_fd), 10);
+    assert!(segments_sent(default_fd) < 10);
+}
+
+// Leaves cwnd alone and paces segments out at one full-sized segment every 10ms.
+#[derive(Debug)]
+struct FixedPacing {
+    mss: usize,
+}
+
+impl CongestionControl for FixedPacing {
+    #[allow(clippy::new_ret_no_self)]
+    fn new(mss: usize, _seq_no: tcp::SeqNumber, _options: Option<cc::Options>) -> Box<dyn CongestionControl> {
+        Box::new(Self { mss })
+    }
+}
+
+impl cc::SlowStartCongestionAvoidance for FixedPacing {
+    fn get_pacing_rate(&self) -> Option<f64> {
+        Some(self.mss as f64 * 100.)
+    }
+}
+impl cc::FastRetransmitRecovery for FixedPacing {}
+impl cc::LimitedTransmit for FixedPacing {}
+
+#[test]
+fn test_pacing() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let options = alice.rt().tcp_options().congestion_ctrl_type(FixedPacing::new);
+    alice.rt().set_tcp_options(options);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+    let mss = alice.tcp_mss(alice_fd).unwrap();
+
+    // Bob's SYN+ACK only opens a small window, so get an ACK from him to open it fully.
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&[0u8][..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    let mut received = vec![];
+    for _ in 0..3 {
+        exchange_frames(&mut alice, &mut bob);
+        pop_all(&mut bob, bob_fd, &mut received);
+    }
+    assert_eq!(received.len(), 1);
+    now += Duration::from_millis(500);
+    alice.rt().advance_clock(now);
+    bob.rt().advance_clock(now);
+    exchange_frames(&mut alice, &mut bob);
+
+    let buf: Vec<u8> = (0..(3 * mss)).map(|i| i as u8).collect();
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&buf[..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+
+    // Until the clock moves on, only the first segment goes, and then one more every 10ms.
+    received.clear();
+    for i in 1..=3 {
+        for _ in 0..3 {
+            exchange_frames(&mut alice, &mut bob);
+            pop_all(&mut bob, bob_fd, &mut received);
+        }
+        assert_eq!(received.len(), i * mss);
+        now += Duration::from_millis(10);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    assert_eq!(received, buf);
+}
+
+#[test]
+fn test_close_reason() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Both sides closing is a graceful close.
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), None);
+    alice.tcp_close(alice_fd).unwrap();
+    bob.tcp_close(bob_fd).unwrap();
+    for _ in 0..3 {
+        exchange_frames(&mut alice, &mut bob);
+    }
+    // Neither closed after the other, so both wait out TIME_WAIT.
+    now += 2 * alice.rt().tcp_options().msl;
+    alice.rt().advance_clock(now);
+    bob.rt().advance_clock(now);
+    exchange_frames(&mut alice, &mut bob);
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::Graceful));
+    assert_eq!(bob.tcp_close_reason(bob_fd).unwrap(), Some(tcp::CloseReason::Graceful));
+
+    // Data that's never acknowledged is retransmitted `retries` times before the connection gives up on it.
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let options = alice.rt().tcp_options().retries(2);
+    alice.rt().set_tcp_options(options);
+    let (alice_fd, _) = establish_connection(&mut alice, &mut bob);
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&[1u8; 10][..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    let mut pop_future = alice.tcp_pop(alice_fd);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    let mut transmissions = 0;
+    for _ in 0..10 {
+        alice.rt().poll_scheduler();
+        while alice.rt().try_pop_frame().is_some() {
+            transmissions += 1;
+        }
+        now += Duration::from_secs(10);
+        alice.rt().advance_clock(now);
+    }
+    assert_eq!(transmissions, 3);
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::RetransmitTimeout));
+    // Giving up fails whatever the application was still waiting on.
+    must_let!(let Poll::Ready(Err(Fail::ConnectionAborted {})) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+}
+
+#[test]
+fn test_reset_fails_waiters() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let options = alice.rt().tcp_options().send_buffer_size(30000);
+    alice.rt().set_tcp_options(options);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    // When Bob resets the connection, Alice is waiting to push more than fits in her send buffer, for what she's
+    // already pushed to get past her congestion window, and for data from Bob.
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&[1u8; 20000][..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let data = alice.rt().pop_frame();
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&[2u8; 20000][..]).freeze());
+    let mut flush_future = alice.tcp_flush(alice_fd);
+    let mut pop_future = alice.tcp_pop(alice_fd);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut flush_future), &mut ctx));
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+
+    bob.tcp_abort(bob_fd).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::ResetReceived));
+    must_let!(let Poll::Ready(Err(Fail::ConnectionAborted {})) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    must_let!(let Poll::Ready(Err(Fail::ConnectionAborted {})) = Future::poll(Pin::new(&mut flush_future), &mut ctx));
+    must_let!(let Poll::Ready(Err(Fail::ConnectionAborted {})) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+
+    // Her side of the connection is gone, and nothing more goes out for it.
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().try_pop_frame().is_none());
+
+    // Bob released his side as well, so her data only reaches his listening socket, which drops it.
+    must_let!(let Err(Fail::Malformed { .. }) = bob.receive(data));
+    assert_eq!(bob.tcp_close_reason(bob_fd).unwrap(), Some(tcp::CloseReason::ResetSent));
+}
+
+#[test]
+fn test_fin_wait_timeout() {
+    let fin_wait_timeout = Duration::from_secs(5);
+    // First Bob vanishes before Alice's FIN reaches him, then he ACKs it but never closes his own side.
+    for &bob_acks_fin in &[false, true] {
+        let mut now = Instant::now();
+        let mut alice = test_helpers::new_alice(now);
+        let mut bob = test_helpers::new_bob(now);
+        let options = alice.rt().tcp_options().fin_wait_timeout(fin_wait_timeout);
+        alice.rt().set_tcp_options(options);
+        let (alice_fd, _) = establish_connection(&mut alice, &mut bob);
+
+        alice.tcp_close(alice_fd).unwrap();
+        alice.rt().poll_scheduler();
+        let fin = alice.rt().pop_frame();
+        assert!(parse_tcp_header(fin.clone()).fin);
+        if bob_acks_fin {
+            bob.receive(fin).unwrap();
+            bob.rt().poll_scheduler();
+            while let Some(frame) = bob.rt().try_pop_frame() {
+                alice.receive(frame).unwrap();
+            }
+        }
+
+        // Alice keeps waiting right up to the timeout.
+        now += fin_wait_timeout - Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), None);
+
+        now += Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::FinWaitTimeout));
+
+        // The connection is released the next time Alice needs a port, without waiting on `release_closed_ports`.
+        let fd = alice.tcp_socket();
+        let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+        let _connect_future = alice.tcp_connect(fd, listen_addr);
+        must_let!(let Err(Fail::Ignored { .. }) = alice.tcp_close(alice_fd));
+        assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::FinWaitTimeout));
+    }
+}
+
+#[test]
+fn test_fin_wait_timeout_restarted_by_data() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let fin_wait_timeout = Duration::from_secs(5);
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let options = alice.rt().tcp_options().fin_wait_timeout(fin_wait_timeout);
+    alice.rt().set_tcp_options(options);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+    alice.tcp_close(alice_fd).unwrap();
+    exchange_frames(&mut alice, &mut bob);
+
+    // Bob ACKs Alice's FIN but still has plenty to send, spread out over longer than the timeout.
+    let mut received = vec![];
+    for _ in 0..3 {
+        now += fin_wait_timeout - Duration::from_secs(1);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+        let mut push_future = bob.tcp_push(bob_fd, BytesMut::from(&[1u8; 10][..]).freeze());
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        exchange_frames(&mut alice, &mut bob);
+        alice.rt().poll_scheduler();
+        pop_all(&mut alice, alice_fd, &mut received);
+        assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), None);
+    }
+    assert_eq!(received.len(), 30);
+
+    // Once he goes quiet, Alice waits out the full timeout from the last of his data.
+    now += fin_wait_timeout - Duration::from_millis(1);
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), None);
+    now += Duration::from_millis(1);
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::FinWaitTimeout));
+}
+
+#[test]
+fn test_retransmit_after_receiving_only() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    // Bob only receives for a while, so his retransmitter has nothing to watch.
+    let mut received = vec![];
+    for _ in 0..3 {
+        let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&[1u8; 10][..]).freeze());
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        exchange_frames(&mut alice, &mut bob);
+        pop_all(&mut bob, bob_fd, &mut received);
+        now += Duration::from_millis(500);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    assert_eq!(received.len(), 30);
+
+    // The first thing he sends is lost, and still gets retransmitted once it times out.
+    let mut push_future = bob.tcp_push(bob_fd, BytesMut::from(&[2u8; 10][..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    let lost = parse_tcp_header(bob.rt().pop_frame());
+    now += Duration::from_secs(5);
+    bob.rt().advance_clock(now);
+    bob.rt().poll_scheduler();
+    let retransmitted = parse_tcp_header(bob.rt().pop_frame());
+    assert_eq!(retransmitted.seq_num, lost.seq_num);
+}
+
+#[test]
+fn test_data_before_connect_resolves() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let syn_ack = bob.rt().pop_frame();
+
+    // Alice's ACK completes the handshake and Bob replies straight away, so the SYN+ACK and Bob's first data
+    // arrive together, before Alice has polled her connect future.
+    alice.receive(syn_ack).unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok((bob_fd, _))) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    let buf = BytesMut::from(&b"hello"[..]).freeze();
+    let mut push_future = bob.tcp_push(bob_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+
+    // The data's waiting once the connection is.
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    let mut pop_future = alice.tcp_pop(alice_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+
+    // The same goes for data that reaches Bob before he's accepted the connection.
+    let second_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(second_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    let mut push_future = alice.tcp_push(second_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    must_let!(let Poll::Ready(Ok((bob_fd, _))) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+}
+
+#[test]
+fn test_fin_piggybacked_on_data() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    // Bob's SYN+ACK only opened a small window, so most of this has to wait in the unsent queue until after Alice
+    // has closed.
+    let buf: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&buf[..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.tcp_close(alice_fd).unwrap();
+
+    let mut fins = vec![];
+    let mut received = vec![];
+    for _ in 0..10 {
+        alice.rt().poll_scheduler();
+        while let Some(frame) = alice.rt().try_pop_frame() {
+            let (_, ipv4_payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+            let (ipv4_hdr, tcp_payload) = Ipv4Header::parse(ipv4_payload).unwrap();
+            let (header, data) = TcpHeader::parse(&ipv4_hdr, tcp_payload).unwrap();
+            if header.fin {
+                fins.push(data.len());
+            }
+            let _ = bob.receive(frame);
+        }
+        bob.rt().poll_scheduler();
+        while let Some(frame) = bob.rt().try_pop_frame() {
+            let _ = alice.receive(frame);
+        }
+        pop_all(&mut bob, bob_fd, &mut received);
+        now += Duration::from_millis(500);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+
+    // The FIN went out on the last data segment rather than on its own.
+    assert_eq!(fins.len(), 1);
+    assert!(fins[0] > 0);
+
+    // And Bob took in the data before the FIN.
+    assert_eq!(received, buf);
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Err(Fail::ResourceNotFound { .. })) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+}
+
+#[test]
+fn test_rtt_sampled_at_arrival() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, _) = establish_connection(&mut alice, &mut bob);
+
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&[1u8; 10][..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // Let Bob's delayed ACK go out.
+    bob.rt().advance_clock(now + Duration::from_secs(1));
+    bob.rt().poll_scheduler();
+    let ack = bob.rt().pop_frame();
+
+    // The ACK came off the wire 10ms after we sent the data, but we only get around to it much later. The RTT
+    // sample should be the 10ms, not however long the frame sat around before we processed it.
+    alice.rt().advance_clock(now + Duration::from_millis(900));
+    alice.receive_at(ack, now + Duration::from_millis(10)).unwrap();
+    assert!(alice.tcp_rto(alice_fd).unwrap() < Duration::from_millis(100));
+}
+
+#[test]
+fn test_dsack_undoes_spurious_fast_retransmit() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    for &dsack in &[false, true] {
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice(now);
+        let mut bob = test_helpers::new_bob(now);
+        let options = bob.rt().tcp_options().dsack(dsack);
+        bob.rt().set_tcp_options(options);
+        let (alice_fd, _) = establish_connection(&mut alice, &mut bob);
+
+        let changes = Rc::new(RefCell::new(vec![]));
+        let changes_ = changes.clone();
+        alice
+            .tcp_set_cwnd_observer(alice_fd, Box::new(move |change| changes_.borrow_mut().push(change)))
+            .unwrap();
+
+        let mut frames = vec![];
+        for i in 0..4u8 {
+            let buf = BytesMut::from(&vec![i; 100][..]).freeze();
+            let mut push_future = alice.tcp_push(alice_fd, buf);
+            must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+            alice.rt().poll_scheduler();
+            frames.push(alice.rt().pop_frame());
+        }
+
+        // The network delivers the first segment several times over, and each copy draws an immediate ACK. Past
+        // the first, they're duplicates as far as Alice can tell, so she fast retransmits the second segment even
+        // though it's still on its way.
+        let _ = bob.receive(frames[0].clone());
+        for _ in 0..4 {
+            let _ = bob.receive(frames[0].clone());
+            bob.rt().poll_scheduler();
+            alice.receive(bob.rt().pop_frame()).unwrap();
+        }
+        alice.rt().poll_scheduler();
+        let retransmitted = alice.rt().pop_frame();
+        assert_eq!(parse_tcp_header(retransmitted.clone()).seq_num, parse_tcp_header(frames[1].clone()).seq_num);
+        assert_eq!(changes.borrow().last().unwrap().reason, cc::CwndChangeReason::FastRecovery);
+
+        // Then the originals arrive, followed by the retransmission.
+        for frame in frames.drain(1..) {
+            let _ = bob.receive(frame);
+        }
+        let _ = bob.receive(retransmitted);
+        bob.rt().poll_scheduler();
+        let mut reported_dsack = false;
+        while let Some(frame) = bob.rt().try_pop_frame() {
+            let header = parse_tcp_header(frame.clone());
+            reported_dsack |= header
+                .iter_options()
+                .any(|option| matches!(option, TcpOptions2::SelectiveAcknowlegement { .. }));
+            alice.receive(frame).unwrap();
+        }
+        assert_eq!(reported_dsack, dsack);
+
+        // Only with the D-SACK can Alice tell the retransmission wasn't needed and put cwnd back.
+        let changes = changes.borrow();
+        let undone = changes.iter().any(|change| change.reason == cc::CwndChangeReason::Undo);
+        assert_eq!(undone, dsack);
+        if dsack {
+            // We were still in slow start, so ssthresh goes back to being unset as well.
+            let last = changes.last().unwrap();
+            assert_eq!(last.reason, cc::CwndChangeReason::Undo);
+            assert_eq!(last.ssthresh, u32::MAX);
+        }
+    }
+}
+
+#[test]
+fn test_out_of_window_acks() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+    let buf = BytesMut::from(&[7u8; 10][..]).freeze();
+
+    // An ACK for data Alice never sent gets Bob an ACK right away, and the data that came with it is dropped.
+    bob.tcp_emit_crafted(bob_fd, |header| header.ack_num += Wrapping(1000), buf.clone()).unwrap();
+    let frame = bob.rt().pop_frame();
+    let segment = parse_tcp_header(frame.clone());
+    alice.receive(frame).unwrap();
+    let ack = parse_tcp_header(alice.rt().pop_frame());
+    assert!(ack.ack);
+    assert_eq!(ack.ack_num, segment.seq_num);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut alice.tcp_pop(alice_fd)), &mut ctx));
+
+    // An old ACK is just ignored, and the data with it taken in as usual.
+    bob.tcp_emit_crafted(bob_fd, |header| header.ack_num -= Wrapping(1000), buf.clone()).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(segment)) = Future::poll(Pin::new(&mut alice.tcp_pop(alice_fd)), &mut ctx));
+    assert_eq!(segment, buf);
+    assert!(alice.tcp_close_reason(alice_fd).unwrap().is_none());
+}
+
+#[test]
+fn test_rst_for_unknown_connection() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, _) = establish_connection(&mut alice, &mut bob);
+
+    // Bob restarts, and so has no idea what Alice's next segment is for. His RST takes its sequence number from
+    // her ACK so she'll accept it.
+    let mut bob = test_helpers::new_bob(now);
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&[7u8; 10][..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    let segment = parse_tcp_header(frame.clone());
+    bob.receive(frame).unwrap();
+    let rst = bob.rt().pop_frame();
+    let rst_hdr = parse_tcp_header(rst.clone());
+    assert!(rst_hdr.rst);
+    assert!(!rst_hdr.ack);
+    assert_eq!(rst_hdr.seq_num, segment.ack_num);
+    alice.receive(rst).unwrap();
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::ResetReceived));
+
+    // Without an ACK to go on, the RST acknowledges all of the segment instead. The reset connection is gone, so
+    // this takes a new one.
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, _) = establish_connection(&mut alice, &mut bob);
+    let mut bob = test_helpers::new_bob(now);
+    alice.tcp_emit_crafted(alice_fd, |header| header.ack = false, BytesMut::from(&[7u8; 10][..]).freeze()).unwrap();
+    let frame = alice.rt().pop_frame();
+    let segment = parse_tcp_header(frame.clone());
+    bob.receive(frame).unwrap();
+    let rst_hdr = parse_tcp_header(bob.rt().pop_frame());
+    assert!(rst_hdr.rst && rst_hdr.ack);
+    assert_eq!(rst_hdr.ack_num, segment.seq_num + Wrapping(10));
+
+    // And a RST never gets one back.
+    alice.tcp_emit_crafted(alice_fd, |header| header.rst = true, Bytes::empty()).unwrap();
+    must_let!(let Err(Fail::Ignored { .. }) = bob.receive(alice.rt().pop_frame()));
+    assert!(bob.rt().try_pop_frame().is_none());
+}
+
+#[test]
+fn test_rst_waits_on_arp() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, _) = establish_connection(&mut alice, &mut bob);
+
+    // Bob restarts with nothing in his ARP cache, so he has to find out where Alice is before he can reset her.
+    let mut bob = test_helpers::new_bob(now);
+    bob.import_arp_cache(HashMap::new());
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&[7u8; 10][..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    let segment = parse_tcp_header(frame.clone());
+    bob.receive(frame).unwrap();
+    bob.rt().poll_scheduler();
+    let arp_request = bob.rt().pop_frame();
+    assert!(bob.rt().try_pop_frame().is_none());
+    alice.receive(arp_request).unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // Once she's answered, the RST goes out as usual.
+    bob.rt().poll_scheduler();
+    let rst = bob.rt().pop_frame();
+    let rst_hdr = parse_tcp_header(rst.clone());
+    assert!(rst_hdr.rst);
+    assert_eq!(rst_hdr.seq_num, segment.ack_num);
+    alice.receive(rst).unwrap();
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::ResetReceived));
+}
+
+#[test]
+fn test_blind_rst() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    // A RST in Alice's window that isn't at the next sequence number she expects could be a guess, so she
+    // challenges it with an ACK of where she really is and stays open.
+    let craft_rst = |offset| {
+        move |header: &mut TcpHeader| {
+            header.rst = true;
+            header.seq_num += offset;
+        }
+    };
+    bob.tcp_emit_crafted(bob_fd, craft_rst(Wrapping(1000)), Bytes::empty()).unwrap();
+    let frame = bob.rt().pop_frame();
+    let expected_seq_num = parse_tcp_header(frame.clone()).seq_num - Wrapping(1000);
+    alice.receive(frame).unwrap();
+    let challenge_ack = parse_tcp_header(alice.rt().pop_frame());
+    assert!(challenge_ack.ack && !challenge_ack.rst);
+    assert_eq!(challenge_ack.ack_num, expected_seq_num);
+    assert!(alice.tcp_close_reason(alice_fd).unwrap().is_none());
+
+    // One outside the window, either side of it, is dropped without a word.
+    for &offset in &[Wrapping(0u32) - Wrapping(1), Wrapping(1 << 30)] {
+        bob.tcp_emit_crafted(bob_fd, craft_rst(offset), Bytes::empty()).unwrap();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+        assert!(alice.rt().try_pop_frame().is_none());
+        assert!(alice.tcp_close_reason(alice_fd).unwrap().is_none());
+    }
+
+    // Only one right at the next sequence number resets the connection.
+    bob.tcp_emit_crafted(bob_fd, craft_rst(Wrapping(0)), Bytes::empty()).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert!(alice.rt().try_pop_frame().is_none());
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::ResetReceived));
+}
+
+#[test]
+fn test_abort() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+    let buf = BytesMut::from(&[7u8; 10][..]).freeze();
+
+    // Alice has data from Bob she hasn't read, and more of her own than she can send him yet.
+    let mut push_future = bob.tcp_push(bob_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let len = 64 * 1024;
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&vec![7u8; len][..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let mut first_seq = None;
+    while let Some(frame) = alice.rt().try_pop_frame() {
+        first_seq.get_or_insert(parse_tcp_header(frame.clone()).seq_num);
+        bob.receive(frame).unwrap();
+    }
+    let mut received = vec![];
+    pop_all(&mut bob, bob_fd, &mut received);
+    assert!(received.len() < len);
+    let mut flush_future = alice.tcp_flush(alice_fd);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut flush_future), &mut ctx));
+    assert_eq!(alice.tcp_bytes_available(alice_fd).unwrap(), 10);
+
+    // Aborting drops all of it, and everything outstanding or still to come fails.
+    alice.tcp_abort(alice_fd).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::ConnectionAborted {})) = Future::poll(Pin::new(&mut flush_future), &mut ctx));
+    assert_eq!(alice.tcp_bytes_available(alice_fd).unwrap(), 0);
+    must_let!(let Poll::Ready(Err(Fail::ConnectionAborted {})) = Future::poll(Pin::new(&mut alice.tcp_pop(alice_fd)), &mut ctx));
+    let mut push_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Err(Fail::ConnectionAborted {})) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+
+    // Bob gets a RST right after the last of the data Alice did send.
+    alice.rt().poll_scheduler();
+    let rst = alice.rt().pop_frame();
+    let rst_hdr = parse_tcp_header(rst.clone());
+    assert!(rst_hdr.rst);
+    assert_eq!(rst_hdr.seq_num, first_seq.unwrap() + Wrapping(received.len() as u32));
+    assert!(alice.rt().try_pop_frame().is_none());
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::ResetSent));
+    must_let!(let Err(Fail::Ignored { .. }) = alice.tcp_abort(alice_fd));
+    bob.receive(rst).unwrap();
+    assert_eq!(bob.tcp_close_reason(bob_fd).unwrap(), Some(tcp::CloseReason::ResetReceived));
+}
+
+#[test]
+fn test_abort_after_fin() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    alice.tcp_close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let fin = alice.rt().pop_frame();
+    let fin_seq_num = parse_tcp_header(fin.clone()).seq_num;
+    bob.receive(fin).unwrap();
+
+    // Bob has taken the FIN, so only a RST after it is in his window.
+    alice.tcp_abort(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let rst = alice.rt().pop_frame();
+    let rst_hdr = parse_tcp_header(rst.clone());
+    assert!(rst_hdr.rst);
+    assert_eq!(rst_hdr.seq_num, fin_seq_num + Wrapping(1));
+    bob.receive(rst).unwrap();
+    assert_eq!(bob.tcp_close_reason(bob_fd).unwrap(), Some(tcp::CloseReason::ResetReceived));
+}
+
+#[cfg(feature = "fault-injection")]
+#[test]
+fn test_ack_faults() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    // Bob takes Alice's data in but never acknowledges it, so she has to retransmit.
+    let faults = tcp::AckFaults {
+        drop_probability: 1.0,
+        ..Default::default()
+    };
+    bob.tcp_set_ack_faults(bob_fd, Some(faults)).unwrap();
+    let mut push_future = alice.tcp_push(alice_fd, BytesMut::from(&[7u8; 10][..]).freeze());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    let sent = parse_tcp_header(frame.clone());
+    bob.receive(frame).unwrap();
+    let mut received = vec![];
+    pop_all(&mut bob, bob_fd, &mut received);
+    assert_eq!(received.len(), 10);
+    now += alice.tcp_rto(alice_fd).unwrap();
+    alice.rt().advance_clock(now);
+    bob.rt().advance_clock(now);
+    bob.rt().poll_scheduler();
+    assert!(bob.rt().try_pop_frame().is_none());
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    assert_eq!(parse_tcp_header(frame.clone()).seq_num, sent.seq_num);
+
+    // Delayed instead, his ACK of the duplicate doesn't go right away, but does go once the delay's up.
+    let faults = tcp::AckFaults {
+        delay: Some(Duration::from_secs(1)),
+        ..Default::default()
+    };
+    bob.tcp_set_ack_faults(bob_fd, Some(faults)).unwrap();
+    bob.receive(frame).unwrap();
+    bob.rt().poll_scheduler();
+    assert!(bob.rt().try_pop_frame().is_none());
+    now += Duration::from_secs(1);
+    bob.rt().advance_clock(now);
+    bob.rt().poll_scheduler();
+    let ack = parse_tcp_header(bob.rt().pop_frame());
+    assert_eq!(ack.ack_num, sent.seq_num + Wrapping(10));
+
+    // And without faults he goes back to ACKing duplicates right away.
+    bob.tcp_set_ack_faults(bob_fd, None).unwrap();
+    alice.tcp_emit_crafted(alice_fd, |header| header.seq_num -= Wrapping(10), BytesMut::from(&[7u8; 10][..]).freeze()).unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    assert!(parse_tcp_header(bob.rt().pop_frame()).ack);
+}
+
+#[test]
+fn test_emit_crafted_segments() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish_connection(&mut alice, &mut bob);
+
+    let buf = BytesMut::from(&[7u8; 10][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    assert!(bob.rt().try_pop_frame().is_none());
+
+    // A copy of the same data that Alice's sender never asked for still reaches Bob as a duplicate, which he ACKs
+    // right away rather than waiting on the delayed ACK timer.
+    alice.tcp_emit_crafted(alice_fd, |header| header.seq_num -= Wrapping(10), buf).unwrap();
+    assert_eq!(alice.tcp_send_buffer_used(alice_fd).unwrap(), 10);
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let ack = parse_tcp_header(bob.rt().pop_frame());
+    assert!(ack.ack);
+
+    // And a bare RST takes Bob's side down.
+    alice.tcp_emit_crafted(alice_fd, |header| header.rst = true, Bytes::empty()).unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(bob.tcp_close_reason(bob_fd).unwrap(), Some(tcp::CloseReason::ResetReceived));
+}
@@ -0,0 +1,328 @@
+use super::cc_harness::MSS;
+use crate::{
+    fail::Fail,
+    protocols::tcp::established::state::receiver::Receiver,
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+    test_helpers::CountingWaker,
+};
+use futures::task::noop_waker_ref;
+use must_let::must_let;
+use std::{
+    num::Wrapping,
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[test]
+fn test_recv_buffer_limit_closes_window() {
+    let now = Instant::now();
+    let max_window_size = 0xffff;
+    let recv_buffer_limit = 2 * MSS as u32;
+    let receiver = Receiver::new(Wrapping(0), max_window_size, 0, recv_buffer_limit, MSS, 2);
+    let segment = BytesMut::from(&vec![0x5a; MSS][..]).freeze();
+
+    // The buffer limit is smaller than the window, so it's what we offer, and the window shrinks as unread data
+    // builds up against it.
+    assert_eq!(receiver.window_size(), recv_buffer_limit);
+    receiver.receive_data(Wrapping(0), segment.clone(), now).unwrap();
+    assert_eq!(receiver.window_size(), recv_buffer_limit - MSS as u32);
+    receiver.receive_data(Wrapping(MSS as u32), segment.clone(), now).unwrap();
+
+    // Once the unread data hits the limit, the window is closed and anything sent into it is dropped.
+    assert_eq!(receiver.window_size(), 0);
+    assert!(receiver.receive_data(Wrapping(2 * MSS as u32), segment.clone(), now).is_err());
+    assert_eq!(receiver.recv_seq_no.get(), Wrapping(2 * MSS as u32));
+
+    // Reading reopens it.
+    receiver.recv().unwrap().unwrap();
+    assert_eq!(receiver.window_size(), recv_buffer_limit - MSS as u32);
+    receiver.receive_data(Wrapping(2 * MSS as u32), segment, now).unwrap();
+}
+
+#[test]
+fn test_window_never_wraps() {
+    // Start just short of the sequence space wrapping, so the outstanding bytes are worked out across it.
+    let base_seq_no = Wrapping(u32::MAX - 100);
+    let max_window_size = 4 * MSS as u32;
+    let receiver = Receiver::new(base_seq_no, max_window_size, 0, 0xffff, MSS, 2);
+
+    // Nothing we receive ourselves gets past the window, but if more than a window's worth ends up outstanding the
+    // window is closed rather than wrapping around to something huge.
+    receiver.recv_seq_no.set(base_seq_no + Wrapping(max_window_size + MSS as u32));
+    assert_eq!(receiver.window_size(), 0);
+    receiver.recv_seq_no.set(base_seq_no + Wrapping(max_window_size));
+    assert_eq!(receiver.window_size(), 0);
+    receiver.recv_seq_no.set(base_seq_no + Wrapping(max_window_size - 1));
+    assert_eq!(receiver.window_size(), 1);
+}
+
+#[test]
+fn test_receive_across_wrap() {
+    let now = Instant::now();
+    let mss = MSS as u32;
+    // Start a segment and a half short of the sequence space wrapping, so the second segment straddles it.
+    let base_seq_no = Wrapping(u32::MAX - mss - mss / 2);
+    let max_window_size = 4 * mss;
+    let receiver = Receiver::new(base_seq_no, max_window_size, 0, 0xffff, MSS, 2);
+    let segment = BytesMut::from(&vec![0x5a; MSS][..]).freeze();
+
+    let second = base_seq_no + Wrapping(mss);
+    let third = second + Wrapping(mss);
+    receiver.receive_data(base_seq_no, segment.clone(), now).unwrap();
+    receiver.receive_data(second, segment.clone(), now).unwrap();
+    assert_eq!(receiver.recv_seq_no.get(), third);
+    assert_eq!(receiver.available.get(), 2 * MSS);
+    assert_eq!(receiver.window_size(), 2 * mss);
+
+    // Data from past the wrap and beyond a gap is turned away, a retransmission from before the wrap is a
+    // duplicate, and one straddling the end of what we have only adds its new bytes.
+    must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(third + Wrapping(mss), segment.clone(), now));
+    must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(second, segment.clone(), now));
+    assert_eq!(receiver.duplicate_range.take(), Some((second, third)));
+    receiver.receive_data(third - Wrapping(mss / 2), segment, now).unwrap();
+    assert_eq!(receiver.recv_seq_no.get(), third + Wrapping(mss / 2));
+    assert_eq!(receiver.available.get(), 2 * MSS + MSS / 2);
+
+    // The urgent pointer only moves forward, even across the wrap.
+    receiver.receive_urgent(second, 10);
+    assert_eq!(receiver.urgent_offset(), Some(MSS + 10));
+    receiver.receive_urgent(base_seq_no, 10);
+    assert_eq!(receiver.urgent_offset(), Some(MSS + 10));
+
+    // Reading past the wrap reopens the window, and leaves the urgent data behind.
+    receiver.consume(2 * MSS).unwrap();
+    assert_eq!(receiver.base_seq_no.get(), third);
+    assert_eq!(receiver.window_size(), max_window_size - mss / 2);
+    assert_eq!(receiver.urgent_offset(), None);
+}
+
+#[test]
+fn test_poll_peek() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let receiver = Receiver::new(Wrapping(0), 0xffff, 0, 0xffff, MSS, 2);
+
+    assert!(receiver.poll_peek(&mut ctx).is_pending());
+    let segment = BytesMut::from(&vec![0x5a; MSS][..]).freeze();
+    receiver.receive_data(Wrapping(0), segment.clone(), now).unwrap();
+
+    // Peeking leaves the data in place for the next read.
+    must_let!(let Poll::Ready(Ok(peeked)) = receiver.poll_peek(&mut ctx));
+    assert_eq!(peeked, segment);
+    must_let!(let Poll::Ready(Ok(peeked)) = receiver.poll_peek(&mut ctx));
+    assert_eq!(peeked, segment);
+    must_let!(let Poll::Ready(Ok(received)) = receiver.poll_recv(&mut ctx));
+    assert_eq!(received, segment);
+    assert!(receiver.poll_peek(&mut ctx).is_pending());
+}
+
+#[test]
+fn test_peek_amount_merges_segments() {
+    let now = Instant::now();
+    let receiver = Receiver::new(Wrapping(0), 0xffff, 0, 0xffff, MSS, 2);
+    let mut seq_no = Wrapping(0);
+    for i in 0..3u8 {
+        let segment = BytesMut::from(&vec![i; 10][..]).freeze();
+        receiver.receive_data(seq_no, segment, now).unwrap();
+        seq_no += Wrapping(10);
+    }
+
+    // The front segment alone is enough.
+    assert_eq!(receiver.peek_amount(4).unwrap().len(), 10);
+
+    // Otherwise we merge whole segments until we have enough.
+    let peeked = receiver.peek_amount(15).unwrap();
+    assert_eq!(peeked.len(), 20);
+    assert_eq!(&peeked[..10], &[0; 10][..]);
+    assert_eq!(&peeked[10..], &[1; 10][..]);
+
+    // Asking for more than is available while the connection is open fails.
+    must_let!(let Err(Fail::ResourceExhausted { .. }) = receiver.peek_amount(31));
+
+    // Nothing was consumed.
+    assert_eq!(receiver.recv().unwrap().unwrap().len(), 10);
+}
+
+#[test]
+fn test_peek_amount_zero() {
+    let now = Instant::now();
+    let receiver = Receiver::new(Wrapping(0), 0xffff, 0, 0xffff, MSS, 2);
+
+    // Asking for nothing from an empty receiver gets nothing back.
+    assert!(receiver.peek_amount(0).unwrap().is_empty());
+
+    // With data queued, it gets the front segment, as for any amount it covers.
+    let segment = BytesMut::from(&vec![0x5a; 10][..]).freeze();
+    receiver.receive_data(Wrapping(0), segment.clone(), now).unwrap();
+    assert_eq!(receiver.peek_amount(0).unwrap(), segment);
+
+    // Once the remote has closed and everything's been read, it's an error like any other peek.
+    receiver.receive_fin(Wrapping(10)).unwrap();
+    receiver.recv().unwrap().unwrap();
+    must_let!(let Err(Fail::ResourceNotFound { .. }) = receiver.peek_amount(0));
+}
+
+#[test]
+fn test_peek_segments_and_consume() {
+    let now = Instant::now();
+    let receiver = Receiver::new(Wrapping(0), 0xffff, 0, 0xffff, MSS, 2);
+    let mut seq_no = Wrapping(0);
+    for i in 0..3u8 {
+        let segment = BytesMut::from(&vec![i; 10][..]).freeze();
+        receiver.receive_data(seq_no, segment, now).unwrap();
+        seq_no += Wrapping(10);
+    }
+
+    // Peeking sees every segment and leaves them in place.
+    let lens: Vec<usize> = receiver.peek_segments().unwrap().map(|s| s.len()).collect();
+    assert_eq!(lens, vec![10, 10, 10]);
+    assert_eq!(receiver.peek_segments().unwrap().count(), 3);
+
+    // Consuming across a segment boundary splits the segment it ends in.
+    receiver.consume(15).unwrap();
+    let segments: Vec<Bytes> = receiver.peek_segments().unwrap().collect();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(&segments[0][..], &[1; 5][..]);
+    assert_eq!(&segments[1][..], &[2; 10][..]);
+    assert_eq!(receiver.window_size(), 0xffff - 15);
+
+    must_let!(let Err(Fail::OutOfRange { .. }) = receiver.consume(16));
+    receiver.consume(15).unwrap();
+    assert_eq!(receiver.peek_segments().unwrap().count(), 0);
+    assert_eq!(receiver.recv().unwrap(), None);
+}
+
+// Builds a segment holding bytes `start..end` of a stream where byte `i` has value `i as u8`.
+fn stream_segment(start: usize, end: usize) -> Bytes {
+    let buf: Vec<u8> = (start..end).map(|i| i as u8).collect();
+    BytesMut::from(&buf[..]).freeze()
+}
+
+#[test]
+fn test_receive_left_overlap() {
+    let now = Instant::now();
+    let receiver = Receiver::new(Wrapping(0), 0xffff, 0, 0xffff, MSS, 2);
+    receiver.receive_data(Wrapping(0), stream_segment(0, 100), now).unwrap();
+    assert_eq!(receiver.recv().unwrap().unwrap(), stream_segment(0, 100));
+
+    // This segment starts before data we've already read, so only the last 50 bytes are new.
+    receiver.receive_data(Wrapping(50), stream_segment(50, 150), now).unwrap();
+    assert_eq!(receiver.recv_seq_no.get(), Wrapping(150));
+    assert_eq!(receiver.recv().unwrap().unwrap(), stream_segment(100, 150));
+}
+
+#[test]
+fn test_receive_right_overlap() {
+    let now = Instant::now();
+    let receiver = Receiver::new(Wrapping(0), 0xffff, 0, 0xffff, MSS, 2);
+    receiver.receive_data(Wrapping(0), stream_segment(0, 100), now).unwrap();
+
+    // This segment starts within unread data and extends past it.
+    receiver.receive_data(Wrapping(80), stream_segment(80, 120), now).unwrap();
+    assert_eq!(receiver.recv_seq_no.get(), Wrapping(120));
+    assert_eq!(receiver.recv().unwrap().unwrap(), stream_segment(0, 100));
+    assert_eq!(receiver.recv().unwrap().unwrap(), stream_segment(100, 120));
+
+    // Segments from beyond `recv_seq_no` are still dropped.
+    assert!(receiver.receive_data(Wrapping(130), stream_segment(130, 140), now).is_err());
+    assert_eq!(receiver.recv_seq_no.get(), Wrapping(120));
+}
+
+#[test]
+fn test_receive_full_duplicate() {
+    let now = Instant::now();
+    let receiver = Receiver::new(Wrapping(0), 0xffff, 0, 0xffff, MSS, 2);
+    receiver.receive_data(Wrapping(0), stream_segment(0, 100), now).unwrap();
+    receiver.ack_sent(Wrapping(100));
+
+    // A duplicate is dropped but schedules an immediate ACK.
+    let later = now + Duration::from_millis(1);
+    must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(20), stream_segment(20, 100), later));
+    assert_eq!(receiver.recv_seq_no.get(), Wrapping(100));
+    assert_eq!(receiver.ack_deadline.get(), Some(later));
+    assert_eq!(receiver.recv().unwrap().unwrap(), stream_segment(0, 100));
+}
+
+#[test]
+fn test_ack_ratio() {
+    let now = Instant::now();
+    let segment = BytesMut::from(&vec![0x5a; MSS][..]).freeze();
+    for &ack_ratio in &[2, 4] {
+        let receiver = Receiver::new(Wrapping(0), 0xffff, 0, 0xffff, MSS, ack_ratio);
+        for round in 0..2u32 {
+            // Every full-size segment short of the ratio waits on the delayed ACK timer.
+            for i in 0..ack_ratio as u32 {
+                let seq_no = Wrapping((round * ack_ratio as u32 + i) * MSS as u32);
+                receiver.receive_data(seq_no, segment.clone(), now).unwrap();
+                if i + 1 < ack_ratio as u32 {
+                    assert_eq!(receiver.ack_deadline.get(), Some(now + Duration::from_millis(500)));
+                }
+            }
+            // And the one that makes up the ratio is ACKed right away, which starts the count over.
+            assert_eq!(receiver.ack_deadline.get(), Some(now));
+            receiver.ack_sent(receiver.recv_seq_no.get());
+        }
+    }
+}
+
+#[test]
+fn test_ack_ratio_reset_by_short_segment() {
+    let now = Instant::now();
+    let delayed = Some(now + Duration::from_millis(500));
+    let full = BytesMut::from(&vec![0x5a; MSS][..]).freeze();
+    let short = BytesMut::from(&vec![0x5a; MSS / 2][..]).freeze();
+    let receiver = Receiver::new(Wrapping(0), 0xffff, 0, 0xffff, MSS, 4);
+
+    // Two full-size segments count towards the ratio, but the short one after them starts the count over. It
+    // still only waits on the delayed ACK timer.
+    receiver.receive_data(receiver.recv_seq_no.get(), full.clone(), now).unwrap();
+    receiver.receive_data(receiver.recv_seq_no.get(), full.clone(), now).unwrap();
+    receiver.receive_data(receiver.recv_seq_no.get(), short, now).unwrap();
+    assert_eq!(receiver.ack_deadline.get(), delayed);
+
+    // So it takes another four full-size segments, not two, before one is ACKed right away.
+    for _ in 0..3 {
+        receiver.receive_data(receiver.recv_seq_no.get(), full.clone(), now).unwrap();
+        assert_eq!(receiver.ack_deadline.get(), delayed);
+    }
+    receiver.receive_data(receiver.recv_seq_no.get(), full, now).unwrap();
+    assert_eq!(receiver.ack_deadline.get(), Some(now));
+}
+
+#[test]
+fn test_fin_after_unread_data() {
+    let now = Instant::now();
+    let counter = CountingWaker::new();
+    let waker = counter.waker();
+    let mut ctx = Context::from_waker(&waker);
+    let receiver = Receiver::new(Wrapping(0), 0xffff, 0, 0xffff, MSS, 2);
+    receiver.receive_data(Wrapping(0), stream_segment(0, 100), now).unwrap();
+
+    // The FIN overtakes the last data segment, so it can't close the receiver yet.
+    must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_fin(Wrapping(200)));
+    receiver.receive_data(Wrapping(100), stream_segment(100, 200), now).unwrap();
+    receiver.receive_fin(Wrapping(200)).unwrap();
+
+    // Everything that arrived before the FIN is still read before the end of the stream.
+    must_let!(let Poll::Ready(Ok(received)) = receiver.poll_recv(&mut ctx));
+    assert_eq!(received, stream_segment(0, 100));
+    assert_eq!(receiver.recv().unwrap().unwrap(), stream_segment(100, 200));
+    must_let!(let Err(Fail::ResourceNotFound { .. }) = receiver.recv());
+    must_let!(let Poll::Ready(Err(Fail::ResourceNotFound { .. })) = receiver.poll_recv(&mut ctx));
+
+    // A reader already waiting when the FIN arrives is woken up to see it.
+    let receiver = Receiver::new(Wrapping(0), 0xffff, 0, 0xffff, MSS, 2);
+    assert!(receiver.poll_recv(&mut ctx).is_pending());
+    receiver.receive_fin(Wrapping(0)).unwrap();
+    assert_eq!(counter.count(), 1);
+    must_let!(let Poll::Ready(Err(Fail::ResourceNotFound { .. })) = receiver.poll_recv(&mut ctx));
+}
@@ -0,0 +1,59 @@
+use crate::{
+    fail::Fail,
+    protocols::tcp::segment::{
+        parse_options,
+        TcpOptions2,
+    },
+};
+use must_let::must_let;
+
+fn parse(buf: &[u8]) -> Result<Vec<TcpOptions2>, Fail> {
+    parse_options(buf).collect()
+}
+
+#[test]
+fn test_parse_options() {
+    let buf = [
+        2, 4, 0x05, 0xb4, // MSS 1460
+        1, // NOP
+        3, 3, 7, // Window scale 7
+        4, 2, // SACK permitted
+        8, 10, 0, 0, 0, 1, 0, 0, 0, 2, // Timestamps 1 and 2
+        5, 10, 0, 0, 0, 10, 0, 0, 0, 20, // SACK of 10 to 20
+        0, 0, // EOL and padding
+    ];
+    let options = parse(&buf).unwrap();
+    assert_eq!(options.len(), 5);
+    must_let!(let TcpOptions2::MaximumSegmentSize(1460) = options[0]);
+    must_let!(let TcpOptions2::WindowScale(7) = options[1]);
+    must_let!(let TcpOptions2::SelectiveAcknowlegementPermitted = options[2]);
+    must_let!(let TcpOptions2::Timestamp { sender_timestamp: 1, echo_timestamp: 2 } = options[3]);
+    must_let!(let TcpOptions2::SelectiveAcknowlegement { num_sacks: 1, sacks } = options[4]);
+    assert_eq!((sacks[0].begin.0, sacks[0].end.0), (10, 20));
+
+    // The options can end without an EOL, and anything after one is ignored.
+    must_let!(let [TcpOptions2::WindowScale(2)] = &parse(&[3, 3, 2]).unwrap()[..]);
+    must_let!(let [] = &parse(&[0, 3, 3]).unwrap()[..]);
+
+    // Options we don't know are skipped.
+    must_let!(let [TcpOptions2::MaximumSegmentSize(536)] = &parse(&[30, 4, 0xff, 0xff, 2, 4, 0x02, 0x18]).unwrap()[..]);
+}
+
+#[test]
+fn test_parse_malformed_options() {
+    // Truncated partway through an option, or before its length.
+    must_let!(let Err(Fail::Malformed { .. }) = parse(&[2, 4, 0x05]));
+    must_let!(let Err(Fail::Malformed { .. }) = parse(&[1, 2]));
+    must_let!(let Err(Fail::Malformed { .. }) = parse(&[30, 6, 0, 0]));
+
+    // Lengths that don't fit the option, including ones that would never move past it.
+    must_let!(let Err(Fail::Malformed { .. }) = parse(&[2, 3, 0x05, 0]));
+    must_let!(let Err(Fail::Malformed { .. }) = parse(&[5, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    must_let!(let Err(Fail::Malformed { .. }) = parse(&[30, 0]));
+    must_let!(let Err(Fail::Malformed { .. }) = parse(&[30, 1, 0]));
+
+    // Nothing is parsed past the first malformed option.
+    let mut options = parse_options(&[2, 3, 0x05, 3, 3, 2]);
+    must_let!(let Some(Err(Fail::Malformed { .. })) = options.next());
+    assert!(options.next().is_none());
+}
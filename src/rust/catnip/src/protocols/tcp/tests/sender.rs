@@ -0,0 +1,213 @@
+use super::cc_harness::{
+    push_unacked,
+    BUFFER_SIZE,
+    MSS,
+};
+use crate::{
+    fail::Fail,
+    protocols::tcp::{
+        congestion_ctrl::{
+            self as cc,
+            CongestionControl,
+        },
+        established::state::sender::Sender,
+    },
+    sync::BytesMut,
+    test_helpers::CountingWaker,
+};
+use must_let::must_let;
+use std::{
+    num::Wrapping,
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[test]
+fn test_karn_ignores_ack_covering_retransmission() {
+    let now = Instant::now();
+    let base_seq_no = Wrapping(0);
+    let sender = Sender::new(base_seq_no, 0xffff, 0, MSS, BUFFER_SIZE, cc::None::new, None);
+    let initial_rto = sender.current_rto();
+
+    // The first segment was retransmitted, the second was only sent once.
+    push_unacked(&sender, None);
+    push_unacked(&sender, Some(now));
+
+    // A cumulative ACK covering both could have been triggered by the retransmission, so it's ambiguous.
+    let later = now + Duration::from_secs(5);
+    sender.remote_ack(base_seq_no + Wrapping(2 * MSS as u32), later).unwrap();
+    assert_eq!(sender.current_rto(), initial_rto);
+
+    // Once a segment that was never retransmitted is cleanly acked, we sample again.
+    push_unacked(&sender, Some(later));
+    let ack_time = later + Duration::from_millis(10);
+    sender.remote_ack(base_seq_no + Wrapping(3 * MSS as u32), ack_time).unwrap();
+    assert!(sender.current_rto() < initial_rto);
+}
+
+#[test]
+fn test_fixed_rto() {
+    let now = Instant::now();
+    let base_seq_no = Wrapping(0);
+    let sender = Sender::new(base_seq_no, 0xffff, 0, MSS, BUFFER_SIZE, cc::None::new, None);
+    let fixed_rto = Duration::from_millis(250);
+    sender.rto.borrow_mut().set_fixed(Some(fixed_rto));
+    assert_eq!(sender.current_rto(), fixed_rto);
+
+    // Samples still move the estimate, but the RTO we use stays put, and doesn't back off on timeouts either.
+    let initial_estimate = sender.rto_estimate();
+    push_unacked(&sender, Some(now));
+    sender.remote_ack(base_seq_no + Wrapping(MSS as u32), now + Duration::from_millis(10)).unwrap();
+    assert!(sender.rto_estimate() < initial_estimate);
+    assert_eq!(sender.current_rto(), fixed_rto);
+    sender.rto.borrow_mut().record_failure();
+    assert_eq!(sender.current_rto(), fixed_rto);
+
+    sender.rto.borrow_mut().set_fixed(None);
+    assert_eq!(sender.current_rto(), sender.rto_estimate());
+}
+
+#[test]
+fn test_ack_within_segment() {
+    let now = Instant::now();
+    let base_seq_no = Wrapping(0);
+    let sender = Sender::new(base_seq_no, 0xffff, 0, MSS, BUFFER_SIZE, cc::None::new, None);
+    push_unacked(&sender, Some(now));
+    push_unacked(&sender, Some(now));
+
+    // ACK half of the first segment.
+    let half = MSS / 2;
+    sender.remote_ack(base_seq_no + Wrapping(half as u32), now).unwrap();
+    assert_eq!(sender.base_seq_no.get(), base_seq_no + Wrapping(half as u32));
+    {
+        let unacked_queue = sender.unacked_queue.borrow();
+        assert_eq!(unacked_queue.len(), 2);
+        assert_eq!(unacked_queue[0].bytes.len(), MSS - half);
+        assert_eq!(unacked_queue[1].bytes.len(), MSS);
+    }
+
+    // Then the rest of it along with part of the next one.
+    sender.remote_ack(base_seq_no + Wrapping((MSS + half) as u32), now).unwrap();
+    assert_eq!(sender.base_seq_no.get(), base_seq_no + Wrapping((MSS + half) as u32));
+    let unacked_queue = sender.unacked_queue.borrow();
+    assert_eq!(unacked_queue.len(), 1);
+    assert_eq!(unacked_queue[0].bytes.len(), MSS - half);
+}
+
+#[test]
+fn test_pop_unsent_coalesces_buffers() {
+    let sender = Sender::new(Wrapping(0), 0xffff, 0, MSS, BUFFER_SIZE, cc::None::new, None);
+    for i in 0..4u8 {
+        let buf = BytesMut::from(&vec![i; 400][..]).freeze();
+        sender.unsent_queue.borrow_mut().push_back(buf);
+    }
+
+    // The first segment is filled from three and a half buffers.
+    let segment = sender.pop_unsent(MSS).unwrap();
+    assert_eq!(segment.len(), MSS);
+    assert!(segment[..400].iter().all(|&b| b == 0));
+    assert!(segment[1200..].iter().all(|&b| b == 3));
+
+    // Leaving the remainder of the last one behind.
+    let segment = sender.pop_unsent(MSS).unwrap();
+    assert_eq!(segment.len(), 4 * 400 - MSS);
+    assert!(segment.iter().all(|&b| b == 3));
+    assert!(sender.pop_unsent(MSS).is_none());
+}
+
+#[test]
+fn test_send_window_occupancy() {
+    let now = Instant::now();
+    let base_seq_no = Wrapping(0);
+    let window_size = 4 * MSS as u32;
+    let sender = Sender::new(base_seq_no, window_size, 0, MSS, BUFFER_SIZE, cc::None::new, None);
+    push_unacked(&sender, Some(now));
+    push_unacked(&sender, Some(now));
+    sender.unsent_seq_no.modify(|s| s + Wrapping(100));
+
+    assert_eq!(sender.bytes_in_flight(), 2 * MSS as u32);
+    assert_eq!(sender.bytes_unsent(), 100);
+    assert_eq!(sender.send_window_available(), 2 * MSS as u32);
+
+    sender.remote_ack(base_seq_no + Wrapping(MSS as u32), now).unwrap();
+    assert_eq!(sender.bytes_in_flight(), MSS as u32);
+    assert_eq!(sender.send_window_available(), 3 * MSS as u32);
+}
+
+#[test]
+fn test_ack_latencies() {
+    let now = Instant::now();
+    let base_seq_no = Wrapping(0);
+    let sender = Sender::new(base_seq_no, 0xffff, 0, MSS, BUFFER_SIZE, cc::None::new, None);
+
+    // One ACK covering three segments gives a sample for each, except the one that was retransmitted.
+    push_unacked(&sender, Some(now));
+    push_unacked(&sender, None);
+    push_unacked(&sender, Some(now + Duration::from_millis(5)));
+    // Half of a fourth segment isn't acknowledged yet.
+    push_unacked(&sender, Some(now + Duration::from_millis(5)));
+    let ack_time = now + Duration::from_millis(20);
+    sender.remote_ack(base_seq_no + Wrapping(7 * MSS as u32 / 2), ack_time).unwrap();
+    assert_eq!(sender.ack_latencies(), vec![Duration::from_millis(20), Duration::from_millis(15)]);
+
+    sender.remote_ack(base_seq_no + Wrapping(4 * MSS as u32), ack_time + Duration::from_millis(5)).unwrap();
+    assert_eq!(sender.ack_latencies().last(), Some(&Duration::from_millis(20)));
+
+    // Only the most recent samples are kept.
+    for i in 0..2000 {
+        push_unacked(&sender, Some(now));
+        let ack_seq_no = sender.sent_seq_no.get();
+        sender.remote_ack(ack_seq_no, now + Duration::from_micros(i)).unwrap();
+    }
+    let latencies = sender.ack_latencies();
+    assert!(latencies.len() < 2000);
+    assert_eq!(latencies.last(), Some(&Duration::from_micros(1999)));
+}
+
+#[test]
+fn test_out_of_window_acks() {
+    let now = Instant::now();
+    let base_seq_no = Wrapping(1 << 20);
+    let sender = Sender::new(base_seq_no, 0xffff, 0, MSS, BUFFER_SIZE, cc::Cubic::new, None);
+    push_unacked(&sender, Some(now));
+    push_unacked(&sender, Some(now));
+    sender.remote_ack(base_seq_no + Wrapping(MSS as u32), now).unwrap();
+
+    // An ACK from before what's already been acknowledged is quietly ignored, and isn't counted as a duplicate.
+    sender.remote_ack(base_seq_no, now).unwrap();
+    assert_eq!(sender.congestion_ctrl.get_duplicate_ack_count(), 0);
+    assert_eq!(sender.base_seq_no.get(), base_seq_no + Wrapping(MSS as u32));
+
+    // An ACK for data that hasn't been sent yet is an error, and leaves everything as it was too.
+    let unsent = base_seq_no + Wrapping(3 * MSS as u32);
+    must_let!(let Err(Fail::Invalid { .. }) = sender.remote_ack(unsent, now));
+    assert_eq!(sender.base_seq_no.get(), base_seq_no + Wrapping(MSS as u32));
+    assert_eq!(sender.unacked_queue.borrow().len(), 1);
+}
+
+#[test]
+fn test_concurrent_flushes_both_woken() {
+    let now = Instant::now();
+    let base_seq_no = Wrapping(0);
+    let sender = Sender::new(base_seq_no, 0xffff, 0, MSS, BUFFER_SIZE, cc::None::new, None);
+    push_unacked(&sender, Some(now));
+
+    // Two tasks wait on the same connection. The second mustn't take the first one's place.
+    let wakers = [CountingWaker::new(), CountingWaker::new()];
+    for waker in &wakers {
+        let waker = waker.waker();
+        let mut ctx = Context::from_waker(&waker);
+        must_let!(let Poll::Pending = sender.poll_flush(&mut ctx));
+    }
+
+    sender.remote_ack(base_seq_no + Wrapping(MSS as u32), now).unwrap();
+    for waker in &wakers {
+        assert_eq!(waker.count(), 1);
+    }
+}
@@ -11,7 +11,10 @@ use crate::{
         Scheduler,
         SchedulerHandle,
     },
-    sync::Bytes,
+    sync::{
+        Bytes,
+        BytesMut,
+    },
 };
 use rand::distributions::{
     Distribution,
@@ -29,12 +32,39 @@ use std::{
 pub trait PacketBuf {
     fn compute_size(&self) -> usize;
     fn serialize(&self, buf: &mut [u8]);
+
+    fn to_bytes(&self) -> Bytes {
+        let size = self.compute_size();
+        let mut buf = BytesMut::zeroed(size);
+        self.serialize(&mut buf[..]);
+        buf.freeze()
+    }
+}
+
+// A frame that's already been serialized, such as one replayed from a capture or crafted by hand, transmitted
+// byte for byte: it's neither padded nor checked.
+#[derive(Clone, Debug)]
+pub struct RawFrame(pub Bytes);
+
+impl PacketBuf for RawFrame {
+    fn compute_size(&self) -> usize {
+        self.0.len()
+    }
+
+    fn serialize(&self, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.0[..]);
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        self.0.clone()
+    }
 }
 
 pub trait Runtime: Clone + Unpin + 'static {
     fn advance_clock(&self, now: Instant);
     fn transmit(&self, pkt: impl PacketBuf);
-    fn receive(&self) -> Option<Bytes>;
+    // Returns the next received frame along with when it came off the wire.
+    fn receive(&self) -> Option<(Bytes, Instant)>;
 
     fn local_link_addr(&self) -> MacAddress;
     fn local_ipv4_addr(&self) -> Ipv4Addr;
@@ -53,3 +83,90 @@ pub trait Runtime: Clone + Unpin + 'static {
     fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) -> SchedulerHandle;
     fn scheduler(&self) -> &Scheduler<Operation<Self>>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        PacketBuf,
+        RawFrame,
+    };
+    use crate::{
+        protocols::{
+            ethernet2::frame::{
+                EtherType2,
+                Ethernet2Header,
+            },
+            ip,
+            ipv4::datagram::{
+                Ipv4Header,
+                Ipv4Protocol2,
+            },
+            tcp::segment::{
+                TcpHeader,
+                TcpOptions2,
+                TcpSegment,
+            },
+        },
+        sync::BytesMut,
+        test_helpers::{
+            ALICE_IPV4,
+            ALICE_MAC,
+            BOB_IPV4,
+            BOB_MAC,
+        },
+        runtime::Runtime,
+        test_helpers,
+    };
+    use std::{
+        convert::TryFrom,
+        time::Instant,
+    };
+
+    // Serializing must write every byte of the frame, padding included, so nothing stale in the buffer reaches the
+    // wire.
+    #[test]
+    fn test_serialize_covers_buffer() {
+        let mut tcp_hdr = TcpHeader::new(ip::Port::try_from(80).unwrap(), ip::Port::try_from(12345).unwrap());
+        tcp_hdr.syn = true;
+        tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(1460));
+        tcp_hdr.push_option(TcpOptions2::WindowScale(7));
+        let segment = TcpSegment {
+            ethernet2_hdr: Ethernet2Header {
+                dst_addr: BOB_MAC,
+                src_addr: ALICE_MAC,
+                ether_type: EtherType2::Ipv4,
+            },
+            ipv4_hdr: Ipv4Header::new(ALICE_IPV4, BOB_IPV4, Ipv4Protocol2::Tcp),
+            tcp_hdr,
+            // Short enough that the frame needs Ethernet padding.
+            data: BytesMut::from(&b"abc"[..]).freeze(),
+        };
+
+        let size = segment.compute_size();
+        let serialize_over = |fill: u8| {
+            let mut buf = BytesMut::zeroed(size);
+            for b in &mut buf[..] {
+                *b = fill;
+            }
+            segment.serialize(&mut buf[..]);
+            buf.freeze()
+        };
+        let zeros = serialize_over(0x00);
+        assert_eq!(zeros, serialize_over(0xff));
+        assert_eq!(zeros, segment.to_bytes());
+    }
+
+    #[test]
+    fn test_raw_frame() {
+        let bytes = BytesMut::from(&b"not even an ethernet header"[..]).freeze();
+        let frame = RawFrame(bytes.clone());
+        assert_eq!(frame.compute_size(), bytes.len());
+        let mut buf = BytesMut::zeroed(bytes.len());
+        frame.serialize(&mut buf[..]);
+        assert_eq!(buf.freeze(), bytes);
+
+        let alice = test_helpers::new_alice(Instant::now());
+        alice.rt().transmit(frame);
+        assert_eq!(alice.rt().pop_frame(), bytes);
+    }
+}
@@ -18,7 +18,10 @@ use crate::{
 };
 use gen_iter::gen_iter;
 use std::{
-    cell::RefCell,
+    cell::{
+        RefCell,
+        RefMut,
+    },
     future::Future,
     pin::Pin,
     rc::Rc,
@@ -113,6 +116,7 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
             slab: PinSlab::new(),
             pages: vec![],
             root_waker: SharedWaker::new(),
+            next_ix: 0,
         };
         Self {
             inner: Rc::new(RefCell::new(inner)),
@@ -162,35 +166,86 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
             };
             if notified != 0 {
                 for subpage_ix in iter_set_bits(notified) {
-                    let ix = page_ix * WAKER_PAGE_SIZE + subpage_ix;
-                    let waker =
-                        unsafe { Waker::from_raw(inner.pages[page_ix].raw_waker(subpage_ix)) };
-                    let mut sub_ctx = Context::from_waker(&waker);
-
-                    let pinned_ref = inner.slab.get_pin_mut(ix).unwrap();
-                    let pinned_ptr = unsafe { Pin::into_inner_unchecked(pinned_ref) as *mut _ };
-
-                    drop(inner);
-                    let pinned_ref = unsafe { Pin::new_unchecked(&mut *pinned_ptr) };
-                    let poll_result = {
-                        Future::poll(pinned_ref, &mut sub_ctx)
-                    };
-                    inner = self.inner.borrow_mut();
-
-                    match poll_result {
-                        Poll::Ready(()) => inner.pages[page_ix].mark_completed(subpage_ix),
-                        Poll::Pending => (),
-                    }
+                    inner = self.poll_one(inner, page_ix, subpage_ix);
                 }
             }
             if dropped != 0 {
-                for subpage_ix in iter_set_bits(dropped) {
-                    let ix = page_ix * WAKER_PAGE_SIZE + subpage_ix;
-                    inner.slab.remove(ix);
-                    inner.pages[page_ix].clear(subpage_ix);
+                inner.remove_dropped(page_ix, dropped);
+            }
+        }
+    }
+
+    // Polls at most `max_ops` ready operations and returns how many ran. Each call picks up where the last one
+    // left off, so a caller that keeps its budget small still gets round-robin progress across every operation.
+    pub fn poll_budget(&self, max_ops: usize) -> usize {
+        let _s = static_span!();
+        let mut inner = self.inner.borrow_mut();
+        let num_pages = inner.pages.len();
+        if num_pages == 0 {
+            return 0;
+        }
+        let start_ix = inner.next_ix % (num_pages * WAKER_PAGE_SIZE);
+        let (start_page, start_subpage) = (start_ix / WAKER_PAGE_SIZE, start_ix % WAKER_PAGE_SIZE);
+        let high_mask = !0u64 << start_subpage;
+
+        let mut ops = 0;
+        // Walk the pages from the cursor around to where we started, finishing with the part of the first page
+        // that's before the cursor.
+        for i in 0..=num_pages {
+            let page_ix = (start_page + i) % num_pages;
+            let mask = match i {
+                0 => high_mask,
+                i if i == num_pages => !high_mask,
+                _ => !0,
+            };
+            let (notified, dropped) = {
+                let page = &inner.pages[page_ix];
+                let notified = page.take_notified();
+                page.restore_notified(notified & !mask);
+                (notified & mask, page.take_dropped())
+            };
+            if dropped != 0 {
+                inner.remove_dropped(page_ix, dropped);
+            }
+            for subpage_ix in iter_set_bits(notified) {
+                if ops == max_ops {
+                    let remaining = notified & (!0u64 << subpage_ix);
+                    inner.pages[page_ix].restore_notified(remaining);
+                    inner.next_ix = page_ix * WAKER_PAGE_SIZE + subpage_ix;
+                    return ops;
                 }
+                inner = self.poll_one(inner, page_ix, subpage_ix);
+                ops += 1;
             }
         }
+        ops
+    }
+
+    fn poll_one<'a>(
+        &'a self,
+        mut inner: RefMut<'a, Inner<F>>,
+        page_ix: usize,
+        subpage_ix: usize,
+    ) -> RefMut<'a, Inner<F>> {
+        let ix = page_ix * WAKER_PAGE_SIZE + subpage_ix;
+        let waker = unsafe { Waker::from_raw(inner.pages[page_ix].raw_waker(subpage_ix)) };
+        let mut sub_ctx = Context::from_waker(&waker);
+
+        let pinned_ref = inner.slab.get_pin_mut(ix).unwrap();
+        let pinned_ptr = unsafe { Pin::into_inner_unchecked(pinned_ref) as *mut _ };
+
+        drop(inner);
+        let pinned_ref = unsafe { Pin::new_unchecked(&mut *pinned_ptr) };
+        let poll_result = {
+            Future::poll(pinned_ref, &mut sub_ctx)
+        };
+        inner = self.inner.borrow_mut();
+
+        match poll_result {
+            Poll::Ready(()) => inner.pages[page_ix].mark_completed(subpage_ix),
+            Poll::Pending => (),
+        }
+        inner
     }
 }
 
@@ -198,6 +253,8 @@ struct Inner<F: Future<Output = ()> + Unpin> {
     slab: PinSlab<F>,
     pages: Vec<WakerPageRef>,
     root_waker: SharedWaker,
+    // Where `poll_budget` resumes.
+    next_ix: usize,
 }
 
 impl<F: Future<Output = ()> + Unpin> Inner<F> {
@@ -207,6 +264,14 @@ impl<F: Future<Output = ()> + Unpin> Inner<F> {
         (&self.pages[page_ix], subpage_ix)
     }
 
+    fn remove_dropped(&mut self, page_ix: usize, dropped: u64) {
+        for subpage_ix in iter_set_bits(dropped) {
+            let ix = page_ix * WAKER_PAGE_SIZE + subpage_ix;
+            self.slab.remove(ix);
+            self.pages[page_ix].clear(subpage_ix);
+        }
+    }
+
     fn insert(&mut self, future: F) -> u64 {
         let key = self.slab.insert(future);
         while key >= self.pages.len() * WAKER_PAGE_SIZE {
@@ -217,3 +282,56 @@ impl<F: Future<Output = ()> + Unpin> Inner<F> {
         key as u64
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Scheduler;
+    use std::{
+        cell::Cell,
+        future::Future,
+        pin::Pin,
+        rc::Rc,
+        task::{
+            Context,
+            Poll,
+        },
+    };
+
+    // Never finishes, but asks to be polled again every time it's polled.
+    struct Spin(Rc<Cell<usize>>);
+
+    impl Future for Spin {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+            self.0.set(self.0.get() + 1);
+            ctx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_poll_budget_round_robin() {
+        let scheduler = Scheduler::new();
+        let counts = (0..3).map(|_| Rc::new(Cell::new(0))).collect::<Vec<_>>();
+        let _handles = counts
+            .iter()
+            .map(|c| scheduler.insert(Spin(c.clone())))
+            .collect::<Vec<_>>();
+        let polls = || counts.iter().map(|c| c.get()).collect::<Vec<_>>();
+
+        assert_eq!(scheduler.poll_budget(2), 2);
+        assert_eq!(polls(), vec![1, 1, 0]);
+
+        // The next call starts with the one that was left out, then wraps around.
+        assert_eq!(scheduler.poll_budget(2), 2);
+        assert_eq!(polls(), vec![2, 1, 1]);
+
+        // With room to spare, everything that's ready runs exactly once.
+        assert_eq!(scheduler.poll_budget(10), 3);
+        assert_eq!(polls(), vec![3, 2, 2]);
+
+        assert_eq!(scheduler.poll_budget(0), 0);
+        assert_eq!(polls(), vec![3, 2, 2]);
+    }
+}
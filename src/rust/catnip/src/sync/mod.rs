@@ -1,8 +1,23 @@
 mod threadsafe;
 mod threadunsafe;
 
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    // Buffers allocated (and zeroed) so far; never exceeds the pool's limit.
+    pub allocated: usize,
+    // Requests served from a buffer that had been released.
+    pub reused: usize,
+    // Buffers that are still referenced by someone outside the pool.
+    pub in_use: usize,
+    // Requests turned away because every buffer was in use and the pool was full.
+    pub exhausted: usize,
+    // Requests turned away because the data was larger than a pool buffer.
+    pub oversized: usize,
+}
+
 #[cfg(feature = "threadunsafe")]
 pub use self::threadunsafe::{
+    BufferPool,
     Bytes,
     BytesMut,
     SharedWaker,
@@ -11,8 +26,74 @@ pub use self::threadunsafe::{
 
 #[cfg(not(feature = "threadunsafe"))]
 pub use self::threadsafe::{
+    BufferPool,
     Bytes,
     BytesMut,
     SharedWaker,
     WakerU64,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BufferPool,
+        BufferPoolStats,
+        Bytes,
+        BytesMut,
+    };
+    use crate::fail::Fail;
+    use must_let::must_let;
+
+    #[test]
+    fn test_buffer_pool_reuse() {
+        let mut pool = BufferPool::new(8, 2);
+        let a = pool.copy_from(b"abc").unwrap();
+        let b = pool.copy_from(b"defgh").unwrap();
+        assert_eq!(&a[..], b"abc");
+        assert_eq!(&b[..], b"defgh");
+
+        // Both buffers are still referenced, so the pool can't hand out any more.
+        assert!(pool.copy_from(b"x").is_none());
+        assert!(pool.copy_from(b"way too long").is_none());
+
+        // Once every reference to a buffer is gone, it's reused without disturbing the one still in use.
+        let (prefix, suffix) = a.split(1);
+        drop(prefix);
+        assert!(pool.copy_from(b"x").is_none());
+        drop(suffix);
+        let c = pool.copy_from(b"ijkl").unwrap();
+        assert_eq!(&c[..], b"ijkl");
+        assert_eq!(&b[..], b"defgh");
+
+        assert_eq!(
+            pool.stats(),
+            BufferPoolStats {
+                allocated: 2,
+                reused: 1,
+                in_use: 2,
+                exhausted: 2,
+                oversized: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_try_split() {
+        let buf = BytesMut::from(&b"abcde"[..]).freeze();
+        let (prefix, suffix) = buf.clone().try_split(2).unwrap();
+        assert_eq!(&prefix[..], b"ab");
+        assert_eq!(&suffix[..], b"cde");
+
+        // Either end is fine, but past it isn't.
+        let (prefix, suffix) = buf.clone().try_split(5).unwrap();
+        assert_eq!(&prefix[..], b"abcde");
+        assert!(suffix.is_empty());
+        let (prefix, suffix) = buf.clone().try_split(0).unwrap();
+        assert!(prefix.is_empty());
+        assert_eq!(&suffix[..], b"abcde");
+        must_let!(let Err(Fail::OutOfRange { .. }) = buf.try_split(6));
+
+        assert!(Bytes::empty().try_split(0).is_ok());
+        must_let!(let Err(Fail::OutOfRange { .. }) = Bytes::empty().try_split(1));
+    }
+}
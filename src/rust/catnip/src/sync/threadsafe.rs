@@ -1,4 +1,6 @@
 #![allow(unused)]
+use super::BufferPoolStats;
+use crate::fail::Fail;
 use futures::task::AtomicWaker;
 use std::{
     fmt,
@@ -100,12 +102,14 @@ impl Bytes {
         }
     }
 
+    // Splits the buffer into its first `ix` bytes and the rest, without copying. Panics if `ix` is past the end, so
+    // use `try_split` for lengths that come from the wire or the application.
     pub fn split(self, ix: usize) -> (Self, Self) {
         if ix == self.len() {
             return (self, Bytes::empty());
         }
         let buf = self.buf.expect("Can't split an empty buffer");
-        assert!(ix < self.len);
+        assert!(ix < self.len, "Split index {} past the end of a {} byte buffer", ix, self.len);
         let prefix = Self {
             buf: Some(buf.clone()),
             offset: self.offset,
@@ -118,6 +122,15 @@ impl Bytes {
         };
         (prefix, suffix)
     }
+
+    pub fn try_split(self, ix: usize) -> Result<(Self, Self), Fail> {
+        if ix > self.len {
+            return Err(Fail::OutOfRange {
+                details: "Split index past the end of the buffer",
+            });
+        }
+        Ok(self.split(ix))
+    }
 }
 
 impl Deref for Bytes {
@@ -150,6 +163,8 @@ impl PartialEq for BytesMut {
 impl Eq for BytesMut {}
 
 impl BytesMut {
+    // Allocate a buffer of `capacity` zeroes to write into. `capacity` must be nonzero; use `Bytes::empty` for an
+    // empty buffer.
     pub fn zeroed(capacity: usize) -> Self {
         assert!(capacity > 0);
         Self {
@@ -157,6 +172,7 @@ impl BytesMut {
         }
     }
 
+    // Hands the buffer over as read-only `Bytes`, without copying.
     pub fn freeze(self) -> Bytes {
         Bytes {
             offset: 0,
@@ -187,3 +203,78 @@ impl DerefMut for BytesMut {
         Arc::get_mut(&mut self.buf).unwrap()
     }
 }
+
+// A fixed set of equally sized buffers that are handed out as `Bytes` and reused once every reference to them has
+// been dropped. Buffers are only zeroed when they're first allocated, and the pool never grows past `max_buffers`.
+pub struct BufferPool {
+    buffer_size: usize,
+    max_buffers: usize,
+    buffers: Vec<Arc<[u8]>>,
+    next: usize,
+    stats: BufferPoolStats,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, max_buffers: usize) -> Self {
+        assert!(buffer_size > 0);
+        Self {
+            buffer_size,
+            max_buffers,
+            buffers: Vec::with_capacity(max_buffers),
+            next: 0,
+            stats: BufferPoolStats::default(),
+        }
+    }
+
+    // Copy `data` into a free buffer from the pool. Returns `None` if `data` doesn't fit in a pool buffer or every
+    // buffer is still referenced and the pool is already at its limit.
+    pub fn copy_from(&mut self, data: &[u8]) -> Option<Bytes> {
+        if data.len() > self.buffer_size {
+            self.stats.oversized += 1;
+            return None;
+        }
+        let ix = match self.find_free() {
+            Some(ix) => {
+                self.stats.reused += 1;
+                ix
+            },
+            None if self.buffers.len() < self.max_buffers => {
+                self.buffers.push(unsafe { Arc::new_zeroed_slice(self.buffer_size).assume_init() });
+                self.stats.allocated += 1;
+                self.buffers.len() - 1
+            },
+            None => {
+                self.stats.exhausted += 1;
+                return None;
+            },
+        };
+        let buf = &mut self.buffers[ix];
+        Arc::get_mut(buf).expect("Free buffer still referenced")[..data.len()].copy_from_slice(data);
+        Some(Bytes {
+            buf: Some(buf.clone()),
+            offset: 0,
+            len: data.len(),
+        })
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            in_use: self.buffers.iter().filter(|b| Arc::strong_count(b) > 1).count(),
+            ..self.stats.clone()
+        }
+    }
+
+    // Scan round-robin from where we last stopped, so recently released buffers get some time to cool off and we
+    // don't rescan the same busy prefix every time.
+    fn find_free(&mut self) -> Option<usize> {
+        let n = self.buffers.len();
+        for i in 0..n {
+            let ix = (self.next + i) % n;
+            if Arc::strong_count(&self.buffers[ix]) == 1 {
+                self.next = (ix + 1) % n;
+                return Some(ix);
+            }
+        }
+        None
+    }
+}
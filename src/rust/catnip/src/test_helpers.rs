@@ -17,16 +17,17 @@ use crate::{
         Scheduler,
         SchedulerHandle,
     },
-    sync::{
-        Bytes,
-        BytesMut,
-    },
+    sync::Bytes,
     timer::{
         Timer,
         TimerRc,
     },
 };
 use futures::{
+    task::{
+        self,
+        ArcWake,
+    },
     FutureExt,
 };
 use rand::{
@@ -40,10 +41,19 @@ use rand::{
 };
 use std::{
     cell::RefCell,
+    cmp,
     collections::VecDeque,
     future::Future,
     net::Ipv4Addr,
     rc::Rc,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    },
+    task::Waker,
     time::{
         Duration,
         Instant,
@@ -60,6 +70,30 @@ pub const CARRIE_IPV4: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 3);
 
 pub type TestEngine = Engine<TestRuntime>;
 
+// Snapshot of the frames and bytes that have passed through a `TestRuntime`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub tx_packets: usize,
+    pub tx_bytes: usize,
+    pub rx_packets: usize,
+    pub rx_bytes: usize,
+    // Frames discarded by fault injection before reaching the wire.
+    pub tx_dropped: usize,
+}
+
+// Faults applied to frames as the runtime transmits them, so tests can exercise loss, delay and reordering
+// without any external tooling. Each fault is drawn from an RNG seeded with `seed`, so runs are reproducible.
+#[derive(Clone, Debug, Default)]
+pub struct FaultInjection {
+    // Fraction of frames silently dropped.
+    pub drop_probability: f64,
+    // Fraction of frames that jump ahead of the frame transmitted just before them.
+    pub reorder_probability: f64,
+    // If set, frames are held back until the clock advances past this delay.
+    pub delay: Option<Duration>,
+    pub seed: [u8; 16],
+}
+
 #[derive(Clone)]
 pub struct TestRuntime {
     inner: Rc<RefCell<Inner>>,
@@ -87,6 +121,11 @@ impl TestRuntime {
             rng: SmallRng::from_seed([0; 16]),
             incoming: VecDeque::new(),
             outgoing: VecDeque::new(),
+            faults: None,
+            fault_rng: SmallRng::from_seed([0; 16]),
+            delayed: VecDeque::new(),
+            metrics: Metrics::default(),
+            auto_advance_clock: false,
             link_addr,
             ipv4_addr,
             tcp_options: tcp::Options::default(),
@@ -102,14 +141,61 @@ impl TestRuntime {
         self.inner.borrow_mut().outgoing.pop_front().unwrap()
     }
 
+    pub fn try_pop_frame(&self) -> Option<Bytes> {
+        self.inner.borrow_mut().outgoing.pop_front()
+    }
+
     pub fn push_frame(&self, buf: Bytes) {
-        self.inner.borrow_mut().incoming.push_back(buf);
+        let mut inner = self.inner.borrow_mut();
+        let now = inner.timer.0.now();
+        inner.incoming.push_back((buf, now));
+    }
+
+    pub fn set_tcp_options(&self, options: tcp::Options) {
+        self.inner.borrow_mut().tcp_options = options;
+    }
+
+    // Moves the runtime to new addresses, e.g. to follow a connection that's been rebound.
+    pub fn set_local_addrs(&self, link_addr: MacAddress, ipv4_addr: Ipv4Addr) {
+        let mut inner = self.inner.borrow_mut();
+        inner.link_addr = link_addr;
+        inner.ipv4_addr = ipv4_addr;
+    }
+
+    pub fn set_fault_injection(&self, faults: FaultInjection) {
+        let mut inner = self.inner.borrow_mut();
+        inner.fault_rng = SmallRng::from_seed(faults.seed);
+        inner.faults = Some(faults);
+    }
+
+    // When enabled, the clock follows `Instant::now()` on every `poll_scheduler`, for running the runtime in real
+    // time without having to call `advance_clock` by hand. Tests that simulate time should leave this off.
+    pub fn set_auto_advance_clock(&self, enabled: bool) {
+        self.inner.borrow_mut().auto_advance_clock = enabled;
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.inner.borrow().metrics
     }
 
     pub fn poll_scheduler(&self) {
         // let mut ctx = Context::from_waker(noop_waker_ref());
+        self.maybe_advance_clock();
         self.scheduler.poll();
     }
+
+    pub fn poll_scheduler_budget(&self, max_ops: usize) -> usize {
+        self.maybe_advance_clock();
+        self.scheduler.poll_budget(max_ops)
+    }
+
+    fn maybe_advance_clock(&self) {
+        if self.inner.borrow().auto_advance_clock {
+            // The clock may have been advanced past real time by hand, and it can't go backwards.
+            let now = cmp::max(Instant::now(), self.now());
+            self.advance_clock(now);
+        }
+    }
 }
 
 struct Inner {
@@ -117,27 +203,75 @@ struct Inner {
     name: &'static str,
     timer: TimerRc,
     rng: SmallRng,
-    incoming: VecDeque<Bytes>,
+    incoming: VecDeque<(Bytes, Instant)>,
     outgoing: VecDeque<Bytes>,
 
+    faults: Option<FaultInjection>,
+    fault_rng: SmallRng,
+    // Frames held back by `FaultInjection::delay`, in transmission order along with their release time.
+    delayed: VecDeque<(Instant, Bytes)>,
+    metrics: Metrics,
+    auto_advance_clock: bool,
+
     link_addr: MacAddress,
     ipv4_addr: Ipv4Addr,
     tcp_options: tcp::Options,
     arp_options: arp::Options,
 }
 
+impl Inner {
+    fn transmit_frame(&mut self, buf: Bytes) {
+        self.metrics.tx_packets += 1;
+        self.metrics.tx_bytes += buf.len();
+        let faults = match self.faults {
+            Some(ref faults) => faults.clone(),
+            None => {
+                self.outgoing.push_back(buf);
+                return;
+            },
+        };
+        if self.fault_rng.gen::<f64>() < faults.drop_probability {
+            self.metrics.tx_dropped += 1;
+            return;
+        }
+        if let Some(delay) = faults.delay {
+            let when = self.timer.0.now() + delay;
+            self.delayed.push_back((when, buf));
+            return;
+        }
+        if !self.outgoing.is_empty() && self.fault_rng.gen::<f64>() < faults.reorder_probability {
+            let ix = self.outgoing.len() - 1;
+            self.outgoing.insert(ix, buf);
+            return;
+        }
+        self.outgoing.push_back(buf);
+    }
+
+    fn release_delayed(&mut self) {
+        let now = self.timer.0.now();
+        while let Some(&(when, _)) = self.delayed.front() {
+            if when > now {
+                break;
+            }
+            let (_, buf) = self.delayed.pop_front().unwrap();
+            self.outgoing.push_back(buf);
+        }
+    }
+}
+
 impl Runtime for TestRuntime {
     type WaitFuture = crate::timer::WaitFuture<TimerRc>;
 
     fn transmit(&self, pkt: impl PacketBuf) {
-        let size = pkt.compute_size();
-        let mut buf = BytesMut::zeroed(size);
-        pkt.serialize(&mut buf[..]);
-        self.inner.borrow_mut().outgoing.push_back(buf.freeze());
+        self.inner.borrow_mut().transmit_frame(pkt.to_bytes());
     }
 
-    fn receive(&self) -> Option<Bytes> {
-        self.inner.borrow_mut().incoming.pop_front()
+    fn receive(&self) -> Option<(Bytes, Instant)> {
+        let mut inner = self.inner.borrow_mut();
+        let (buf, arrival) = inner.incoming.pop_front()?;
+        inner.metrics.rx_packets += 1;
+        inner.metrics.rx_bytes += buf.len();
+        Some((buf, arrival))
     }
 
     fn scheduler(&self) -> &Scheduler<Operation<Self>> {
@@ -161,7 +295,9 @@ impl Runtime for TestRuntime {
     }
 
     fn advance_clock(&self, now: Instant) {
-        self.inner.borrow_mut().timer.0.advance_clock(now);
+        let mut inner = self.inner.borrow_mut();
+        inner.timer.0.advance_clock(now);
+        inner.release_delayed();
     }
 
     fn wait(&self, duration: Duration) -> Self::WaitFuture {
@@ -210,3 +346,67 @@ pub fn new_carrie(now: Instant) -> Engine<TestRuntime> {
     let rt = TestRuntime::new("carrie", now, CARRIE_MAC, CARRIE_IPV4);
     Engine::new(rt).unwrap()
 }
+
+// A waker that counts how many times it's been woken, for tests that check who gets woken and how often.
+pub struct CountingWaker(AtomicUsize);
+
+impl CountingWaker {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self(AtomicUsize::new(0)))
+    }
+
+    pub fn waker(self: &Arc<Self>) -> Waker {
+        task::waker(self.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl ArcWake for CountingWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::new_alice;
+    use crate::runtime::Runtime;
+    use futures::{
+        task::noop_waker_ref,
+        Future,
+    };
+    use std::{
+        task::{
+            Context,
+            Poll,
+        },
+        thread,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    #[test]
+    fn test_auto_advance_clock() {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let start = Instant::now();
+        let alice = new_alice(start);
+        let wait_future = alice.rt().wait(Duration::from_millis(1));
+        futures::pin_mut!(wait_future);
+
+        // Without auto-advance, the timer never fires no matter how long we wait.
+        thread::sleep(Duration::from_millis(2));
+        alice.rt().poll_scheduler();
+        assert!(Future::poll(wait_future.as_mut(), &mut ctx).is_pending());
+        assert_eq!(alice.rt().now(), start);
+
+        alice.rt().set_auto_advance_clock(true);
+        alice.rt().poll_scheduler();
+        assert_eq!(Future::poll(wait_future.as_mut(), &mut ctx), Poll::Ready(()));
+        assert!(alice.rt().now() >= start + Duration::from_millis(2));
+    }
+}
@@ -124,6 +124,13 @@ impl<P: TimerPtr> Timer<P> {
         self.inner.borrow().now
     }
 
+    // When the earliest timer that's being waited on expires, if there are any.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        let inner = self.inner.borrow();
+        let first = inner.heap.peek_min()?;
+        Some(unsafe { first.as_ref().expiry })
+    }
+
     pub fn wait(&self, ptr: P, timeout: Duration) -> WaitFuture<P> {
         self.wait_until(ptr, self.now() + timeout)
     }
@@ -0,0 +1,330 @@
+#![feature(const_fn, const_panic, const_alloc_layout)]
+#![feature(const_mut_refs, const_type_name)]
+
+use tracy_client::static_span;
+use catnip::{
+    engine::Engine,
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        arp,
+        ethernet2::MacAddress,
+        ip,
+        ipv4,
+        tcp,
+    },
+    runtime::{
+        PacketBuf,
+        Runtime,
+    },
+    scheduler::{
+        Operation,
+        Scheduler,
+        SchedulerHandle,
+    },
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+    test_helpers::{
+        ALICE_IPV4,
+        ALICE_MAC,
+        BOB_IPV4,
+        BOB_MAC,
+    },
+    timer::{
+        Timer,
+        TimerRc,
+    },
+};
+use futures::FutureExt;
+use rand::{
+    distributions::{
+        Distribution,
+        Standard,
+    },
+    rngs::SmallRng,
+    Rng,
+    SeedableRng,
+};
+use std::{
+    cell::RefCell,
+    convert::TryFrom,
+    env,
+    future::Future,
+    net::Ipv4Addr,
+    rc::Rc,
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// How long either side waits for the connection to finish closing before failing the test.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Short enough that whoever closes first isn't held in TIME_WAIT past `CLOSE_TIMEOUT`.
+const MSL: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
+pub struct TestRuntime {
+    inner: Rc<RefCell<Inner>>,
+    scheduler: Scheduler<Operation<TestRuntime>>,
+}
+
+impl TestRuntime {
+    pub fn new(
+        now: Instant,
+        link_addr: MacAddress,
+        ipv4_addr: Ipv4Addr,
+        incoming: crossbeam_channel::Receiver<Bytes>,
+        outgoing: crossbeam_channel::Sender<Bytes>,
+    ) -> Self {
+        let mut arp_options = arp::Options::default();
+        arp_options.retry_count = 2;
+        arp_options.cache_ttl = Duration::from_secs(600);
+        arp_options.request_timeout = Duration::from_secs(1);
+        arp_options.initial_values.insert(ALICE_MAC, ALICE_IPV4);
+        arp_options.initial_values.insert(BOB_MAC, BOB_IPV4);
+
+        let inner = Inner {
+            timer: TimerRc(Rc::new(Timer::new(now))),
+            rng: SmallRng::from_seed([0; 16]),
+            incoming,
+            outgoing,
+            link_addr,
+            ipv4_addr,
+            tcp_options: tcp::Options::default().msl(MSL),
+            arp_options,
+        };
+        Self {
+            inner: Rc::new(RefCell::new(inner)),
+            scheduler: Scheduler::new(),
+        }
+    }
+}
+
+struct Inner {
+    timer: TimerRc,
+    rng: SmallRng,
+    incoming: crossbeam_channel::Receiver<Bytes>,
+    outgoing: crossbeam_channel::Sender<Bytes>,
+
+    link_addr: MacAddress,
+    ipv4_addr: Ipv4Addr,
+    tcp_options: tcp::Options,
+    arp_options: arp::Options,
+}
+
+impl Runtime for TestRuntime {
+    type WaitFuture = catnip::timer::WaitFuture<TimerRc>;
+
+    fn transmit(&self, pkt: impl PacketBuf) {
+        let _s = static_span!();
+        self.inner
+            .borrow_mut()
+            .outgoing
+            .try_send(pkt.to_bytes())
+            .unwrap();
+    }
+
+    fn receive(&self) -> Option<(Bytes, Instant)> {
+        let _s = static_span!();
+        let buf = self.inner.borrow_mut().incoming.try_recv().ok()?;
+        Some((buf, self.now()))
+    }
+
+    fn scheduler(&self) -> &Scheduler<Operation<Self>> {
+        &self.scheduler
+    }
+
+    fn local_link_addr(&self) -> MacAddress {
+        self.inner.borrow().link_addr
+    }
+
+    fn local_ipv4_addr(&self) -> Ipv4Addr {
+        self.inner.borrow().ipv4_addr
+    }
+
+    fn tcp_options(&self) -> tcp::Options {
+        self.inner.borrow().tcp_options.clone()
+    }
+
+    fn arp_options(&self) -> arp::Options {
+        self.inner.borrow().arp_options.clone()
+    }
+
+    fn advance_clock(&self, now: Instant) {
+        self.inner.borrow_mut().timer.0.advance_clock(now);
+    }
+
+    fn wait(&self, duration: Duration) -> Self::WaitFuture {
+        let inner = self.inner.borrow_mut();
+        let now = inner.timer.0.now();
+        inner
+            .timer
+            .0
+            .wait_until(inner.timer.clone(), now + duration)
+    }
+
+    fn wait_until(&self, when: Instant) -> Self::WaitFuture {
+        let inner = self.inner.borrow_mut();
+        inner.timer.0.wait_until(inner.timer.clone(), when)
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.borrow().timer.0.now()
+    }
+
+    fn rng_gen<T>(&self) -> T
+    where
+        Standard: Distribution<T>,
+    {
+        let mut inner = self.inner.borrow_mut();
+        inner.rng.gen()
+    }
+
+    fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) -> SchedulerHandle {
+        self.scheduler
+            .insert(Operation::Background(future.boxed_local()))
+    }
+}
+
+// Keeps the engine running until the connection on `fd` has closed, and returns how it closed.
+fn wait_for_close(engine: &mut Engine<TestRuntime>, fd: FileDescriptor) -> tcp::CloseReason {
+    let deadline = Instant::now() + CLOSE_TIMEOUT;
+    loop {
+        if let Some(reason) = engine.tcp_close_reason(fd).unwrap() {
+            return reason;
+        }
+        assert!(Instant::now() < deadline, "Timed out waiting for the connection to close");
+        let wait_future = engine.rt().wait(Duration::from_millis(1));
+        engine.run_until(wait_future);
+    }
+}
+
+// Both peers are catnip: Bob listens and accepts, echoing everything Alice sends until she closes her side.
+#[test]
+fn tcp_echo() {
+    let (forward_tx, forward_rx) = crossbeam_channel::unbounded();
+    let (backward_tx, backward_rx) = crossbeam_channel::unbounded();
+
+    let now = Instant::now();
+    let listen_addr = ipv4::Endpoint::new(BOB_IPV4, ip::Port::try_from(80).unwrap());
+
+    // Large enough that the transfer has to grow the congestion window rather than fit in the initial one.
+    let len: usize = env::var("TEST_LEN")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(256 * 1024);
+    assert!(len > 0, "TEST_LEN must be positive");
+
+    let client = thread::spawn(move || {
+        let alice_rt = TestRuntime::new(now, ALICE_MAC, ALICE_IPV4, backward_rx, forward_tx);
+        let mut alice = Engine::new(alice_rt).unwrap();
+
+        let alice_fd = alice.tcp_socket();
+        let connect_future = alice.tcp_connect(alice_fd, listen_addr);
+        alice.run_until(connect_future).unwrap();
+
+        let buf: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let push_future = alice.tcp_push(alice_fd, BytesMut::from(&buf[..]).freeze());
+        alice.run_until(push_future).unwrap();
+
+        let mut received = Vec::with_capacity(len);
+        while received.len() < len {
+            let pop_future = alice.tcp_pop(alice_fd);
+            let segment = alice.run_until(pop_future).unwrap();
+            received.extend_from_slice(&segment[..]);
+        }
+        assert_eq!(received, buf);
+
+        alice.tcp_close(alice_fd).unwrap();
+        assert_eq!(wait_for_close(&mut alice, alice_fd), tcp::CloseReason::Graceful);
+    });
+
+    let server = thread::spawn(move || {
+        let bob_rt = TestRuntime::new(now, BOB_MAC, BOB_IPV4, forward_rx, backward_tx);
+        serve_echo(bob_rt, listen_addr, len);
+    });
+
+    client.join().unwrap();
+    server.join().unwrap();
+}
+
+// Bob's side of the echo tests: accepts one connection and sends back everything on it until the client closes.
+fn serve_echo(bob_rt: TestRuntime, listen_addr: ipv4::Endpoint, len: usize) {
+    let mut bob = Engine::new(bob_rt).unwrap();
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let accept_future = bob.tcp_accept(listen_fd);
+    let (bob_fd, remote) = bob.run_until(accept_future).unwrap();
+    assert_eq!(remote.addr, ALICE_IPV4);
+
+    let mut echoed = 0;
+    loop {
+        let pop_future = bob.tcp_pop(bob_fd);
+        let segment = match bob.run_until(pop_future) {
+            Ok(segment) => segment,
+            // Alice closed her side once she had everything back.
+            Err(Fail::ResourceNotFound { .. }) => break,
+            Err(e) => panic!("Pop failed: {:?}", e),
+        };
+        echoed += segment.len();
+        let push_future = bob.tcp_push(bob_fd, segment);
+        bob.run_until(push_future).unwrap();
+    }
+    assert_eq!(echoed, len);
+
+    bob.tcp_close(bob_fd).unwrap();
+    assert_eq!(wait_for_close(&mut bob, bob_fd), tcp::CloseReason::Graceful);
+}
+
+// The same exchange, with Alice written against the blocking facade the way a `std::net` client would be.
+#[cfg(feature = "async-io")]
+#[test]
+fn tcp_echo_blocking() {
+    use catnip::protocols::tcp::blocking::BlockingConnection;
+    use std::io::{
+        Read,
+        Write,
+    };
+
+    let (forward_tx, forward_rx) = crossbeam_channel::unbounded();
+    let (backward_tx, backward_rx) = crossbeam_channel::unbounded();
+
+    let now = Instant::now();
+    let listen_addr = ipv4::Endpoint::new(BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let len = 64 * 1024;
+
+    let client = thread::spawn(move || {
+        let alice_rt = TestRuntime::new(now, ALICE_MAC, ALICE_IPV4, backward_rx, forward_tx);
+        let alice = Engine::new(alice_rt).unwrap();
+        let mut connection = BlockingConnection::connect(alice, listen_addr).unwrap();
+
+        // Written only against `std::io`, as it would be for a `std::net::TcpStream`.
+        fn round_trip<S: Read + Write>(stream: &mut S, buf: &[u8]) -> Vec<u8> {
+            stream.write_all(buf).unwrap();
+            stream.flush().unwrap();
+            let mut received = vec![0u8; buf.len()];
+            stream.read_exact(&mut received).unwrap();
+            received
+        }
+        let buf: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        assert_eq!(round_trip(&mut connection, &buf), buf);
+
+        let fd = connection.fd();
+        let mut alice = connection.close().unwrap();
+        assert_eq!(wait_for_close(&mut alice, fd), tcp::CloseReason::Graceful);
+    });
+
+    let server = thread::spawn(move || {
+        let bob_rt = TestRuntime::new(now, BOB_MAC, BOB_IPV4, forward_rx, backward_tx);
+        serve_echo(bob_rt, listen_addr, len);
+    });
+
+    client.join().unwrap();
+    server.join().unwrap();
+}
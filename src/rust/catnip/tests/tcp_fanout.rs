@@ -0,0 +1,182 @@
+#![feature(const_fn, const_mut_refs, const_type_name)]
+
+use catnip::{
+    file_table::FileDescriptor,
+    protocols::{
+        ip,
+        ipv4,
+    },
+    runtime::Runtime,
+    sync::BytesMut,
+    test_helpers::{
+        self,
+        TestEngine,
+    },
+};
+use futures::{
+    task::noop_waker_ref,
+    Future,
+};
+use must_let::must_let;
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    env,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// Upper bound on how many rounds of frame exchange the transfer may take, so a stalled flow fails the test
+// instead of hanging it.
+const MAX_ROUNDS: usize = 100_000;
+
+// Run both peers' background work and deliver every frame they emit to the other side.
+fn exchange_frames(alice: &mut TestEngine, bob: &mut TestEngine) {
+    alice.rt().poll_scheduler();
+    while let Some(frame) = alice.rt().try_pop_frame() {
+        let _ = bob.receive(frame);
+    }
+    bob.rt().poll_scheduler();
+    while let Some(frame) = bob.rt().try_pop_frame() {
+        let _ = alice.receive(frame);
+    }
+}
+
+// Opens `num_flows` connections from Alice's one engine to a single listener on Bob's, all sharing Alice's
+// scheduler. Returns Alice's descriptors and Bob's, which come back in whatever order the handshakes finished.
+fn open_flows(
+    ctx: &mut Context,
+    alice: &mut TestEngine,
+    bob: &mut TestEngine,
+    num_flows: usize,
+) -> (Vec<FileDescriptor>, Vec<FileDescriptor>) {
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, num_flows).unwrap();
+
+    let mut connecting = (0..num_flows)
+        .map(|_| {
+            let fd = alice.tcp_socket();
+            (fd, alice.tcp_connect(fd, listen_addr))
+        })
+        .collect::<Vec<_>>();
+    exchange_frames(alice, bob);
+    exchange_frames(alice, bob);
+
+    let mut alice_fds = Vec::with_capacity(num_flows);
+    for (alice_fd, connect_future) in &mut connecting {
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(connect_future), ctx));
+        alice_fds.push(*alice_fd);
+    }
+    let mut remotes = HashSet::new();
+    let bob_fds = (0..num_flows)
+        .map(|_| {
+            let mut accept_future = bob.tcp_accept(listen_fd);
+            must_let!(let Poll::Ready(Ok((bob_fd, remote))) = Future::poll(Pin::new(&mut accept_future), ctx));
+            remotes.insert(remote);
+            bob_fd
+        })
+        .collect::<Vec<_>>();
+    // Each connection got its own ephemeral port on Alice's side.
+    assert_eq!(remotes.len(), num_flows);
+
+    (alice_fds, bob_fds)
+}
+
+#[test]
+fn tcp_fanout() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let num_flows: usize = env::var("TEST_FLOWS")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(8);
+    let flow_len: usize = env::var("TEST_FLOW_LEN")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(64 * 1024);
+    assert!(num_flows > 0 && num_flows < 256, "TEST_FLOWS must be between 1 and 255");
+    assert!(flow_len > 0, "TEST_FLOW_LEN must be positive");
+
+    let (alice_fds, bob_fds) = open_flows(&mut ctx, &mut alice, &mut bob, num_flows);
+
+    // Each flow's payload is filled with its own index so Bob can tell them apart and notice any crosstalk.
+    let mut push_futures = alice_fds
+        .iter()
+        .enumerate()
+        .map(|(i, &alice_fd)| {
+            let buf = BytesMut::from(&vec![i as u8; flow_len][..]).freeze();
+            alice.tcp_push(alice_fd, buf)
+        })
+        .collect::<Vec<_>>();
+    let mut pushed = vec![false; num_flows];
+    let mut received: Vec<Option<u8>> = vec![None; num_flows];
+    let mut received_len = vec![0; num_flows];
+    let mut first_done: Option<Vec<usize>> = None;
+
+    let start = Instant::now();
+    let mut rounds = 0;
+    while received_len.iter().any(|&n| n < flow_len) {
+        rounds += 1;
+        assert!(rounds < MAX_ROUNDS, "Flows stalled at {:?} of {} bytes", received_len, flow_len);
+
+        for (i, push_future) in push_futures.iter_mut().enumerate() {
+            if !pushed[i] {
+                match Future::poll(Pin::new(push_future), &mut ctx) {
+                    Poll::Ready(Ok(())) => pushed[i] = true,
+                    Poll::Ready(Err(e)) => panic!("Push on flow {} failed: {:?}", i, e),
+                    Poll::Pending => (),
+                }
+            }
+        }
+        exchange_frames(&mut alice, &mut bob);
+
+        for (j, &bob_fd) in bob_fds.iter().enumerate() {
+            loop {
+                let mut pop_future = bob.tcp_pop(bob_fd);
+                let segment = match Future::poll(Pin::new(&mut pop_future), &mut ctx) {
+                    Poll::Ready(Ok(segment)) => segment,
+                    _ => break,
+                };
+                let tag = *received[j].get_or_insert(segment[0]);
+                assert!(segment.iter().all(|&b| b == tag), "Flow {} got another flow's data", tag);
+                received_len[j] += segment.len();
+            }
+        }
+        if first_done.is_none() && received_len.iter().any(|&n| n >= flow_len) {
+            first_done = Some(received_len.clone());
+        }
+
+        now += Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        bob.rt().advance_clock(now);
+    }
+    let elapsed = start.elapsed();
+
+    // Every flow ended up on its own connection with all of its data.
+    let mut tags = received.iter().map(|t| t.unwrap()).collect::<Vec<_>>();
+    tags.sort_unstable();
+    assert_eq!(tags, (0..num_flows as u8).collect::<Vec<_>>());
+    assert!(received_len.iter().all(|&n| n == flow_len));
+
+    // The scheduler shouldn't let any flow run away with the link: by the time the first one finished, every
+    // other flow should have made it at least halfway.
+    let first_done = first_done.unwrap();
+    println!("Received per flow when the first finished: {:?}", first_done);
+    assert!(first_done.iter().all(|&n| n >= flow_len / 2));
+
+    println!(
+        "{} flows of {} bytes in {} rounds, {:?}",
+        num_flows, flow_len, rounds, elapsed
+    );
+}
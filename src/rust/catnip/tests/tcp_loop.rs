@@ -5,6 +5,7 @@ use catnip::{
     protocols::{
         ip,
         ipv4,
+        tcp::constants::DEFAULT_MSS,
     },
     sync::{
         Bytes,
@@ -32,6 +33,24 @@ use std::{
 };
 use tracy_client::static_span;
 
+// Builds the payload from `TEST_DATA_PATTERN` repeated out to `TEST_DATA_LEN` bytes, so the receiver can check
+// the content and not just the length. Each round trip is a single segment, so the length is capped at the MSS.
+fn test_data() -> Bytes {
+    let size: usize = env::var("TEST_DATA_LEN")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(32);
+    assert!(size > 0 && size <= DEFAULT_MSS, "TEST_DATA_LEN must be between 1 and {}", DEFAULT_MSS);
+    let pattern = env::var("TEST_DATA_PATTERN")
+        .unwrap_or_else(|_| "0123456789abcdefghijklmnopqrstuvwxyz".to_owned());
+    assert!(!pattern.is_empty(), "TEST_DATA_PATTERN must not be empty");
+
+    let mut buf = BytesMut::zeroed(size);
+    for (b, p) in buf.iter_mut().zip(pattern.bytes().cycle()) {
+        *b = p;
+    }
+    buf.freeze()
+}
+
 pub fn one_send_recv_round(
     ctx: &mut Context,
     buf: Bytes,
@@ -96,15 +115,11 @@ fn tcp_loop() {
     alice.rt().poll_scheduler();
     bob.receive(alice.rt().pop_frame()).unwrap();
 
-    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok((bob_fd, _))) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
     must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
 
-    let size = 32;
-    let mut buf = BytesMut::zeroed(size);
-    for i in 0..size {
-        buf[i] = i as u8;
-    }
-    let buf = buf.freeze();
+    let buf = test_data();
+    let size = buf.len();
 
     // Send data from Alice to Bob
     let mut push_future = alice.tcp_push(alice_fd, buf.clone());
@@ -114,8 +129,9 @@ fn tcp_loop() {
 
     // Receive it on Bob's side.
     let mut pop_future = bob.tcp_pop(bob_fd);
-    must_let!(let Poll::Ready(Ok(buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
-    assert_eq!(buf.len(), size);
+    must_let!(let Poll::Ready(Ok(received_buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received_buf.len(), size);
+    assert_eq!(received_buf, buf);
 
     let num_rounds: usize = env::var("SEND_RECV_ITERS")
         .map(|s| s.parse().unwrap())
@@ -0,0 +1,112 @@
+#![feature(const_fn, const_panic, const_alloc_layout)]
+#![feature(const_mut_refs, const_type_name)]
+
+use catnip::{
+    engine::Engine,
+    loopback::{
+        self,
+        LoopbackRuntime,
+    },
+    protocols::{
+        ip,
+        ipv4,
+        tcp,
+    },
+    runtime::Runtime,
+    sync::BytesMut,
+    test_helpers::{
+        ALICE_IPV4,
+        ALICE_MAC,
+        BOB_IPV4,
+        BOB_MAC,
+    },
+};
+use std::{
+    convert::TryFrom,
+    future::Future,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// The same exchange as `tcp_echo`, but with both engines on one thread talking over a loopback pair.
+#[test]
+fn tcp_loopback() {
+    echo(false);
+}
+
+// Each run on simulated time takes exactly as long as any other, however fast it actually runs.
+#[test]
+fn tcp_loopback_simulated() {
+    let elapsed = echo(true);
+    assert!(elapsed > Duration::new(0, 0));
+    assert_eq!(echo(true), elapsed);
+}
+
+fn drive<F: Future>(simulated: bool, alice: &mut Engine<LoopbackRuntime>, bob: &mut Engine<LoopbackRuntime>, future: F) -> F::Output {
+    if simulated {
+        loopback::run_until_simulated(alice, bob, future)
+    } else {
+        loopback::run_until(alice, bob, future)
+    }
+}
+
+// Alice sends Bob a buffer, he echoes it back, and then they both close. Returns how long that took on the pair's
+// clocks.
+fn echo(simulated: bool) -> Duration {
+    let now = Instant::now();
+    let (alice_rt, bob_rt) = LoopbackRuntime::pair(now, (ALICE_MAC, ALICE_IPV4), (BOB_MAC, BOB_IPV4));
+    // They close at the same time, so both wait out TIME_WAIT, which needs to be over quickly in real time.
+    for rt in &[&alice_rt, &bob_rt] {
+        rt.set_tcp_options(rt.tcp_options().msl(Duration::from_millis(100)));
+    }
+    let mut alice = Engine::new(alice_rt).unwrap();
+    let mut bob = Engine::new(bob_rt).unwrap();
+
+    let listen_addr = ipv4::Endpoint::new(BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let accept_future = bob.tcp_accept(listen_fd);
+
+    let alice_fd = alice.tcp_socket();
+    let connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    drive(simulated, &mut alice, &mut bob, connect_future).unwrap();
+    let (bob_fd, remote) = drive(simulated, &mut alice, &mut bob, accept_future).unwrap();
+    assert_eq!(remote.addr, ALICE_IPV4);
+
+    let len = 256 * 1024;
+    let buf: Vec<u8> = (0..len).map(|i| i as u8).collect();
+    let push_future = alice.tcp_push(alice_fd, BytesMut::from(&buf[..]).freeze());
+    drive(simulated, &mut alice, &mut bob, push_future).unwrap();
+
+    // Bob echoes everything back as it comes in.
+    let mut echoed = 0;
+    while echoed < len {
+        let pop_future = bob.tcp_pop(bob_fd);
+        let segment = drive(simulated, &mut alice, &mut bob, pop_future).unwrap();
+        echoed += segment.len();
+        let push_future = bob.tcp_push(bob_fd, segment);
+        drive(simulated, &mut alice, &mut bob, push_future).unwrap();
+    }
+    let mut received = Vec::with_capacity(len);
+    while received.len() < len {
+        let pop_future = alice.tcp_pop(alice_fd);
+        let segment = drive(simulated, &mut alice, &mut bob, pop_future).unwrap();
+        received.extend_from_slice(&segment[..]);
+    }
+    assert_eq!(received, buf);
+
+    alice.tcp_close(alice_fd).unwrap();
+    bob.tcp_close(bob_fd).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while alice.tcp_close_reason(alice_fd).unwrap().is_none() || bob.tcp_close_reason(bob_fd).unwrap().is_none() {
+        assert!(Instant::now() < deadline, "Timed out waiting for the connection to close");
+        let wait_future = alice.rt().wait(Duration::from_millis(1));
+        drive(simulated, &mut alice, &mut bob, wait_future);
+    }
+    assert_eq!(alice.tcp_close_reason(alice_fd).unwrap(), Some(tcp::CloseReason::Graceful));
+    assert_eq!(bob.tcp_close_reason(bob_fd).unwrap(), Some(tcp::CloseReason::Graceful));
+    alice.rt().now() - now
+}
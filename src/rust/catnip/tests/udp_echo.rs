@@ -24,10 +24,7 @@ use catnip::{
         Scheduler,
         SchedulerHandle,
     },
-    sync::{
-        Bytes,
-        BytesMut,
-    },
+    sync::Bytes,
     test_helpers::{
         ALICE_IPV4,
         ALICE_MAC,
@@ -120,19 +117,17 @@ impl Runtime for TestRuntime {
 
     fn transmit(&self, pkt: impl PacketBuf) {
         let _s = static_span!();
-        let size = pkt.compute_size();
-        let mut buf = BytesMut::zeroed(size);
-        pkt.serialize(&mut buf[..]);
         self.inner
             .borrow_mut()
             .outgoing
-            .try_send(buf.freeze())
+            .try_send(pkt.to_bytes())
             .unwrap();
     }
 
-    fn receive(&self) -> Option<Bytes> {
+    fn receive(&self) -> Option<(Bytes, Instant)> {
         let _s = static_span!();
-        self.inner.borrow_mut().incoming.try_recv().ok()
+        let buf = self.inner.borrow_mut().incoming.try_recv().ok()?;
+        Some((buf, self.now()))
     }
 
     fn scheduler(&self) -> &Scheduler<Operation<Self>> {
@@ -6,6 +6,7 @@ use catnip::{
         ip,
         ipv4,
     },
+    runtime::Runtime,
     sync::BytesMut,
     test_helpers,
 };
@@ -115,3 +116,35 @@ fn udp_loop() {
     );
     println!("Max:   {:?}", Duration::from_nanos(h.maximum().unwrap()));
 }
+
+#[test]
+fn udp_run_until() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let port = ip::Port::try_from(80).unwrap();
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port);
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
+
+    let alice_fd = alice.socket(Protocol::Udp);
+    alice.bind(alice_fd, alice_addr).unwrap();
+    alice.connect(alice_fd, bob_addr);
+    let bob_fd = bob.socket(Protocol::Udp);
+    bob.bind(bob_fd, bob_addr).unwrap();
+
+    // Hand Alice's datagram to Bob's runtime as if it had come in off the wire, and let `run_until` take it from
+    // there.
+    let buf = BytesMut::from(&b"hello"[..]).freeze();
+    alice.udp_push(alice_fd, buf.clone()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.rt().push_frame(alice.rt().pop_frame());
+    let pop_future = bob.udp_pop(bob_fd);
+    let (remote, recv_buf) = bob.run_until(pop_future).unwrap();
+    assert_eq!(remote, Some(alice_addr));
+    assert_eq!(recv_buf, buf);
+
+    // The clock keeps moving while we wait, so timers fire on their own.
+    let wait_future = bob.rt().wait(Duration::from_millis(1));
+    bob.run_until(wait_future);
+}
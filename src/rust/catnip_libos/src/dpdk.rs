@@ -8,6 +8,7 @@ use crate::{
         rte_eth_dev_count_avail,
         rte_eth_dev_flow_ctrl_get,
         rte_eth_dev_flow_ctrl_set,
+        rte_eth_dev_get_port_by_name,
         rte_eth_dev_info_get,
         rte_eth_dev_is_valid_port,
         rte_eth_dev_start,
@@ -63,11 +64,19 @@ macro_rules! expect_zero {
     }};
 }
 
+// Which ethernet port to run on, either by DPDK's port id or by device name (e.g. a PCI address or a vdev name like
+// `net_tap0`).
+pub enum DpdkPort {
+    Id(u16),
+    Name(CString),
+}
+
 pub fn initialize_dpdk(
     local_ipv4_addr: Ipv4Addr,
     eal_init_args: &[CString],
     arp_table: HashMap<MacAddress, Ipv4Addr>,
     disable_arp: bool,
+    port: Option<DpdkPort>,
 ) -> Result<DPDKRuntime, Error> {
     std::env::set_var("MLX5_SHUT_UP_BF", "1");
     let eal_init_refs = eal_init_args
@@ -102,18 +111,37 @@ pub fn initialize_dpdk(
     if mbuf_pool.is_null() {
         Err(format_err!("rte_pktmbuf_pool_create failed"))?;
     }
-    let mut port_id = 0;
-    {
-        let owner = RTE_ETH_DEV_NO_OWNER as u64;
-        let mut p = unsafe { rte_eth_find_next_owned_by(0, owner) as u16 };
+    let port_id = match port {
+        Some(DpdkPort::Id(port_id)) => {
+            if unsafe { rte_eth_dev_is_valid_port(port_id) } == 0 {
+                bail!("Invalid port {}", port_id);
+            }
+            initialize_dpdk_port(port_id, mbuf_pool)?;
+            port_id
+        },
+        Some(DpdkPort::Name(name)) => {
+            let mut port_id = 0;
+            unsafe { expect_zero!(rte_eth_dev_get_port_by_name(name.as_ptr(), &mut port_id as *mut _)) }
+                .map_err(|e| format_err!("Couldn't find port {:?}: {}", name, e))?;
+            initialize_dpdk_port(port_id, mbuf_pool)?;
+            port_id
+        },
+        // Without a port in the config, bring up every port we can find and run on the last one.
+        None => {
+            let mut port_id = 0;
+            let owner = RTE_ETH_DEV_NO_OWNER as u64;
+            let mut p = unsafe { rte_eth_find_next_owned_by(0, owner) as u16 };
 
-        while p < RTE_MAX_ETHPORTS as u16 {
-            // TODO: This is pretty hax, we clearly only support one port.
-            port_id = p;
-            initialize_dpdk_port(p, mbuf_pool)?;
-            p = unsafe { rte_eth_find_next_owned_by(p + 1, owner) as u16 };
-        }
-    }
+            while p < RTE_MAX_ETHPORTS as u16 {
+                // TODO: This is pretty hax, we clearly only support one port.
+                port_id = p;
+                initialize_dpdk_port(p, mbuf_pool)?;
+                p = unsafe { rte_eth_find_next_owned_by(p + 1, owner) as u16 };
+            }
+            port_id
+        },
+    };
+    eprintln!("Using DPDK port {}", port_id);
 
     // TODO: Where is this function?
     // if unsafe { rte_lcore_count() } > 1 {
@@ -51,7 +51,10 @@ mod bindings;
 mod dpdk;
 mod runtime;
 
-use crate::runtime::DPDKRuntime;
+use crate::{
+    dpdk::DpdkPort,
+    runtime::DPDKRuntime,
+};
 use anyhow::{
     format_err,
     Error,
@@ -164,7 +167,17 @@ pub extern "C" fn dmtr_init(argc: c_int, argv: *mut *mut c_char) -> c_int {
             _ => Err(format_err!("Malformed YAML config"))?,
         };
 
-        let runtime = self::dpdk::initialize_dpdk(local_ipv4_addr, &eal_init_args, arp_table, disable_arp)?;
+        let port = match (&config_obj["dpdk"]["port_id"], &config_obj["dpdk"]["port_name"]) {
+            (Yaml::BadValue, Yaml::BadValue) => None,
+            (Yaml::Integer(id), Yaml::BadValue) => {
+                let id = u16::try_from(*id).map_err(|_| format_err!("Invalid port_id in config"))?;
+                Some(DpdkPort::Id(id))
+            },
+            (Yaml::BadValue, Yaml::String(name)) => Some(DpdkPort::Name(CString::new(name.as_str())?)),
+            _ => Err(format_err!("Expected at most one of an integer port_id or a string port_name in config"))?,
+        };
+
+        let runtime = self::dpdk::initialize_dpdk(local_ipv4_addr, &eal_init_args, arp_table, disable_arp, port)?;
         logging::initialize();
         LibOS::new(runtime)?
     };
@@ -21,6 +21,8 @@ use catnip::{
         SchedulerHandle,
     },
     sync::{
+        BufferPool,
+        BufferPoolStats,
         Bytes,
         BytesMut,
     },
@@ -57,6 +59,12 @@ use std::{
 
 const MAX_QUEUE_DEPTH: usize = 4;
 
+// Received frames are copied into buffers from a fixed pool rather than freshly allocated ones. Buffers a connection
+// is still holding on to can't be reused, so this also caps how much received data we'll buffer across all
+// connections: once the pool runs dry we drop incoming frames until the application catches up.
+const RX_BUFFER_SIZE: usize = 2048;
+const RX_POOL_SIZE: usize = 8192;
+
 #[derive(Clone)]
 pub struct TimerRc(Rc<Timer<TimerRc>>);
 
@@ -126,12 +134,50 @@ impl DPDKRuntime {
 
             num_buffered: 0,
             buffered: unsafe { buffered.assume_init() },
+            buffered_at: now,
+            rx_pool: BufferPool::new(RX_BUFFER_SIZE, RX_POOL_SIZE),
+            rx_malformed: 0,
         };
         Self {
             inner: Rc::new(RefCell::new(inner)),
             scheduler: Scheduler::new(),
         }
     }
+
+    pub fn rx_pool_stats(&self) -> BufferPoolStats {
+        self.inner.borrow().rx_pool.stats()
+    }
+
+    // Frames dropped because their mbuf chain was inconsistent with the length the NIC reported for them.
+    pub fn rx_malformed(&self) -> usize {
+        self.inner.borrow().rx_malformed
+    }
+}
+
+// Copies a multi-segment mbuf chain into a single buffer, or returns `None` if the segments don't add up to the
+// packet length in the head mbuf.
+unsafe fn gather_segments(packet: *mut rte_mbuf) -> Option<Bytes> {
+    let pkt_len = (*packet).pkt_len as usize;
+    if pkt_len == 0 {
+        return None;
+    }
+    let mut buf = BytesMut::zeroed(pkt_len);
+    let mut offset = 0;
+    let mut segment = packet;
+    while !segment.is_null() {
+        let data_len = (*segment).data_len as usize;
+        if offset + data_len > pkt_len {
+            return None;
+        }
+        let p = ((*segment).buf_addr as *const u8).offset((*segment).data_off as isize);
+        buf[offset..(offset + data_len)].copy_from_slice(slice::from_raw_parts(p, data_len));
+        offset += data_len;
+        segment = (*segment).next;
+    }
+    if offset != pkt_len {
+        return None;
+    }
+    Some(buf.freeze())
 }
 
 struct Inner {
@@ -147,14 +193,21 @@ struct Inner {
 
     num_buffered: usize,
     buffered: [Bytes; MAX_QUEUE_DEPTH],
+    // When the frames in `buffered` came off the NIC. They all arrive in the same RX burst.
+    buffered_at: Instant,
+    rx_pool: BufferPool,
+    rx_malformed: usize,
 }
 
 impl Runtime for DPDKRuntime {
     type WaitFuture = WaitFuture<TimerRc>;
 
     fn transmit(&self, buf: impl PacketBuf) {
-        let pool = { self.inner.borrow().dpdk_mempool };
-        let dpdk_port_id = { self.inner.borrow().dpdk_port_id };
+        // Copy out what we need in one borrow so nothing is held across the serialize and TX burst below.
+        let (pool, dpdk_port_id) = {
+            let inner = self.inner.borrow();
+            (inner.dpdk_mempool, inner.dpdk_port_id)
+        };
         let mut pkt = unsafe { catnip_libos_alloc_pkt(pool) };
         assert!(!pkt.is_null());
 
@@ -178,13 +231,14 @@ impl Runtime for DPDKRuntime {
         assert_eq!(num_sent, 1);
     }
 
-    fn receive(&self) -> Option<Bytes> {
+    fn receive(&self) -> Option<(Bytes, Instant)> {
         let mut inner = self.inner.borrow_mut();
         loop {
             if inner.num_buffered > 0 {
                 inner.num_buffered -= 1;
                 let ix = inner.num_buffered;
-                return Some(mem::replace(&mut inner.buffered[ix], Bytes::empty()));
+                let buf = mem::replace(&mut inner.buffered[ix], Bytes::empty());
+                return Some((buf, inner.buffered_at));
             }
 
             let dpdk_port = inner.dpdk_port_id;
@@ -203,21 +257,49 @@ impl Runtime for DPDKRuntime {
             if nb_rx == 0 {
                 return None;
             }
+            // Read the clock once for the whole burst. The runtime's clock only moves on `advance_clock`, so it
+            // would stamp these with whenever the loop last got around to that rather than when they came in.
+            inner.buffered_at = Instant::now();
             // let dev = unsafe { rte_eth_devices[dpdk_port as usize] };
             // let rx_burst = dev.rx_pkt_burst.expect("Missing RX burst function");
             // // This only supports queue_id 0.
             // let nb_rx = unsafe { (rx_burst)(*(*dev.data).rx_queues, todo!(), MAX_QUEUE_DEPTH as u16) };
 
             for &packet in &packets[..nb_rx as usize] {
+                // A frame too big for one mbuf comes back as a chain of them. Copying just the first segment would
+                // hand the engine a truncated frame, so gather the whole chain instead, and drop the frame if the
+                // chain doesn't add up to the length the NIC reported.
+                if unsafe { (*packet).nb_segs } > 1 {
+                    match unsafe { gather_segments(packet) } {
+                        Some(buf) => {
+                            let ix = inner.num_buffered;
+                            inner.buffered[ix] = buf;
+                            inner.num_buffered += 1;
+                        },
+                        None => inner.rx_malformed += 1,
+                    }
+                    unsafe { catnip_libos_free_pkt(packet as *const _ as *mut _) };
+                    continue;
+                }
+
                 // auto * const p = rte_pktmbuf_mtod(packet, uint8_t *);
                 let p = unsafe {
                     ((*packet).buf_addr as *const u8).offset((*packet).data_off as isize)
                 };
 
                 let data = unsafe { slice::from_raw_parts(p, (*packet).data_len as usize) };
-                let ix = inner.num_buffered;
-                inner.buffered[ix] = BytesMut::from(data).freeze();
-                inner.num_buffered += 1;
+                let buf = match inner.rx_pool.copy_from(data) {
+                    Some(buf) => Some(buf),
+                    // Jumbo frames don't fit in a pool buffer, so give them their own allocation.
+                    None if data.len() > RX_BUFFER_SIZE => Some(BytesMut::from(data).freeze()),
+                    // The pool is exhausted, which shows up in `rx_pool_stats`.
+                    None => None,
+                };
+                if let Some(buf) = buf {
+                    let ix = inner.num_buffered;
+                    inner.buffered[ix] = buf;
+                    inner.num_buffered += 1;
+                }
 
                 unsafe { catnip_libos_free_pkt(packet as *const _ as *mut _) };
             }